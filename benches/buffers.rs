@@ -0,0 +1,273 @@
+//! Benchmarks of the shadow buffers and the save path, run with `cargo bench`.
+//!
+//! Each buffer bench inserts 10 minutes of capture into a buffer holding 60 or 300 seconds, so
+//! nearly every insert also trims. The time per insert should not depend on how long the buffer
+//! is, if the 300s run gets noticeably slower than the 60s one something went back to removing
+//! frames in O(n).
+//!
+//! Baseline on a single core Intel Xeon VM, recorded when the benches were added. Compare
+//! against a run on the same machine rather than these numbers, what matters is the ratio
+//! between the runs:
+//!
+//! | bench             | time    | per frame |
+//! |-------------------|---------|-----------|
+//! | video_insert/60s  | 140 ms  | 0.97 us   |
+//! | video_insert/300s | 143 ms  | 1.0 us    |
+//! | audio_insert/60s  | 6.5 ms  | 0.22 us   |
+//! | audio_insert/300s | 8.8 ms  | 0.29 us   |
+//! | save/300s         | 13.4 ms | 0.15 us   |
+//!
+//! `save_lock` compares how long a save of a full 300s buffer keeps the capture from inserting
+//! into it. A save used to hold the buffer locks for the whole mux, copying every packet into
+//! libav like `FileSink` does, now the locks are only held to snapshot the buffers, which shares
+//! the encoded frames instead of copying them. On the same VM:
+//!
+//! | bench                        | time    |
+//! |------------------------------|---------|
+//! | save_lock/mux_and_copy/300s  | 47.8 ms |
+//! | save_lock/snapshot/300s      | 7.0 ms  |
+//!
+//! Writing the clip to disk comes on top of the first, the locks used to be held for that too.
+//!
+//! The crate is a binary, so the modules under test are included by path.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ffmpeg_next::{codec::Parameters, Rational};
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+#[allow(dead_code)]
+#[path = "../src/dbus_types.rs"]
+mod dbus_types;
+
+#[allow(dead_code)]
+#[path = "../src/clips"]
+mod clips {
+    pub mod markers;
+}
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/encoders"]
+mod encoders {
+    pub mod buffer;
+    pub mod muxer;
+}
+
+use clips::markers::Chapter;
+use encoders::{
+    buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer},
+    muxer::{ClipMuxer, MuxPacket, PacketSink, StreamParams},
+};
+
+const CAPTURE_SECONDS: i64 = 10 * 60;
+const VIDEO_FPS: i64 = 240;
+/// One key frame per second of video.
+const GOP_FRAMES: i64 = VIDEO_FPS;
+const P_FRAME_BYTES: usize = 4 * 1024;
+const KEY_FRAME_BYTES: usize = 40 * 1024;
+/// 20ms Opus frames at 48kHz.
+const AUDIO_FRAME_SAMPLES: i64 = 960;
+const AUDIO_FRAMES_PER_SECOND: i64 = 48_000 / AUDIO_FRAME_SAMPLES;
+const AUDIO_FRAME_BYTES: usize = 160;
+
+const VIDEO_FRAMES: i64 = CAPTURE_SECONDS * VIDEO_FPS;
+const AUDIO_FRAMES: i64 = CAPTURE_SECONDS * AUDIO_FRAMES_PER_SECOND;
+
+fn video_frame(index: i64) -> (i64, EncodedVideoFrame) {
+    let pts = index * 1_000_000 / VIDEO_FPS;
+    let is_keyframe = index % GOP_FRAMES == 0;
+    let size = match is_keyframe {
+        true => KEY_FRAME_BYTES,
+        false => P_FRAME_BYTES,
+    };
+    let frame = EncodedVideoFrame {
+        data: vec![0; size],
+        is_keyframe,
+        pts,
+        dts: pts,
+    };
+    (pts, frame)
+}
+
+fn fill_video(buffer: &mut ShadowCaptureVideoBuffer, frames: i64) {
+    for index in 0..frames {
+        let (dts, frame) = video_frame(index);
+        buffer.insert(dts, frame);
+    }
+}
+
+fn fill_audio(buffer: &mut ShadowCaptureAudioBuffer, frames: i64) {
+    for index in 0..frames {
+        buffer.insert_capture_time(index * 1_000_000 / AUDIO_FRAMES_PER_SECOND);
+        buffer.insert(index * AUDIO_FRAME_SAMPLES, vec![0; AUDIO_FRAME_BYTES]);
+    }
+}
+
+fn video_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("video_insert");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(VIDEO_FRAMES as u64));
+    for seconds in [60, 300] {
+        group.bench_function(format!("{seconds}s"), |b| {
+            b.iter_batched_ref(
+                || ShadowCaptureVideoBuffer::new(seconds * 1_000_000),
+                |buffer| fill_video(buffer, VIDEO_FRAMES),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn audio_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("audio_insert");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(AUDIO_FRAMES as u64));
+    for seconds in [60, 300] {
+        group.bench_function(format!("{seconds}s"), |b| {
+            b.iter_batched_ref(
+                || ShadowCaptureAudioBuffer::new(seconds * 1_000_000),
+                |buffer| fill_audio(buffer, AUDIO_FRAMES),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Throws the packets away, so only the muxer's own work is measured.
+#[derive(Default)]
+struct NullSink {
+    streams: usize,
+    bytes: usize,
+}
+
+impl PacketSink for NullSink {
+    fn add_stream(&mut self, _params: &StreamParams) -> anyhow::Result<usize> {
+        self.streams += 1;
+        Ok(self.streams - 1)
+    }
+
+    fn add_chapter(&mut self, _chapter: &Chapter) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn add_metadata(&mut self, _key: &str, _value: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_packet(&mut self, _stream: usize, packet: &MuxPacket) -> anyhow::Result<()> {
+        self.bytes += packet.data.len();
+        Ok(())
+    }
+
+    fn write_trailer(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Copies every packet like [`encoders::muxer::FileSink`] does when handing it to libav.
+#[derive(Default)]
+struct CopySink {
+    inner: NullSink,
+}
+
+impl PacketSink for CopySink {
+    fn add_stream(&mut self, params: &StreamParams) -> anyhow::Result<usize> {
+        self.inner.add_stream(params)
+    }
+
+    fn add_chapter(&mut self, chapter: &Chapter) -> anyhow::Result<()> {
+        self.inner.add_chapter(chapter)
+    }
+
+    fn add_metadata(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.inner.add_metadata(key, value)
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        self.inner.write_header()
+    }
+
+    fn write_packet(&mut self, stream: usize, packet: &MuxPacket) -> anyhow::Result<()> {
+        black_box(packet.data.to_vec());
+        self.inner.write_packet(stream, packet)
+    }
+
+    fn write_trailer(&mut self) -> anyhow::Result<()> {
+        self.inner.write_trailer()
+    }
+}
+
+/// Full buffers of `seconds` and a muxer for them.
+fn full_buffers(
+    seconds: usize,
+) -> (
+    ShadowCaptureVideoBuffer,
+    ShadowCaptureAudioBuffer,
+    ClipMuxer,
+) {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(seconds * 1_000_000);
+    fill_video(&mut video_buffer, seconds as i64 * VIDEO_FPS);
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(seconds * 1_000_000);
+    fill_audio(&mut audio_buffer, seconds as i64 * AUDIO_FRAMES_PER_SECOND);
+
+    let params = |time_base| StreamParams {
+        codec: None,
+        parameters: Parameters::new(),
+        time_base,
+        frame_rate: None,
+    };
+    let muxer = ClipMuxer::new(
+        params(Rational::new(1, 1_000_000)),
+        Some(params(Rational::new(1, 48_000))),
+    );
+    (video_buffer, audio_buffer, muxer)
+}
+
+fn save(c: &mut Criterion) {
+    let seconds = 300;
+    let (video_buffer, audio_buffer, muxer) = full_buffers(seconds);
+
+    let mut group = c.benchmark_group("save");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(
+        (video_buffer.get_frames().len() + audio_buffer.get_frames().len()) as u64,
+    ));
+    group.bench_function(format!("{seconds}s"), |b| {
+        b.iter(|| {
+            let mut sink = NullSink::default();
+            muxer
+                .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+                .unwrap();
+            black_box(sink.bytes)
+        })
+    });
+    group.finish();
+}
+
+fn save_lock(c: &mut Criterion) {
+    let seconds = 300;
+    let (video_buffer, audio_buffer, muxer) = full_buffers(seconds);
+
+    let mut group = c.benchmark_group("save_lock");
+    group.sample_size(10);
+    group.bench_function(format!("mux_and_copy/{seconds}s"), |b| {
+        b.iter(|| {
+            let mut sink = CopySink::default();
+            muxer
+                .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+                .unwrap();
+            black_box(sink.inner.bytes)
+        })
+    });
+    group.bench_function(format!("snapshot/{seconds}s"), |b| {
+        b.iter(|| black_box((video_buffer.clone(), audio_buffer.clone())))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, video_insert, audio_insert, save, save_lock);
+criterion_main!(benches);
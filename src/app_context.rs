@@ -1,12 +1,142 @@
-use std::sync::{atomic::AtomicBool, Arc};
-use waycap_rs::Capture;
+use crossbeam::channel::Receiver;
+use std::sync::{
+    atomic::{AtomicBool, AtomicI64},
+    Arc,
+};
+use waycap_rs::{
+    types::{audio_frame::EncodedAudioFrame, video_frame::EncodedVideoFrame},
+    Capture,
+};
 
-use crate::application_config::AppConfig;
+use crate::{
+    application_config::AppConfig,
+    audio_levels::{AudioLevelHistory, LevelMeter},
+    audio_stream_params,
+    capture_watch::CaptureLoss,
+    encoders::muxer::StreamParams,
+    inhibit::Inhibitor,
+    stats::{DropCounters, EncodeCounters},
+};
+
+/// The calls the run loop and the modes make on the capture. [`Capture`] is the one used for
+/// real, the tests drive the run loop with one which captures nothing.
+pub trait CaptureControl: Send + Sync {
+    fn start(&mut self) -> anyhow::Result<()>;
+    fn pause(&mut self) -> anyhow::Result<()>;
+    fn finish(&mut self) -> anyhow::Result<()>;
+    fn reset(&mut self) -> anyhow::Result<()>;
+    fn close(&mut self) -> anyhow::Result<()>;
+    fn video_receiver(&mut self) -> Receiver<EncodedVideoFrame>;
+    fn audio_receiver(&mut self) -> anyhow::Result<Receiver<EncodedAudioFrame>>;
+    /// Stream parameters of the video encoder, `None` if there is none.
+    fn video_params(&self) -> Option<StreamParams>;
+    /// Stream parameters of the audio encoder. Only asked for if the capture was built with
+    /// audio, see [`AppContext::has_audio`].
+    fn audio_params(&self) -> Option<StreamParams>;
+}
+
+impl CaptureControl for Capture {
+    fn start(&mut self) -> anyhow::Result<()> {
+        Ok(Capture::start(self)?)
+    }
+
+    fn pause(&mut self) -> anyhow::Result<()> {
+        Ok(Capture::pause(self)?)
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(Capture::finish(self)?)
+    }
+
+    fn reset(&mut self) -> anyhow::Result<()> {
+        Ok(Capture::reset(self)?)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        Ok(Capture::close(self)?)
+    }
+
+    fn video_receiver(&mut self) -> Receiver<EncodedVideoFrame> {
+        self.get_video_receiver()
+    }
+
+    fn audio_receiver(&mut self) -> anyhow::Result<Receiver<EncodedAudioFrame>> {
+        Ok(self.get_audio_receiver()?)
+    }
+
+    fn video_params(&self) -> Option<StreamParams> {
+        self.with_video_encoder(|enc| {
+            enc.as_ref().map(|encoder| StreamParams {
+                codec: encoder.codec(),
+                parameters: encoder.into(),
+                time_base: encoder.time_base(),
+                frame_rate: None,
+            })
+        })
+    }
+
+    fn audio_params(&self) -> Option<StreamParams> {
+        self.with_audio_encoder(|enc| {
+            enc.as_ref().map(|encoder| StreamParams {
+                codec: encoder.codec(),
+                parameters: encoder.into(),
+                time_base: encoder.time_base(),
+                frame_rate: None,
+            })
+        })
+    }
+}
 
 pub struct AppContext {
     pub saving: Arc<AtomicBool>,
+    /// Set over dbus to abort the running save, checked by the muxer between packets.
+    pub cancel_save: Arc<AtomicBool>,
     pub stop: Arc<AtomicBool>,
+    /// Wall clock time in milliseconds at which the last video frame was received.
+    pub last_video_frame: Arc<AtomicI64>,
+    /// Wall clock time in milliseconds at which the last audio frame was received.
+    pub last_audio_frame: Arc<AtomicI64>,
+    pub drops: Arc<DropCounters>,
+    pub encode: Arc<EncodeCounters>,
+    /// Levels of the most recent audio, see [`Self::level_meter`].
+    pub levels: Arc<AudioLevelHistory>,
     pub join_handles: Vec<std::thread::JoinHandle<()>>,
-    pub capture: Capture,
+    pub capture: Box<dyn CaptureControl>,
     pub config: AppConfig,
+    /// Whether the capture was built with an audio encoder. waycap-rs panics when asked for the
+    /// audio encoder of a capture without one.
+    pub has_audio: bool,
+    /// Set while the capture is paused over dbus.
+    pub paused: bool,
+    /// Set once the screencast is gone, until `Reconnect` builds a new capture.
+    pub capture_lost: Option<CaptureLoss>,
+    pub inhibitor: Inhibitor,
+}
+
+impl AppContext {
+    /// Starts the capture unless it was paused over dbus, in which case resuming starts it.
+    pub fn start_capture(&mut self) -> anyhow::Result<()> {
+        if !self.paused {
+            self.capture.start()?;
+        }
+        Ok(())
+    }
+
+    /// The capture's audio frames, or a receiver which never yields any if the capture was built
+    /// without audio.
+    pub fn audio_receiver(&mut self) -> anyhow::Result<Receiver<EncodedAudioFrame>> {
+        if !self.has_audio {
+            return Ok(crossbeam::channel::never());
+        }
+        self.capture.audio_receiver()
+    }
+
+    /// A meter for the audio worker of a mode to feed [`Self::levels`] with, `None` if the
+    /// capture records no audio or it can't be decoded.
+    pub fn level_meter(&self) -> Option<LevelMeter> {
+        let params = audio_stream_params(self)?;
+        LevelMeter::new(&params, Arc::clone(&self.levels))
+            .inspect_err(|e| log::warn!("Audio levels are unavailable: {e:#}"))
+            .ok()
+    }
 }
@@ -1,12 +1,29 @@
-use std::{fs, path::Path};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use anyhow::Result;
-use config::{Config, File};
+use anyhow::{Context, Result};
+use config::{Config, File, FileFormat};
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
-use zbus::zvariant::Type;
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub use crate::dbus_types::{AppConfigDbus, AppModeDbus};
+use crate::{
+    cli::ConfigOverrides,
+    clips::{audio_events::AudioEventsConfig, retention::RetentionConfig},
+    config_migration::{migrate, replace_with_migrated, version_of, CONFIG_VERSION},
+    encoders::tiering::TieringConfig,
+    logging::LoggingConfig,
+    shortcuts::ShortcutsConfig,
+    thread_priority::ThreadsConfig,
+};
+
+/// Passed as the target frame rate for no cap, pacing frames at 1µs lets every frame through.
+const UNCAPPED_FPS: u64 = 1_000_000;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum QualityPreset {
     Low,
@@ -15,127 +32,410 @@ pub enum QualityPreset {
     Ultra,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+impl From<QualityPreset> for waycap_rs::types::config::QualityPreset {
+    fn from(quality: QualityPreset) -> Self {
+        match quality {
+            QualityPreset::Low => Self::Low,
+            QualityPreset::Medium => Self::Medium,
+            QualityPreset::High => Self::High,
+            QualityPreset::Ultra => Self::Ultra,
+        }
+    }
+}
+
+impl FromStr for QualityPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "low" => Ok(QualityPreset::Low),
+            "medium" => Ok(QualityPreset::Medium),
+            "high" => Ok(QualityPreset::High),
+            "ultra" => Ok(QualityPreset::Ultra),
+            other => Err(format!(
+                "Unknown quality value: {:?}, Valid values: {:?}",
+                other,
+                vec!(
+                    QualityPreset::Low,
+                    QualityPreset::Medium,
+                    QualityPreset::High,
+                    QualityPreset::Ultra
+                )
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum EncoderToUse {
     H264Nvenc,
     H264Vaapi,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(default)]
+impl EncoderToUse {
+    /// Whether the encoder runs on the GPU. Both do, the capture has no software encoder.
+    pub fn is_hardware(self) -> bool {
+        match self {
+            EncoderToUse::H264Nvenc | EncoderToUse::H264Vaapi => true,
+        }
+    }
+}
+
+impl FromStr for EncoderToUse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "h264_nvenc" => Ok(EncoderToUse::H264Nvenc),
+            "h264_vaapi" => Ok(EncoderToUse::H264Vaapi),
+            other => Err(format!(
+                "Unknown encoder: {:?}, Valid values: {:?}",
+                other,
+                vec!(EncoderToUse::H264Nvenc, EncoderToUse::H264Vaapi)
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
 pub struct AppConfig {
+    /// Format of the file, older ones are migrated when they are read.
+    pub version: u32,
     pub encoder: EncoderToUse,
     pub max_seconds: u32,
+    /// How much of the buffer `SaveClip` saves, starting at the key frame before it. The whole
+    /// buffer is saved when left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_clip_seconds: Option<u32>,
     pub use_mic: bool,
+    /// Capture the desktop audio alongside the video.
+    pub audio: bool,
     pub quality: QualityPreset,
+    /// Delay of the audio against the video in saved clips, negative moves it earlier. Makes up
+    /// for a fixed skew such as the latency of a Bluetooth headset.
+    pub audio_offset_ms: i32,
+    /// Frames arriving sooner than `1/max_fps` after the last encoded one are dropped before they
+    /// reach the encoder. 0 encodes every frame PipeWire delivers.
+    pub max_fps: u32,
+    /// Upper bound on the memory used by the shadow buffers. The buffered window gets shorter
+    /// than `max_seconds` once this is reached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_buffer_mb: Option<u32>,
+    /// Mux MP4 clips with the moov atom up front so they can be played while still downloading.
+    pub faststart: bool,
+    /// Restart the capture when no video frames arrive for this many seconds. 0 disables it.
+    pub stall_timeout_seconds: u32,
+    /// Save whatever is in the shadow buffer when the application shuts down.
+    pub save_on_exit: bool,
+    /// Write the shadow buffer to the state directory on shutdown and load it back on the next
+    /// start, so a restart keeps the buffered footage. The file is as large as the buffer.
+    pub persist_buffer: bool,
+    /// Keep the session from suspending or going idle in shadow mode too. Record and stream
+    /// mode, and recording in hybrid mode, always do. Takes effect the next time the mode starts.
+    pub inhibit_suspend_in_shadow: bool,
+    /// Directory the clips are saved to.
+    pub output_dir: PathBuf,
+    /// Limits after which old clips in `output_dir` are deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionConfig>,
+    /// Command run after every successful save, with the clip path substituted for `{path}` or
+    /// appended.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_save_command: Option<String>,
+    /// Run `post_save_command` through `sh -c` instead of splitting it into arguments.
+    pub post_save_shell: bool,
+    /// The post save command is killed after this many seconds.
+    pub post_save_timeout_seconds: u32,
+    /// Record mode starts a new file every this many minutes. 0 records into a single file.
+    pub segment_minutes: u32,
+    /// Audio-only mode records the audio to a file instead of buffering it.
+    pub audio_only_record: bool,
+    /// Export the capture as a PipeWire video source other applications such as OBS can show.
+    /// Decoding it costs CPU while a consumer is connected, so it is off by default.
+    pub preview_stream: bool,
+    /// `rtmp://` or `srt://` URL stream mode pushes the capture to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_url: Option<String>,
+    /// `host:port` the Prometheus metrics are served on. Only used when built with the `metrics`
+    /// feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_address: Option<String>,
+    pub logging: LoggingConfig,
+    /// Shortcuts registered through the desktop portal at startup.
+    pub shortcuts: ShortcutsConfig,
+    pub threads: ThreadsConfig,
+    /// Re-encoding of the oldest shadow footage at a lower quality.
+    pub tiering: TieringConfig,
+    /// Automatic markers on loud moments of the audio.
+    pub audio_events: AudioEventsConfig,
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             encoder: EncoderToUse::H264Vaapi,
             max_seconds: 300,
+            default_clip_seconds: None,
             use_mic: false,
+            audio: true,
             quality: QualityPreset::Medium,
+            audio_offset_ms: 0,
+            max_fps: 60,
+            max_buffer_mb: None,
+            faststart: true,
+            stall_timeout_seconds: 10,
+            save_on_exit: false,
+            persist_buffer: false,
+            inhibit_suspend_in_shadow: false,
+            output_dir: PathBuf::from("."),
+            retention: None,
+            post_save_command: None,
+            post_save_shell: false,
+            post_save_timeout_seconds: 300,
+            segment_minutes: 0,
+            audio_only_record: false,
+            preview_stream: false,
+            stream_url: None,
+            metrics_address: None,
+            logging: LoggingConfig::default(),
+            shortcuts: ShortcutsConfig::default(),
+            threads: ThreadsConfig::default(),
+            tiering: TieringConfig::default(),
+            audio_events: AudioEventsConfig::default(),
         }
     }
 }
 
-#[derive(Type, Serialize, Deserialize)]
-pub struct AppConfigDbus {
-    pub encoder: String,
-    pub max_seconds: u32,
-    pub use_mic: bool,
-    pub quality: String,
-}
-
-impl TryFrom<AppConfigDbus> for AppConfig {
-    type Error = String;
+impl AppConfig {
+    /// Returns the names of the fields which differ from `new` but are baked into the capture
+    /// pipeline, meaning they only take effect after a restart.
+    pub fn fields_requiring_rebuild(&self, new: &AppConfig) -> Vec<String> {
+        let mut fields = Vec::new();
+        if self.encoder != new.encoder {
+            fields.push("encoder".to_string());
+        }
+        if self.use_mic != new.use_mic {
+            fields.push("use_mic".to_string());
+        }
+        if self.audio != new.audio {
+            fields.push("audio".to_string());
+        }
+        if self.quality != new.quality {
+            fields.push("quality".to_string());
+        }
+        if self.max_fps != new.max_fps {
+            fields.push("max_fps".to_string());
+        }
+        if self.logging != new.logging {
+            fields.push("logging".to_string());
+        }
+        if self.metrics_address != new.metrics_address {
+            fields.push("metrics_address".to_string());
+        }
+        if self.shortcuts != new.shortcuts {
+            fields.push("shortcuts".to_string());
+        }
+        fields
+    }
 
-    fn try_from(value: AppConfigDbus) -> Result<Self, Self::Error> {
-        let encoder = match value.encoder.to_lowercase().as_str() {
-            "h264_nvenc" => Ok(EncoderToUse::H264Nvenc),
-            "h264_vaapi" => Ok(EncoderToUse::H264Vaapi),
-            other => Err(format!(
-                "Unknown encoder: {:?}, Valid values: {:?}",
-                other,
-                vec!(EncoderToUse::H264Nvenc, EncoderToUse::H264Vaapi)
+    /// Checks the values serde can't, reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if !(1..=86400).contains(&self.max_seconds) {
+            problems.push(format!(
+                "max_seconds must be between 1 and 86400, not {}",
+                self.max_seconds
+            ));
+        }
+        match self.default_clip_seconds {
+            Some(0) => problems.push(
+                "default_clip_seconds must be above 0, leave it out to save the whole buffer"
+                    .to_string(),
+            ),
+            Some(seconds) if seconds > self.max_seconds => problems.push(format!(
+                "default_clip_seconds ({seconds}) can't be longer than the {} seconds max_seconds \
+                 buffers, raise max_seconds or leave default_clip_seconds out to save the whole \
+                 buffer",
+                self.max_seconds
             )),
-        }?;
+            _ => {}
+        }
+        if self.max_buffer_mb == Some(0) {
+            problems.push("max_buffer_mb must be above 0, leave it out for no limit".to_string());
+        }
+        if self.audio_offset_ms.unsigned_abs() > 10_000 {
+            problems.push(format!(
+                "audio_offset_ms must be within 10 seconds either way, not {}",
+                self.audio_offset_ms
+            ));
+        }
+        if self.post_save_timeout_seconds == 0 {
+            problems.push("post_save_timeout_seconds must be above 0".to_string());
+        }
+        if !(100..=100_000).contains(&self.tiering.bitrate_kbps) {
+            problems.push(format!(
+                "tiering.bitrate_kbps must be between 100 and 100000, not {}",
+                self.tiering.bitrate_kbps
+            ));
+        }
+        if !(self.audio_events.threshold_db > 0.0 && self.audio_events.threshold_db.is_finite()) {
+            problems.push(format!(
+                "audio_events.threshold_db must be above 0, not {}",
+                self.audio_events.threshold_db
+            ));
+        }
+        if let Some(max_total_gb) = self.retention.as_ref().and_then(|r| r.max_total_gb) {
+            if !(max_total_gb > 0.0 && max_total_gb.is_finite()) {
+                problems.push(format!(
+                    "retention.max_total_gb must be above 0, not {max_total_gb}"
+                ));
+            }
+        }
+        anyhow::ensure!(problems.is_empty(), "{}", problems.join(", "));
+        Ok(())
+    }
 
-        let quality = match value.quality.to_lowercase().as_str() {
-            "low" => Ok(QualityPreset::Low),
-            "medium" => Ok(QualityPreset::Medium),
-            "high" => Ok(QualityPreset::High),
-            "ultra" => Ok(QualityPreset::Ultra),
-            other => Err(format!(
-                "Unknown quality value: {:?}, Valid values: {:?}",
-                other,
-                vec!(
-                    QualityPreset::Low,
-                    QualityPreset::Medium,
-                    QualityPreset::High,
-                    QualityPreset::Ultra
-                )
-            )),
-        }?;
+    /// Frame rate the capture paces its encoder to. The capture always paces, so no cap is a
+    /// rate high enough that every frame passes.
+    pub fn target_fps(&self) -> u64 {
+        match self.max_fps {
+            0 => UNCAPPED_FPS,
+            fps => fps.into(),
+        }
+    }
+}
+
+impl From<&AppConfig> for AppConfigDbus {
+    fn from(config: &AppConfig) -> Self {
+        let encoder = match config.encoder {
+            EncoderToUse::H264Nvenc => "h264_nvenc",
+            EncoderToUse::H264Vaapi => "h264_vaapi",
+        };
+        let quality = match config.quality {
+            QualityPreset::Low => "low",
+            QualityPreset::Medium => "medium",
+            QualityPreset::High => "high",
+            QualityPreset::Ultra => "ultra",
+        };
+        Self {
+            encoder: encoder.to_string(),
+            max_seconds: config.max_seconds,
+            use_mic: config.use_mic,
+            quality: quality.to_string(),
+            audio_offset_ms: config.audio_offset_ms,
+        }
+    }
+}
+
+impl AppConfigDbus {
+    /// Validates the dbus fields and applies them on top of `base`. Fields which are not exposed
+    /// over dbus keep their current value.
+    pub fn apply_to(self, base: &AppConfig) -> Result<AppConfig, String> {
+        let encoder = self.encoder.parse()?;
+        let quality = self.quality.parse()?;
 
         Ok(AppConfig {
             encoder,
-            max_seconds: value.max_seconds,
-            use_mic: value.use_mic,
+            max_seconds: self.max_seconds,
+            use_mic: self.use_mic,
             quality,
+            audio_offset_ms: self.audio_offset_ms,
+            ..base.clone()
         })
     }
 }
 
-#[derive(Type, Serialize, Deserialize, PartialEq)]
-pub enum AppModeDbus {
-    Shadow,
+/// Where the config is read from and written back to, along with the command line overrides
+/// which are layered on top of it for this run.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    pub path: Option<PathBuf>,
+    pub overrides: ConfigOverrides,
 }
 
-pub fn load_or_create_config() -> AppConfig {
-    let mut settings = Config::builder();
-
-    // Check for an user level config
-    if let Some(proj_dirs) = ProjectDirs::from("com", "rust", "waycap") {
-        let config_path = proj_dirs.config_dir().join("config.toml");
-
-        if !config_path.exists() {
-            let default_config = AppConfig::default();
-            write_config(&config_path, &default_config)
-                .expect("Failed to write default config file");
+impl ConfigSource {
+    /// Uses `path` or, without one, the user level config.
+    pub fn new(path: Option<PathBuf>, overrides: ConfigOverrides) -> Self {
+        Self {
+            path: path.or_else(default_config_path),
+            overrides,
         }
+    }
 
-        settings = settings.add_source(File::from(config_path).required(false));
+    /// Reads the config file, creating it with the defaults if it doesn't exist, and applies
+    /// the overrides. A file which doesn't parse or holds invalid values is an error.
+    pub fn load(&self) -> Result<AppConfig> {
+        let config = self
+            .overrides
+            .apply(load_or_create_config(self.path.as_deref())?);
+        config.validate().with_context(|| match &self.path {
+            Some(path) => format!("Invalid config in {path:?}"),
+            None => "Invalid config".to_string(),
+        })?;
+        Ok(config)
     }
 
-    let config = settings.build();
+    /// The defaults with the overrides applied, for running despite an invalid config file.
+    pub fn defaults(&self) -> AppConfig {
+        self.overrides.apply(AppConfig::default())
+    }
 
-    match config {
-        Ok(c) => c.try_deserialize().unwrap_or_default(),
-        Err(_) => AppConfig::default(),
+    /// Writes `config` to the config file. Overridden fields which still hold their command line
+    /// value keep what the file had so the overrides never end up in it. An invalid file is left
+    /// alone rather than replaced.
+    pub fn save(&self, config: &AppConfig) -> Result<()> {
+        if let Some(path) = &self.path {
+            let file_config = load_or_create_config(Some(path))
+                .context("Not writing the config back over the invalid file")?;
+            let stored = self.overrides.restore(config.clone(), &file_config);
+            write_config(path, &stored)
+                .with_context(|| format!("Could not write the config to {path:?}"))?;
+        }
+        Ok(())
     }
 }
 
-pub fn update_config(config: AppConfig) -> AppConfig {
-    let mut settings = Config::builder();
-
-    if let Some(proj_dirs) = ProjectDirs::from("com", "rust", "waycap") {
-        let config_path = proj_dirs.config_dir().join("config.toml");
-
-        write_config(&config_path, &config).expect("Failed to update config file");
+pub fn default_config_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "rust", "waycap")
+        .map(|proj_dirs| proj_dirs.config_dir().join("config.toml"))
+}
 
-        settings = settings.add_source(File::from(config_path).required(false));
+/// Reads the config at `config_path`, writing the defaults there first if it doesn't exist and
+/// migrating it if it is from an older version. Without a path the defaults are used.
+pub fn load_or_create_config(config_path: Option<&Path>) -> Result<AppConfig> {
+    let Some(config_path) = config_path else {
+        return Ok(AppConfig::default());
+    };
+    if !config_path.exists() {
+        write_config(config_path, &AppConfig::default())
+            .with_context(|| format!("Could not write the default config to {config_path:?}"))?;
     }
 
-    let config = settings.build();
+    let contents = fs::read_to_string(config_path)
+        .with_context(|| format!("Could not read {config_path:?}"))?;
+    let mut table: toml::Table = contents
+        .parse()
+        .with_context(|| format!("Invalid config in {config_path:?}"))?;
+    let from = version_of(&table)?;
+    let migrated = migrate(&mut table).with_context(|| format!("Can't read {config_path:?}"))?;
+    let contents = match migrated {
+        true => toml::to_string_pretty(&table)?,
+        false => contents,
+    };
 
-    match config {
-        Ok(c) => c.try_deserialize().unwrap_or_default(),
-        Err(_) => AppConfig::default(),
+    let config = Config::builder()
+        .add_source(File::from_str(&contents, FileFormat::Toml))
+        .build()
+        .and_then(|config| config.try_deserialize())
+        .with_context(|| format!("Invalid config in {config_path:?}"))?;
+    // Only a file which could be read is replaced, one with a typo is left for the user to fix
+    if migrated {
+        replace_with_migrated(config_path, &contents, from)?;
     }
+    Ok(config)
 }
 
 fn write_config(path: &Path, config: &AppConfig) -> Result<()> {
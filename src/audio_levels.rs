@@ -0,0 +1,242 @@
+//! Live levels of the captured audio, so a mixer widget can show the right source is being
+//! captured. waycap-rs only hands out encoded audio, so the levels are measured on the decoded
+//! Opus frames, which follow the raw samples closely enough for a meter.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use ffmpeg_next::{
+    codec::{self, Packet},
+    format::{sample, Sample},
+    frame,
+};
+use zbus::Connection;
+
+use crate::{
+    dbus::{ClipService, GameClip},
+    dbus_types::AudioLevels,
+    encoders::muxer::StreamParams,
+};
+
+/// Peaks and RMS are taken over this much of the most recent audio.
+const LEVEL_WINDOW: Duration = Duration::from_millis(300);
+/// Clipped samples are counted over this much of the most recent audio.
+const CLIP_WINDOW: Duration = Duration::from_secs(1);
+/// How often `AudioLevels` is emitted while audio is arriving.
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Levels of one decoded chunk of audio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkLevels {
+    pub peaks: Vec<f32>,
+    pub sum_squares: Vec<f64>,
+    /// Samples per channel.
+    pub samples: usize,
+    pub clipped: u64,
+}
+
+impl ChunkLevels {
+    fn silent(channels: usize) -> Self {
+        Self {
+            peaks: vec![0.0; channels],
+            sum_squares: vec![0.0; channels],
+            samples: 0,
+            clipped: 0,
+        }
+    }
+
+    /// Measures a chunk given as one slice of samples per channel.
+    pub fn measure_planar(planes: &[&[f32]]) -> Self {
+        let mut levels = Self::silent(planes.len());
+        for (channel, plane) in planes.iter().enumerate() {
+            for &sample in *plane {
+                levels.add(channel, sample);
+            }
+        }
+        levels.samples = planes.first().map_or(0, |plane| plane.len());
+        levels
+    }
+
+    /// Measures a chunk of interleaved samples.
+    pub fn measure_interleaved(samples: &[f32], channels: usize) -> Self {
+        let mut levels = Self::silent(channels);
+        if channels == 0 {
+            return levels;
+        }
+        for frame in samples.chunks_exact(channels) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                levels.add(channel, sample);
+            }
+        }
+        levels.samples = samples.len() / channels;
+        levels
+    }
+
+    fn add(&mut self, channel: usize, sample: f32) {
+        let magnitude = sample.abs();
+        self.peaks[channel] = self.peaks[channel].max(magnitude);
+        self.sum_squares[channel] += f64::from(sample) * f64::from(sample);
+        if magnitude >= 1.0 {
+            self.clipped += 1;
+        }
+    }
+}
+
+/// The chunks measured within the last [`CLIP_WINDOW`], written by the audio worker of the
+/// running mode and read for `GetAudioLevels` and the `AudioLevels` signal.
+#[derive(Debug, Default)]
+pub struct AudioLevelHistory {
+    chunks: Mutex<VecDeque<(Instant, ChunkLevels)>>,
+}
+
+impl AudioLevelHistory {
+    pub fn record(&self, chunk: ChunkLevels) {
+        self.record_at(Instant::now(), chunk);
+    }
+
+    pub fn record_at(&self, at: Instant, chunk: ChunkLevels) {
+        let Ok(mut chunks) = self.chunks.lock() else {
+            return;
+        };
+        while chunks
+            .front()
+            .is_some_and(|(recorded, _)| at.duration_since(*recorded) > CLIP_WINDOW)
+        {
+            chunks.pop_front();
+        }
+        chunks.push_back((at, chunk));
+    }
+
+    pub fn levels(&self) -> AudioLevels {
+        self.levels_at(Instant::now())
+    }
+
+    pub fn levels_at(&self, now: Instant) -> AudioLevels {
+        let Ok(chunks) = self.chunks.lock() else {
+            return AudioLevels::default();
+        };
+        let recent = |window: Duration| {
+            chunks
+                .iter()
+                .filter(move |(at, _)| now.saturating_duration_since(*at) <= window)
+                .map(|(_, chunk)| chunk)
+        };
+
+        let channels = recent(LEVEL_WINDOW).map(|chunk| chunk.peaks.len()).max();
+        let mut levels = AudioLevels {
+            clipped_samples: recent(CLIP_WINDOW).map(|chunk| chunk.clipped).sum(),
+            ..Default::default()
+        };
+        let Some(channels) = channels else {
+            return levels;
+        };
+        let mut sum_squares = vec![0.0; channels];
+        let mut samples = 0;
+        levels.peaks = vec![0.0; channels];
+        for chunk in recent(LEVEL_WINDOW) {
+            for (channel, &peak) in chunk.peaks.iter().enumerate() {
+                levels.peaks[channel] = levels.peaks[channel].max(f64::from(peak));
+                sum_squares[channel] += chunk.sum_squares[channel];
+            }
+            samples += chunk.samples;
+        }
+        levels.rms = sum_squares
+            .into_iter()
+            .map(|sum| match samples {
+                0 => 0.0,
+                samples => (sum / samples as f64).sqrt(),
+            })
+            .collect();
+        levels
+    }
+}
+
+/// Decodes the encoded audio frames of a mode's audio worker and records their levels.
+pub struct LevelMeter {
+    decoder: ffmpeg_next::decoder::Audio,
+    decoded: frame::Audio,
+    history: Arc<AudioLevelHistory>,
+}
+
+impl LevelMeter {
+    pub fn new(params: &StreamParams, history: Arc<AudioLevelHistory>) -> Result<Self> {
+        let decoder = codec::Context::from_parameters(params.parameters.clone())?
+            .decoder()
+            .audio()
+            .context("Could not open a decoder for the captured audio")?;
+        Ok(Self {
+            decoder,
+            decoded: frame::Audio::empty(),
+            history,
+        })
+    }
+
+    /// Records the levels of one encoded frame and returns them, one entry per decoded chunk.
+    /// Failures are only logged, the meter must never hold up the capture.
+    pub fn measure(&mut self, data: &[u8]) -> Vec<ChunkLevels> {
+        let mut chunks = Vec::new();
+        if let Err(e) = self.decode(data, &mut chunks) {
+            log::debug!("Could not measure the audio levels: {e:#}");
+        }
+        chunks
+    }
+
+    fn decode(&mut self, data: &[u8], chunks: &mut Vec<ChunkLevels>) -> Result<()> {
+        self.decoder.send_packet(&Packet::copy(data))?;
+        while self.decoder.receive_frame(&mut self.decoded).is_ok() {
+            let chunk = match self.decoded.format() {
+                Sample::F32(sample::Type::Planar) => {
+                    let planes: Vec<_> = (0..self.decoded.planes())
+                        .map(|plane| self.decoded.plane::<f32>(plane))
+                        .collect();
+                    ChunkLevels::measure_planar(&planes)
+                }
+                Sample::F32(sample::Type::Packed) => {
+                    let channels = usize::from(self.decoded.channels());
+                    let samples: Vec<_> = self
+                        .decoded
+                        .data(0)
+                        .chunks_exact(4)
+                        .take(self.decoded.samples() * channels)
+                        .map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                        .collect();
+                    ChunkLevels::measure_interleaved(&samples, channels)
+                }
+                format => bail!("Unsupported sample format {format:?}"),
+            };
+            self.history.record(chunk.clone());
+            chunks.push(chunk);
+        }
+        Ok(())
+    }
+}
+
+/// Emits `AudioLevels` a few times a second while audio is arriving, until the connection
+/// closes.
+pub async fn publish(conn: Connection, history: Arc<AudioLevelHistory>) {
+    let mut interval = tokio::time::interval(SIGNAL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let levels = history.levels();
+        if levels.peaks.is_empty() {
+            continue;
+        }
+        let iface = match conn
+            .object_server()
+            .interface::<_, ClipService>("/com/rust/WayCap")
+            .await
+        {
+            Ok(iface) => iface,
+            Err(e) => {
+                log::debug!("Stopped publishing audio levels: {e:?}");
+                return;
+            }
+        };
+        if let Err(e) = ClipService::audio_levels(iface.signal_emitter(), levels).await {
+            log::debug!("Could not emit the audio levels: {e:?}");
+        }
+    }
+}
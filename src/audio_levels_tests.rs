@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+use super::audio_levels::*;
+
+#[test]
+fn test_measure_planar() {
+    let left = [0.5, -0.5, 0.5, -0.5];
+    let right = [0.0, 1.0, -1.0, 0.25];
+    let levels = ChunkLevels::measure_planar(&[&left, &right]);
+
+    assert_eq!(levels.samples, 4);
+    assert_eq!(levels.peaks, vec![0.5, 1.0]);
+    assert_eq!(levels.sum_squares, vec![1.0, 2.0625]);
+    assert_eq!(levels.clipped, 2);
+}
+
+#[test]
+fn test_measure_interleaved_matches_planar() {
+    let interleaved = [0.5, 0.0, -0.5, 1.0, 0.5, -1.0, -0.5, 0.25];
+    let left = [0.5, -0.5, 0.5, -0.5];
+    let right = [0.0, 1.0, -1.0, 0.25];
+
+    assert_eq!(
+        ChunkLevels::measure_interleaved(&interleaved, 2),
+        ChunkLevels::measure_planar(&[&left, &right])
+    );
+}
+
+#[test]
+fn test_levels_cover_the_recent_chunks() {
+    let history = AudioLevelHistory::default();
+    let start = Instant::now();
+    history.record_at(start, ChunkLevels::measure_planar(&[&[1.0; 4], &[1.0; 4]]));
+    history.record_at(
+        start + Duration::from_millis(500),
+        ChunkLevels::measure_planar(&[&[0.5; 4], &[0.0; 4]]),
+    );
+    history.record_at(
+        start + Duration::from_millis(600),
+        ChunkLevels::measure_planar(&[&[-0.5; 4], &[0.25; 4]]),
+    );
+
+    // The first chunk is too old for the peaks but its clipping still counts
+    let levels = history.levels_at(start + Duration::from_millis(700));
+    assert_eq!(levels.peaks, vec![0.5, 0.25]);
+    assert_eq!(levels.rms, vec![0.5, (0.0625f64 / 2.0).sqrt()]);
+    assert_eq!(levels.clipped_samples, 8);
+
+    let levels = history.levels_at(start + Duration::from_millis(1200));
+    assert_eq!(levels.clipped_samples, 0);
+}
+
+#[test]
+fn test_levels_are_empty_without_recent_audio() {
+    let history = AudioLevelHistory::default();
+    let start = Instant::now();
+    assert_eq!(history.levels_at(start), Default::default());
+
+    history.record_at(start, ChunkLevels::measure_planar(&[&[0.5; 4]]));
+    let levels = history.levels_at(start + Duration::from_secs(5));
+    assert!(levels.peaks.is_empty());
+    assert!(levels.rms.is_empty());
+}
@@ -0,0 +1,420 @@
+#![deny(
+    clippy::all,
+    clippy::correctness,
+    clippy::style,
+    clippy::complexity,
+    clippy::perf
+)]
+
+//! Command line client for a running WayCap daemon.
+
+// The daemon's own dbus types so the wire format can't drift. Not every field is read here.
+#[allow(dead_code)]
+#[path = "../../dbus_types.rs"]
+mod dbus_types;
+mod waybar;
+#[cfg(test)]
+mod waybar_tests;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use dbus_types::{AppConfigDbus, AppModeDbus, AppStatus, SaveReport};
+use futures_util::StreamExt;
+use serde::Serialize;
+use zbus::{proxy, proxy::OwnerChangedStream, Connection};
+
+/// How long `save` waits for the daemon to report the written clip.
+const SAVE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often `status --follow` refreshes the buffered length between signals.
+const FOLLOW_REFRESH: Duration = Duration::from_secs(1);
+
+#[proxy(
+    interface = "com.rust.WayCap",
+    default_service = "com.rust.WayCap",
+    default_path = "/com/rust/WayCap"
+)]
+trait WayCap {
+    fn save_clip(&self) -> zbus::Result<(u32, String)>;
+    fn save_clip_last(&self, seconds: u32) -> zbus::Result<(u32, String)>;
+    fn get_status(&self) -> zbus::Result<AppStatus>;
+    fn pause(&self) -> zbus::Result<()>;
+    fn resume(&self) -> zbus::Result<()>;
+    fn reconnect(&self) -> zbus::Result<()>;
+    fn change_mode(&self, new_mode: AppModeDbus) -> zbus::Result<()>;
+    fn get_config(&self) -> zbus::Result<AppConfigDbus>;
+    fn update_config(&self, new_config: AppConfigDbus) -> zbus::Result<Vec<String>>;
+
+    #[zbus(signal)]
+    fn clip_saved(&self, report: SaveReport, request_id: u32) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn save_failed(&self, error: String, request_id: u32) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn save_cancelled(&self, request_id: u32) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn status_changed(&self, status: AppStatus) -> zbus::Result<()>;
+}
+
+/// Controls a running WayCap daemon over dbus.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Print JSON instead of text, for scripts.
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Save the shadow buffer to a clip and wait until it is written.
+    Save {
+        /// Only save the last this many seconds, keeping the rest of the buffer.
+        #[arg(long, value_name = "SECONDS")]
+        last: Option<u32>,
+    },
+    /// Show what the daemon is currently doing.
+    Status {
+        /// Keep printing the status whenever it changes, waiting for the daemon if it is not
+        /// running.
+        #[arg(long)]
+        follow: bool,
+        /// Follow the status as JSON lines for a waybar custom module.
+        #[arg(long)]
+        waybar: bool,
+    },
+    /// Stop capturing. The buffered footage is kept and can still be saved.
+    Pause,
+    /// Continue capturing after a pause.
+    Resume,
+    /// Pick the screen to share again after the capture was lost.
+    Reconnect,
+    /// Switch modes: shadow, stream, record, hybrid or audio-only.
+    SetMode { mode: AppModeDbus },
+    /// Change a config value: encoder, max_seconds, use_mic, quality or audio_offset_ms.
+    Set { key: String, value: String },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let connection = Connection::session()
+        .await
+        .context("Could not connect to the session bus")?;
+    let proxy = WayCapProxy::new(&connection).await?;
+
+    run(&cli, &proxy)
+        .await
+        .map_err(|e| match e.downcast_ref::<zbus::Error>() {
+            Some(error) if daemon_missing(error) => {
+                anyhow!("WayCap is not running, start the daemon with `waycap` first")
+            }
+            _ => e,
+        })
+}
+
+async fn run(cli: &Cli, proxy: &WayCapProxy<'_>) -> Result<()> {
+    match &cli.command {
+        Command::Save { last } => {
+            let report = save(proxy, *last).await?;
+            if cli.json {
+                print_json(&report)?;
+            } else {
+                println!(
+                    "Saved {} ({:.1} s, {:.1} MB) in {:.1} s",
+                    report.path,
+                    report.clip_duration_ms as f64 / 1000.0,
+                    report.bytes_on_disk as f64 / 1_000_000.0,
+                    report.save_duration_ms as f64 / 1000.0
+                );
+            }
+        }
+        Command::Status { follow, waybar } => {
+            let format = if *waybar {
+                StatusFormat::Waybar
+            } else if cli.json {
+                StatusFormat::Json
+            } else {
+                StatusFormat::Text
+            };
+            if *follow || *waybar {
+                follow_status(proxy, format).await?;
+            } else {
+                let status = proxy.get_status().await?;
+                print!("{}", format.render(Some(&status), None)?);
+            }
+        }
+        Command::Pause => {
+            proxy.pause().await?;
+            print_done(cli.json, "Capture paused")?;
+        }
+        Command::Resume => {
+            proxy.resume().await?;
+            print_done(cli.json, "Capture resumed")?;
+        }
+        Command::Reconnect => {
+            proxy.reconnect().await?;
+            print_done(cli.json, "Capture reconnected")?;
+        }
+        Command::SetMode { mode } => {
+            proxy.change_mode(*mode).await?;
+            print_done(cli.json, &format!("Switched to {mode:?} mode"))?;
+        }
+        Command::Set { key, value } => {
+            let mut config = proxy.get_config().await?;
+            set_config_value(&mut config, key, value)?;
+            let restart_required = proxy.update_config(config).await?;
+            if cli.json {
+                print_json(&serde_json::json!({ "restart_required": restart_required }))?;
+            } else if restart_required.is_empty() {
+                println!("Set {key} to {value}");
+            } else {
+                println!("Set {key} to {value}, restart WayCap for it to take effect");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Requests a save and waits for the daemon to announce how it went. The signals are subscribed
+/// to first so a quick save can't be missed, the ones of other saves are skipped.
+async fn save(proxy: &WayCapProxy<'_>, last: Option<u32>) -> Result<SaveReport> {
+    let mut saved = proxy.receive_clip_saved().await?;
+    let mut failed = proxy.receive_save_failed().await?;
+    let mut cancelled = proxy.receive_save_cancelled().await?;
+    // A busy full save is the pending one, which covers this request too
+    let (id, status) = match last {
+        Some(seconds) => proxy.save_clip_last(seconds).await?,
+        None => proxy.save_clip().await?,
+    };
+    if status == "failed" {
+        bail!("WayCap could not queue the save");
+    }
+
+    let outcome = async {
+        loop {
+            tokio::select! {
+                Some(signal) = saved.next() => {
+                    let args = signal.args()?;
+                    if args.request_id == id {
+                        return Ok(args.report);
+                    }
+                },
+                Some(signal) = failed.next() => {
+                    let args = signal.args()?;
+                    if args.request_id == id {
+                        return Err(anyhow!("The save failed: {}", args.error));
+                    }
+                },
+                Some(signal) = cancelled.next() => {
+                    if signal.args()?.request_id == id {
+                        return Err(anyhow!("The save was cancelled"));
+                    }
+                },
+                else => return Err(anyhow!("WayCap went away before the clip was saved")),
+            }
+        }
+    };
+    tokio::time::timeout(SAVE_TIMEOUT, outcome)
+        .await
+        .context("Timed out waiting for the clip to be saved")?
+}
+
+fn set_config_value(config: &mut AppConfigDbus, key: &str, value: &str) -> Result<()> {
+    match key {
+        // Validated by the daemon, which knows the valid values
+        "encoder" => config.encoder = value.to_string(),
+        "quality" => config.quality = value.to_string(),
+        "max_seconds" => {
+            config.max_seconds = value.parse().with_context(|| {
+                format!("max_seconds must be a number of seconds, got {value:?}")
+            })?
+        }
+        "use_mic" => {
+            config.use_mic = value
+                .parse()
+                .with_context(|| format!("use_mic must be true or false, got {value:?}"))?
+        }
+        "audio_offset_ms" => {
+            config.audio_offset_ms = value.parse().with_context(|| {
+                format!("audio_offset_ms must be a number of milliseconds, got {value:?}")
+            })?
+        }
+        other => {
+            bail!(
+                "Unknown config key {other:?}, Valid keys: encoder, max_seconds, use_mic, quality, audio_offset_ms"
+            )
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StatusFormat {
+    Text,
+    Json,
+    Waybar,
+}
+
+impl StatusFormat {
+    /// Renders `status`, `None` meaning the daemon is not running, along with the error of the
+    /// last failed save.
+    fn render(self, status: Option<&AppStatus>, error: Option<&str>) -> Result<String> {
+        Ok(match (self, status) {
+            (StatusFormat::Waybar, _) => {
+                format!("{}\n", serde_json::to_string(&waybar::line(status, error))?)
+            }
+            (StatusFormat::Json, Some(status)) => format!("{}\n", serde_json::to_string(status)?),
+            (StatusFormat::Json, None) => "null\n".to_string(),
+            (StatusFormat::Text, Some(status)) => status_text(status, error),
+            (StatusFormat::Text, None) => "WayCap is not running\n".to_string(),
+        })
+    }
+}
+
+/// Prints the status every time it changes until interrupted. The daemon going away is shown as
+/// a status of its own and following resumes once it is back.
+async fn follow_status(proxy: &WayCapProxy<'_>, format: StatusFormat) -> Result<()> {
+    let mut owner = proxy.inner().receive_owner_changed().await?;
+    let mut last_output = String::new();
+    loop {
+        match follow_daemon(proxy, format, &mut owner, &mut last_output).await {
+            Ok(()) => {}
+            Err(e) if e.downcast_ref::<zbus::Error>().is_some_and(daemon_missing) => {}
+            Err(e) => return Err(e),
+        }
+        print_changed(format.render(None, None)?, &mut last_output);
+
+        // Signal streams only end with the connection, the owner changing is what tells us the
+        // daemon is back
+        loop {
+            match owner.next().await {
+                Some(Some(_)) => break,
+                Some(None) => {}
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Follows the status of the running daemon, returning once it goes away.
+async fn follow_daemon(
+    proxy: &WayCapProxy<'_>,
+    format: StatusFormat,
+    owner: &mut OwnerChangedStream<'_>,
+    last_output: &mut String,
+) -> Result<()> {
+    let mut changes = proxy.receive_status_changed().await?;
+    let mut saved = proxy.receive_clip_saved().await?;
+    let mut failed = proxy.receive_save_failed().await?;
+    let mut status = proxy.get_status().await?;
+    let mut error = None;
+    let mut refresh = tokio::time::interval(FOLLOW_REFRESH);
+
+    loop {
+        print_changed(format.render(Some(&status), error.as_deref())?, last_output);
+        tokio::select! {
+            Some(signal) = changes.next() => {
+                status = signal.args()?.status;
+                // A new save replaces the error of the last one
+                if status.saving {
+                    error = None;
+                }
+            },
+            Some(_) = saved.next() => error = None,
+            Some(signal) = failed.next() => error = Some(signal.args()?.error),
+            Some(new_owner) = owner.next() => {
+                if new_owner.is_none() {
+                    return Ok(());
+                }
+            },
+            _ = refresh.tick() => status = proxy.get_status().await?,
+        }
+    }
+}
+
+fn print_changed(output: String, last_output: &mut String) {
+    if output != *last_output {
+        print!("{output}");
+        *last_output = output;
+    }
+}
+
+fn status_text(status: &AppStatus, error: Option<&str>) -> String {
+    let buffered = status.buffered_seconds as u64;
+    let mut text = format!("Mode:      {}\n", status.mode);
+    text.push_str(&format!(
+        "Buffered:  {}:{:02} ({} markers)\n",
+        buffered / 60,
+        buffered % 60,
+        status.marker_count
+    ));
+    if status.default_clip_seconds > 0 {
+        text.push_str(&format!(
+            "Saves:     the last {}:{:02}\n",
+            status.default_clip_seconds / 60,
+            status.default_clip_seconds % 60
+        ));
+    }
+    let cap = match status.max_fps {
+        0 => "uncapped".to_string(),
+        fps => format!("max {fps}"),
+    };
+    text.push_str(&format!("Frames:    {:.1} fps ({cap})\n", status.fps));
+    let mut activity = Vec::new();
+    if status.capture_lost {
+        activity.push("capture lost, run `waycap-ctl reconnect`");
+    }
+    if status.paused {
+        activity.push("paused");
+    }
+    if status.saving {
+        activity.push("saving");
+    }
+    if status.streaming {
+        activity.push("streaming");
+    }
+    if status.recording {
+        activity.push("recording");
+    }
+    if activity.is_empty() {
+        activity.push("buffering");
+    }
+    text.push_str(&format!("Activity:  {}\n", activity.join(", ")));
+    if let Some(error) = error {
+        text.push_str(&format!("Last save failed: {error}\n"));
+    }
+    text
+}
+
+fn print_done(json: bool, message: &str) -> Result<()> {
+    if json {
+        print_json(&serde_json::json!({ "ok": true }))
+    } else {
+        println!("{message}");
+        Ok(())
+    }
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+/// Whether `error` means nothing owns the WayCap name on the bus.
+fn daemon_missing(error: &zbus::Error) -> bool {
+    match error {
+        zbus::Error::MethodError(name, _, _) => matches!(
+            name.as_str(),
+            "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner"
+        ),
+        zbus::Error::FDO(error) => matches!(
+            **error,
+            zbus::fdo::Error::ServiceUnknown(_) | zbus::fdo::Error::NameHasNoOwner(_)
+        ),
+        _ => false,
+    }
+}
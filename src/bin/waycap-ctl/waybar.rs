@@ -0,0 +1,50 @@
+use serde::Serialize;
+
+use crate::dbus_types::AppStatus;
+
+/// One update of a waybar custom module with `"return-type": "json"`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct WaybarLine {
+    pub text: String,
+    /// CSS class to style the module with: recording, saving, paused, error or stopped.
+    pub class: &'static str,
+    pub tooltip: String,
+}
+
+/// Module contents for `status`, `None` meaning the daemon is not running, and the error of the
+/// last failed save. The error wins over everything else until the next save.
+pub fn line(status: Option<&AppStatus>, error: Option<&str>) -> WaybarLine {
+    let Some(status) = status else {
+        return WaybarLine {
+            text: String::new(),
+            class: "stopped",
+            tooltip: "WayCap is not running".to_string(),
+        };
+    };
+
+    let buffered = status.buffered_seconds as u64;
+    let length = format!("{}:{:02}", buffered / 60, buffered % 60);
+    let (icon, class) = if error.is_some() || status.capture_lost {
+        ("⚠", "error")
+    } else if status.saving {
+        ("⏺", "saving")
+    } else if status.paused {
+        ("⏸", "paused")
+    } else {
+        ("●", "recording")
+    };
+    let tooltip = match error {
+        Some(error) => format!("{}\nLast save failed: {error}", status.mode),
+        None if status.capture_lost => format!(
+            "{}\nThe capture was lost, {length} buffered. Run waycap-ctl reconnect",
+            status.mode
+        ),
+        None => format!("{}\n{length} buffered", status.mode),
+    };
+
+    WaybarLine {
+        text: format!("{icon} {length}"),
+        class,
+        tooltip,
+    }
+}
@@ -0,0 +1,54 @@
+use super::{dbus_types::AppStatus, waybar::*};
+
+fn status(buffered_seconds: f64) -> AppStatus {
+    AppStatus {
+        mode: "Shadow Capture Mode".to_string(),
+        buffered_seconds,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_buffering() {
+    assert_eq!(
+        line(Some(&status(277.6)), None),
+        WaybarLine {
+            text: "● 4:37".to_string(),
+            class: "recording",
+            tooltip: "Shadow Capture Mode\n4:37 buffered".to_string(),
+        }
+    );
+}
+
+#[test]
+fn test_state_classes() {
+    let saving = AppStatus {
+        saving: true,
+        paused: true,
+        ..status(30.0)
+    };
+    assert_eq!(line(Some(&saving), None).class, "saving");
+
+    let paused = AppStatus {
+        paused: true,
+        ..status(30.0)
+    };
+    assert_eq!(line(Some(&paused), None).text, "⏸ 0:30");
+
+    // The failure stays visible until the next save
+    let failed = line(Some(&status(30.0)), Some("No space left on device"));
+    assert_eq!(failed.class, "error");
+    assert!(failed
+        .tooltip
+        .ends_with("Last save failed: No space left on device"));
+
+    let lost = AppStatus {
+        capture_lost: true,
+        ..status(30.0)
+    };
+    let lost = line(Some(&lost), None);
+    assert_eq!(lost.class, "error");
+    assert!(lost.tooltip.contains("reconnect"));
+
+    assert_eq!(line(None, None).class, "stopped");
+}
@@ -0,0 +1,112 @@
+//! Noticing that the screencast is gone for good. waycap-rs keeps the state of its PipeWire
+//! stream to itself, so a lost stream shows up here as the portal leaving the bus, or as frames
+//! not coming back after the watchdog restarted the capture.
+use anyhow::Result;
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use zbus::Connection;
+
+use crate::diagnostics::PORTAL_SERVICE;
+
+/// How many times the watchdog restarts a stalled capture before it is taken for lost.
+const MAX_STALL_RESTARTS: u32 = 1;
+
+/// Why the screencast can't deliver frames anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureLoss {
+    /// xdg-desktop-portal left the bus, taking the screencast session with it.
+    PortalRestarted,
+    /// No frames arrived even after restarting the capture, e.g. the screen share was stopped
+    /// from the compositor.
+    StreamStopped,
+}
+
+impl CaptureLoss {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::PortalRestarted => "the desktop portal restarted",
+            Self::StreamStopped => "the screen share stopped",
+        }
+    }
+}
+
+/// Something which happened to the capture, told to the running mode through
+/// [`crate::modes::AppMode::on_capture_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureEvent {
+    /// The watchdog restarted the capture after this long without video frames.
+    Restarted {
+        stalled_seconds: u64,
+    },
+    Lost(CaptureLoss),
+    /// Video frames arrived again after the stream looked stopped.
+    Recovered,
+    /// `Reconnect` replaced the lost capture, the mode was started over on the new one.
+    Reconnected,
+}
+
+/// What the watchdog should do about the video frames it has not received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallAction {
+    Wait,
+    Restart { stalled_seconds: u64 },
+    Lost { stalled_seconds: u64 },
+}
+
+/// Counts the restarts of a stalled capture, so one which keeps stalling is reported as lost
+/// instead of being restarted forever.
+#[derive(Debug, Default)]
+pub struct StallWatch {
+    restarts: u32,
+    /// Wall clock time in milliseconds of the last restart.
+    restarted_at: Option<i64>,
+}
+
+impl StallWatch {
+    /// Decides what to do `now` about a capture whose last video frame arrived at `last_frame`,
+    /// both in milliseconds. A `timeout_seconds` of 0 disables the watchdog.
+    ///
+    /// The caller stores `now` as the last frame time after a restart, any frame after that
+    /// means the restart worked.
+    pub fn check(&mut self, now: i64, last_frame: i64, timeout_seconds: u32) -> StallAction {
+        if self.restarted_at.is_some_and(|at| last_frame > at) {
+            *self = Self::default();
+        }
+        let stalled_seconds = (now - last_frame).max(0) as u64 / 1000;
+        if timeout_seconds == 0 || stalled_seconds < u64::from(timeout_seconds) {
+            return StallAction::Wait;
+        }
+        if self.restarts >= MAX_STALL_RESTARTS {
+            return StallAction::Lost { stalled_seconds };
+        }
+        self.restarts += 1;
+        self.restarted_at = Some(now);
+        StallAction::Restart { stalled_seconds }
+    }
+}
+
+/// Reports a [`CaptureLoss::PortalRestarted`] to `lost_tx` every time the portal leaves the bus,
+/// until the connection closes.
+pub async fn watch_portal(conn: Connection, lost_tx: mpsc::Sender<CaptureLoss>) {
+    if let Err(e) = watch(&conn, &lost_tx).await {
+        log::warn!("Stopped watching the desktop portal: {e:?}");
+    }
+}
+
+async fn watch(conn: &Connection, lost_tx: &mpsc::Sender<CaptureLoss>) -> Result<()> {
+    let portal = zbus::Proxy::new(
+        conn,
+        PORTAL_SERVICE,
+        "/org/freedesktop/portal/desktop",
+        "org.freedesktop.portal.ScreenCast",
+    )
+    .await?;
+    let mut owner_changes = portal.receive_owner_changed().await?;
+    while let Some(owner) = owner_changes.next().await {
+        // The session lived in the process which left, a new portal doesn't bring it back
+        if owner.is_none() && lost_tx.send(CaptureLoss::PortalRestarted).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use super::capture_watch::{StallAction, StallWatch};
+
+#[test]
+fn test_stall_is_restarted_then_lost() {
+    let mut watch = StallWatch::default();
+    assert_eq!(watch.check(4_000, 0, 5), StallAction::Wait);
+    assert_eq!(
+        watch.check(6_000, 0, 5),
+        StallAction::Restart { stalled_seconds: 6 }
+    );
+    // The restart stores the time it happened as the last frame
+    assert_eq!(watch.check(9_000, 6_000, 5), StallAction::Wait);
+    assert_eq!(
+        watch.check(12_000, 6_000, 5),
+        StallAction::Lost { stalled_seconds: 6 }
+    );
+}
+
+#[test]
+fn test_frames_after_a_restart_allow_another_one() {
+    let mut watch = StallWatch::default();
+    assert_eq!(
+        watch.check(6_000, 0, 5),
+        StallAction::Restart { stalled_seconds: 6 }
+    );
+    assert_eq!(watch.check(7_000, 6_500, 5), StallAction::Wait);
+    assert_eq!(
+        watch.check(20_000, 6_500, 5),
+        StallAction::Restart {
+            stalled_seconds: 13
+        }
+    );
+}
+
+#[test]
+fn test_disabled_watchdog_never_acts() {
+    let mut watch = StallWatch::default();
+    assert_eq!(watch.check(600_000, 0, 0), StallAction::Wait);
+}
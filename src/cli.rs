@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser};
+
+use crate::application_config::{AppConfig, EncoderToUse, QualityPreset};
+
+/// Screen recorder which keeps the last few minutes of your screen in memory, ready to be saved.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file to use instead of the one in the user config directory. It is created with
+    /// the default values if it doesn't exist.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Print the effective configuration, including the command line overrides, and exit.
+    #[arg(long)]
+    pub print_config: bool,
+    /// Start with the default configuration when the config file can't be read or holds invalid
+    /// values, instead of refusing to start.
+    #[arg(long)]
+    pub ignore_config_errors: bool,
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+}
+
+/// Config values given on the command line. They take precedence over the config file for this
+/// run only and are never written back to it.
+#[derive(Debug, Clone, Default, PartialEq, Args)]
+pub struct ConfigOverrides {
+    /// Seconds of footage kept in the shadow buffer.
+    #[arg(long, value_name = "SECONDS")]
+    pub max_seconds: Option<u32>,
+    /// Quality preset: low, medium, high or ultra.
+    #[arg(long, value_name = "PRESET")]
+    pub quality: Option<QualityPreset>,
+    /// Video encoder: h264_nvenc or h264_vaapi.
+    #[arg(long, value_name = "ENCODER")]
+    pub encoder: Option<EncoderToUse>,
+    /// Directory the clips are saved to.
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+    /// Capture the video only.
+    #[arg(long)]
+    pub no_audio: bool,
+}
+
+impl ConfigOverrides {
+    pub fn apply(&self, mut config: AppConfig) -> AppConfig {
+        if let Some(max_seconds) = self.max_seconds {
+            config.max_seconds = max_seconds;
+        }
+        if let Some(quality) = self.quality {
+            config.quality = quality;
+        }
+        if let Some(encoder) = self.encoder {
+            config.encoder = encoder;
+        }
+        if let Some(output_dir) = &self.output_dir {
+            config.output_dir = output_dir.clone();
+        }
+        if self.no_audio {
+            config.audio = false;
+        }
+        config
+    }
+
+    /// Puts the values of `file` back into the fields of `config` which still hold their
+    /// override. Fields changed since, e.g. over dbus, are kept.
+    pub fn restore(&self, mut config: AppConfig, file: &AppConfig) -> AppConfig {
+        if self.max_seconds == Some(config.max_seconds) {
+            config.max_seconds = file.max_seconds;
+        }
+        if self.quality == Some(config.quality) {
+            config.quality = file.quality;
+        }
+        if self.encoder == Some(config.encoder) {
+            config.encoder = file.encoder;
+        }
+        if self.output_dir.as_ref() == Some(&config.output_dir) {
+            config.output_dir = file.output_dir.clone();
+        }
+        if self.no_audio && !config.audio {
+            config.audio = file.audio;
+        }
+        config
+    }
+}
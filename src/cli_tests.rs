@@ -0,0 +1,139 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    application_config::{AppConfig, ConfigSource, QualityPreset},
+    cli::*,
+};
+
+/// Config path in a directory under `dir` which does not exist yet.
+fn config_path(dir: &Path) -> PathBuf {
+    dir.join("waycap").join("config.toml")
+}
+
+#[test]
+fn test_overrides_only_replace_given_values() {
+    let overrides = ConfigOverrides {
+        max_seconds: Some(30),
+        no_audio: true,
+        ..Default::default()
+    };
+
+    let config = overrides.apply(AppConfig::default());
+    assert_eq!(
+        config,
+        AppConfig {
+            max_seconds: 30,
+            audio: false,
+            ..AppConfig::default()
+        }
+    );
+}
+
+#[test]
+fn test_alternate_config_is_created_with_defaults() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = config_path(dir.path());
+
+    let source = ConfigSource::new(Some(path.clone()), ConfigOverrides::default());
+    assert_eq!(source.load().unwrap(), AppConfig::default());
+    assert!(path.exists());
+}
+
+#[test]
+fn test_overrides_are_not_written_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = config_path(dir.path());
+    let source = ConfigSource::new(
+        Some(path.clone()),
+        ConfigOverrides {
+            max_seconds: Some(30),
+            quality: Some(QualityPreset::Ultra),
+            ..Default::default()
+        },
+    );
+
+    let mut config = source.load().unwrap();
+    assert_eq!(config.max_seconds, 30);
+    assert_eq!(config.quality, QualityPreset::Ultra);
+
+    // Quality is changed again at runtime, the buffer length keeps its override
+    config.quality = QualityPreset::Low;
+    config.faststart = false;
+    source.save(&config).unwrap();
+
+    let file = ConfigSource::new(Some(path), ConfigOverrides::default())
+        .load()
+        .unwrap();
+    assert_eq!(file.max_seconds, AppConfig::default().max_seconds);
+    assert_eq!(file.quality, QualityPreset::Low);
+    assert!(!file.faststart);
+}
+
+#[test]
+fn test_invalid_config_file_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = config_path(dir.path());
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, "qualty = \"HIGH\"\n").unwrap();
+    let source = ConfigSource::new(Some(path.clone()), ConfigOverrides::default());
+
+    let error = format!("{:#}", source.load().unwrap_err());
+    assert!(error.contains("qualty"), "{error}");
+    assert!(error.contains("config.toml"), "{error}");
+    assert_eq!(source.defaults(), AppConfig::default());
+
+    // The file is kept for the user to fix rather than overwritten
+    assert!(source.save(&AppConfig::default()).is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "qualty = \"HIGH\"\n");
+}
+
+#[test]
+fn test_validation_reports_every_problem() {
+    assert!(AppConfig::default().validate().is_ok());
+
+    let config = AppConfig {
+        max_seconds: 0,
+        max_buffer_mb: Some(0),
+        ..AppConfig::default()
+    };
+    let error = config.validate().unwrap_err().to_string();
+    assert!(error.contains("max_seconds"), "{error}");
+    assert!(error.contains("max_buffer_mb"), "{error}");
+
+    let dir = tempfile::tempdir().unwrap();
+    let source = ConfigSource::new(
+        Some(config_path(dir.path())),
+        ConfigOverrides {
+            max_seconds: Some(0),
+            ..Default::default()
+        },
+    );
+    assert!(source.load().is_err());
+}
+
+#[test]
+fn test_default_clip_fits_in_the_buffer() {
+    let config = AppConfig {
+        max_seconds: 120,
+        default_clip_seconds: Some(30),
+        ..AppConfig::default()
+    };
+    assert!(config.validate().is_ok());
+
+    let longer = AppConfig {
+        default_clip_seconds: Some(121),
+        ..config.clone()
+    };
+    let error = longer.validate().unwrap_err().to_string();
+    assert!(error.contains("default_clip_seconds (121)"), "{error}");
+    assert!(error.contains("120 seconds"), "{error}");
+
+    let zero = AppConfig {
+        default_clip_seconds: Some(0),
+        ..config
+    };
+    assert!(zero.validate().is_err());
+}
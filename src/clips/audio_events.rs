@@ -0,0 +1,139 @@
+//! Marks the moments the captured audio gets much louder than it has been, which is where the
+//! clips worth saving tend to be.
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio_levels::ChunkLevels;
+
+/// Label of the markers added by [`AudioEventDetector`].
+pub const AUDIO_PEAK_LABEL: &str = "audio-peak";
+
+/// Loudness is measured over this much of the most recent audio, in microseconds.
+const SHORT_TERM_WINDOW: i64 = 400_000;
+
+/// Time constant of the rolling baseline the loudness is compared to, in microseconds.
+const BASELINE_WINDOW: i64 = 10_000_000;
+
+/// Nothing is marked before this much audio was heard, so the baseline has settled first.
+const WARMUP: i64 = 2_000_000;
+
+/// Anything quieter counts as silence, which keeps a quiet stretch from dragging the baseline so
+/// far down that any sound becomes an event.
+const SILENCE_DB: f64 = -50.0;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioEventsConfig {
+    /// Add an `audio-peak` marker whenever the audio gets `threshold_db` louder than usual.
+    pub enabled: bool,
+    pub threshold_db: f64,
+    /// At most one marker is added per this many seconds, however long the audio stays loud.
+    pub debounce_seconds: u32,
+}
+
+impl Default for AudioEventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_db: 12.0,
+            debounce_seconds: 10,
+        }
+    }
+}
+
+/// Compares the short-term loudness of the audio to its rolling baseline. A loud section only
+/// counts once, until it quietens down again, and sections starting within the debounce time of
+/// the last marker don't count at all.
+#[derive(Debug)]
+pub struct AudioEventDetector {
+    threshold_db: f64,
+    debounce: i64,
+    /// Capture time, sum of squares and sample count of the chunks within the short-term window.
+    recent: VecDeque<(i64, f64, usize)>,
+    baseline_db: Option<f64>,
+    first_timestamp: Option<i64>,
+    previous_timestamp: Option<i64>,
+    last_marker: Option<i64>,
+    loud: bool,
+}
+
+impl AudioEventDetector {
+    pub fn new(config: &AudioEventsConfig) -> Self {
+        Self {
+            threshold_db: config.threshold_db,
+            debounce: i64::from(config.debounce_seconds) * 1_000_000,
+            recent: VecDeque::new(),
+            baseline_db: None,
+            first_timestamp: None,
+            previous_timestamp: None,
+            last_marker: None,
+            loud: false,
+        }
+    }
+
+    /// Feeds the levels of the audio captured at `timestamp`, in microseconds. Returns whether a
+    /// marker should be added for it.
+    pub fn observe(&mut self, timestamp: i64, chunk: &ChunkLevels) -> bool {
+        let sum_squares: f64 = chunk.sum_squares.iter().sum();
+        self.recent.push_back((
+            timestamp,
+            sum_squares,
+            chunk.samples * chunk.sum_squares.len(),
+        ));
+        while self
+            .recent
+            .front()
+            .is_some_and(|&(at, _, _)| timestamp - at > SHORT_TERM_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        let loudness = self.short_term_db();
+
+        let first = *self.first_timestamp.get_or_insert(timestamp);
+        let elapsed = self
+            .previous_timestamp
+            .replace(timestamp)
+            .map_or(0, |previous| (timestamp - previous).max(0));
+        let baseline = match self.baseline_db {
+            Some(baseline) => {
+                let weight = (elapsed as f64 / BASELINE_WINDOW as f64).min(1.0);
+                baseline + (loudness - baseline) * weight
+            }
+            None => loudness,
+        };
+        // Compared to the baseline from before this chunk, so a sudden bang isn't averaged in
+        let threshold = self.baseline_db.unwrap_or(baseline) + self.threshold_db;
+        self.baseline_db = Some(baseline);
+
+        if loudness <= threshold {
+            self.loud = false;
+            return false;
+        }
+        if self.loud || timestamp - first < WARMUP {
+            return false;
+        }
+        self.loud = true;
+        if self
+            .last_marker
+            .is_some_and(|marker| timestamp - marker < self.debounce)
+        {
+            return false;
+        }
+        self.last_marker = Some(timestamp);
+        true
+    }
+
+    fn short_term_db(&self) -> f64 {
+        let (sum_squares, samples) = self
+            .recent
+            .iter()
+            .fold((0.0, 0), |(sum, count), &(_, chunk_sum, chunk_count)| {
+                (sum + chunk_sum, count + chunk_count)
+            });
+        if samples == 0 || sum_squares <= 0.0 {
+            return SILENCE_DB;
+        }
+        (10.0 * (sum_squares / samples as f64).log10()).max(SILENCE_DB)
+    }
+}
@@ -0,0 +1,69 @@
+use super::{super::audio_levels::ChunkLevels, audio_events::*};
+
+/// 20ms of stereo audio at `amplitude`, one chunk per Opus frame.
+fn chunk(amplitude: f32) -> ChunkLevels {
+    ChunkLevels::measure_planar(&[&[amplitude; 960], &[-amplitude; 960]])
+}
+
+/// Feeds `seconds` of audio at `amplitude` starting at `*timestamp` and returns the capture times
+/// a marker was asked for at.
+fn feed(
+    detector: &mut AudioEventDetector,
+    timestamp: &mut i64,
+    seconds: i64,
+    amplitude: f32,
+) -> Vec<i64> {
+    let mut markers = Vec::new();
+    for _ in 0..seconds * 50 {
+        if detector.observe(*timestamp, &chunk(amplitude)) {
+            markers.push(*timestamp);
+        }
+        *timestamp += 20_000;
+    }
+    markers
+}
+
+#[test]
+fn test_sustained_loud_section_marks_once() {
+    let mut detector = AudioEventDetector::new(&AudioEventsConfig::default());
+    let mut timestamp = 0;
+    assert!(feed(&mut detector, &mut timestamp, 5, 0.01).is_empty());
+
+    let loud_start = timestamp;
+    let markers = feed(&mut detector, &mut timestamp, 8, 0.5);
+    assert_eq!(markers.len(), 1);
+    assert!(markers[0] - loud_start < 200_000);
+}
+
+#[test]
+fn test_events_are_debounced() {
+    let config = AudioEventsConfig {
+        debounce_seconds: 10,
+        ..Default::default()
+    };
+    let mut detector = AudioEventDetector::new(&config);
+    let mut timestamp = 0;
+    feed(&mut detector, &mut timestamp, 5, 0.01);
+
+    // Two short bangs a few seconds apart only mark the first
+    assert_eq!(feed(&mut detector, &mut timestamp, 1, 0.5).len(), 1);
+    feed(&mut detector, &mut timestamp, 3, 0.01);
+    assert!(feed(&mut detector, &mut timestamp, 1, 0.5).is_empty());
+
+    // Once the debounce time passed a new bang counts again
+    feed(&mut detector, &mut timestamp, 10, 0.01);
+    assert_eq!(feed(&mut detector, &mut timestamp, 1, 0.5).len(), 1);
+}
+
+#[test]
+fn test_nothing_marked_while_warming_up_or_steady() {
+    let mut detector = AudioEventDetector::new(&AudioEventsConfig::default());
+    let mut timestamp = 0;
+    // Loud from the start is the baseline, not an event
+    assert!(feed(&mut detector, &mut timestamp, 1, 0.5).is_empty());
+    assert!(feed(&mut detector, &mut timestamp, 20, 0.5).is_empty());
+
+    // Silence followed by quiet sound below the threshold
+    feed(&mut detector, &mut timestamp, 20, 0.0);
+    assert!(feed(&mut detector, &mut timestamp, 2, 0.001).is_empty());
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// Events kept at most, the oldest go first. Trimming with the buffer normally keeps the log far
+/// below this, it only matters when frames are dropped continuously.
+const MAX_EVENTS: usize = 10_000;
+
+/// Something which happened to the capture that is worth knowing when looking at a clip.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The shadow buffer could not keep up and a staged frame was dropped.
+    VideoFrameDropped,
+    AudioFrameDropped,
+    Marker {
+        id: u32,
+        label: String,
+        automatic: bool,
+    },
+    Paused,
+    Resumed,
+    /// The watchdog restarted the capture and its encoders after this long without frames.
+    CaptureRestarted {
+        stalled_seconds: u64,
+    },
+    CaptureLost {
+        reason: String,
+    },
+    CaptureRecovered,
+    CaptureReconnected,
+}
+
+/// An event at `timestamp`, a capture time in microseconds like the frame timestamps in the
+/// shadow buffers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedEvent {
+    pub timestamp: i64,
+    pub kind: EventKind,
+}
+
+/// Events ordered by timestamp, trimmed together with the shadow buffers.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: VecDeque<LoggedEvent>,
+}
+
+impl EventLog {
+    pub fn record(&mut self, timestamp: i64, kind: EventKind) {
+        // Events come in as they happen so this is nearly always a push to the back
+        let index = self
+            .events
+            .iter()
+            .rposition(|e| e.timestamp <= timestamp)
+            .map_or(0, |i| i + 1);
+        self.events.insert(index, LoggedEvent { timestamp, kind });
+        if self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    /// Discards events from before `timestamp`, i.e. the ones whose footage was trimmed.
+    pub fn trim_before(&mut self, timestamp: i64) {
+        while self.events.front().is_some_and(|e| e.timestamp < timestamp) {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn events(&self) -> &VecDeque<LoggedEvent> {
+        &self.events
+    }
+}
+
+/// An event as written next to a clip.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClipEvent {
+    /// Microseconds from the start of the clip.
+    pub offset: i64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// The events between `start` and `end` (capture times in microseconds), relative to `start`.
+pub fn events_for(events: &[LoggedEvent], start: i64, end: i64) -> Vec<ClipEvent> {
+    events
+        .iter()
+        .filter(|e| (start..=end).contains(&e.timestamp))
+        .map(|e| ClipEvent {
+            offset: e.timestamp - start,
+            kind: e.kind.clone(),
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct EventsFile<'a> {
+    clip: &'a str,
+    events: &'a [ClipEvent],
+}
+
+/// Where the events of `clip` are written, `<clip file name>.events.json` next to it.
+pub fn events_path(clip: &Path) -> PathBuf {
+    let file_name = clip
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    clip.with_file_name(format!("{file_name}.events.json"))
+}
+
+/// Writes the events of `clip` to [`events_path`].
+pub fn write_events(clip: &Path, events: &[ClipEvent]) -> Result<()> {
+    let file = EventsFile {
+        clip: &clip.display().to_string(),
+        events,
+    };
+    fs::write(events_path(clip), serde_json::to_string_pretty(&file)?)?;
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::fs;
+
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+use super::events::*;
+use crate::encoders::buffer::ShadowCaptureVideoBuffer;
+
+fn video_frame(pts: i64, is_keyframe: bool) -> EncodedVideoFrame {
+    EncodedVideoFrame {
+        data: vec![0; 16],
+        is_keyframe,
+        pts,
+        dts: pts,
+    }
+}
+
+#[test]
+fn test_events_ordered_and_bounded() {
+    let mut log = EventLog::default();
+    log.record(100, EventKind::Paused);
+    log.record(300, EventKind::Resumed);
+    // Recorded late but happened between the other two
+    log.record(200, EventKind::VideoFrameDropped);
+    let timestamps: Vec<_> = log.events().iter().map(|e| e.timestamp).collect();
+    assert_eq!(timestamps, vec![100, 200, 300]);
+
+    for timestamp in 0..20_000 {
+        log.record(1_000 + timestamp, EventKind::AudioFrameDropped);
+    }
+    assert_eq!(log.events().len(), 10_000);
+    assert_eq!(log.events()[0].timestamp, 11_000);
+}
+
+#[test]
+fn test_trimming_the_buffer_expires_events() {
+    // One second of buffer with a key frame every 250ms
+    let mut buffer = ShadowCaptureVideoBuffer::new(1_000_000);
+    let mut log = EventLog::default();
+    for pts in (0..=2_000_000).step_by(50_000) {
+        buffer.insert(pts, video_frame(pts, pts % 250_000 == 0));
+        if pts % 500_000 == 0 {
+            log.record(pts, EventKind::VideoFrameDropped);
+        }
+    }
+
+    let oldest = buffer.oldest_pts().unwrap();
+    assert!(oldest > 0);
+    assert_eq!(log.events().len(), 5);
+    log.trim_before(oldest);
+    assert!(log.events().len() < 5);
+    assert!(log.events().iter().all(|e| e.timestamp >= oldest));
+    assert_eq!(log.events().back().unwrap().timestamp, 2_000_000);
+}
+
+#[test]
+fn test_events_rebased_to_the_clip() {
+    let mut log = EventLog::default();
+    log.record(50, EventKind::Paused);
+    log.record(
+        150,
+        EventKind::Marker {
+            id: 3,
+            label: "good play".to_string(),
+            automatic: false,
+        },
+    );
+    log.record(
+        400,
+        EventKind::CaptureRestarted {
+            stalled_seconds: 10,
+        },
+    );
+    log.record(1_000, EventKind::Resumed);
+    let events: Vec<_> = log.events().iter().cloned().collect();
+
+    let in_clip = events_for(&events, 100, 400);
+    let offsets: Vec<_> = in_clip.iter().map(|e| e.offset).collect();
+    assert_eq!(offsets, vec![50, 300]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let clip = dir.path().join("clip_1.mp4");
+    write_events(&clip, &in_clip).unwrap();
+
+    let path = dir.path().join("clip_1.mp4.events.json");
+    assert_eq!(events_path(&clip), path);
+    let json: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(json["events"][0]["type"], "marker");
+    assert_eq!(json["events"][0]["label"], "good play");
+    assert_eq!(json["events"][1]["type"], "capture_restarted");
+    assert_eq!(json["events"][1]["offset"], 300);
+}
@@ -0,0 +1,142 @@
+use std::{path::Path, process::Stdio, time::Duration};
+
+use anyhow::{bail, Result};
+use tokio::process::Command;
+
+/// Placeholder in `post_save_command` which is replaced by the clip path.
+const PATH_PLACEHOLDER: &str = "{path}";
+
+/// Builds the program and arguments to run for `clip` from the `post_save_command` template.
+///
+/// With `shell` the template is run through `sh -c` with the path passed as `$1`, so it never
+/// has to be quoted into the script. Otherwise the template is split like a shell would (quotes
+/// and backslashes, no expansions) and `{path}` is substituted in each argument. Either way the
+/// path is appended as the last argument when the template has no placeholder.
+pub fn post_save_argv(template: &str, shell: bool, clip: &Path) -> Result<Vec<String>> {
+    let path = clip.display().to_string();
+    if shell {
+        let script = if template.contains(PATH_PLACEHOLDER) {
+            template.replace(PATH_PLACEHOLDER, "\"$1\"")
+        } else {
+            format!("{template} \"$1\"")
+        };
+        return Ok(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            script,
+            "sh".to_string(),
+            path,
+        ]);
+    }
+
+    let mut argv = split_command(template)?;
+    if argv.is_empty() {
+        bail!("post_save_command is empty");
+    }
+    if argv.iter().any(|arg| arg.contains(PATH_PLACEHOLDER)) {
+        for arg in argv.iter_mut() {
+            *arg = arg.replace(PATH_PLACEHOLDER, &path);
+        }
+    } else {
+        argv.push(path);
+    }
+    Ok(argv)
+}
+
+/// Splits `command` into arguments on unquoted whitespace, honouring single quotes, double quotes
+/// and backslash escapes.
+pub fn split_command(command: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => bail!("Unterminated single quote in {command:?}"),
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => current.push(c),
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => bail!("Unterminated double quote in {command:?}"),
+                        },
+                        Some(c) => current.push(c),
+                        None => bail!("Unterminated double quote in {command:?}"),
+                    }
+                }
+            }
+            '\\' => {
+                in_arg = true;
+                match chars.next() {
+                    Some(c) => current.push(c),
+                    None => bail!("Trailing backslash in {command:?}"),
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// Runs the post save command, killing it once `timeout` has passed. Failures are only logged,
+/// the hook never affects the save itself.
+pub async fn run_post_save(argv: Vec<String>, timeout: Duration) {
+    let Some((program, args)) = argv.split_first() else {
+        return;
+    };
+
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            log::error!("Could not start post save command {argv:?}: {e:?}");
+            return;
+        }
+    };
+
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) if output.status.success() => {
+            log::info!("Post save command {argv:?} finished");
+        }
+        Ok(Ok(output)) => log::error!(
+            "Post save command {argv:?} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(Err(e)) => log::error!("Could not wait for post save command {argv:?}: {e:?}"),
+        Err(_) => log::error!("Post save command {argv:?} timed out after {timeout:?}, killed it"),
+    }
+}
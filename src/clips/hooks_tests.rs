@@ -0,0 +1,52 @@
+use std::path::Path;
+
+use super::hooks::*;
+
+#[test]
+fn test_split_command_quotes() {
+    assert_eq!(
+        split_command(r#"rsync -a "my clips/" 'nas:/srv/a b' back\ slash"#).unwrap(),
+        vec!["rsync", "-a", "my clips/", "nas:/srv/a b", "back slash"]
+    );
+    assert_eq!(
+        split_command(r#"echo "" "a\"b""#).unwrap(),
+        vec!["echo", "", "a\"b"]
+    );
+    assert!(split_command("   ").unwrap().is_empty());
+    assert!(split_command("echo 'oops").is_err());
+}
+
+#[test]
+fn test_post_save_argv_placeholder_and_append() {
+    let clip = Path::new("/clips/clip 1.mp4");
+
+    assert_eq!(
+        post_save_argv("cp {path} /mnt/nas/", false, clip).unwrap(),
+        vec!["cp", "/clips/clip 1.mp4", "/mnt/nas/"]
+    );
+    assert_eq!(
+        post_save_argv("upload --quiet", false, clip).unwrap(),
+        vec!["upload", "--quiet", "/clips/clip 1.mp4"]
+    );
+    assert!(post_save_argv("", false, clip).is_err());
+}
+
+#[test]
+fn test_post_save_argv_shell_passes_path_as_argument() {
+    let clip = Path::new("/clips/$(rm -rf).mp4");
+
+    assert_eq!(
+        post_save_argv("cp {path} /mnt/nas/ && notify-send done", true, clip).unwrap(),
+        vec![
+            "sh",
+            "-c",
+            "cp \"$1\" /mnt/nas/ && notify-send done",
+            "sh",
+            "/clips/$(rm -rf).mp4"
+        ]
+    );
+    assert_eq!(
+        post_save_argv("upload", true, clip).unwrap()[2],
+        "upload \"$1\""
+    );
+}
@@ -0,0 +1,104 @@
+//! Catalog of the saved clips, kept as one JSON line per clip in the output directory so the
+//! library can be searched without probing every file. Lines which don't parse are skipped with a
+//! warning, a damaged index never stops a save.
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+
+use super::naming::{index_path, partial_path};
+use crate::dbus_types::ClipInfo;
+
+/// Held while the index is read or written. Saves append to it on the run loop while the
+/// retention policy rewrites it on a blocking thread.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// Adds `clip` to the index of `output_dir`.
+pub fn append(output_dir: &Path, clip: &ClipInfo) -> Result<()> {
+    let path = index_path(output_dir);
+    let _guard = INDEX_LOCK.lock();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Could not open the clip index {path:?}"))?;
+    // One write per line, so a crash can't leave half a record in the middle of the file
+    let mut line = serde_json::to_string(clip)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Every clip in the index of `output_dir`, oldest first.
+pub fn load(output_dir: &Path) -> Result<Vec<ClipInfo>> {
+    let _guard = INDEX_LOCK.lock();
+    read(&index_path(output_dir))
+}
+
+/// The `limit` most recently saved clips, newest first. 0 returns all of them.
+pub fn recent(output_dir: &Path, limit: u32) -> Result<Vec<ClipInfo>> {
+    let mut clips = load(output_dir)?;
+    clips.reverse();
+    if limit > 0 {
+        clips.truncate(limit as usize);
+    }
+    Ok(clips)
+}
+
+/// The record of the clip at `path`, relative to `output_dir` unless absolute.
+pub fn find(output_dir: &Path, path: &Path) -> Result<Option<ClipInfo>> {
+    let wanted = output_dir.join(path);
+    Ok(load(output_dir)?
+        .into_iter()
+        .rev()
+        .find(|clip| Path::new(&clip.path) == wanted))
+}
+
+/// Drops the records of the `deleted` clips, rewriting the index of `output_dir`.
+pub fn remove(output_dir: &Path, deleted: &[PathBuf]) -> Result<()> {
+    let path = index_path(output_dir);
+    let _guard = INDEX_LOCK.lock();
+    let kept: Vec<_> = read(&path)?
+        .into_iter()
+        .filter(|clip| {
+            !deleted
+                .iter()
+                .any(|deleted| Path::new(&clip.path) == deleted)
+        })
+        .collect();
+
+    let mut contents = String::new();
+    for clip in &kept {
+        contents.push_str(&serde_json::to_string(clip)?);
+        contents.push('\n');
+    }
+    let partial = partial_path(&path);
+    fs::write(&partial, contents)
+        .and_then(|()| fs::rename(&partial, &path))
+        .with_context(|| format!("Could not rewrite the clip index {path:?}"))
+}
+
+fn read(path: &Path) -> Result<Vec<ClipInfo>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Could not read the clip index {path:?}")),
+    };
+
+    let mut clips = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<ClipInfo>(line) {
+            Ok(clip) if !clip.path.is_empty() => clips.push(clip),
+            Ok(_) => log::warn!("Skipping line {} of {path:?}, it names no clip", number + 1),
+            Err(e) => log::warn!("Skipping line {} of {path:?}: {e}", number + 1),
+        }
+    }
+    Ok(clips)
+}
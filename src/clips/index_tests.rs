@@ -0,0 +1,65 @@
+use std::{fs, path::Path};
+
+use super::{index::*, naming::index_path};
+use crate::dbus_types::ClipInfo;
+
+fn clip(dir: &Path, timestamp: i64) -> ClipInfo {
+    ClipInfo {
+        path: dir
+            .join(format!("clip_{timestamp}.mp4"))
+            .display()
+            .to_string(),
+        saved_at_ms: timestamp * 1000,
+        mode: "Shadow".to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_recent_clips_come_newest_first() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    assert!(recent(dir, 0).unwrap().is_empty());
+    for timestamp in 1..=3 {
+        append(dir, &clip(dir, timestamp)).unwrap();
+    }
+
+    let all = recent(dir, 0).unwrap();
+    assert_eq!(all, vec![clip(dir, 3), clip(dir, 2), clip(dir, 1)]);
+    assert_eq!(recent(dir, 2).unwrap().len(), 2);
+
+    assert_eq!(
+        find(dir, Path::new("clip_2.mp4")).unwrap(),
+        Some(clip(dir, 2))
+    );
+    assert_eq!(
+        find(dir, &dir.join("clip_3.mp4")).unwrap(),
+        Some(clip(dir, 3))
+    );
+    assert_eq!(find(dir, Path::new("clip_4.mp4")).unwrap(), None);
+}
+
+#[test]
+fn test_corrupt_lines_are_skipped() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    append(dir, &clip(dir, 1)).unwrap();
+    let mut contents = fs::read_to_string(index_path(dir)).unwrap();
+    contents.push_str("{\"path\": \"half a rec\n\n{}\n");
+    fs::write(index_path(dir), contents).unwrap();
+    append(dir, &clip(dir, 2)).unwrap();
+
+    assert_eq!(load(dir).unwrap(), vec![clip(dir, 1), clip(dir, 2)]);
+}
+
+#[test]
+fn test_removed_clips_leave_the_index() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    for timestamp in 1..=3 {
+        append(dir, &clip(dir, timestamp)).unwrap();
+    }
+
+    remove(dir, &[dir.join("clip_1.mp4"), dir.join("clip_3.mp4")]).unwrap();
+    assert_eq!(load(dir).unwrap(), vec![clip(dir, 2)]);
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// A moment marked over dbus, `timestamp` being a capture time in microseconds like the frame
+/// timestamps in the shadow buffers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub id: u32,
+    pub timestamp: i64,
+    pub label: String,
+    /// Added by the audio event detector rather than over dbus.
+    pub automatic: bool,
+}
+
+/// Markers ordered by timestamp, trimmed together with the shadow buffers.
+#[derive(Debug, Default)]
+pub struct Markers {
+    markers: VecDeque<Marker>,
+    next_id: u32,
+}
+
+impl Markers {
+    /// Records a marker and returns its id.
+    pub fn add(&mut self, timestamp: i64, label: String) -> u32 {
+        self.insert(timestamp, label, false)
+    }
+
+    /// Records a marker found by the audio event detector and returns its id.
+    pub fn add_automatic(&mut self, timestamp: i64, label: String) -> u32 {
+        self.insert(timestamp, label, true)
+    }
+
+    fn insert(&mut self, timestamp: i64, label: String, automatic: bool) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // Markers come in as they happen so this is nearly always a push to the back
+        let index = self
+            .markers
+            .iter()
+            .rposition(|m| m.timestamp <= timestamp)
+            .map_or(0, |i| i + 1);
+        self.markers.insert(
+            index,
+            Marker {
+                id,
+                timestamp,
+                label,
+                automatic,
+            },
+        );
+        id
+    }
+
+    /// Discards markers from before `timestamp`, i.e. the ones whose footage was trimmed.
+    pub fn trim_before(&mut self, timestamp: i64) {
+        while self
+            .markers
+            .front()
+            .is_some_and(|m| m.timestamp < timestamp)
+        {
+            self.markers.pop_front();
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Marker> {
+        self.markers.iter().find(|m| m.id == id)
+    }
+
+    pub fn markers(&self) -> &VecDeque<Marker> {
+        &self.markers
+    }
+
+    pub fn len(&self) -> usize {
+        self.markers.len()
+    }
+
+    pub fn automatic_len(&self) -> usize {
+        self.markers.iter().filter(|m| m.automatic).count()
+    }
+
+    #[cfg(test)]
+    pub fn clear(&mut self) {
+        self.markers.clear();
+    }
+}
+
+/// A marker as written to a clip, relative to the start of the clip.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Chapter {
+    pub id: u32,
+    pub title: String,
+    /// Microseconds from the start of the clip.
+    pub start: i64,
+    /// Microseconds from the start of the clip, where the next chapter or the clip ends.
+    pub end: i64,
+}
+
+/// Turns the markers between `start` and `end` (capture times in microseconds) into chapters.
+pub fn chapters_for(markers: &[Marker], start: i64, end: i64) -> Vec<Chapter> {
+    let in_clip: Vec<_> = markers
+        .iter()
+        .filter(|m| (start..=end).contains(&m.timestamp))
+        .collect();
+
+    in_clip
+        .iter()
+        .enumerate()
+        .map(|(i, marker)| Chapter {
+            id: marker.id,
+            title: marker.label.clone(),
+            start: marker.timestamp - start,
+            end: in_clip.get(i + 1).map_or(end, |next| next.timestamp) - start,
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    clip: &'a str,
+    markers: &'a [Chapter],
+}
+
+/// Where the chapters of `clip` are written, `<clip name>.json` next to it.
+pub fn sidecar_path(clip: &Path) -> PathBuf {
+    clip.with_extension("json")
+}
+
+/// Writes the chapters next to `clip` as [`sidecar_path`] for tools which ignore chapters.
+pub fn write_sidecar(clip: &Path, chapters: &[Chapter]) -> Result<()> {
+    let sidecar = Sidecar {
+        clip: &clip.display().to_string(),
+        markers: chapters,
+    };
+    fs::write(sidecar_path(clip), serde_json::to_string_pretty(&sidecar)?)?;
+    Ok(())
+}
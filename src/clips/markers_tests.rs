@@ -0,0 +1,74 @@
+use std::fs;
+
+use super::markers::*;
+
+#[test]
+fn test_markers_ordered_and_trimmed() {
+    let mut markers = Markers::default();
+    let first = markers.add(100, "first".to_string());
+    let third = markers.add(300, "third".to_string());
+    // Added late but belongs between the other two
+    let second = markers.add(200, "second".to_string());
+
+    assert_eq!((first, third, second), (0, 1, 2));
+    let timestamps: Vec<_> = markers.markers().iter().map(|m| m.timestamp).collect();
+    assert_eq!(timestamps, vec![100, 200, 300]);
+
+    markers.trim_before(200);
+    assert_eq!(markers.len(), 2);
+    assert_eq!(markers.markers()[0].label, "second");
+
+    // Ids keep counting up after markers are dropped
+    markers.clear();
+    assert_eq!(markers.add(400, "fourth".to_string()), 3);
+}
+
+#[test]
+fn test_automatic_markers_counted() {
+    let mut markers = Markers::default();
+    markers.add(100, "manual".to_string());
+    let id = markers.add_automatic(200, "audio-peak".to_string());
+    markers.add_automatic(300, "audio-peak".to_string());
+
+    assert!(markers.get(id).is_some_and(|m| m.automatic));
+    assert_eq!((markers.len(), markers.automatic_len()), (3, 2));
+    markers.trim_before(250);
+    assert_eq!((markers.len(), markers.automatic_len()), (1, 1));
+}
+
+#[test]
+fn test_chapters_for_clip_window() {
+    let mut markers = Markers::default();
+    markers.add(50, "before".to_string());
+    markers.add(100, "start".to_string());
+    markers.add(250, "middle".to_string());
+    markers.add(1_000, "after".to_string());
+    let markers: Vec<_> = markers.markers().iter().cloned().collect();
+
+    let chapters = chapters_for(&markers, 100, 400);
+
+    let spans: Vec<_> = chapters
+        .iter()
+        .map(|c| (c.title.as_str(), c.start, c.end))
+        .collect();
+    assert_eq!(spans, vec![("start", 0, 150), ("middle", 150, 300)]);
+}
+
+#[test]
+fn test_write_sidecar() {
+    let dir = tempfile::tempdir().unwrap();
+    let clip = dir.path().join("clip_1.mp4");
+    let chapters = vec![Chapter {
+        id: 4,
+        title: "good play".to_string(),
+        start: 1_000_000,
+        end: 2_000_000,
+    }];
+
+    write_sidecar(&clip, &chapters).unwrap();
+
+    let json: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dir.path().join("clip_1.json")).unwrap()).unwrap();
+    assert_eq!(json["markers"][0]["title"], "good play");
+    assert_eq!(json["markers"][0]["start"], 1_000_000);
+}
@@ -0,0 +1,19 @@
+pub mod audio_events;
+#[cfg(test)]
+mod audio_events_tests;
+pub mod events;
+#[cfg(test)]
+mod events_tests;
+pub mod hooks;
+#[cfg(test)]
+mod hooks_tests;
+pub mod index;
+#[cfg(test)]
+mod index_tests;
+pub mod markers;
+#[cfg(test)]
+mod markers_tests;
+pub mod naming;
+pub mod retention;
+#[cfg(test)]
+mod retention_tests;
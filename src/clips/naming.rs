@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+const CLIP_PREFIX: &str = "clip_";
+const CLIP_EXTENSION: &str = "mp4";
+/// Extension of clips holding re-encoded footage, MKV copes with the stream parameters changing
+/// midway where MP4 doesn't.
+const MIXED_CLIP_EXTENSION: &str = "mkv";
+/// Extensions of audio-only clips, depending on the audio codec.
+const AUDIO_CLIP_EXTENSIONS: [&str; 3] = ["opus", "m4a", "mka"];
+const RECORDING_PREFIX: &str = "recording_";
+const PARTIAL_PREFIX: &str = ".partial_";
+const SCREENSHOT_PREFIX: &str = "screenshot_";
+const SCREENSHOT_EXTENSION: &str = "png";
+const GIF_PREFIX: &str = "gif_";
+const GIF_EXTENSION: &str = "gif";
+const TRANSCODED_SUFFIX: &str = "_transcoded";
+const INDEX_FILE_NAME: &str = "index.jsonl";
+
+/// Path of the clip saved at `timestamp` (unix milliseconds) inside `output_dir`. Saves of
+/// windows can finish within the same second, so seconds alone don't tell clips apart.
+pub fn clip_path(output_dir: &Path, timestamp: i64) -> PathBuf {
+    output_dir.join(format!("{CLIP_PREFIX}{timestamp}.{CLIP_EXTENSION}"))
+}
+
+/// Path of a clip saved at `timestamp` like [`clip_path`], for clips which hold footage
+/// re-encoded at a lower quality.
+pub fn mixed_quality_clip_path(output_dir: &Path, timestamp: i64) -> PathBuf {
+    output_dir.join(format!("{CLIP_PREFIX}{timestamp}.{MIXED_CLIP_EXTENSION}"))
+}
+
+/// Path of an audio-only clip saved at `timestamp` like [`clip_path`], `extension` being one
+/// fitting its codec.
+pub fn audio_clip_path(output_dir: &Path, timestamp: i64, extension: &str) -> PathBuf {
+    output_dir.join(format!("{CLIP_PREFIX}{timestamp}.{extension}"))
+}
+
+/// Path of segment `sequence` of the recording started at `timestamp` (unix seconds) inside
+/// `output_dir`.
+pub fn recording_path(output_dir: &Path, timestamp: i64, sequence: u32) -> PathBuf {
+    output_dir.join(format!(
+        "{RECORDING_PREFIX}{timestamp}_{sequence:03}.{CLIP_EXTENSION}"
+    ))
+}
+
+/// Path of the audio-only recording started at `timestamp` (unix seconds) inside `output_dir`,
+/// `extension` being one fitting its codec.
+pub fn audio_recording_path(output_dir: &Path, timestamp: i64, extension: &str) -> PathBuf {
+    output_dir.join(format!("{RECORDING_PREFIX}{timestamp}.{extension}"))
+}
+
+/// Path of the screenshot taken at `timestamp` (unix seconds) inside `output_dir`.
+pub fn screenshot_path(output_dir: &Path, timestamp: i64) -> PathBuf {
+    output_dir.join(format!(
+        "{SCREENSHOT_PREFIX}{timestamp}.{SCREENSHOT_EXTENSION}"
+    ))
+}
+
+/// Path of the GIF saved at `timestamp` (unix seconds) inside `output_dir`.
+pub fn gif_path(output_dir: &Path, timestamp: i64) -> PathBuf {
+    output_dir.join(format!("{GIF_PREFIX}{timestamp}.{GIF_EXTENSION}"))
+}
+
+/// Path the transcode of `input` to a container with `extension` is written to, next to the
+/// input and never the same file.
+pub fn transcoded_path(input: &Path, extension: &str) -> PathBuf {
+    let stem = input
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    input.with_file_name(format!("{stem}{TRANSCODED_SUFFIX}.{extension}"))
+}
+
+/// Path of the clip index inside `output_dir`.
+pub fn index_path(output_dir: &Path) -> PathBuf {
+    output_dir.join(INDEX_FILE_NAME)
+}
+
+/// Moves the complete `partial` file to `path`. Unlike a rename this fails with
+/// [`std::io::ErrorKind::AlreadyExists`] instead of replacing a file already at `path`.
+pub fn move_into_place(partial: &Path, path: &Path) -> std::io::Result<()> {
+    std::fs::hard_link(partial, path)?;
+    std::fs::remove_file(partial)
+}
+
+/// Hidden file next to `path` which is written first and renamed to `path` once complete, so a
+/// failed write never leaves a broken file under the final name.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!("{PARTIAL_PREFIX}{file_name}"))
+}
+
+/// Whether `file_name` is one of the files [`partial_path`] names.
+pub fn is_partial_file(file_name: &str) -> bool {
+    file_name
+        .strip_prefix(PARTIAL_PREFIX)
+        .is_some_and(|rest| !rest.is_empty())
+}
+
+/// Whether `file_name` looks like a clip written by [`clip_path`], [`mixed_quality_clip_path`] or
+/// [`audio_clip_path`].
+pub fn is_clip_file(file_name: &str) -> bool {
+    file_name
+        .strip_prefix(CLIP_PREFIX)
+        .and_then(|rest| {
+            rest.strip_suffix(CLIP_EXTENSION)
+                .or_else(|| rest.strip_suffix(MIXED_CLIP_EXTENSION))
+                .or_else(|| {
+                    AUDIO_CLIP_EXTENSIONS
+                        .iter()
+                        .find_map(|extension| rest.strip_suffix(extension))
+                })
+        })
+        .and_then(|rest| rest.strip_suffix('.'))
+        .is_some_and(|timestamp| {
+            !timestamp.is_empty() && timestamp.chars().all(|c| c.is_ascii_digit())
+        })
+}
@@ -0,0 +1,123 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    events::events_path,
+    markers::sidecar_path,
+    naming::{is_clip_file, is_partial_file},
+};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Oldest clips are deleted once all clips together take more than this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_gb: Option<f64>,
+    /// Clips older than this are deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<u32>,
+}
+
+struct ClipFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Deletes the oldest clips in `dir` until they fit within the limits of `retention` and
+/// returns the deleted paths. Only files named like WayCap clips are considered and the newest
+/// clip is always kept, so the one which was just saved never gets pruned. The marker and event
+/// files written next to a clip are deleted with it.
+pub fn prune_clips(
+    dir: &Path,
+    retention: &RetentionConfig,
+    now: SystemTime,
+) -> Result<Vec<PathBuf>> {
+    let mut clips = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || !entry.file_name().to_str().is_some_and(is_clip_file) {
+            continue;
+        }
+
+        clips.push(ClipFile {
+            path: entry.path(),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+    clips.sort_by_key(|clip| clip.modified);
+
+    let max_bytes = retention
+        .max_total_gb
+        .map(|gb| (gb * 1024.0 * 1024.0 * 1024.0) as u64);
+    let max_age = retention
+        .max_age_days
+        .map(|days| Duration::from_secs(days as u64 * 24 * 60 * 60));
+    let mut total_bytes: u64 = clips.iter().map(|clip| clip.size).sum();
+
+    let mut deleted = Vec::new();
+    let keep_newest = clips.len().saturating_sub(1);
+    for clip in clips.into_iter().take(keep_newest) {
+        let age = now.duration_since(clip.modified).unwrap_or_default();
+        let reason = if max_age.is_some_and(|max| age > max) {
+            format!(
+                "older than {} days",
+                retention.max_age_days.unwrap_or_default()
+            )
+        } else if max_bytes.is_some_and(|max| total_bytes > max) {
+            format!(
+                "clips take {total_bytes} bytes, over the {} GB limit",
+                retention.max_total_gb.unwrap_or_default()
+            )
+        } else {
+            // Clips are sorted oldest first so every other clip is within the limits too
+            break;
+        };
+
+        fs::remove_file(&clip.path)?;
+        log::info!("Deleted {:?}: {reason}", clip.path);
+        for sidecar in [sidecar_path(&clip.path), events_path(&clip.path)] {
+            match fs::remove_file(&sidecar) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Could not delete {sidecar:?}: {e:?}"),
+            }
+        }
+        total_bytes -= clip.size;
+        deleted.push(clip.path);
+    }
+
+    Ok(deleted)
+}
+
+/// Deletes the partial files a save, transcode or join left in `dir` when WayCap exited before
+/// it finished, and returns their paths. Only safe while holding the instance lock, as the
+/// partial files of a running instance would go too.
+pub fn remove_partial_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut removed = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() || !entry.file_name().to_str().is_some_and(is_partial_file)
+        {
+            continue;
+        }
+        fs::remove_file(entry.path())?;
+        log::info!("Deleted the unfinished {:?}", entry.path());
+        removed.push(entry.path());
+    }
+    Ok(removed)
+}
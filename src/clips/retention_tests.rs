@@ -0,0 +1,196 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use super::{
+    naming::{
+        audio_clip_path, clip_path, is_clip_file, is_partial_file, mixed_quality_clip_path,
+        move_into_place, partial_path,
+    },
+    retention::*,
+};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn write_file(dir: &Path, name: &str, size: usize, modified: SystemTime) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, vec![0u8; size]).unwrap();
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    path
+}
+
+#[test]
+fn test_clip_naming() {
+    let path = clip_path(Path::new("clips"), 1700000000123);
+    assert_eq!(path, Path::new("clips/clip_1700000000123.mp4"));
+    assert!(is_clip_file("clip_1700000000123.mp4"));
+    // Clips saved before the names had milliseconds
+    assert!(is_clip_file("clip_1700000000.mp4"));
+
+    assert!(!is_clip_file("clip_.mp4"));
+    assert!(!is_clip_file("clip_abc.mp4"));
+    assert!(!is_clip_file("clip_1700000000.webm"));
+    assert!(!is_clip_file("holiday.mp4"));
+
+    let mixed = mixed_quality_clip_path(Path::new("clips"), 1700000000);
+    assert_eq!(mixed, Path::new("clips/clip_1700000000.mkv"));
+    assert!(is_clip_file("clip_1700000000.mkv"));
+
+    let audio = audio_clip_path(Path::new("clips"), 1700000000, "opus");
+    assert_eq!(audio, Path::new("clips/clip_1700000000.opus"));
+    assert!(is_clip_file("clip_1700000000.opus"));
+    assert!(is_clip_file("clip_1700000000.m4a"));
+
+    // Clips still being written are never pruned
+    let partial = partial_path(&path);
+    assert_eq!(partial, Path::new("clips/.partial_clip_1700000000123.mp4"));
+    assert!(!is_clip_file(
+        &partial.file_name().unwrap().to_string_lossy()
+    ));
+}
+
+#[test]
+fn test_move_into_place_keeps_existing_clips() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    let path = clip_path(dir, 1700000000123);
+    let partial = partial_path(&path);
+
+    fs::write(&partial, b"first").unwrap();
+    move_into_place(&partial, &path).unwrap();
+    assert!(!partial.exists());
+
+    // A second save finishing at the same time fails instead of replacing the first
+    fs::write(&partial, b"second").unwrap();
+    let error = move_into_place(&partial, &path).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(&path).unwrap(), b"first");
+    assert!(partial.exists());
+}
+
+#[test]
+fn test_prune_by_age() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    let now = SystemTime::now();
+    let old = write_file(dir, "clip_1.mp4", 10, now - DAY * 10);
+    let recent = write_file(dir, "clip_2.mp4", 10, now - DAY * 2);
+    let newest = write_file(dir, "clip_3.mp4", 10, now);
+
+    let retention = RetentionConfig {
+        max_age_days: Some(7),
+        ..Default::default()
+    };
+    let deleted = prune_clips(dir, &retention, now).unwrap();
+
+    assert_eq!(deleted, vec![old.clone()]);
+    assert!(!old.exists());
+    assert!(recent.exists());
+    assert!(newest.exists());
+}
+
+#[test]
+fn test_prune_by_total_size() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    let now = SystemTime::now();
+    let mb = 1024 * 1024;
+    let oldest = write_file(dir, "clip_1.mp4", mb, now - DAY * 3);
+    let older = write_file(dir, "clip_2.mp4", mb, now - DAY * 2);
+    let newer = write_file(dir, "clip_3.mp4", mb, now - DAY);
+    let newest = write_file(dir, "clip_4.mp4", mb, now);
+
+    // Room for 2.5 clips
+    let retention = RetentionConfig {
+        max_total_gb: Some(2.5 / 1024.0),
+        ..Default::default()
+    };
+    let deleted = prune_clips(dir, &retention, now).unwrap();
+
+    assert_eq!(deleted, vec![oldest, older]);
+    assert!(newer.exists());
+    assert!(newest.exists());
+}
+
+#[test]
+fn test_prune_only_touches_clips() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    let now = SystemTime::now();
+    let other = write_file(dir, "notes.txt", 10, now - DAY * 30);
+    let renamed = write_file(dir, "clip_best_moment.mp4", 10, now - DAY * 30);
+    let old = write_file(dir, "clip_1.mp4", 10, now - DAY * 30);
+    let newest = write_file(dir, "clip_2.mp4", 10, now - DAY * 29);
+
+    let retention = RetentionConfig {
+        max_age_days: Some(1),
+        max_total_gb: Some(0.0),
+    };
+    let deleted = prune_clips(dir, &retention, now).unwrap();
+
+    // The newest clip is kept even though it is over both limits
+    assert_eq!(deleted, vec![old]);
+    assert!(other.exists());
+    assert!(renamed.exists());
+    assert!(newest.exists());
+}
+
+#[test]
+fn test_prune_deletes_the_sidecars_with_the_clip() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    let now = SystemTime::now();
+    let old = write_file(dir, "clip_1.mp4", 10, now - DAY * 30);
+    let old_markers = write_file(dir, "clip_1.json", 10, now - DAY * 30);
+    let old_events = write_file(dir, "clip_1.mp4.events.json", 10, now - DAY * 30);
+    let newest = write_file(dir, "clip_2.mp4", 10, now);
+    let newest_markers = write_file(dir, "clip_2.json", 10, now);
+    let newest_events = write_file(dir, "clip_2.mp4.events.json", 10, now);
+
+    let retention = RetentionConfig {
+        max_age_days: Some(1),
+        max_total_gb: None,
+    };
+    let deleted = prune_clips(dir, &retention, now).unwrap();
+
+    assert_eq!(deleted, vec![old]);
+    assert!(!old_markers.exists());
+    assert!(!old_events.exists());
+    assert!(newest.exists());
+    assert!(newest_markers.exists());
+    assert!(newest_events.exists());
+}
+
+#[test]
+fn test_remove_partial_files() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    let now = SystemTime::now();
+    let clip = write_file(dir, "clip_1.mp4", 10, now);
+    let partial = partial_path(&clip_path(dir, 2));
+    fs::write(&partial, b"unfinished").unwrap();
+    let other = write_file(dir, ".partial_", 10, now);
+
+    assert_eq!(remove_partial_files(dir).unwrap(), vec![partial.clone()]);
+    assert!(!partial.exists());
+    assert!(clip.exists());
+    assert!(other.exists());
+
+    assert!(remove_partial_files(&dir.join("missing"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_partial_files_are_recognised() {
+    assert!(is_partial_file(".partial_clip_1.mp4"));
+    assert!(!is_partial_file(".partial_"));
+    assert!(!is_partial_file("clip_1.mp4"));
+}
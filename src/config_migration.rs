@@ -0,0 +1,72 @@
+//! Upgrades config files written by older versions of WayCap. Every file records the `version`
+//! of its format, one without it predates the field and is version 0.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Version of the config format written by this build.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// `MIGRATIONS[n]` upgrades a version `n` config to version `n + 1`, the version itself is set by
+/// [`migrate`].
+const MIGRATIONS: [fn(&mut toml::Table); CONFIG_VERSION as usize] = [from_unversioned];
+
+/// Version 0 only lacked the version, its fields are read as they are.
+fn from_unversioned(_config: &mut toml::Table) {}
+
+/// The format version of `config`.
+pub fn version_of(config: &toml::Table) -> Result<u32> {
+    match config.get("version") {
+        None => Ok(0),
+        Some(toml::Value::Integer(version)) => u32::try_from(*version)
+            .with_context(|| format!("The config version must be positive, not {version}")),
+        Some(other) => bail!("The config version must be a number, not {other}"),
+    }
+}
+
+/// Upgrades `config` to [`CONFIG_VERSION`], returning whether anything had to change. Configs
+/// from a newer WayCap are refused, their fields may mean something this build doesn't know.
+pub fn migrate(config: &mut toml::Table) -> Result<bool> {
+    let version = version_of(config)?;
+    if version > CONFIG_VERSION {
+        bail!(
+            "The config is version {version}, written by a newer WayCap. This one reads up to \
+             version {CONFIG_VERSION}"
+        );
+    }
+    if version == CONFIG_VERSION {
+        return Ok(false);
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(config);
+    }
+    config.insert("version".to_string(), i64::from(CONFIG_VERSION).into());
+    Ok(true)
+}
+
+/// Where the file at `path` is kept before it is migrated, `config.toml.bak` next to it.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!("{file_name}.bak"))
+}
+
+/// Replaces the config file at `path` with its `migrated` contents, copying the original from
+/// version `from` to [`backup_path`] first.
+pub fn replace_with_migrated(path: &Path, migrated: &str, from: u32) -> Result<()> {
+    let backup = backup_path(path);
+    fs::copy(path, &backup).with_context(|| format!("Could not back up {path:?} to {backup:?}"))?;
+    fs::write(path, migrated)
+        .with_context(|| format!("Could not write the migrated config to {path:?}"))?;
+    // Logging isn't set up yet when the config is first read
+    eprintln!(
+        "Migrated {path:?} from config version {from} to {CONFIG_VERSION}, the old file is kept \
+         as {backup:?}"
+    );
+    Ok(())
+}
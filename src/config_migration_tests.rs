@@ -0,0 +1,73 @@
+use std::{fs, path::PathBuf};
+
+use crate::{
+    application_config::{load_or_create_config, AppConfig, QualityPreset},
+    config_migration::*,
+};
+
+/// A config as written before the format had a version.
+const UNVERSIONED: &str = r#"
+encoder = "h264_nvenc"
+max_seconds = 42
+quality = "HIGH"
+audio_offset_ms = -150
+output_dir = "/tmp/clips"
+
+[tiering]
+bitrate_kbps = 3000
+"#;
+
+#[test]
+fn test_unversioned_config_keeps_its_values() {
+    let mut table: toml::Table = UNVERSIONED.parse().unwrap();
+    assert_eq!(version_of(&table).unwrap(), 0);
+
+    assert!(migrate(&mut table).unwrap());
+    assert_eq!(version_of(&table).unwrap(), CONFIG_VERSION);
+    assert!(!migrate(&mut table).unwrap());
+
+    let config: AppConfig = toml::from_str(&toml::to_string(&table).unwrap()).unwrap();
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.max_seconds, 42);
+    assert_eq!(config.quality, QualityPreset::High);
+    assert_eq!(config.audio_offset_ms, -150);
+    assert_eq!(config.output_dir, PathBuf::from("/tmp/clips"));
+    assert_eq!(config.tiering.bitrate_kbps, 3000);
+}
+
+#[test]
+fn test_migrated_file_is_backed_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, UNVERSIONED).unwrap();
+
+    let config = load_or_create_config(Some(&path)).unwrap();
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.max_seconds, 42);
+    assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), UNVERSIONED);
+    let migrated: toml::Table = fs::read_to_string(&path).unwrap().parse().unwrap();
+    assert_eq!(version_of(&migrated).unwrap(), CONFIG_VERSION);
+
+    // A current file is read as it is, without another backup
+    fs::remove_file(backup_path(&path)).unwrap();
+    assert_eq!(load_or_create_config(Some(&path)).unwrap(), config);
+    assert!(!backup_path(&path).exists());
+}
+
+#[test]
+fn test_newer_config_is_refused() {
+    let mut table: toml::Table = format!("version = {}\nmax_seconds = 42", CONFIG_VERSION + 1)
+        .parse()
+        .unwrap();
+    let error = migrate(&mut table).unwrap_err().to_string();
+    assert!(error.contains("newer WayCap"), "{error}");
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, format!("version = {}", CONFIG_VERSION + 1)).unwrap();
+    assert!(load_or_create_config(Some(&path)).is_err());
+    assert!(!backup_path(&path).exists());
+
+    let table: toml::Table = "version = \"one\"".parse().unwrap();
+    assert!(version_of(&table).is_err());
+}
@@ -1,49 +1,900 @@
-use tokio::sync::mpsc;
-use zbus::interface;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use crate::application_config::{AppConfig, AppConfigDbus, AppModeDbus};
+use tokio::sync::{mpsc, oneshot};
+use zbus::{interface, object_server::SignalEmitter, zvariant::Value};
+
+pub use crate::dbus_types::AppStatus;
+use crate::{
+    audio_levels::AudioLevelHistory,
+    dbus_types::{
+        AppConfigDbus, AppModeDbus, AudioLevels, ClipInfo, Diagnostics, SaveClipOptions,
+        SaveReport, TranscodeOptions,
+    },
+    diagnostics::{self, PROBE_TIMEOUT, RECENT_SECONDS},
+    encoders::{gif::GifOptions, job::JobState, muxer::ClipStreams},
+    stats::{DropCounters, EncodeCounters},
+};
+
+/// Sent alongside a config update so the run loop can report back which fields were not applied.
+pub type ConfigUpdateReply = oneshot::Sender<Result<Vec<String>, String>>;
+/// Sent alongside a new marker so the run loop can reply with its id.
+pub type MarkerReply = oneshot::Sender<Result<u32, String>>;
+/// Sent alongside a screenshot request so the run loop can reply with the written path.
+pub type ScreenshotReply = oneshot::Sender<Result<String, String>>;
+/// Sent alongside a GIF request so the run loop can reply with the written path.
+pub type GifReply = oneshot::Sender<Result<String, String>>;
+/// Sent alongside a mode change so the run loop can report whether the new mode is usable.
+pub type ModeChangeReply = oneshot::Sender<Result<(), String>>;
+/// Sent alongside a streaming toggle so the run loop can report whether it took effect.
+pub type StreamingReply = oneshot::Sender<Result<(), String>>;
+/// Sent alongside a recording toggle so the run loop can reply with the recorded file.
+pub type RecordingReply = oneshot::Sender<Result<String, String>>;
+/// Sent alongside a marker save so the run loop can report whether the marker was found.
+pub type MarkerSaveReply = oneshot::Sender<Result<SaveQueued, String>>;
+/// Sent alongside a save of the last seconds so the run loop can report whether it was queued.
+pub type RecentSaveReply = oneshot::Sender<Result<SaveQueued, String>>;
+/// Sent alongside a pause or resume so the run loop can report whether the capture followed.
+pub type PauseReply = oneshot::Sender<Result<(), String>>;
+/// Sent alongside a transcode so the job can reply with the path it writes to.
+pub type TranscodeReply = oneshot::Sender<Result<String, String>>;
+/// Sent alongside a join of clips so the job can reply with the path it writes to.
+pub type ConcatReply = oneshot::Sender<Result<String, String>>;
+/// Sent alongside a look up in the clip index so the run loop can reply with what it found.
+pub type ClipIndexReply = oneshot::Sender<Result<Vec<ClipInfo>, String>>;
+/// Sent with a reconnect so the run loop can report whether the new capture is running.
+pub type ReconnectReply = oneshot::Sender<Result<(), String>>;
+
+/// Hands out the ids of save, GIF and concat requests, which the signals announcing their outcome
+/// carry so callers can tell which request they are about.
+#[derive(Debug)]
+pub struct RequestIds(AtomicU32);
+
+impl Default for RequestIds {
+    fn default() -> Self {
+        Self(AtomicU32::new(1))
+    }
+}
+
+impl RequestIds {
+    pub fn next(&self) -> u32 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Outcome of queueing a save, which the save methods reply with alongside the request id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveQueued {
+    /// Nothing else was waiting, the save runs next.
+    Queued,
+    /// Other saves run first. A full save is folded into the one already waiting, which will
+    /// cover this request too, a windowed save waits for its turn behind them.
+    AlreadyPending,
+    /// The save could not be queued, the run loop is gone or too many saves are waiting. No
+    /// signal follows.
+    Failed,
+}
+
+impl SaveQueued {
+    /// How the save methods report the outcome.
+    pub fn status(self) -> &'static str {
+        match self {
+            Self::Queued => "accepted",
+            Self::AlreadyPending => "busy",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Queues full saves for the run loop, which receives their request ids. The channel only holds
+/// one request, so saves requested while another one is waiting are folded into it instead of
+/// producing a clip each.
+pub struct SaveQueue {
+    save_tx: mpsc::Sender<u32>,
+    ids: Arc<RequestIds>,
+    /// Id of the save waiting in the channel, if one is.
+    pending: Mutex<u32>,
+}
+
+impl SaveQueue {
+    pub fn new(save_tx: mpsc::Sender<u32>, ids: Arc<RequestIds>) -> Self {
+        Self {
+            save_tx,
+            ids,
+            pending: Mutex::new(0),
+        }
+    }
+
+    /// Queues a save and returns its request id. A request folded into the waiting save gets the
+    /// id of that one, it is the save which will answer it.
+    pub fn queue(&self) -> (u32, SaveQueued) {
+        // Held across the send so the pending id always belongs to the save in the channel
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let id = self.ids.next();
+        match self.save_tx.try_send(id) {
+            Ok(()) => {
+                *pending = id;
+                (id, SaveQueued::Queued)
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => (*pending, SaveQueued::AlreadyPending),
+            Err(mpsc::error::TrySendError::Closed(_)) => (id, SaveQueued::Failed),
+        }
+    }
+}
+
+/// A save of the last `seconds` of the buffer, or all of it if `None`, holding only `streams`.
+pub struct RecentSaveRequest {
+    pub id: u32,
+    pub seconds: Option<u32>,
+    pub streams: ClipStreams,
+}
+
+/// A look up in the clip index of the output directory.
+pub enum ClipIndexQuery {
+    /// The given number of most recent clips, all of them for 0.
+    Recent(u32),
+    /// The clip at this path.
+    Find(String),
+}
+
+pub struct TranscodeRequest {
+    pub input_path: String,
+    pub options: TranscodeOptions,
+}
+
+pub struct ConcatRequest {
+    pub id: u32,
+    pub paths: Vec<String>,
+    pub output_name: String,
+}
+
+pub struct GifRequest {
+    pub id: u32,
+    pub options: GifOptions,
+}
+
+pub struct MarkerSaveRequest {
+    pub id: u32,
+    pub marker_id: u32,
+    pub before_secs: u32,
+    pub after_secs: u32,
+}
 
 pub trait GameClip {
-    async fn save_clip(&self);
-    async fn update_config(&self, new_config: AppConfigDbus) -> zbus::fdo::Result<()>;
+    async fn save_clip(&self) -> zbus::fdo::Result<(u32, String)>;
+    async fn save_clip_last(&self, seconds: u32) -> zbus::fdo::Result<(u32, String)>;
+    async fn save_clip_with_options(
+        &self,
+        options: SaveClipOptions,
+    ) -> zbus::fdo::Result<(u32, String)>;
+    async fn get_config(&self) -> zbus::fdo::Result<AppConfigDbus>;
+    async fn update_config(&self, new_config: AppConfigDbus) -> zbus::fdo::Result<Vec<String>>;
     async fn change_mode(&self, new_mode: AppModeDbus) -> zbus::fdo::Result<()>;
+    async fn get_stats(&self) -> HashMap<&'static str, Value<'static>>;
+    async fn add_marker(&self, label: String) -> zbus::fdo::Result<u32>;
+    async fn save_clip_around_marker(
+        &self,
+        marker_id: u32,
+        before_secs: u32,
+        after_secs: u32,
+    ) -> zbus::fdo::Result<(u32, String)>;
+    async fn get_status(&self) -> zbus::fdo::Result<AppStatus>;
+    async fn take_screenshot(&self) -> zbus::fdo::Result<String>;
+    async fn save_gif(
+        &self,
+        seconds: u32,
+        fps: u32,
+        width: u32,
+    ) -> zbus::fdo::Result<(u32, String)>;
+    async fn set_streaming(&self, enabled: bool) -> zbus::fdo::Result<()>;
+    async fn start_recording(&self) -> zbus::fdo::Result<String>;
+    async fn stop_recording(&self) -> zbus::fdo::Result<String>;
+    async fn cancel_save(&self) -> bool;
+    async fn pause(&self) -> zbus::fdo::Result<()>;
+    async fn resume(&self) -> zbus::fdo::Result<()>;
+    async fn quit(&self) -> zbus::fdo::Result<()>;
+    async fn get_version(&self) -> String;
+    async fn get_audio_levels(&self) -> AudioLevels;
+    async fn transcode_clip(
+        &self,
+        input_path: String,
+        options: TranscodeOptions,
+    ) -> zbus::fdo::Result<String>;
+    async fn cancel_transcode(&self) -> bool;
+    async fn concat_clips(
+        &self,
+        paths: Vec<String>,
+        output_name: String,
+    ) -> zbus::fdo::Result<(u32, String)>;
+    async fn cancel_concat(&self) -> bool;
+    async fn list_clips(&self, limit: u32) -> zbus::fdo::Result<Vec<ClipInfo>>;
+    async fn get_clip_info(&self, path: String) -> zbus::fdo::Result<ClipInfo>;
+    async fn diagnose(&self, conn: &zbus::Connection) -> Diagnostics;
+    async fn reconnect(&self) -> zbus::fdo::Result<()>;
+    async fn clip_saved(
+        emitter: &SignalEmitter<'_>,
+        report: SaveReport,
+        request_id: u32,
+    ) -> zbus::Result<()>;
+    async fn save_failed(
+        emitter: &SignalEmitter<'_>,
+        error: String,
+        request_id: u32,
+    ) -> zbus::Result<()>;
+    async fn save_cancelled(emitter: &SignalEmitter<'_>, request_id: u32) -> zbus::Result<()>;
+    async fn capture_restarted(
+        emitter: &SignalEmitter<'_>,
+        stalled_seconds: u64,
+    ) -> zbus::Result<()>;
+    async fn capture_lost(emitter: &SignalEmitter<'_>, reason: String) -> zbus::Result<()>;
+    async fn status_changed(emitter: &SignalEmitter<'_>, status: AppStatus) -> zbus::Result<()>;
+    async fn audio_levels(emitter: &SignalEmitter<'_>, levels: AudioLevels) -> zbus::Result<()>;
+    async fn transcode_progress(
+        emitter: &SignalEmitter<'_>,
+        input_path: String,
+        progress: f64,
+    ) -> zbus::Result<()>;
+    async fn transcode_done(emitter: &SignalEmitter<'_>, path: String) -> zbus::Result<()>;
+    async fn transcode_failed(
+        emitter: &SignalEmitter<'_>,
+        input_path: String,
+        error: String,
+    ) -> zbus::Result<()>;
+    async fn concat_done(
+        emitter: &SignalEmitter<'_>,
+        path: String,
+        request_id: u32,
+    ) -> zbus::Result<()>;
+    async fn concat_failed(
+        emitter: &SignalEmitter<'_>,
+        output_path: String,
+        error: String,
+        request_id: u32,
+    ) -> zbus::Result<()>;
+}
+
+/// Senders of the requests [`ClipService`] hands to the run loop, built next to their receivers
+/// in `WayCap::new`.
+pub struct ClipChannels {
+    pub saves: Arc<SaveQueue>,
+    pub config_tx: mpsc::Sender<(AppConfigDbus, ConfigUpdateReply)>,
+    pub change_mode_tx: mpsc::Sender<(AppModeDbus, ModeChangeReply)>,
+    pub marker_tx: mpsc::Sender<(String, MarkerReply)>,
+    pub marker_save_tx: mpsc::Sender<(MarkerSaveRequest, MarkerSaveReply)>,
+    pub status_tx: mpsc::Sender<oneshot::Sender<AppStatus>>,
+    pub screenshot_tx: mpsc::Sender<ScreenshotReply>,
+    pub gif_tx: mpsc::Sender<(GifRequest, GifReply)>,
+    pub streaming_tx: mpsc::Sender<(bool, StreamingReply)>,
+    pub recording_tx: mpsc::Sender<(bool, RecordingReply)>,
+    pub recent_save_tx: mpsc::Sender<(RecentSaveRequest, RecentSaveReply)>,
+    pub pause_tx: mpsc::Sender<(bool, PauseReply)>,
+    pub config_request_tx: mpsc::Sender<oneshot::Sender<AppConfigDbus>>,
+    pub transcode_tx: mpsc::Sender<(TranscodeRequest, TranscodeReply)>,
+    pub concat_tx: mpsc::Sender<(ConcatRequest, ConcatReply)>,
+    pub clip_index_tx: mpsc::Sender<(ClipIndexQuery, ClipIndexReply)>,
+    pub diagnose_tx: mpsc::Sender<oneshot::Sender<Diagnostics>>,
+    pub reconnect_tx: mpsc::Sender<ReconnectReply>,
+    pub quit_tx: mpsc::Sender<()>,
+}
+
+/// State [`ClipService`] shares with the run loop, which hands its parts on to the
+/// [`crate::app_context::AppContext`].
+#[derive(Clone, Default)]
+pub struct ClipState {
+    pub ids: Arc<RequestIds>,
+    pub drops: Arc<DropCounters>,
+    pub encode: Arc<EncodeCounters>,
+    pub levels: Arc<AudioLevelHistory>,
+    /// Set while a clip is being written.
+    pub saving: Arc<AtomicBool>,
+    pub cancel_save: Arc<AtomicBool>,
+    /// The run loop starts the transcodes.
+    pub transcodes: Arc<JobState>,
+    /// The run loop starts the joins of clips.
+    pub concats: Arc<JobState>,
 }
 
 pub struct ClipService {
-    save_tx: mpsc::Sender<()>,
-    config_tx: mpsc::Sender<AppConfig>,
-    change_mode_tx: mpsc::Sender<AppModeDbus>,
+    channels: ClipChannels,
+    state: ClipState,
 }
 
 impl ClipService {
-    pub fn new(
-        save_tx: mpsc::Sender<()>,
-        config_tx: mpsc::Sender<AppConfig>,
-        change_mode_tx: mpsc::Sender<AppModeDbus>,
-    ) -> Self {
-        Self {
-            save_tx,
-            config_tx,
-            change_mode_tx,
+    pub fn new(channels: ClipChannels, state: ClipState) -> Self {
+        Self { channels, state }
+    }
+
+    /// Rejects requests which can't safely run while a clip is being written.
+    fn ensure_not_saving(&self) -> zbus::fdo::Result<()> {
+        if self.state.saving.load(Ordering::Acquire) {
+            return Err(zbus::fdo::Error::Failed(
+                "A clip is being saved, try again once it is done".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn set_recording(&self, enabled: bool) -> zbus::fdo::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .recording_tx
+            .send((enabled, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Queues a save of part of the buffer and returns its request id with how the run loop
+    /// queued it.
+    async fn save_recent(
+        &self,
+        seconds: Option<u32>,
+        streams: ClipStreams,
+    ) -> zbus::fdo::Result<(u32, String)> {
+        let id = self.state.ids.next();
+        let request = RecentSaveRequest {
+            id,
+            seconds,
+            streams,
+        };
+        Self::queue_window_save(id, &self.channels.recent_save_tx, request).await
+    }
+
+    /// Hands a windowed save to the run loop. A request it turns down, e.g. for a mode which
+    /// can't save, is an error, one it can't take at all is `failed` like a full save.
+    async fn queue_window_save<T>(
+        id: u32,
+        tx: &mpsc::Sender<(T, oneshot::Sender<Result<SaveQueued, String>>)>,
+        request: T,
+    ) -> zbus::fdo::Result<(u32, String)> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let queued = match tx.send((request, reply_tx)).await {
+            Ok(()) => reply_rx
+                .await
+                .unwrap_or(Ok(SaveQueued::Failed))
+                .map_err(zbus::fdo::Error::Failed)?,
+            Err(_) => SaveQueued::Failed,
+        };
+        if queued == SaveQueued::Failed {
+            log::error!("Could not queue save {id}");
         }
+        Ok((id, queued.status().to_string()))
+    }
+
+    async fn query_clip_index(&self, query: ClipIndexQuery) -> zbus::fdo::Result<Vec<ClipInfo>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .clip_index_tx
+            .send((query, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    async fn set_paused(&self, paused: bool) -> zbus::fdo::Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .pause_tx
+            .send((paused, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
     }
 }
 
 #[interface(name = "com.rust.WayCap")]
 impl GameClip for ClipService {
-    async fn save_clip(&self) {
+    /// Queues a save of the whole buffer and returns its request id with `accepted`. While
+    /// another save is still waiting to run the request is folded into it, returning `busy` and
+    /// the id of that save, and `failed` if the run loop is gone. `ClipSaved`, `SaveFailed` or
+    /// `SaveCancelled` carry the id once the save is done.
+    async fn save_clip(&self) -> zbus::fdo::Result<(u32, String)> {
         log::debug!("Save clip received!");
-        let _ = self.save_tx.send(()).await;
+        let (id, queued) = self.channels.saves.queue();
+        match queued {
+            SaveQueued::Queued => {}
+            SaveQueued::AlreadyPending => {
+                log::info!("Save {id} is already pending, not queueing another one")
+            }
+            SaveQueued::Failed => log::error!("Could not queue save {id}, the run loop is gone"),
+        }
+        Ok((id, queued.status().to_string()))
     }
 
-    async fn update_config(&self, new_config: AppConfigDbus) -> zbus::fdo::Result<()> {
-        let config = AppConfig::try_from(new_config).map_err(zbus::fdo::Error::Failed)?;
-        let _ = self.config_tx.send(config).await;
-        Ok(())
+    /// Saves only the last `seconds` of the buffer, leaving the rest of it in place, and returns
+    /// the request id with `accepted`, or `busy` if other saves run first. The clip is announced
+    /// through `ClipSaved` with that id once written.
+    async fn save_clip_last(&self, seconds: u32) -> zbus::fdo::Result<(u32, String)> {
+        self.save_recent(Some(seconds), ClipStreams::Both).await
+    }
+
+    /// Saves the whole buffer with only the streams `options` include, leaving the buffer in
+    /// place. Audio-only clips are written to a container fitting the audio codec, `.opus` for
+    /// Opus. Replies like `SaveClipLast`.
+    async fn save_clip_with_options(
+        &self,
+        options: SaveClipOptions,
+    ) -> zbus::fdo::Result<(u32, String)> {
+        let streams = ClipStreams::new(
+            options.include_video.unwrap_or(true),
+            options.include_audio.unwrap_or(true),
+        )
+        .ok_or_else(|| {
+            zbus::fdo::Error::InvalidArgs("A clip needs at least one of video and audio".into())
+        })?;
+        self.save_recent(None, streams).await
+    }
+
+    /// The config fields which can be changed through `UpdateConfig`, with their current values.
+    async fn get_config(&self) -> zbus::fdo::Result<AppConfigDbus> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .config_request_tx
+            .send(reply_tx)
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Applies the new config to the running application and persists it. Returns the fields
+    /// which changed but need a restart to take effect.
+    async fn update_config(&self, new_config: AppConfigDbus) -> zbus::fdo::Result<Vec<String>> {
+        self.ensure_not_saving()?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .config_tx
+            .send((new_config, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
     }
 
     async fn change_mode(&self, new_mode: AppModeDbus) -> zbus::fdo::Result<()> {
-        let _ = self.change_mode_tx.send(new_mode).await;
-        Ok(())
+        self.ensure_not_saving()?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .change_mode_tx
+            .send((new_mode, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Frames dropped by the shadow workers since the last save, and what the workers saw of the
+    /// encode path.
+    async fn get_stats(&self) -> HashMap<&'static str, Value<'static>> {
+        let drops = self.state.drops.snapshot();
+        let (video_dropped_total, audio_dropped_total) = self.state.drops.totals();
+        let pipeline = self.state.encode.snapshot();
+        HashMap::from([
+            ("video_frames_dropped", drops.video_frames_dropped.into()),
+            ("audio_frames_dropped", drops.audio_frames_dropped.into()),
+            ("video_frames_staged", drops.video_frames_staged.into()),
+            ("audio_frames_staged", drops.audio_frames_staged.into()),
+            ("video_frames_dropped_total", video_dropped_total.into()),
+            ("audio_frames_dropped_total", audio_dropped_total.into()),
+            ("uptime_seconds", pipeline.uptime_seconds.into()),
+            ("video_frames_encoded", pipeline.video_frames_encoded.into()),
+            ("encode_fps", pipeline.encode_fps.into()),
+            ("average_packet_bytes", pipeline.average_packet_bytes.into()),
+            ("bitrate_kbps", pipeline.bitrate_kbps.into()),
+            ("video_queue_depth", pipeline.video_queue_depth.into()),
+            ("audio_queue_depth", pipeline.audio_queue_depth.into()),
+            ("saves", pipeline.saves().into()),
+            ("last_error", pipeline.last_error.unwrap_or_default().into()),
+        ])
+    }
+
+    /// Marks the current moment with `label`. Markers within a saved clip are written as
+    /// chapters. Returns the id of the marker.
+    async fn add_marker(&self, label: String) -> zbus::fdo::Result<u32> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .marker_tx
+            .send((label, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Saves `before_secs` to `after_secs` around a marker, waiting until the end of that window
+    /// has been captured, and replies like `SaveClipLast`. The clip is announced through
+    /// `ClipSaved` with the request id once written, an error is returned right away if the
+    /// marker is no longer buffered.
+    async fn save_clip_around_marker(
+        &self,
+        marker_id: u32,
+        before_secs: u32,
+        after_secs: u32,
+    ) -> zbus::fdo::Result<(u32, String)> {
+        let id = self.state.ids.next();
+        let request = MarkerSaveRequest {
+            id,
+            marker_id,
+            before_secs,
+            after_secs,
+        };
+        Self::queue_window_save(id, &self.channels.marker_save_tx, request).await
+    }
+
+    async fn get_status(&self) -> zbus::fdo::Result<AppStatus> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .status_tx
+            .send(reply_tx)
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Saves the most recent frame as a PNG in the output directory and returns its path.
+    async fn take_screenshot(&self) -> zbus::fdo::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .screenshot_tx
+            .send(reply_tx)
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Saves the last `seconds` of buffered video as a GIF `width` pixels wide at `fps` frames
+    /// per second in the output directory and returns the request id and its path once written.
+    /// The limits are 15 seconds, 30 fps and 640 pixels.
+    async fn save_gif(
+        &self,
+        seconds: u32,
+        fps: u32,
+        width: u32,
+    ) -> zbus::fdo::Result<(u32, String)> {
+        let options = GifOptions::new(seconds, fps, width)
+            .map_err(|e| zbus::fdo::Error::InvalidArgs(e.to_string()))?;
+        let id = self.state.ids.next();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .gif_tx
+            .send((GifRequest { id, options }, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        let path = reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)?;
+        Ok((id, path))
+    }
+
+    /// Starts or stops pushing the capture to `stream_url`. Only available in stream mode.
+    async fn set_streaming(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .streaming_tx
+            .send((enabled, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Starts a recording which begins with the buffered footage and returns its path. Only
+    /// available in hybrid mode.
+    async fn start_recording(&self) -> zbus::fdo::Result<String> {
+        self.set_recording(true).await
+    }
+
+    /// Stops the running recording once its trailer is written and returns its path.
+    async fn stop_recording(&self) -> zbus::fdo::Result<String> {
+        self.set_recording(false).await
+    }
+
+    /// Aborts the running save, deleting the partial clip and keeping the buffer for another
+    /// try. Returns false if no save was running. Handled here rather than in the run loop,
+    /// which is busy with the save.
+    async fn cancel_save(&self) -> bool {
+        if !self.state.saving.load(Ordering::Acquire) {
+            log::info!("No save to cancel");
+            return false;
+        }
+        log::info!("Cancelling the running save");
+        self.state.cancel_save.store(true, Ordering::Release);
+        true
     }
+
+    /// Stops capturing frames until `Resume` is called. The buffered footage is kept and can
+    /// still be saved.
+    async fn pause(&self) -> zbus::fdo::Result<()> {
+        self.set_paused(true).await
+    }
+
+    async fn resume(&self) -> zbus::fdo::Result<()> {
+        self.set_paused(false).await
+    }
+
+    /// Shuts the application down the same way Ctrl+C does. A save which is running finishes
+    /// first as the run loop only picks this up once it is done.
+    async fn quit(&self) -> zbus::fdo::Result<()> {
+        log::info!("Quit requested over dbus");
+        self.channels
+            .quit_tx
+            .send(())
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Version of the running daemon, so scripts can check they talk to one they support.
+    async fn get_version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+
+    /// Peak and RMS level of every channel over the last 300ms of audio, and the clipped samples
+    /// within the last second.
+    async fn get_audio_levels(&self) -> AudioLevels {
+        self.state.levels.levels()
+    }
+
+    /// Remuxes or re-encodes `input_path`, a file inside the output directory, in the
+    /// background and returns the path it is written to. Only one transcode runs at a time, its
+    /// progress is announced through `TranscodeProgress` and the outcome through `TranscodeDone`
+    /// or `TranscodeFailed`.
+    async fn transcode_clip(
+        &self,
+        input_path: String,
+        options: TranscodeOptions,
+    ) -> zbus::fdo::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .transcode_tx
+            .send((
+                TranscodeRequest {
+                    input_path,
+                    options,
+                },
+                reply_tx,
+            ))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Stops the running transcode, deleting what it wrote so far. Returns false if none was
+    /// running.
+    async fn cancel_transcode(&self) -> bool {
+        let cancelled = self.state.transcodes.cancel();
+        if cancelled {
+            log::info!("Cancelling the running transcode");
+        } else {
+            log::info!("No transcode to cancel");
+        }
+        cancelled
+    }
+
+    /// Joins `paths`, clips inside the output directory, back to back into `output_name` in the
+    /// output directory and returns the request id and the path it is written to. The clips must
+    /// be encoded the same way, they are only remuxed. Only one join runs at a time, the outcome
+    /// is announced through `ConcatDone` or `ConcatFailed` with the request id.
+    async fn concat_clips(
+        &self,
+        paths: Vec<String>,
+        output_name: String,
+    ) -> zbus::fdo::Result<(u32, String)> {
+        let id = self.state.ids.next();
+        let request = ConcatRequest {
+            id,
+            paths,
+            output_name,
+        };
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .concat_tx
+            .send((request, reply_tx))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        let path = reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)?;
+        Ok((id, path))
+    }
+
+    /// Stops the running join, deleting what it wrote so far. Returns false if none was running.
+    async fn cancel_concat(&self) -> bool {
+        let cancelled = self.state.concats.cancel();
+        if cancelled {
+            log::info!("Cancelling the running join of clips");
+        } else {
+            log::info!("No join of clips to cancel");
+        }
+        cancelled
+    }
+
+    /// The `limit` most recently saved clips from the clip index, newest first. 0 lists all of
+    /// them.
+    async fn list_clips(&self, limit: u32) -> zbus::fdo::Result<Vec<ClipInfo>> {
+        self.query_clip_index(ClipIndexQuery::Recent(limit)).await
+    }
+
+    /// What the clip index knows about the clip at `path`, relative to the output directory
+    /// unless absolute.
+    async fn get_clip_info(&self, path: String) -> zbus::fdo::Result<ClipInfo> {
+        self.query_clip_index(ClipIndexQuery::Find(path.clone()))
+            .await?
+            .pop()
+            .ok_or_else(|| {
+                zbus::fdo::Error::FileNotFound(format!("{path} is not in the clip index"))
+            })
+    }
+
+    /// Checks the pieces the capture depends on and lists what looks wrong. The run loop and the
+    /// portal are asked side by side and given [`PROBE_TIMEOUT`], a run loop busy with a save
+    /// only leaves the capture fields empty.
+    async fn diagnose(&self, #[zbus(connection)] conn: &zbus::Connection) -> Diagnostics {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let capture = async {
+            self.channels.diagnose_tx.try_send(reply_tx).ok()?;
+            reply_rx.await.ok()
+        };
+        let (capture, portal_running) = tokio::join!(
+            tokio::time::timeout(PROBE_TIMEOUT, capture),
+            tokio::time::timeout(PROBE_TIMEOUT, diagnostics::portal_running(conn)),
+        );
+
+        let mut report = match capture {
+            Ok(Some(report)) => report,
+            _ => diagnostics::unresponsive_report(
+                self.state.encode.recent_video_packets(RECENT_SECONDS),
+            ),
+        };
+        report.portal_running = portal_running.unwrap_or(false);
+        report.problems = diagnostics::problems(&report);
+        report
+    }
+
+    /// Builds a new capture once the screencast was lost, see `CaptureLost`. The portal asks for
+    /// the screen to share again, the call returns once it was picked. Footage buffered before
+    /// the loss is kept and ends up in the next clip.
+    async fn reconnect(&self) -> zbus::fdo::Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.channels
+            .reconnect_tx
+            .send(reply_tx)
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Emitted after every successful save with a summary of the clip and the id of the request
+    /// which asked for it.
+    #[zbus(signal)]
+    async fn clip_saved(
+        emitter: &SignalEmitter<'_>,
+        report: SaveReport,
+        request_id: u32,
+    ) -> zbus::Result<()>;
+
+    /// Emitted when a save fails, with the id of its request like `ClipSaved`. The buffered
+    /// footage is kept so the save can be retried once the problem, e.g. a full disk, is fixed.
+    #[zbus(signal)]
+    async fn save_failed(
+        emitter: &SignalEmitter<'_>,
+        error: String,
+        request_id: u32,
+    ) -> zbus::Result<()>;
+
+    /// Emitted instead of `ClipSaved` or `SaveFailed` when a save is stopped through
+    /// `CancelSave`, with the id of its request. The buffer is kept.
+    #[zbus(signal)]
+    async fn save_cancelled(emitter: &SignalEmitter<'_>, request_id: u32) -> zbus::Result<()>;
+
+    /// Emitted when the watchdog restarts a capture which stopped delivering frames.
+    #[zbus(signal)]
+    async fn capture_restarted(
+        emitter: &SignalEmitter<'_>,
+        stalled_seconds: u64,
+    ) -> zbus::Result<()>;
+
+    /// Emitted when the screencast is gone and restarting the capture can't bring it back, with
+    /// why. Picking the screen again through `Reconnect` is needed, the buffered footage can be
+    /// saved meanwhile.
+    #[zbus(signal)]
+    async fn capture_lost(emitter: &SignalEmitter<'_>, reason: String) -> zbus::Result<()>;
+
+    /// Emitted when the mode changes or a save, pause, stream or recording starts or stops. The
+    /// buffered length changes constantly and is left to `GetStatus`.
+    #[zbus(signal)]
+    async fn status_changed(emitter: &SignalEmitter<'_>, status: AppStatus) -> zbus::Result<()>;
+
+    /// Emitted a few times a second while audio is arriving, with what `GetAudioLevels` returns.
+    #[zbus(signal)]
+    async fn audio_levels(emitter: &SignalEmitter<'_>, levels: AudioLevels) -> zbus::Result<()>;
+
+    /// Emitted whenever the running transcode of `input_path` got another percent further,
+    /// `progress` going from 0 to 1.
+    #[zbus(signal)]
+    async fn transcode_progress(
+        emitter: &SignalEmitter<'_>,
+        input_path: String,
+        progress: f64,
+    ) -> zbus::Result<()>;
+
+    /// Emitted with the path of the written file once a transcode is done.
+    #[zbus(signal)]
+    async fn transcode_done(emitter: &SignalEmitter<'_>, path: String) -> zbus::Result<()>;
+
+    /// Emitted when a transcode fails or is cancelled, nothing is left behind.
+    #[zbus(signal)]
+    async fn transcode_failed(
+        emitter: &SignalEmitter<'_>,
+        input_path: String,
+        error: String,
+    ) -> zbus::Result<()>;
+
+    /// Emitted with the path of the written file and the id of the `ConcatClips` request once
+    /// clips were joined.
+    #[zbus(signal)]
+    async fn concat_done(
+        emitter: &SignalEmitter<'_>,
+        path: String,
+        request_id: u32,
+    ) -> zbus::Result<()>;
+
+    /// Emitted with the path which was being written and the request id when joining clips fails
+    /// or is cancelled, nothing is left behind.
+    #[zbus(signal)]
+    async fn concat_failed(
+        emitter: &SignalEmitter<'_>,
+        output_path: String,
+        error: String,
+        request_id: u32,
+    ) -> zbus::Result<()>;
 }
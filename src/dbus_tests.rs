@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use zbus::{connection, Connection, Guid};
+
+use super::{
+    application_config::{AppConfig, AppConfigDbus, AppModeDbus, EncoderToUse, QualityPreset},
+    dbus::*,
+};
+
+const PATH: &str = "/com/rust/WayCap";
+const INTERFACE: &str = "com.rust.WayCap";
+
+fn save_queue() -> (SaveQueue, mpsc::Receiver<u32>) {
+    let (save_tx, save_rx) = mpsc::channel(1);
+    (SaveQueue::new(save_tx, Arc::default()), save_rx)
+}
+
+#[test]
+fn test_queue_save_dedups_pending_saves() {
+    let (saves, mut save_rx) = save_queue();
+
+    // A save is running and the user keeps hitting the keybind
+    let (id, queued) = saves.queue();
+    assert_eq!(queued, SaveQueued::Queued);
+    for _ in 0..10 {
+        assert_eq!(saves.queue(), (id, SaveQueued::AlreadyPending));
+    }
+
+    // Only one save runs once the current one finishes
+    assert_eq!(save_rx.try_recv(), Ok(id));
+    assert!(save_rx.try_recv().is_err());
+
+    // Later requests queue again, with an id of their own
+    let (next_id, queued) = saves.queue();
+    assert_eq!(queued, SaveQueued::Queued);
+    assert_ne!(next_id, id);
+    assert_eq!(save_rx.try_recv(), Ok(next_id));
+}
+
+#[test]
+fn test_queue_save_closed() {
+    let (saves, save_rx) = save_queue();
+    drop(save_rx);
+
+    assert_eq!(saves.queue().1, SaveQueued::Failed);
+}
+
+/// Serves a `ClipService` over a private connection, returning the service's end, the client's
+/// end and the queue of full saves the run loop would read. Every other request fails, their
+/// channels are closed.
+async fn serve_clip_service() -> (Connection, Connection, mpsc::Receiver<u32>) {
+    let (save_tx, save_rx) = mpsc::channel(1);
+    let state = ClipState::default();
+    let channels = ClipChannels {
+        saves: Arc::new(SaveQueue::new(save_tx, Arc::clone(&state.ids))),
+        config_tx: mpsc::channel(1).0,
+        change_mode_tx: mpsc::channel(1).0,
+        marker_tx: mpsc::channel(1).0,
+        marker_save_tx: mpsc::channel(1).0,
+        status_tx: mpsc::channel(1).0,
+        screenshot_tx: mpsc::channel(1).0,
+        gif_tx: mpsc::channel(1).0,
+        streaming_tx: mpsc::channel(1).0,
+        recording_tx: mpsc::channel(1).0,
+        recent_save_tx: mpsc::channel(1).0,
+        pause_tx: mpsc::channel(1).0,
+        config_request_tx: mpsc::channel(1).0,
+        transcode_tx: mpsc::channel(1).0,
+        concat_tx: mpsc::channel(1).0,
+        clip_index_tx: mpsc::channel(1).0,
+        diagnose_tx: mpsc::channel(1).0,
+        reconnect_tx: mpsc::channel(1).0,
+        quit_tx: mpsc::channel(1).0,
+    };
+    let service = ClipService::new(channels, state);
+
+    let (server, client) = tokio::net::UnixStream::pair().unwrap();
+    let server = connection::Builder::unix_stream(server)
+        .server(Guid::generate())
+        .unwrap()
+        .p2p()
+        .serve_at(PATH, service)
+        .unwrap()
+        .build();
+    let client = connection::Builder::unix_stream(client).p2p().build();
+    let (server, client) = tokio::try_join!(server, client).unwrap();
+    (server, client, save_rx)
+}
+
+async fn save_clip(client: &Connection) -> (u32, String) {
+    client
+        .call_method(None::<&str>, PATH, Some(INTERFACE), "SaveClip", &())
+        .await
+        .unwrap()
+        .body()
+        .deserialize()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_save_clip_fails_without_a_run_loop() {
+    let (_server, client, save_rx) = serve_clip_service().await;
+    drop(save_rx);
+
+    // Nothing will answer the request, so no id is worth waiting for
+    assert_eq!(save_clip(&client).await.1, "failed");
+}
+
+#[test]
+fn test_config_round_trips_through_dbus() {
+    let config = AppConfig {
+        encoder: EncoderToUse::H264Nvenc,
+        quality: QualityPreset::Ultra,
+        max_seconds: 42,
+        audio_offset_ms: -150,
+        ..AppConfig::default()
+    };
+
+    let applied = AppConfigDbus::from(&config)
+        .apply_to(&AppConfig::default())
+        .unwrap();
+    assert_eq!(applied, config);
+}
+
+#[test]
+fn test_mode_names() {
+    assert_eq!("Hybrid".parse(), Ok(AppModeDbus::Hybrid));
+    assert_eq!("record".parse(), Ok(AppModeDbus::Record));
+    assert_eq!("audio-only".parse(), Ok(AppModeDbus::AudioOnly));
+    assert!("replay".parse::<AppModeDbus>().is_err());
+}
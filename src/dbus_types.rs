@@ -0,0 +1,184 @@
+//! Types sent over dbus. Shared with `waycap-ctl`, which includes this file directly, so they
+//! must not depend on anything else in the crate.
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::{DeserializeDict, SerializeDict, Type};
+
+#[derive(Debug, Clone, Default, Type, Serialize, Deserialize)]
+pub struct AppConfigDbus {
+    pub encoder: String,
+    pub max_seconds: u32,
+    pub use_mic: bool,
+    pub quality: String,
+    pub audio_offset_ms: i32,
+}
+
+#[derive(Debug, Clone, Copy, Type, Serialize, Deserialize, PartialEq)]
+pub enum AppModeDbus {
+    Shadow,
+    Stream,
+    Record,
+    Hybrid,
+    AudioOnly,
+}
+
+impl FromStr for AppModeDbus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "shadow" => Ok(AppModeDbus::Shadow),
+            "stream" => Ok(AppModeDbus::Stream),
+            "record" => Ok(AppModeDbus::Record),
+            "hybrid" => Ok(AppModeDbus::Hybrid),
+            "audio_only" | "audio-only" => Ok(AppModeDbus::AudioOnly),
+            other => Err(format!(
+                "Unknown mode: {other:?}, Valid values: shadow, stream, record, hybrid, audio-only"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Type, Serialize, Deserialize)]
+pub struct AppStatus {
+    pub mode: String,
+    pub saving: bool,
+    /// Length of the footage currently in the shadow buffer.
+    pub buffered_seconds: f64,
+    /// How much of the buffer `SaveClip` saves, 0 when it saves all of it.
+    pub default_clip_seconds: u32,
+    /// Memory held by the shadow buffers.
+    pub buffered_bytes: u64,
+    /// Markers within the buffered footage.
+    pub marker_count: u32,
+    /// How many of those the audio event detector added.
+    pub auto_marker_count: u32,
+    /// Whether stream mode is currently pushing to its URL.
+    pub streaming: bool,
+    /// Whether record mode, or a recording in hybrid mode, is currently writing to disk.
+    pub recording: bool,
+    /// Whether the capture was paused over dbus.
+    pub paused: bool,
+    /// Frames per second coming out of the encoder over the last 10 seconds. The capture
+    /// delivers a variable rate, up to `max_fps`.
+    pub fps: f64,
+    /// The rate frames are capped at before the encoder, 0 when uncapped.
+    pub max_fps: u32,
+    /// Whether the screencast was lost and waits for `Reconnect`, the buffered footage can still
+    /// be saved meanwhile.
+    pub capture_lost: bool,
+    /// Milliseconds since the newest video frame arrived. Keeps growing while the compositor
+    /// sends nothing, the watchdog restarting the capture starts it over.
+    pub last_video_frame_age_ms: u64,
+    /// Milliseconds since the newest audio frame arrived, 0 when no audio is captured.
+    pub last_audio_frame_age_ms: u64,
+}
+
+/// Levels of the most recent captured audio, one entry per channel. All levels are linear with
+/// 1.0 being full scale, the lists are empty while no audio is arriving.
+#[derive(Debug, Clone, Default, PartialEq, Type, Serialize, Deserialize)]
+pub struct AudioLevels {
+    pub peaks: Vec<f64>,
+    pub rms: Vec<f64>,
+    /// Samples at or beyond full scale within the last second, across all channels.
+    pub clipped_samples: u64,
+}
+
+/// Options of `SaveClipWithOptions`. Sent as a dictionary, so any left out take their default.
+#[derive(Debug, Clone, Default, PartialEq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct SaveClipOptions {
+    /// Whether the clip holds the video, `true` if left out.
+    pub include_video: Option<bool>,
+    /// Whether the clip holds the audio, `true` if left out.
+    pub include_audio: Option<bool>,
+}
+
+/// Summary of a finished save.
+#[derive(Debug, Clone, Type, Serialize, Deserialize)]
+pub struct SaveReport {
+    pub path: String,
+    pub save_duration_ms: u64,
+    pub clip_duration_ms: u64,
+    /// Average frame rate of the video, 0 for an audio-only clip.
+    pub frame_rate: f64,
+    pub video_frames: u64,
+    pub audio_frames: u64,
+    pub bytes_on_disk: u64,
+    pub skipped_video_frames: u64,
+    pub skipped_audio_frames: u64,
+    /// Frames dropped by the shadow workers while this clip was being buffered.
+    pub dropped_video_frames: u64,
+    pub dropped_audio_frames: u64,
+}
+
+/// Options of `TranscodeClip`. Sent as a dictionary, so any left out take their default.
+#[derive(Debug, Clone, Default, PartialEq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct TranscodeOptions {
+    /// `mp4`, `mkv` or `webm`, the container of the input if left out.
+    pub container: Option<String>,
+    /// `h264`, `hevc`, `av1` or `vp9`. If left out the video is kept as it is when the
+    /// container allows it.
+    pub video_codec: Option<String>,
+    /// Constant quality to encode at, lower is better. Defaults to one fitting the codec.
+    pub quality: Option<u32>,
+    /// Encode with the GPU instead of on the CPU, `false` if left out. Only NVENC is supported.
+    pub hardware: Option<bool>,
+}
+
+/// A saved clip as recorded in the clip index.
+#[derive(Debug, Clone, Default, PartialEq, Type, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClipInfo {
+    pub path: String,
+    /// Unix time in milliseconds the clip was saved at.
+    pub saved_at_ms: i64,
+    /// Unix time in milliseconds the first frame of the clip was captured at.
+    pub started_at_ms: i64,
+    pub duration_ms: u64,
+    pub bytes_on_disk: u64,
+    /// Mode the clip was saved in.
+    pub mode: String,
+    pub encoder: String,
+    pub quality: String,
+    pub markers: Vec<ClipMarker>,
+}
+
+/// Answer of `Diagnose`, what is known about the health of the capture.
+#[derive(Debug, Clone, Default, Type, Serialize, Deserialize)]
+pub struct Diagnostics {
+    /// Whether the run loop answered in time, the capture fields are left empty if it didn't.
+    pub responsive: bool,
+    pub mode: String,
+    pub paused: bool,
+    /// Whether xdg-desktop-portal is on the session bus.
+    pub portal_running: bool,
+    /// Whether the PipeWire socket exists.
+    pub pipewire_running: bool,
+    /// Video frames received in the last few seconds. The stream state of the capture isn't
+    /// exposed by waycap-rs, frames arriving are the sign it is streaming.
+    pub recent_video_frames: u64,
+    pub recent_seconds: u64,
+    pub last_video_frame_age_ms: u64,
+    /// Why the screencast was lost, empty while it is running.
+    pub capture_lost: String,
+    pub encoder: String,
+    pub hardware_encoder: bool,
+    pub vaapi_device: String,
+    pub vaapi_device_accessible: bool,
+    pub output_dir: String,
+    pub free_disk_bytes: u64,
+    pub config: AppConfigDbus,
+    /// Human readable findings, empty if nothing looks wrong.
+    pub problems: Vec<String>,
+}
+
+/// A marker within a clip, as recorded in the clip index.
+#[derive(Debug, Clone, Default, PartialEq, Type, Serialize, Deserialize)]
+pub struct ClipMarker {
+    pub label: String,
+    /// Milliseconds from the start of the clip.
+    pub offset_ms: u64,
+}
@@ -0,0 +1,173 @@
+//! Probes behind the `Diagnose` dbus method. Each one answers from state WayCap already keeps or
+//! from a single syscall, so a report never waits on the capture itself.
+use std::{
+    ffi::CString,
+    fs::OpenOptions,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use zbus::Connection;
+
+use crate::{
+    application_config::{AppConfig, AppConfigDbus},
+    dbus_types::Diagnostics,
+};
+
+/// The render node waycap-rs opens for the VAAPI encoder.
+pub const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+/// Seconds the recent frames of a report are counted over.
+pub const RECENT_SECONDS: u64 = 5;
+/// How long a report waits for the run loop and the portal probe, which run side by side.
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(900);
+/// Free space below which the output directory is reported.
+const LOW_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+pub const PORTAL_SERVICE: &str = "org.freedesktop.portal.Desktop";
+
+/// What the run loop knows about the capture. The portal is probed over dbus by the caller.
+pub fn capture_report(
+    config: &AppConfig,
+    mode: String,
+    paused: bool,
+    recent_video_frames: u64,
+    last_video_frame_age_ms: u64,
+) -> Diagnostics {
+    let free_disk_bytes = free_space(&config.output_dir).unwrap_or_else(|e| {
+        log::warn!(
+            "Could not check the free space of {:?}: {e:?}",
+            config.output_dir
+        );
+        0
+    });
+    let settings = AppConfigDbus::from(config);
+    Diagnostics {
+        responsive: true,
+        mode,
+        paused,
+        pipewire_running: pipewire_socket().is_some(),
+        recent_video_frames,
+        recent_seconds: RECENT_SECONDS,
+        last_video_frame_age_ms,
+        encoder: settings.encoder.clone(),
+        hardware_encoder: config.encoder.is_hardware(),
+        vaapi_device: VAAPI_DEVICE.to_string(),
+        vaapi_device_accessible: device_accessible(Path::new(VAAPI_DEVICE)),
+        output_dir: config.output_dir.display().to_string(),
+        free_disk_bytes,
+        config: settings,
+        ..Default::default()
+    }
+}
+
+/// What is known while the run loop doesn't answer, only the counters shared with the dbus
+/// service.
+pub fn unresponsive_report(recent_video_frames: u64) -> Diagnostics {
+    Diagnostics {
+        pipewire_running: pipewire_socket().is_some(),
+        recent_video_frames,
+        recent_seconds: RECENT_SECONDS,
+        ..Default::default()
+    }
+}
+
+/// Everything in `report` which explains why nothing is being captured or saved.
+pub fn problems(report: &Diagnostics) -> Vec<String> {
+    let mut problems = Vec::new();
+    if !report.portal_running {
+        problems.push(format!(
+            "{PORTAL_SERVICE} is not on the session bus, the screen can't be shared"
+        ));
+    }
+    if !report.pipewire_running {
+        problems.push("No PipeWire socket was found, is PipeWire running?".to_string());
+    }
+    if !report.responsive {
+        problems.push(format!(
+            "The run loop did not answer within {}ms, a save or mode switch may be holding it",
+            PROBE_TIMEOUT.as_millis()
+        ));
+        return problems;
+    }
+
+    if !report.capture_lost.is_empty() {
+        problems.push(format!(
+            "The screencast was lost as {}, call Reconnect to pick the screen again",
+            report.capture_lost
+        ));
+    } else if report.paused {
+        problems.push("The capture is paused, call Resume to restart it".to_string());
+    } else if report.recent_video_frames == 0 {
+        problems.push(format!(
+            "No video frames arrived in the last {} seconds, the screen share may have ended",
+            report.recent_seconds
+        ));
+    }
+    if report.encoder == "h264_vaapi" && !report.vaapi_device_accessible {
+        problems.push(format!(
+            "{} can't be opened, the user may need to be in the render group",
+            report.vaapi_device
+        ));
+    }
+    if report.free_disk_bytes < LOW_DISK_BYTES {
+        problems.push(format!(
+            "Only {} MiB are free in {}",
+            report.free_disk_bytes / (1024 * 1024),
+            report.output_dir
+        ));
+    }
+    problems
+}
+
+/// Bytes available to unprivileged users on the file system holding `path`. The output directory
+/// is only created by the first save, so the nearest directory which exists is checked.
+pub fn free_space(path: &Path) -> Result<u64> {
+    let existing = path
+        .ancestors()
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| dir.exists())
+        .with_context(|| format!("No part of {path:?} exists"))?;
+    let c_path = CString::new(existing.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain data which the call fills in, the path is NUL terminated
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        bail!("statvfs failed: {}", std::io::Error::last_os_error());
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Whether the device node at `path` can be opened for reading and writing, which the encoder
+/// needs.
+pub fn device_accessible(path: &Path) -> bool {
+    OpenOptions::new().read(true).write(true).open(path).is_ok()
+}
+
+/// The socket PipeWire listens on, `None` if it doesn't exist.
+fn pipewire_socket() -> Option<PathBuf> {
+    let remote = std::env::var_os("PIPEWIRE_REMOTE").unwrap_or_else(|| "pipewire-0".into());
+    let socket = match Path::new(&remote).is_absolute() {
+        true => PathBuf::from(remote),
+        false => PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR")?).join(remote),
+    };
+    socket.exists().then_some(socket)
+}
+
+/// Whether the desktop portal is on the session bus of `conn`.
+pub async fn portal_running(conn: &Connection) -> bool {
+    let owned = async {
+        let proxy = zbus::fdo::DBusProxy::new(conn).await?;
+        let name = zbus::names::BusName::try_from(PORTAL_SERVICE)?;
+        anyhow::Ok(proxy.name_has_owner(name).await?)
+    };
+    match owned.await {
+        Ok(owned) => owned,
+        Err(e) => {
+            log::warn!("Could not look up {PORTAL_SERVICE}: {e:?}");
+            false
+        }
+    }
+}
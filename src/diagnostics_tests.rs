@@ -0,0 +1,90 @@
+use std::path::Path;
+
+use super::{
+    dbus_types::Diagnostics,
+    diagnostics::{free_space, problems},
+};
+
+fn healthy() -> Diagnostics {
+    Diagnostics {
+        responsive: true,
+        mode: "Shadow Capture Mode".to_string(),
+        portal_running: true,
+        pipewire_running: true,
+        recent_video_frames: 300,
+        recent_seconds: 5,
+        encoder: "h264_vaapi".to_string(),
+        hardware_encoder: true,
+        vaapi_device: "/dev/dri/renderD128".to_string(),
+        vaapi_device_accessible: true,
+        free_disk_bytes: 50 * 1024 * 1024 * 1024,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_healthy_capture_has_no_problems() {
+    assert!(problems(&healthy()).is_empty());
+
+    // The device only matters to the VAAPI encoder
+    let nvenc = Diagnostics {
+        encoder: "h264_nvenc".to_string(),
+        vaapi_device_accessible: false,
+        ..healthy()
+    };
+    assert!(problems(&nvenc).is_empty());
+}
+
+#[test]
+fn test_problems_are_reported() {
+    let stalled = Diagnostics {
+        recent_video_frames: 0,
+        vaapi_device_accessible: false,
+        free_disk_bytes: 100 * 1024 * 1024,
+        ..healthy()
+    };
+    let found = problems(&stalled);
+    assert_eq!(found.len(), 3, "{found:?}");
+    assert!(found[0].contains("No video frames"));
+    assert!(found[1].contains("renderD128"));
+    assert!(found[2].contains("100 MiB"));
+
+    // A paused capture isn't expected to deliver frames
+    let paused = Diagnostics {
+        paused: true,
+        recent_video_frames: 0,
+        ..healthy()
+    };
+    assert_eq!(problems(&paused).len(), 1);
+
+    let lost = Diagnostics {
+        capture_lost: "the screen share stopped".to_string(),
+        recent_video_frames: 0,
+        ..healthy()
+    };
+    let found = problems(&lost);
+    assert_eq!(found.len(), 1, "{found:?}");
+    assert!(found[0].contains("Reconnect"));
+}
+
+#[test]
+fn test_unresponsive_run_loop_skips_the_capture_checks() {
+    let report = Diagnostics {
+        recent_video_frames: 0,
+        portal_running: false,
+        pipewire_running: true,
+        ..Default::default()
+    };
+    let found = problems(&report);
+    assert_eq!(found.len(), 2, "{found:?}");
+    assert!(found[1].contains("did not answer"));
+}
+
+#[test]
+fn test_free_space_of_a_missing_directory() {
+    // Checked on the nearest directory which exists, the temp dir here
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("waycap/clips");
+    assert!(free_space(&missing).is_ok());
+    assert!(free_space(Path::new("relative/missing")).is_ok());
+}
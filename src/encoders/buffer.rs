@@ -1,57 +1,184 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 
+use bytes::Bytes;
 use waycap_rs::types::video_frame::EncodedVideoFrame;
 
+/// An encoded video frame as held by [`ShadowCaptureVideoBuffer`], keyed by its DTS.
+///
+/// The data is reference counted so snapshotting the buffer for a save doesn't copy any of the
+/// encoded frames.
+#[derive(Clone, Debug)]
+pub struct BufferedVideoFrame {
+    pub data: Bytes,
+    pub is_keyframe: bool,
+    /// Encoder value for when it should be presented (Presentation TimeStamp)
+    pub pts: i64,
+    /// PTS and DTS as the encoder produced them, before the offset keeping them increasing
+    /// across encoder resets.
+    pub raw_pts: i64,
+    pub raw_dts: i64,
+    /// Whether the frame was re-encoded at a lower quality, see [`crate::encoders::tiering`].
+    pub reencoded: bool,
+}
+
+impl From<EncodedVideoFrame> for BufferedVideoFrame {
+    fn from(frame: EncodedVideoFrame) -> Self {
+        Self {
+            // Takes ownership of the allocation without copying
+            data: Bytes::from(frame.data),
+            is_keyframe: frame.is_keyframe,
+            pts: frame.pts,
+            raw_pts: frame.pts,
+            raw_dts: frame.dts,
+            reencoded: false,
+        }
+    }
+}
+
+/// How far back a video DTS may go, in micro seconds, before it is taken for an encoder reset
+/// rather than a reordered frame.
+const VIDEO_RESET_JUMP: i64 = 1_000_000;
+
+/// Offset added to the encoder timestamps so the stored ones keep increasing after an encoder
+/// reset, which starts counting again near zero while the buffer still holds the older frames.
+#[derive(Clone, Default)]
+struct ResetOffset {
+    offset: i64,
+    /// Encoder timestamp of the previous frame.
+    previous: Option<i64>,
+    /// Spacing of the previous frames, used to place the first frame after a reset.
+    step: i64,
+}
+
+impl ResetOffset {
+    /// Stored timestamp for the encoder timestamp `raw`. A timestamp going back more than
+    /// `tolerance` means the encoder was reset.
+    fn apply(&mut self, raw: i64, tolerance: i64) -> i64 {
+        if let Some(previous) = self.previous {
+            let step = raw - previous;
+            if step < -tolerance {
+                let continued = previous + self.offset + self.step.max(1);
+                self.offset = continued - raw;
+                log::info!(
+                    "Encoder timestamps went back from {previous} to {raw}, continuing at {continued}"
+                );
+            } else if step > 0 {
+                self.step = step;
+            }
+        }
+        self.previous = Some(raw);
+        raw + self.offset
+    }
+}
+
 /// Represents a time window between Presentation Time Stamps.
 /// Used in Shadow Buffers to cache
+///
+/// Frames are pushed in decoding order and trimmed from the front by DTS, so the oldest and newest
+/// PTS are kept in monotonic queues of `(dts, pts)` candidates. This keeps out of order PTS
+/// (B-frames) exact while trimming in amortized O(1) instead of walking every buffered frame.
+#[derive(Clone)]
 struct TimeWindow {
-    min_time: Option<i64>,
-    max_time: Option<i64>,
+    /// Candidates for the minimum PTS, increasing from front to back.
+    min_candidates: VecDeque<(i64, i64)>,
+    /// Candidates for the maximum PTS, decreasing from front to back.
+    max_candidates: VecDeque<(i64, i64)>,
 }
 
 impl TimeWindow {
     pub fn new() -> Self {
         Self {
-            min_time: None,
-            max_time: None,
+            min_candidates: VecDeque::new(),
+            max_candidates: VecDeque::new(),
         }
     }
 
-    pub fn insert_time(&mut self, time: i64) {
-        self.min_time = Some(self.min_time.map_or(time, |min| min.min(time)));
-        self.max_time = Some(self.max_time.map_or(time, |max| max.max(time)));
+    /// Records the PTS of a frame. Must be called in increasing `dts` order.
+    pub fn insert_time(&mut self, dts: i64, pts: i64) {
+        while self
+            .min_candidates
+            .back()
+            .is_some_and(|&(_, min)| min >= pts)
+        {
+            self.min_candidates.pop_back();
+        }
+        self.min_candidates.push_back((dts, pts));
+
+        while self
+            .max_candidates
+            .back()
+            .is_some_and(|&(_, max)| max <= pts)
+        {
+            self.max_candidates.pop_back();
+        }
+        self.max_candidates.push_back((dts, pts));
+    }
+
+    /// Forgets every frame with a DTS lower than `dts`.
+    pub fn trim_before(&mut self, dts: i64) {
+        while self.min_candidates.front().is_some_and(|&(d, _)| d < dts) {
+            self.min_candidates.pop_front();
+        }
+        while self.max_candidates.front().is_some_and(|&(d, _)| d < dts) {
+            self.max_candidates.pop_front();
+        }
+    }
+
+    pub fn min_time(&self) -> Option<i64> {
+        self.min_candidates.front().map(|&(_, pts)| pts)
+    }
+
+    pub fn max_time(&self) -> Option<i64> {
+        self.max_candidates.front().map(|&(_, pts)| pts)
     }
 
     pub fn get_elapsed(&self) -> Option<i64> {
-        match (self.min_time, self.max_time) {
+        match (self.min_time(), self.max_time()) {
             (Some(min), Some(max)) => Some(max - min),
             _ => None,
         }
     }
 
     pub fn reset(&mut self) {
-        self.min_time = None;
-        self.max_time = None;
+        self.min_candidates.clear();
+        self.max_candidates.clear();
     }
 }
 
-/// Rolling buffer which holds up to the last `max_time` seconds of video frames.
+/// Rolling buffer which holds up to the last `max_time` micro seconds of video frames.
 ///
 /// The buffer is ordered by decoding timestamp (DTS) and maintains complete GOPs (groups of pictures),
 /// ensuring that no partial GOPs are kept when trimming for ease of muxing and playback.
+#[derive(Clone)]
 pub struct ShadowCaptureVideoBuffer {
-    frames: BTreeMap<i64, EncodedVideoFrame>,
+    frames: BTreeMap<i64, BufferedVideoFrame>,
 
-    /// Maximum duration (in seconds) that the buffer should retain.
+    /// Maximum duration (in micro seconds) that the buffer should retain.
     /// Once the difference between the newest and oldest frame exceeds this, older GOPs are trimmed.
     max_time: usize,
 
     /// List of DTS values corresponding to key frames, ordered by insertion.
     /// Used to identify GOP boundaries for trimming purposes.
-    key_frame_keys: Vec<i64>,
+    key_frame_keys: VecDeque<i64>,
 
-    /// Cached time window. Updated every call to `trim_oldest_gop()`
+    /// Cached time window. Updated on every insert and every call to `trim_oldest_gop()`
     time_window: TimeWindow,
+
+    /// Set when a frame was inserted out of DTS order, in which case the time window has to be
+    /// rebuilt from the frames on the next trim.
+    time_window_dirty: bool,
+
+    #[cfg(test)]
+    full_recalculations: usize,
+
+    /// Total size in bytes of the encoded frames currently held.
+    size_bytes: usize,
+
+    /// Optional upper bound on `size_bytes`. Older GOPs are trimmed once it is exceeded.
+    max_bytes: Option<usize>,
+
+    /// Keeps the DTS and PTS increasing across encoder resets.
+    reset_offset: ResetOffset,
 }
 
 impl ShadowCaptureVideoBuffer {
@@ -59,43 +186,160 @@ impl ShadowCaptureVideoBuffer {
     ///
     /// # Arguments
     ///
-    /// * `max_time` - Maximum duration (in seconds) of video frames to retain in the buffer.
+    /// * `max_time` - Maximum duration (in micro seconds) of video frames to retain in the buffer.
     pub fn new(max_time: usize) -> Self {
         Self {
             frames: BTreeMap::new(),
             max_time,
-            key_frame_keys: Vec::new(),
+            key_frame_keys: VecDeque::new(),
             time_window: TimeWindow::new(),
+            time_window_dirty: false,
+            #[cfg(test)]
+            full_recalculations: 0,
+            size_bytes: 0,
+            max_bytes: None,
+            reset_offset: ResetOffset::default(),
         }
     }
 
-    /// Inserts a new video frame into the buffer, keeping the buffer size within `max_time`.
+    /// Inserts a new video frame into the buffer, keeping the buffer within `max_time` and
+    /// `max_bytes`.
     ///
     /// If the inserted frame is a key frame, its timestamp is recorded to track GOP boundaries.
-    /// After insertion, older frames are trimmed if the total duration exceeds `max_time`.
+    /// After insertion, the oldest GOPs are trimmed while the total duration exceeds `max_time` or
+    /// the total size exceeds `max_bytes`.
+    ///
+    /// When the encoder was reset while the buffer still holds frames, its timestamps start over
+    /// near zero. They are then offset to continue after the buffered frames, the encoder values
+    /// are kept in [`BufferedVideoFrame::raw_pts`] and [`BufferedVideoFrame::raw_dts`]. A frame
+    /// whose DTS is still taken is moved to the next free DTS instead of replacing the earlier
+    /// frame. A key frame is always placed after the buffered frames since it starts a new GOP,
+    /// any other frame older than every buffered one is dropped.
     ///
     /// # Arguments
     ///
     /// * `timestamp` - The decoding timestamp (DTS) of the frame.
     /// * `frame` - A [`VideoFrameData`] representing an encoded frame.
     pub fn insert(&mut self, timestamp: i64, frame: EncodedVideoFrame) {
+        let mut frame = BufferedVideoFrame::from(frame);
+        let raw_timestamp = timestamp;
+        // Key frames never arrive reordered, one going back means the encoder was reset
+        let tolerance = match frame.is_keyframe {
+            true => 0,
+            false => VIDEO_RESET_JUMP,
+        };
+        let mut timestamp = self.reset_offset.apply(raw_timestamp, tolerance);
+        frame.pts += timestamp - raw_timestamp;
+        if self.frames.contains_key(&timestamp) {
+            let taken = timestamp;
+            while self.frames.contains_key(&timestamp) {
+                timestamp += 1;
+            }
+            log::warn!("Video frame DTS {taken} is already buffered, inserting it at {timestamp}");
+        }
         if frame.is_keyframe {
-            self.key_frame_keys.push(timestamp);
+            // A key frame starts a new GOP, it can't go in between the buffered frames
+            if let Some((&newest, _)) = self.frames.last_key_value() {
+                if newest >= timestamp {
+                    log::warn!(
+                        "Video key frame DTS {timestamp} is not the newest, inserting it at {}",
+                        newest + 1
+                    );
+                    timestamp = newest + 1;
+                }
+            }
+        } else if self
+            .frames
+            .first_key_value()
+            .is_some_and(|(&oldest, _)| timestamp < oldest)
+        {
+            // Whatever it refers to was trimmed already, keeping it would start the buffer mid GOP
+            log::warn!("Dropping video frame DTS {timestamp}, it is older than the buffered ones");
+            return;
+        }
+        self.push(timestamp, frame);
+    }
+
+    /// Buffers a frame whose DTS was already settled by [`Self::insert`], after every buffered
+    /// one unless it is reordered, and trims the oldest GOPs past the limits.
+    fn push(&mut self, timestamp: i64, frame: BufferedVideoFrame) {
+        if frame.is_keyframe {
+            self.key_frame_keys.push_back(timestamp);
         }
 
-        self.time_window.insert_time(frame.pts);
+        if self
+            .frames
+            .last_key_value()
+            .is_some_and(|(&newest, _)| newest >= timestamp)
+        {
+            self.time_window_dirty = true;
+        }
+        self.time_window.insert_time(timestamp, frame.pts);
+        self.size_bytes += frame.data.len();
         self.frames.insert(timestamp, frame);
 
-        // Trim old GOPs if buffer exceeds max_time
-        match self.time_window.get_elapsed() {
-            Some(elapsed) => {
-                if elapsed >= self.max_time as i64 {
+        // Trim old GOPs while the buffer exceeds max_time or max_bytes. Usually one GOP is
+        // enough, but a jump in the timestamps can put the buffer several GOPs over. Like
+        // trim_to_limits this stops at the last complete GOP and the key frame starting the one
+        // in progress, so there is always a GOP to save
+        while self.key_frame_keys.len() > 2 {
+            match self.time_window.get_elapsed() {
+                Some(elapsed) if elapsed >= self.max_time as i64 || self.exceeds_max_bytes() => {
                     self.trim_oldest_gop();
                 }
+                Some(_) => break,
+                None => {
+                    log::warn!("Time window imporperly set");
+                    break;
+                }
             }
-            None => {
-                log::warn!("Time window imporperly set");
+        }
+    }
+
+    /// Updates the maximum duration (in micro seconds) that the buffer should retain.
+    ///
+    /// Growing the limit keeps every buffered frame, shrinking it trims the oldest GOPs right
+    /// away instead of waiting for the next insert. If the new limit is shorter than a single GOP
+    /// the last complete GOP is kept anyway so there is always something to save.
+    pub fn set_max_time(&mut self, max_time: usize) {
+        self.max_time = max_time;
+        self.trim_to_limits();
+    }
+
+    /// Updates the maximum size in bytes the buffer should hold, `None` meaning unbounded.
+    ///
+    /// Like [`Self::set_max_time`] this trims right away but never below the last complete GOP.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.trim_to_limits();
+    }
+
+    /// Total size in bytes of the encoded frames currently buffered.
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes
+    }
+
+    fn exceeds_max_bytes(&self) -> bool {
+        self.max_bytes.is_some_and(|max| self.size_bytes > max)
+    }
+
+    fn trim_to_limits(&mut self) {
+        while let Some(elapsed) = self.time_window.get_elapsed() {
+            if elapsed < self.max_time as i64 && !self.exceeds_max_bytes() {
+                break;
+            }
+
+            // Last complete GOP plus the key frame starting the one in progress
+            if self.key_frame_keys.len() <= 2 {
+                log::warn!(
+                    "Buffer limits ({}us, {:?} bytes) are smaller than a single GOP. Keeping the last complete GOP.",
+                    self.max_time,
+                    self.max_bytes
+                );
+                break;
             }
+
+            self.trim_oldest_gop();
         }
     }
 
@@ -103,7 +347,16 @@ impl ShadowCaptureVideoBuffer {
     ///
     /// Returns `None` if no key frames have been inserted.
     pub fn get_last_gop_start(&self) -> Option<&i64> {
-        self.key_frame_keys.last()
+        self.key_frame_keys.back()
+    }
+
+    /// Returns the DTS of the last key frame at or before `dts`, i.e. where a clip has to start
+    /// to include the frame at `dts`.
+    pub fn key_frame_at_or_before(&self, dts: i64) -> Option<i64> {
+        let index = self.key_frame_keys.partition_point(|&key| key <= dts);
+        index
+            .checked_sub(1)
+            .and_then(|i| self.key_frame_keys.get(i).copied())
     }
 
     /// Removes the oldest group of pictures (GOP) from the buffer.
@@ -121,51 +374,188 @@ impl ShadowCaptureVideoBuffer {
 
         let stop_dts = self.key_frame_keys[1]; // First complete GOP ends at second key frame
 
-        self.frames.retain(|&dts, _| dts >= stop_dts);
+        // Pop from the front so the cost scales with the trimmed GOP and not the whole buffer
+        while let Some(oldest) = self.frames.first_entry() {
+            if *oldest.key() >= stop_dts {
+                break;
+            }
+            self.size_bytes -= oldest.remove().data.len();
+        }
 
         // Remove deleted key frame
-        self.key_frame_keys.remove(0);
-        self.recalculate_pts();
+        self.key_frame_keys.pop_front();
+        if self.time_window_dirty {
+            self.recalculate_pts();
+        } else {
+            self.time_window.trim_before(stop_dts);
+        }
     }
 
-    #[cfg(test)]
+    /// Takes every buffered frame for a full save, leaving a fresh buffer which only holds the
+    /// GOP in progress. A clip ends on the key frame starting that GOP, so the next one picks up
+    /// right there.
+    ///
+    /// The limits and the offset across encoder resets carry over, so the frames buffered next
+    /// continue the timestamps of the taken ones. The time window and size start over from the
+    /// kept GOP.
+    pub fn take(&mut self) -> Self {
+        let mut rest = Self::new(self.max_time);
+        rest.max_bytes = self.max_bytes;
+        rest.reset_offset = self.reset_offset.clone();
+        if let Some(&start) = self.key_frame_keys.back() {
+            for (&dts, frame) in self.frames.range(start..) {
+                rest.push(dts, frame.clone());
+            }
+        }
+        std::mem::replace(self, rest)
+    }
+
+    /// Puts back the frames taken with [`Self::take`] for a save which failed or was cancelled,
+    /// in front of the frames buffered since, and trims them to the limits again.
+    pub fn restore(&mut self, taken: Self) {
+        let since = std::mem::replace(self, taken);
+        self.max_time = since.max_time;
+        self.max_bytes = since.max_bytes;
+        self.reset_offset = since.reset_offset;
+        // The GOP kept by take is part of both
+        let newest = self.frames.last_key_value().map(|(&dts, _)| dts);
+        for (dts, frame) in since.frames {
+            if newest.is_none_or(|newest| dts > newest) {
+                self.push(dts, frame);
+            }
+        }
+        self.trim_to_limits();
+    }
+
+    /// Presentation time of the oldest buffered frame.
     pub fn oldest_pts(&self) -> Option<i64> {
-        self.time_window.min_time
+        self.time_window.min_time()
     }
 
-    #[cfg(test)]
+    /// Presentation time of the newest buffered frame.
     pub fn newest_pts(&self) -> Option<i64> {
-        self.time_window.max_time
+        self.time_window.max_time()
+    }
+
+    /// DTS of the buffered key frames, oldest first.
+    #[cfg(test)]
+    pub fn key_frames(&self) -> &VecDeque<i64> {
+        &self.key_frame_keys
+    }
+
+    /// Number of times the time window had to be rebuilt by walking every frame.
+    #[cfg(test)]
+    pub fn full_recalculations(&self) -> usize {
+        self.full_recalculations
     }
 
     fn recalculate_pts(&mut self) {
         self.time_window.reset();
+        self.time_window_dirty = false;
+        #[cfg(test)]
+        {
+            self.full_recalculations += 1;
+        }
 
-        for frame in self.frames.values() {
-            self.time_window.insert_time(frame.pts);
+        for (&dts, frame) in self.frames.iter() {
+            self.time_window.insert_time(dts, frame.pts);
         }
     }
 
-    pub fn get_frames(&self) -> &BTreeMap<i64, EncodedVideoFrame> {
+    pub fn get_frames(&self) -> &BTreeMap<i64, BufferedVideoFrame> {
         &self.frames
     }
 
+    /// Returns the oldest complete GOP which wasn't re-encoded yet, if it was presented entirely
+    /// before `before_pts`.
+    pub fn oldest_original_gop(&self, before_pts: i64) -> Option<Vec<(i64, BufferedVideoFrame)>> {
+        for (&start, &end) in self
+            .key_frame_keys
+            .iter()
+            .zip(self.key_frame_keys.iter().skip(1))
+        {
+            // The next GOP starts after every frame of this one was presented
+            if self.frames.get(&end)?.pts > before_pts {
+                return None;
+            }
+            if self
+                .frames
+                .get(&start)
+                .is_some_and(|frame| !frame.reencoded)
+            {
+                return Some(
+                    self.frames
+                        .range(start..end)
+                        .map(|(&dts, frame)| (dts, frame.clone()))
+                        .collect(),
+                );
+            }
+        }
+        None
+    }
+
+    /// Swaps the frames of a GOP taken with [`Self::oldest_original_gop`] for their re-encoded
+    /// versions. Returns false and leaves the buffer untouched if any of the frames was trimmed
+    /// or replaced in the meantime.
+    pub fn replace_gop(&mut self, frames: Vec<(i64, BufferedVideoFrame)>) -> bool {
+        if !frames
+            .iter()
+            .all(|(dts, _)| self.frames.get(dts).is_some_and(|frame| !frame.reencoded))
+        {
+            return false;
+        }
+
+        for (dts, frame) in frames {
+            self.size_bytes += frame.data.len();
+            if let Some(replaced) = self.frames.insert(dts, frame) {
+                self.size_bytes -= replaced.data.len();
+            }
+        }
+        // The PTS stay the same but may pair up with other DTS than before
+        self.time_window_dirty = true;
+        true
+    }
+
+    /// Whether any frame was re-encoded. GOPs are re-encoded oldest first, so it is enough to
+    /// look at the oldest frame.
+    pub fn has_reencoded_frames(&self) -> bool {
+        self.frames
+            .first_key_value()
+            .is_some_and(|(_, frame)| frame.reencoded)
+    }
+
+    #[cfg(test)]
     pub fn reset(&mut self) {
         self.frames.clear();
         self.key_frame_keys.clear();
         self.time_window.reset();
+        self.time_window_dirty = false;
+        self.size_bytes = 0;
+        self.reset_offset = ResetOffset::default();
     }
 }
 
 #[derive(Clone)]
 pub struct ShadowCaptureAudioBuffer {
-    frames: BTreeMap<i64, Vec<u8>>,
+    frames: BTreeMap<i64, Bytes>,
 
-    /// Maximum duration (in seconds) that the buffer should retain.
-    /// Once the difference between the newest and oldest frame exceeds this, older GOPs are trimmed.
+    /// Maximum duration (in micro seconds) that the buffer should retain.
+    /// Once the difference between the newest and oldest frame exceeds this, older frames are trimmed.
     max_time: usize,
 
-    capture_times: Vec<i64>,
+    capture_times: VecDeque<i64>,
+
+    /// Total size in bytes of the encoded frames currently held.
+    size_bytes: usize,
+
+    /// Optional upper bound on `size_bytes`. Oldest frames are trimmed once it is exceeded.
+    max_bytes: Option<usize>,
+
+    /// Keeps the PTS increasing across encoder resets.
+    reset_offset: ResetOffset,
+
+    /// Keeps the capture times increasing once a rebuilt capture starts its clock over.
+    capture_time_offset: ResetOffset,
 }
 
 impl ShadowCaptureAudioBuffer {
@@ -173,7 +563,11 @@ impl ShadowCaptureAudioBuffer {
         Self {
             frames: BTreeMap::new(),
             max_time,
-            capture_times: Vec::new(),
+            capture_times: VecDeque::new(),
+            size_bytes: 0,
+            max_bytes: None,
+            reset_offset: ResetOffset::default(),
+            capture_time_offset: ResetOffset::default(),
         }
     }
 
@@ -181,21 +575,62 @@ impl ShadowCaptureAudioBuffer {
     ///
     /// It converts the encoder PTS into real world micro seconds to keep track of elapsed time
     ///
+    /// Audio frames are never reordered, so any PTS going back comes from an encoder reset and is
+    /// offset to continue after the buffered frames. A PTS which is still taken is moved after
+    /// the newest frame so every capture time keeps its frame.
+    ///
     /// # Arguments
     ///
     /// * `timestamp` - The presentation timestamp (PTS) of the frame according to the audio
     ///   encoder.
     /// * `frame` - A [`AudioFrameData`] representing an encoded frame.
     pub fn insert(&mut self, timestamp: i64, frame: Vec<u8>) {
+        let mut timestamp = self.reset_offset.apply(timestamp, 0);
+        // Replacing the earlier frame would leave its capture time without a frame
+        if self.frames.contains_key(&timestamp) {
+            let taken = timestamp;
+            timestamp = self
+                .frames
+                .last_key_value()
+                .map_or(taken, |(&newest, _)| newest)
+                + 1;
+            log::warn!("Audio frame PTS {taken} is already buffered, inserting it at {timestamp}");
+        }
+        let frame = Bytes::from(frame);
+        self.size_bytes += frame.len();
         self.frames.insert(timestamp, frame);
+        self.trim();
+    }
 
+    /// Updates the maximum duration (in micro seconds) that the buffer should retain, trimming
+    /// the oldest frames right away when shrinking.
+    pub fn set_max_time(&mut self, max_time: usize) {
+        self.max_time = max_time;
+        self.trim();
+    }
+
+    /// Updates the maximum size in bytes the buffer should hold, `None` meaning unbounded.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.trim();
+    }
+
+    /// Total size in bytes of the encoded frames currently buffered.
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes
+    }
+
+    fn trim(&mut self) {
         while let (Some(oldest), Some(newest)) =
-            (self.capture_times.first(), self.capture_times.last())
+            (self.capture_times.front(), self.capture_times.back())
         {
-            if newest - oldest >= self.max_time as i64 {
+            let exceeds_max_bytes = self.max_bytes.is_some_and(|max| self.size_bytes > max);
+            if newest - oldest >= self.max_time as i64 || exceeds_max_bytes {
                 if let Some(oldest_frame) = self.frames.first_entry() {
-                    oldest_frame.remove();
-                    self.capture_times.remove(0);
+                    self.size_bytes -= oldest_frame.remove().len();
+                    self.capture_times.pop_front();
+                } else {
+                    break;
                 }
             } else {
                 break;
@@ -203,20 +638,67 @@ impl ShadowCaptureAudioBuffer {
         }
     }
 
-    pub fn get_capture_times(&self) -> &Vec<i64> {
+    /// Takes every buffered frame for a full save, leaving a fresh buffer which only holds the
+    /// frames captured at or after `keep_from`. Like [`ShadowCaptureVideoBuffer::take`] the
+    /// limits and offsets carry over.
+    pub fn take(&mut self, keep_from: i64) -> Self {
+        let mut rest = Self::new(self.max_time);
+        rest.max_bytes = self.max_bytes;
+        rest.reset_offset = self.reset_offset.clone();
+        rest.capture_time_offset = self.capture_time_offset.clone();
+        for ((&pts, frame), &time) in self.frames.iter().zip(&self.capture_times) {
+            if time >= keep_from {
+                rest.push(pts, frame.clone(), time);
+            }
+        }
+        std::mem::replace(self, rest)
+    }
+
+    /// Puts back the frames taken with [`Self::take`] for a save which failed or was cancelled,
+    /// in front of the frames buffered since.
+    pub fn restore(&mut self, taken: Self) {
+        let since = std::mem::replace(self, taken);
+        self.max_time = since.max_time;
+        self.max_bytes = since.max_bytes;
+        self.reset_offset = since.reset_offset;
+        self.capture_time_offset = since.capture_time_offset;
+        let newest = self.frames.last_key_value().map(|(&pts, _)| pts);
+        for ((pts, frame), time) in since.frames.into_iter().zip(since.capture_times) {
+            if newest.is_none_or(|newest| pts > newest) {
+                self.push(pts, frame, time);
+            }
+        }
+        self.trim();
+    }
+
+    /// Appends a frame and its capture time, both already offset.
+    fn push(&mut self, pts: i64, frame: Bytes, capture_time: i64) {
+        self.size_bytes += frame.len();
+        self.frames.insert(pts, frame);
+        self.capture_times.push_back(capture_time);
+    }
+
+    pub fn get_capture_times(&self) -> &VecDeque<i64> {
         &self.capture_times
     }
 
-    pub fn get_frames(&self) -> &BTreeMap<i64, Vec<u8>> {
+    pub fn get_frames(&self) -> &BTreeMap<i64, Bytes> {
         &self.frames
     }
 
+    /// Records the capture time of the next frame. A time going back means the capture was
+    /// rebuilt, it is then offset to continue after the buffered frames like the PTS.
     pub fn insert_capture_time(&mut self, time: i64) {
-        self.capture_times.push(time);
+        let time = self.capture_time_offset.apply(time, 0);
+        self.capture_times.push_back(time);
     }
 
+    #[cfg(test)]
     pub fn reset(&mut self) {
         self.frames.clear();
         self.capture_times.clear();
+        self.size_bytes = 0;
+        self.reset_offset = ResetOffset::default();
+        self.capture_time_offset = ResetOffset::default();
     }
 }
@@ -0,0 +1,196 @@
+//! Random frame sequences fed to the shadow buffers, checking what the muxer relies on after
+//! every insert.
+use proptest::prelude::*;
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+use super::buffer::*;
+
+/// Spacing of the generated frames, around 60 fps.
+const FRAME_MICROS: i64 = 16_667;
+
+#[derive(Debug, Clone)]
+enum VideoEvent {
+    /// The next frame, `gap` after the previous DTS and presented `delay` frames later, which
+    /// reorders the PTS like B-frames do.
+    Frame {
+        gap: i64,
+        delay: i64,
+        keyframe: bool,
+        size: usize,
+    },
+    /// A frame with the DTS of the previous one.
+    Duplicate { keyframe: bool },
+    /// The encoder starts over near zero.
+    Reset,
+}
+
+fn video_event(cadence: u32) -> impl Strategy<Value = VideoEvent> {
+    prop_oneof![
+        30 => (1..=3 * FRAME_MICROS, 0..=3i64, 0..cadence, 1..2048usize).prop_map(
+            |(gap, delay, phase, size)| VideoEvent::Frame {
+                gap,
+                delay,
+                keyframe: phase == 0,
+                size,
+            }
+        ),
+        1 => any::<bool>().prop_map(|keyframe| VideoEvent::Duplicate { keyframe }),
+        1 => Just(VideoEvent::Reset),
+    ]
+}
+
+fn video_case() -> impl Strategy<Value = (usize, Vec<VideoEvent>)> {
+    (1..90u32).prop_flat_map(|cadence| {
+        (
+            100_000..3_000_000usize,
+            prop::collection::vec(video_event(cadence), 1..600),
+        )
+    })
+}
+
+fn check_video(
+    buffer: &ShadowCaptureVideoBuffer,
+    max_time: usize,
+    inserted: usize,
+    inserted_key_frames: usize,
+) {
+    let frames = buffer.get_frames();
+    assert!(!frames.is_empty());
+
+    let key_frames: Vec<_> = frames
+        .iter()
+        .filter(|(_, frame)| frame.is_keyframe)
+        .map(|(&dts, _)| dts)
+        .collect();
+    assert_eq!(
+        buffer.key_frames().iter().copied().collect::<Vec<_>>(),
+        key_frames
+    );
+    if frames.len() < inserted {
+        let (_, oldest) = frames.first_key_value().unwrap();
+        assert!(oldest.is_keyframe, "The buffer was trimmed mid GOP");
+    }
+
+    let size: usize = frames.values().map(|frame| frame.data.len()).sum();
+    assert_eq!(buffer.size_bytes(), size);
+    let min_pts = frames.values().map(|frame| frame.pts).min();
+    let max_pts = frames.values().map(|frame| frame.pts).max();
+    assert_eq!(buffer.oldest_pts(), min_pts);
+    assert_eq!(buffer.newest_pts(), max_pts);
+
+    // However short max_time is, a complete GOP is kept for a save once one was buffered
+    assert!(
+        key_frames.len() >= inserted_key_frames.min(2),
+        "No complete GOP left to save"
+    );
+
+    // Past the last complete GOP and the key frame after it, only the oldest GOP may reach past
+    // max_time
+    if key_frames.len() > 2 {
+        let newer_min_pts = frames
+            .range(key_frames[1]..)
+            .map(|(_, frame)| frame.pts)
+            .min()
+            .unwrap();
+        assert!(
+            max_pts.unwrap() - newer_min_pts < max_time as i64,
+            "More than one GOP beyond {max_time}us"
+        );
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_video_buffer_invariants((max_time, events) in video_case()) {
+        let mut buffer = ShadowCaptureVideoBuffer::new(max_time);
+        let mut dts = 0;
+        let mut inserted = 0;
+        let mut inserted_key_frames = 0;
+        for event in events {
+            let (delay, keyframe, size) = match event {
+                VideoEvent::Frame { gap, delay, keyframe, size } => {
+                    dts += gap;
+                    (delay, keyframe, size)
+                }
+                VideoEvent::Duplicate { keyframe } => (0, keyframe, 16),
+                VideoEvent::Reset => {
+                    dts = 0;
+                    (0, true, 1024)
+                }
+            };
+            let pts = dts + delay * FRAME_MICROS;
+            buffer.insert(
+                dts,
+                EncodedVideoFrame {
+                    data: vec![0; size],
+                    is_keyframe: keyframe,
+                    pts,
+                    dts,
+                },
+            );
+            inserted += 1;
+            inserted_key_frames += usize::from(keyframe);
+            check_video(&buffer, max_time, inserted, inserted_key_frames);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum AudioEvent {
+    /// The next frame, `samples` after the previous PTS and captured `gap` later.
+    Frame { samples: i64, gap: i64, size: usize },
+    /// A frame with the PTS and capture time of the previous one.
+    Duplicate,
+    /// The encoder starts over at zero.
+    Reset,
+}
+
+fn audio_event() -> impl Strategy<Value = AudioEvent> {
+    prop_oneof![
+        30 => (1..=1920i64, 0..=40_000i64, 1..512usize)
+            .prop_map(|(samples, gap, size)| AudioEvent::Frame { samples, gap, size }),
+        1 => Just(AudioEvent::Duplicate),
+        1 => Just(AudioEvent::Reset),
+    ]
+}
+
+fn check_audio(buffer: &ShadowCaptureAudioBuffer, max_time: usize) {
+    let frames = buffer.get_frames();
+    let capture_times = buffer.get_capture_times();
+    assert_eq!(frames.len(), capture_times.len());
+
+    let size: usize = frames.values().map(|frame| frame.len()).sum();
+    assert_eq!(buffer.size_bytes(), size);
+    if let (Some(oldest), Some(newest)) = (capture_times.front(), capture_times.back()) {
+        assert!(newest - oldest < max_time as i64);
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_audio_buffer_invariants(
+        max_time in 20_000..3_000_000usize,
+        events in prop::collection::vec(audio_event(), 1..600),
+    ) {
+        let mut buffer = ShadowCaptureAudioBuffer::new(max_time);
+        let mut pts = 0;
+        let mut capture_time = 1_000_000;
+        for event in events {
+            let size = match event {
+                AudioEvent::Frame { samples, gap, size } => {
+                    pts += samples;
+                    capture_time += gap;
+                    size
+                }
+                AudioEvent::Duplicate => 64,
+                AudioEvent::Reset => {
+                    pts = 0;
+                    64
+                }
+            };
+            buffer.insert_capture_time(capture_time);
+            buffer.insert(pts, vec![0; size]);
+            check_audio(&buffer, max_time);
+        }
+    }
+}
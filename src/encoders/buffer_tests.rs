@@ -117,6 +117,254 @@ fn test_video_buffer_stress_realistic() {
     }
 
     assert!(buffer.get_last_gop_start().is_some());
+
+    // Trimming keeps the window up to date without walking the whole buffer
+    assert_eq!(buffer.full_recalculations(), 0);
+    let expected_oldest = buffer.get_frames().values().map(|f| f.pts).min();
+    let expected_newest = buffer.get_frames().values().map(|f| f.pts).max();
+    assert_eq!(buffer.oldest_pts(), expected_oldest);
+    assert_eq!(buffer.newest_pts(), expected_newest);
+}
+
+#[test]
+fn test_video_buffer_out_of_order_insert_recalculates() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(10);
+
+    buffer.insert(0, new_video_frame(vec![1], 0, true, 0));
+    buffer.insert(2, new_video_frame(vec![1], 2, false, 2));
+    // Arrives late, the cached window can no longer be trimmed incrementally
+    buffer.insert(1, new_video_frame(vec![1], 1, false, 1));
+    buffer.insert(5, new_video_frame(vec![1], 5, true, 5));
+    buffer.insert(6, new_video_frame(vec![1], 6, false, 6));
+    buffer.insert(10, new_video_frame(vec![1], 10, true, 10));
+
+    assert_eq!(buffer.full_recalculations(), 1);
+    assert_eq!(buffer.oldest_pts(), Some(5));
+    assert_eq!(buffer.newest_pts(), Some(10));
+    assert_eq!(buffer.get_frames().len(), 3);
+}
+
+#[test]
+fn test_video_buffer_duplicate_dts_keeps_both_frames() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(10);
+
+    buffer.insert(0, new_video_frame(vec![1], 0, true, 0));
+    buffer.insert(3, new_video_frame(vec![1], 3, false, 3));
+    // An encoder reset can hand out a DTS that is already buffered
+    buffer.insert(3, new_video_frame(vec![1, 2], 4, false, 3));
+
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, vec![0, 3, 4]);
+    assert_eq!(buffer.get_frames()[&4].data.len(), 2);
+}
+
+#[test]
+fn test_video_buffer_continues_after_encoder_reset() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(10_000_000);
+
+    buffer.insert(
+        5_000_000,
+        new_video_frame(vec![1], 5_000_000, true, 5_000_000),
+    );
+    buffer.insert(
+        5_010_000,
+        new_video_frame(vec![1], 5_010_000, false, 5_010_000),
+    );
+    // The encoder was reset and counts from zero again
+    buffer.insert(0, new_video_frame(vec![1], 0, true, 0));
+    buffer.insert(10_000, new_video_frame(vec![1], 10_000, false, 10_000));
+
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, vec![5_000_000, 5_010_000, 5_020_000, 5_030_000]);
+    assert_eq!(buffer.get_frames()[&5_030_000].raw_dts, 10_000);
+    assert_eq!(buffer.oldest_pts(), Some(5_000_000));
+    assert_eq!(buffer.newest_pts(), Some(5_030_000));
+    assert_eq!(buffer.full_recalculations(), 0);
+}
+
+#[test]
+fn test_audio_buffer_continues_after_encoder_reset() {
+    let mut buffer = ShadowCaptureAudioBuffer::new(10_000_000);
+
+    for (i, pts) in [0, 960, 1920, 0, 960].into_iter().enumerate() {
+        buffer.insert_capture_time(i as i64 * 20_000);
+        buffer.insert(pts, vec![1]);
+    }
+
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, vec![0, 960, 1920, 2880, 3840]);
+}
+
+#[test]
+fn test_audio_buffer_keeps_duplicate_pts() {
+    let mut buffer = ShadowCaptureAudioBuffer::new(10_000_000);
+    for (i, pts) in [0, 960, 960, 1920].into_iter().enumerate() {
+        buffer.insert_capture_time(i as i64 * 20_000);
+        buffer.insert(pts, vec![1]);
+    }
+
+    // Every capture time still has its frame
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, vec![0, 960, 961, 1920]);
+    assert_eq!(buffer.get_capture_times().len(), 4);
+}
+
+#[test]
+fn test_audio_buffer_capture_times_continue_after_a_new_capture() {
+    let mut buffer = ShadowCaptureAudioBuffer::new(100_000);
+    for i in 0..3 {
+        buffer.insert_capture_time(5_000_000 + i * 20_000);
+        buffer.insert(i * 960, vec![1]);
+    }
+    // A rebuilt capture counts from zero again, which must not read as the buffer spanning
+    // seconds of audio
+    for i in 0..2 {
+        buffer.insert_capture_time(i * 20_000);
+        buffer.insert(i * 960, vec![1]);
+    }
+
+    assert_eq!(
+        *buffer.get_capture_times(),
+        vec![5_000_000, 5_020_000, 5_040_000, 5_060_000, 5_080_000]
+    );
+    assert_eq!(buffer.get_frames().len(), 5);
+}
+
+#[test]
+fn test_video_buffer_late_frames_after_trim() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(100);
+    buffer.insert(0, new_video_frame(vec![1], 0, true, 0));
+    buffer.insert(30, new_video_frame(vec![1], 30, true, 30));
+    buffer.insert(50, new_video_frame(vec![1], 100, false, 50));
+    // The repeated key frame starts the next GOP, which trims the first one
+    buffer.insert(50, new_video_frame(vec![1], 100, true, 50));
+    assert_eq!(
+        buffer.key_frames().iter().copied().collect::<Vec<_>>(),
+        vec![30, 51]
+    );
+
+    // Nothing the late frames refer to is buffered anymore
+    buffer.insert(10, new_video_frame(vec![1], 10, false, 10));
+    buffer.insert(10, new_video_frame(vec![1], 10, true, 10));
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, vec![30, 50, 51, 52]);
+    assert!(buffer.get_frames()[&52].is_keyframe);
+}
+
+/// Eight frames 10us apart with a key frame every third one.
+fn video_buffer_with_three_gops() -> ShadowCaptureVideoBuffer {
+    let mut buffer = ShadowCaptureVideoBuffer::new(10_000);
+    for dts in 0..8 {
+        buffer.insert(
+            dts * 10,
+            new_video_frame(vec![1; 4], dts * 10, dts % 3 == 0, dts * 10),
+        );
+    }
+    buffer
+}
+
+#[test]
+fn test_video_buffer_take_keeps_the_gop_in_progress() {
+    let mut buffer = video_buffer_with_three_gops();
+    let taken = buffer.take();
+    assert_eq!(taken.get_frames().len(), 8);
+
+    // The next clip starts on the key frame the taken footage ends on
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, vec![60, 70]);
+    assert_eq!(buffer.size_bytes(), 8);
+    assert_eq!(buffer.oldest_pts(), Some(60));
+
+    // An encoder reset still continues after the taken frames
+    buffer.insert(0, new_video_frame(vec![1; 4], 0, true, 0));
+    assert_eq!(buffer.get_last_gop_start(), Some(&80));
+}
+
+#[test]
+fn test_video_buffer_restore_puts_taken_frames_back() {
+    let mut buffer = video_buffer_with_three_gops();
+    let taken = buffer.take();
+    for dts in 8..10 {
+        buffer.insert(
+            dts * 10,
+            new_video_frame(vec![1; 4], dts * 10, dts % 3 == 0, dts * 10),
+        );
+    }
+
+    buffer.restore(taken);
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, (0..10).map(|dts| dts * 10).collect::<Vec<_>>());
+    assert_eq!(
+        buffer.key_frames().iter().copied().collect::<Vec<_>>(),
+        vec![0, 30, 60, 90]
+    );
+    assert_eq!(buffer.size_bytes(), 40);
+    assert_eq!(buffer.newest_pts(), Some(90));
+}
+
+#[test]
+fn test_audio_buffer_take_and_restore() {
+    let mut buffer = ShadowCaptureAudioBuffer::new(10_000_000);
+    for i in 0..5 {
+        buffer.insert_capture_time(i * 20_000);
+        buffer.insert(i * 960, vec![1]);
+    }
+
+    let taken = buffer.take(60_000);
+    assert_eq!(taken.get_frames().len(), 5);
+    assert_eq!(*buffer.get_capture_times(), vec![60_000, 80_000]);
+    buffer.insert_capture_time(100_000);
+    buffer.insert(4800, vec![1]);
+
+    buffer.restore(taken);
+    let frames: Vec<_> = buffer.get_frames().keys().copied().collect();
+    assert_eq!(frames, (0..6).map(|i| i * 960).collect::<Vec<_>>());
+    assert_eq!(
+        *buffer.get_capture_times(),
+        (0..6).map(|i| i * 20_000).collect::<Vec<_>>()
+    );
+    assert_eq!(buffer.size_bytes(), 6);
+}
+
+#[test]
+fn test_video_buffer_replace_oldest_gop() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(10_000);
+    for dts in 0..9 {
+        buffer.insert(
+            dts * 10,
+            new_video_frame(vec![1; 4], dts * 10, dts % 3 == 0, dts * 10),
+        );
+    }
+
+    // The first GOP is presented until the next key frame at 30
+    assert!(buffer.oldest_original_gop(25).is_none());
+    assert!(buffer
+        .oldest_original_gop(30)
+        .is_some_and(|gop| gop.len() == 3));
+
+    let reencoded = buffer
+        .oldest_original_gop(30)
+        .unwrap()
+        .into_iter()
+        .map(|(dts, mut frame)| {
+            frame.data = vec![1].into();
+            frame.reencoded = true;
+            (dts, frame)
+        })
+        .collect::<Vec<_>>();
+    assert!(buffer.replace_gop(reencoded.clone()));
+    assert!(buffer.has_reencoded_frames());
+    assert_eq!(buffer.size_bytes(), 3 + 6 * 4);
+    // Already replaced, so neither handed out again nor replaced twice
+    assert_eq!(
+        buffer
+            .oldest_original_gop(60)
+            .unwrap()
+            .first()
+            .map(|(dts, _)| *dts),
+        Some(30)
+    );
+    assert!(!buffer.replace_gop(reencoded));
 }
 
 #[test]
@@ -173,3 +421,186 @@ fn test_audio_buffer_trimming() {
     assert!(audio_buffer.get_capture_times().is_empty());
     assert!(audio_buffer.get_frames().is_empty());
 }
+
+/// Inserts 12 frames with a key frame every 3rd frame and PTS == DTS == index.
+fn fill_video_buffer(buffer: &mut ShadowCaptureVideoBuffer) {
+    for i in 0..12 {
+        buffer.insert(i, new_video_frame(vec![1], i, i % 3 == 0, i));
+    }
+}
+
+#[test]
+fn test_video_buffer_shrink_max_time() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(100);
+    fill_video_buffer(&mut buffer);
+    assert_eq!(buffer.get_frames().len(), 12);
+
+    buffer.set_max_time(6);
+
+    // GOPs starting at 0 and 3 are trimmed right away, 6..=11 spans 5 which is within the limit
+    assert_eq!(buffer.get_frames().len(), 6);
+    assert_eq!(*buffer.get_frames().keys().next().unwrap(), 6);
+    assert_eq!(buffer.oldest_pts(), Some(6));
+    assert_eq!(*buffer.get_last_gop_start().unwrap(), 9);
+}
+
+#[test]
+fn test_video_buffer_grow_max_time() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(100);
+    fill_video_buffer(&mut buffer);
+
+    buffer.set_max_time(1_000);
+    assert_eq!(buffer.get_frames().len(), 12);
+
+    // Would have been trimmed with the old limit
+    for i in 12..150 {
+        buffer.insert(i, new_video_frame(vec![1], i, i % 3 == 0, i));
+    }
+    assert_eq!(buffer.get_frames().len(), 150);
+    assert_eq!(buffer.oldest_pts(), Some(0));
+}
+
+#[test]
+fn test_video_buffer_shrink_below_one_gop() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(100);
+    fill_video_buffer(&mut buffer);
+
+    buffer.set_max_time(1);
+
+    // Last complete GOP (6..9) and the GOP in progress (9..) are kept
+    assert_eq!(buffer.get_frames().len(), 6);
+    let (first_dts, first_frame) = buffer.get_frames().iter().next().unwrap();
+    assert_eq!(*first_dts, 6);
+    assert!(first_frame.is_keyframe);
+    assert_eq!(*buffer.get_last_gop_start().unwrap(), 9);
+}
+
+#[test]
+fn test_audio_buffer_resize_max_time() {
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(100);
+    for pts in 0..10 {
+        audio_buffer.insert_capture_time(pts);
+        audio_buffer.insert(pts, vec![1]);
+    }
+
+    audio_buffer.set_max_time(1_000);
+    assert_eq!(audio_buffer.get_frames().len(), 10);
+
+    audio_buffer.set_max_time(4);
+    assert_eq!(audio_buffer.get_frames().len(), 4);
+    assert_eq!(audio_buffer.get_capture_times()[0], 6);
+    assert_eq!(*audio_buffer.get_frames().keys().next().unwrap(), 6);
+}
+
+#[test]
+fn test_video_buffer_trims_by_size() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(1_000_000);
+    buffer.set_max_bytes(Some(100));
+
+    // 10 byte frames with a key frame every 3rd frame, time limit never reached
+    for i in 0..30 {
+        buffer.insert(i, new_video_frame(vec![0; 10], i, i % 3 == 0, i));
+        assert!(buffer.size_bytes() <= 100 + 10);
+    }
+
+    let frames = buffer.get_frames();
+    assert_eq!(
+        buffer.size_bytes(),
+        frames.values().map(|f| f.data.len()).sum::<usize>()
+    );
+    // Only whole GOPs are removed so the oldest frame is always a key frame
+    assert!(frames.values().next().unwrap().is_keyframe);
+    assert_eq!(*frames.keys().next().unwrap() % 3, 0);
+}
+
+#[test]
+fn test_video_buffer_shrink_max_bytes() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(1_000_000);
+    fill_video_buffer(&mut buffer);
+    assert_eq!(buffer.size_bytes(), 12);
+
+    buffer.set_max_bytes(Some(7));
+
+    // GOPs are trimmed until 6 frames (<= 7 bytes) remain
+    assert_eq!(buffer.size_bytes(), 6);
+    assert_eq!(*buffer.get_frames().keys().next().unwrap(), 6);
+
+    buffer.reset();
+    assert_eq!(buffer.size_bytes(), 0);
+}
+
+#[test]
+fn test_audio_buffer_trims_by_size() {
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(1_000_000);
+    audio_buffer.set_max_bytes(Some(50));
+
+    for pts in 0..20 {
+        audio_buffer.insert_capture_time(pts);
+        audio_buffer.insert(pts, vec![0; 10]);
+    }
+
+    assert_eq!(audio_buffer.size_bytes(), 50);
+    assert_eq!(audio_buffer.get_frames().len(), 5);
+    assert_eq!(audio_buffer.get_capture_times()[0], 15);
+}
+
+#[test]
+fn test_steady_state_trim_long_buffer() {
+    // 60 second buffer at 240fps fed with two minutes of frames so every GOP insert trims
+    let frame_duration_us = 4167;
+    let mut buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+
+    for i in 0..(240 * 120) {
+        let ts = i * frame_duration_us;
+        buffer.insert(ts, new_video_frame(vec![1], ts, i % 240 == 0, ts));
+        audio_buffer.insert_capture_time(ts);
+        audio_buffer.insert(ts, vec![1]);
+    }
+
+    // The oldest GOP is always kept whole and the window never exceeds max_time
+    let (oldest_dts, oldest_frame) = buffer.get_frames().iter().next().unwrap();
+    assert!(oldest_frame.is_keyframe);
+    let newest_dts = *buffer.get_frames().keys().next_back().unwrap();
+    assert!(newest_dts - oldest_dts < 60_000_000);
+    assert!(buffer.get_frames().len() >= 240 * 59);
+
+    let capture_times = audio_buffer.get_capture_times();
+    assert_eq!(capture_times.len(), audio_buffer.get_frames().len());
+    assert!(capture_times.back().unwrap() - capture_times.front().unwrap() < 60_000_000);
+}
+
+#[test]
+fn test_buffer_snapshots_share_frame_data() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(100);
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(100);
+    buffer.insert(0, new_video_frame(vec![1; 1024], 0, true, 0));
+    audio_buffer.insert_capture_time(0);
+    audio_buffer.insert(0, vec![1; 1024]);
+
+    let snapshot = buffer.clone();
+    let audio_snapshot = audio_buffer.clone();
+    buffer.reset();
+    audio_buffer.reset();
+
+    assert_eq!(snapshot.get_frames()[&0].data.len(), 1024);
+    assert_eq!(audio_snapshot.get_frames()[&0].len(), 1024);
+
+    // Clones point at the same allocation rather than copying it
+    let again = snapshot.clone();
+    assert_eq!(
+        again.get_frames()[&0].data.as_ptr(),
+        snapshot.get_frames()[&0].data.as_ptr()
+    );
+}
+
+#[test]
+fn test_video_buffer_key_frame_at_or_before() {
+    let mut buffer = ShadowCaptureVideoBuffer::new(1_000);
+    fill_video_buffer(&mut buffer);
+
+    assert_eq!(buffer.key_frame_at_or_before(4), Some(3));
+    assert_eq!(buffer.key_frame_at_or_before(6), Some(6));
+    assert_eq!(buffer.key_frame_at_or_before(100), Some(9));
+    assert_eq!(buffer.key_frame_at_or_before(-1), None);
+}
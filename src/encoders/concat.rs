@@ -0,0 +1,397 @@
+//! Joins saved clips back to back for `ConcatClips`. The packets are copied as they are, so the
+//! clips need to hold the same streams encoded the same way, only their timestamps are moved to
+//! follow on from the clip before. Like a transcode, only one join runs at a time on a thread of
+//! its own.
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use ffmpeg_next::{
+    self as ffmpeg, codec,
+    format::{self, context},
+    media, Rational,
+};
+use tokio::sync::mpsc;
+use zbus::Connection;
+
+use super::{
+    job::{Job, JobState},
+    transcode::{Container, ProgressTracker, VideoCodec},
+};
+use crate::{
+    clips::naming::partial_path,
+    dbus::{ClipService, GameClip},
+};
+
+/// What ffmpeg reports as the start of a stream when it doesn't know it.
+const NO_TIMESTAMP: i64 = i64::MIN;
+
+/// How a stream of a clip is encoded. Clips can only be joined if theirs all match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamLayout {
+    pub medium: media::Type,
+    pub codec: codec::Id,
+    /// Width and height of video streams.
+    pub size: Option<(u32, u32)>,
+    pub time_base: Rational,
+}
+
+impl StreamLayout {
+    fn describe(&self) -> String {
+        let size = self
+            .size
+            .map(|(width, height)| format!(" at {width}x{height}"))
+            .unwrap_or_default();
+        format!(
+            "{:?} {:?}{size} in {}/{}",
+            self.codec,
+            self.medium,
+            self.time_base.numerator(),
+            self.time_base.denominator()
+        )
+    }
+}
+
+/// The streams of a clip to join, in the order they are stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipLayout {
+    pub path: PathBuf,
+    pub streams: Vec<StreamLayout>,
+}
+
+/// Checks that every clip can be joined onto the first and that `container` can hold them,
+/// naming the first clip which doesn't fit.
+pub fn check_layouts(clips: &[ClipLayout], container: Container) -> Result<()> {
+    let Some((first, rest)) = clips.split_first() else {
+        bail!("There are no clips to join");
+    };
+    for stream in &first.streams {
+        let fits = match stream.medium {
+            media::Type::Video => {
+                VideoCodec::from_id(stream.codec).is_some_and(|codec| container.holds_video(codec))
+            }
+            media::Type::Audio => container.holds_audio(stream.codec),
+            _ => true,
+        };
+        ensure!(
+            fits,
+            "{} can't be put in {}, and clips are never re-encoded when joined",
+            stream.describe(),
+            container.extension()
+        );
+    }
+
+    for clip in rest {
+        ensure!(
+            clip.streams.len() == first.streams.len(),
+            "{:?} has {} streams where {:?} has {}, they can't be joined",
+            clip.path,
+            clip.streams.len(),
+            first.path,
+            first.streams.len()
+        );
+        let mismatch = clip
+            .streams
+            .iter()
+            .zip(&first.streams)
+            .enumerate()
+            .find(|(_, (stream, expected))| stream != expected);
+        if let Some((index, (stream, expected))) = mismatch {
+            bail!(
+                "{:?} can't be joined to {:?}, its stream {index} is {} where it is {}",
+                clip.path,
+                first.path,
+                stream.describe(),
+                expected.describe()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Resolves `output_name` to a new file in `output_dir`, with `default_extension` if the name
+/// has none. Joined clips never replace an existing file.
+pub fn resolve_output(
+    output_dir: &Path,
+    output_name: &str,
+    default_extension: &str,
+) -> Result<PathBuf> {
+    let name = Path::new(output_name);
+    ensure!(
+        !output_name.is_empty()
+            && !output_name.starts_with('.')
+            && name.file_name() == Some(name.as_os_str()),
+        "{output_name:?} is not a file name, the joined clip is always written to the output \
+         directory"
+    );
+    let path = match name.extension() {
+        Some(_) => output_dir.join(name),
+        None => output_dir.join(format!("{output_name}.{default_extension}")),
+    };
+    ensure!(!path.exists(), "{path:?} already exists");
+    Ok(path)
+}
+
+/// Scales `timestamp` from the time base `from` to `to`, rounding to the nearest.
+fn rescale(timestamp: i64, from: Rational, to: Rational) -> i64 {
+    let numerator =
+        i128::from(timestamp) * i128::from(from.numerator()) * i128::from(to.denominator());
+    let denominator = i128::from(from.denominator()) * i128::from(to.numerator());
+    if denominator <= 0 {
+        return timestamp;
+    }
+    (numerator + denominator / 2).div_euclid(denominator) as i64
+}
+
+fn microseconds() -> Rational {
+    Rational::new(1, 1_000_000)
+}
+
+/// Moves the timestamps of each clip so it starts where the clip before it ended. Streams keep
+/// their time bases and their offsets to each other within a clip.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    /// End of the latest packet so far in microseconds, where the next clip starts.
+    end: i64,
+    /// Time base of every stream of the current clip and what is added to its timestamps.
+    streams: Vec<(Rational, i64)>,
+}
+
+impl Timeline {
+    /// Starts the next clip, given the time base and start time of each of its streams.
+    pub fn start_clip(&mut self, streams: &[(Rational, i64)]) {
+        let start = streams
+            .iter()
+            .filter(|(_, start)| *start != NO_TIMESTAMP)
+            .map(|&(time_base, start)| rescale(start, time_base, microseconds()))
+            .min()
+            .unwrap_or(0);
+        let offset = self.end - start;
+        self.streams = streams
+            .iter()
+            .map(|&(time_base, _)| (time_base, rescale(offset, microseconds(), time_base)))
+            .collect();
+    }
+
+    /// Shifts the `pts` and `dts` of a packet of `stream` lasting `duration`, all in the time
+    /// base of the stream, onto the joined timeline.
+    pub fn shift(
+        &mut self,
+        stream: usize,
+        pts: Option<i64>,
+        dts: Option<i64>,
+        duration: i64,
+    ) -> (Option<i64>, Option<i64>) {
+        let Some(&(time_base, offset)) = self.streams.get(stream) else {
+            return (pts, dts);
+        };
+        let (pts, dts) = (pts.map(|pts| pts + offset), dts.map(|dts| dts + offset));
+        if let Some(timestamp) = pts.or(dts) {
+            // Packets without a duration still take up a tick, so the next clip starts after them
+            let end = rescale(timestamp + duration.max(1), time_base, microseconds());
+            self.end = self.end.max(end);
+        }
+        (pts, dts)
+    }
+
+    /// Length of the joined clips so far, in microseconds.
+    pub fn end(&self) -> i64 {
+        self.end
+    }
+}
+
+pub struct ConcatJob {
+    /// Clips to join in order, already resolved to files inside the output directory.
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+}
+
+/// What a running join reports, turned into dbus signals by [`publish`].
+#[derive(Debug)]
+pub enum ConcatEvent {
+    Done(PathBuf),
+    Failed(String),
+}
+
+impl Job for ConcatJob {
+    type Opened = Vec<context::Input>;
+    type Event = ConcatEvent;
+
+    const THREAD_NAME: &'static str = "waycap-concat";
+
+    fn open(&self) -> Result<(Self::Opened, PathBuf)> {
+        let inputs =
+            open(self).inspect_err(|e| log::error!("Not joining {:?}: {e:#}", self.inputs))?;
+        Ok((inputs, self.output.clone()))
+    }
+
+    fn run(
+        &self,
+        mut inputs: Self::Opened,
+        output: &Path,
+        state: &JobState,
+        _events: &mpsc::UnboundedSender<ConcatEvent>,
+    ) -> ConcatEvent {
+        log::info!("Joining {:?} into {output:?}", self.inputs);
+        match concat(&mut inputs, output, state) {
+            Ok(()) => {
+                log::info!("Joined {} clips into {output:?}", inputs.len());
+                ConcatEvent::Done(output.to_path_buf())
+            }
+            Err(e) => {
+                log::error!("Could not join the clips into {output:?}: {e:#}");
+                ConcatEvent::Failed(format!("{e:#}"))
+            }
+        }
+    }
+}
+
+/// Opens every input and checks they can be joined into the output.
+fn open(job: &ConcatJob) -> Result<Vec<context::Input>> {
+    ensure!(
+        job.inputs.len() >= 2,
+        "At least two clips are needed to join"
+    );
+    let container = Container::from_path(&job.output)
+        .with_context(|| format!("Can't tell the container of {:?}", job.output))?;
+
+    let mut inputs = Vec::with_capacity(job.inputs.len());
+    let mut layouts = Vec::with_capacity(job.inputs.len());
+    for path in &job.inputs {
+        let input = format::input(path).with_context(|| format!("Could not open {path:?}"))?;
+        layouts.push(layout(path, &input)?);
+        inputs.push(input);
+    }
+    check_layouts(&layouts, container)?;
+    Ok(inputs)
+}
+
+fn layout(path: &Path, input: &context::Input) -> Result<ClipLayout> {
+    let mut streams = Vec::new();
+    for stream in input.streams() {
+        let parameters = stream.parameters();
+        let medium = parameters.medium();
+        let size = match medium {
+            media::Type::Video => {
+                let decoder = codec::Context::from_parameters(parameters.clone())?
+                    .decoder()
+                    .video()
+                    .with_context(|| format!("Could not read the video of {path:?}"))?;
+                Some((decoder.width(), decoder.height()))
+            }
+            _ => None,
+        };
+        streams.push(StreamLayout {
+            medium,
+            codec: parameters.id(),
+            size,
+            time_base: stream.time_base(),
+        });
+    }
+    Ok(ClipLayout {
+        path: path.to_path_buf(),
+        streams,
+    })
+}
+
+/// Writes the joined clips to a partial file which only becomes `output` once complete, and
+/// which is deleted if anything fails or the join is cancelled.
+fn concat(inputs: &mut [context::Input], output: &Path, state: &JobState) -> Result<()> {
+    let partial = partial_path(output);
+    let result = write(inputs, &partial, state).and_then(|()| {
+        std::fs::rename(&partial, output)
+            .with_context(|| format!("Could not move the joined clips to {output:?}"))
+    });
+    if result.is_err() {
+        if let Err(e) = std::fs::remove_file(&partial) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::error!("Could not remove the partially joined clips {partial:?}: {e:?}");
+            }
+        }
+    }
+    result
+}
+
+fn write(inputs: &mut [context::Input], path: &Path, state: &JobState) -> Result<()> {
+    let mut output = format::output(path)?;
+    let first = inputs.first().context("There are no clips to join")?;
+    // The clips all hold the same streams, the output gets those of the first
+    for stream in first.streams() {
+        let mut copied = output.add_stream(ffmpeg::encoder::find(codec::Id::None))?;
+        copied.set_parameters(stream.parameters());
+    }
+    output.write_header()?;
+
+    // The muxer may pick its own time bases while writing the header
+    let output_time_bases = (0..first.streams().count())
+        .map(|index| output.stream(index).map(|stream| stream.time_base()))
+        .collect::<Option<Vec<_>>>()
+        .context("The output lost a stream")?;
+
+    // The durations of the inputs are in microseconds
+    let total = inputs.iter().map(|input| input.duration()).sum::<i64>();
+    let mut progress = ProgressTracker::new(total as f64 / 1_000_000.0);
+    let mut timeline = Timeline::default();
+    for input in inputs.iter_mut() {
+        let streams: Vec<_> = input
+            .streams()
+            .map(|stream| (stream.time_base(), stream.start_time()))
+            .collect();
+        timeline.start_clip(&streams);
+
+        for (stream, mut packet) in input.packets() {
+            ensure!(!state.is_cancelled(), "Joining the clips was cancelled");
+            let index = stream.index();
+            let Some(&output_time_base) = output_time_bases.get(index) else {
+                continue;
+            };
+            let (pts, dts) = timeline.shift(index, packet.pts(), packet.dts(), packet.duration());
+            packet.set_pts(pts);
+            packet.set_dts(dts);
+            packet.rescale_ts(stream.time_base(), output_time_base);
+            packet.set_position(-1);
+            packet.set_stream(index);
+            packet.write_interleaved(&mut output)?;
+
+            if let Some(progress) = progress.update(timeline.end() as f64 / 1_000_000.0) {
+                log::debug!("Joined {:.0}% of the clips", progress * 100.0);
+            }
+        }
+    }
+    output.write_trailer()?;
+    Ok(())
+}
+
+/// Turns the events of the job joining into `output` into dbus signals carrying `request_id`
+/// until it ends.
+pub async fn publish(
+    conn: Connection,
+    request_id: u32,
+    output: String,
+    mut events: mpsc::UnboundedReceiver<ConcatEvent>,
+) {
+    while let Some(event) = events.recv().await {
+        let iface = match conn
+            .object_server()
+            .interface::<_, ClipService>("/com/rust/WayCap")
+            .await
+        {
+            Ok(iface) => iface,
+            Err(e) => {
+                log::debug!("Stopped publishing the join into {output}: {e:?}");
+                return;
+            }
+        };
+        let emitter = iface.signal_emitter();
+        let result = match event {
+            ConcatEvent::Done(path) => {
+                ClipService::concat_done(emitter, path.display().to_string(), request_id).await
+            }
+            ConcatEvent::Failed(error) => {
+                ClipService::concat_failed(emitter, output.clone(), error, request_id).await
+            }
+        };
+        if let Err(e) = result {
+            log::error!("Could not emit the concat signal: {e:?}");
+        }
+    }
+}
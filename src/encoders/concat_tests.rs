@@ -0,0 +1,134 @@
+use std::{fs, path::PathBuf};
+
+use ffmpeg_next::{codec, media, Rational};
+
+use super::{concat::*, transcode::Container};
+
+fn video(width: u32, height: u32) -> StreamLayout {
+    StreamLayout {
+        medium: media::Type::Video,
+        codec: codec::Id::H264,
+        size: Some((width, height)),
+        time_base: Rational::new(1, 1_000_000),
+    }
+}
+
+fn audio() -> StreamLayout {
+    StreamLayout {
+        medium: media::Type::Audio,
+        codec: codec::Id::OPUS,
+        size: None,
+        time_base: Rational::new(1, 48_000),
+    }
+}
+
+fn clip(name: &str, streams: Vec<StreamLayout>) -> ClipLayout {
+    ClipLayout {
+        path: PathBuf::from(name),
+        streams,
+    }
+}
+
+#[test]
+fn test_matching_clips_can_be_joined() {
+    let clips = [
+        clip("clip_1.mp4", vec![video(1920, 1080), audio()]),
+        clip("clip_2.mp4", vec![video(1920, 1080), audio()]),
+    ];
+    assert!(check_layouts(&clips, Container::Mp4).is_ok());
+    assert!(check_layouts(&clips, Container::Mkv).is_ok());
+    // WebM can't hold H.264 and nothing is re-encoded
+    assert!(check_layouts(&clips, Container::Webm).is_err());
+}
+
+#[test]
+fn test_mismatch_names_the_clip() {
+    let clips = [
+        clip("clip_1.mp4", vec![video(1920, 1080), audio()]),
+        clip("clip_2.mp4", vec![video(1920, 1080), audio()]),
+        clip("clip_3.mp4", vec![video(2560, 1440), audio()]),
+    ];
+    let error = check_layouts(&clips, Container::Mp4)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("clip_3.mp4"), "{error}");
+    assert!(error.contains("2560x1440"), "{error}");
+    assert!(!error.contains("clip_2.mp4"), "{error}");
+
+    let other_time_base = StreamLayout {
+        time_base: Rational::new(1, 90_000),
+        ..video(1920, 1080)
+    };
+    let clips = [
+        clip("clip_1.mp4", vec![video(1920, 1080), audio()]),
+        clip("clip_2.mp4", vec![other_time_base, audio()]),
+    ];
+    let error = check_layouts(&clips, Container::Mp4)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("clip_2.mp4"), "{error}");
+
+    let without_audio = [
+        clip("clip_1.mp4", vec![video(1920, 1080), audio()]),
+        clip("clip_2.mp4", vec![video(1920, 1080)]),
+    ];
+    let error = check_layouts(&without_audio, Container::Mp4)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("clip_2.mp4"), "{error}");
+}
+
+#[test]
+fn test_timeline_follows_on_from_the_clip_before() {
+    let micros = Rational::new(1, 1_000_000);
+    let samples = Rational::new(1, 48_000);
+    let mut timeline = Timeline::default();
+
+    // One second at 10 fps with 20ms audio frames, the audio starting 100ms before the video
+    timeline.start_clip(&[(micros, 100_000), (samples, 0)]);
+    let mut written = Vec::new();
+    for index in 0..10 {
+        let pts = 100_000 + index * 100_000;
+        written.push(timeline.shift(0, Some(pts), Some(pts), 100_000));
+    }
+    for index in 0..55 {
+        timeline.shift(1, Some(index * 960), Some(index * 960), 960);
+    }
+    assert_eq!(written[0], (Some(100_000), Some(100_000)));
+    assert_eq!(timeline.end(), 1_100_000);
+
+    // The next clip's capture times are far later, they are moved right after the first
+    timeline.start_clip(&[(micros, 50_000_000), (samples, 2_400_000)]);
+    assert_eq!(
+        timeline.shift(0, Some(50_000_000), Some(50_000_000), 100_000),
+        (Some(1_100_000), Some(1_100_000))
+    );
+    // 2_400_000 samples are 50 seconds, the audio lines up with the video again
+    assert_eq!(
+        timeline.shift(1, Some(2_400_000), None, 960),
+        (Some(52_800), None)
+    );
+    assert_eq!(timeline.end(), 1_200_000);
+}
+
+#[test]
+fn test_output_is_a_new_file_in_the_output_dir() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+
+    assert_eq!(
+        resolve_output(dir, "joined", "mp4").unwrap(),
+        dir.join("joined.mp4")
+    );
+    assert_eq!(
+        resolve_output(dir, "joined.mkv", "mp4").unwrap(),
+        dir.join("joined.mkv")
+    );
+    assert!(resolve_output(dir, "", "mp4").is_err());
+    assert!(resolve_output(dir, "../joined.mp4", "mp4").is_err());
+    assert!(resolve_output(dir, "/tmp/joined.mp4", "mp4").is_err());
+    assert!(resolve_output(dir, ".hidden.mp4", "mp4").is_err());
+
+    fs::write(dir.join("clip_1.mp4"), b"").unwrap();
+    assert!(resolve_output(dir, "clip_1.mp4", "mp4").is_err());
+}
@@ -0,0 +1,103 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ffmpeg_next::{
+    self as ffmpeg,
+    codec::{self, Packet},
+    format::Pixel,
+    frame,
+    software::scaling,
+    Rational,
+};
+
+use super::{buffer::BufferedVideoFrame, muxer::StreamParams};
+
+/// A run of encoded frames starting at a key frame, detached from the shadow buffer so it can be
+/// decoded on another thread.
+pub struct GopSnapshot {
+    params: StreamParams,
+    /// Frames in decoding order, the first one being a key frame.
+    frames: Vec<(i64, BufferedVideoFrame)>,
+}
+
+impl GopSnapshot {
+    pub fn new(params: StreamParams, frames: Vec<(i64, BufferedVideoFrame)>) -> Self {
+        Self { params, frames }
+    }
+
+    /// Software decodes the whole run and returns the frame presented last.
+    pub fn decode_last_frame(&self) -> Result<frame::Video> {
+        let mut latest = None;
+        self.decode(|frame| {
+            latest = Some(frame);
+            Ok(())
+        })?;
+
+        latest.context("The decoder did not return any frame")
+    }
+
+    /// Software decodes the whole run, handing every frame to `on_frame` in presentation order
+    /// as soon as the decoder returns it.
+    pub fn decode(&self, mut on_frame: impl FnMut(frame::Video) -> Result<()>) -> Result<()> {
+        let mut decoder = codec::Context::from_parameters(self.params.parameters.clone())?
+            .decoder()
+            .video()
+            .context("Could not open a decoder for the buffered video")?;
+
+        for (dts, frame) in &self.frames {
+            let mut packet = Packet::copy(&frame.data);
+            packet.set_pts(Some(frame.pts));
+            packet.set_dts(Some(*dts));
+            decoder.send_packet(&packet)?;
+            receive_frames(&mut decoder, &mut on_frame)?;
+        }
+        decoder.send_eof()?;
+        receive_frames(&mut decoder, &mut on_frame)
+    }
+}
+
+/// Drains the frames the decoder has ready into `on_frame`.
+fn receive_frames(
+    decoder: &mut ffmpeg::decoder::Video,
+    on_frame: &mut impl FnMut(frame::Video) -> Result<()>,
+) -> Result<()> {
+    let mut decoded = frame::Video::empty();
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        on_frame(std::mem::replace(&mut decoded, frame::Video::empty()))?;
+    }
+    Ok(())
+}
+
+/// Converts `frame` to RGB and writes it to `path` as a PNG.
+pub fn write_png(frame: &frame::Video, path: &Path) -> Result<()> {
+    let (width, height) = (frame.width(), frame.height());
+    let mut scaler = scaling::Context::get(
+        frame.format(),
+        width,
+        height,
+        Pixel::RGB24,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )?;
+    let mut rgb = frame::Video::empty();
+    scaler.run(frame, &mut rgb)?;
+
+    let png = ffmpeg::encoder::find(codec::Id::PNG).context("No PNG encoder available")?;
+    let mut encoder = codec::Context::new_with_codec(png).encoder().video()?;
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(Pixel::RGB24);
+    encoder.set_time_base(Rational::new(1, 1));
+    let mut encoder = encoder.open_as(png)?;
+
+    encoder.send_frame(&rgb)?;
+    encoder.send_eof()?;
+    let mut packet = Packet::empty();
+    encoder
+        .receive_packet(&mut packet)
+        .context("The PNG encoder did not return an image")?;
+
+    std::fs::write(path, packet.data().unwrap_or_default())
+        .with_context(|| format!("Could not write {path:?}"))
+}
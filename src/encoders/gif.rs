@@ -0,0 +1,220 @@
+//! Animated GIFs of the buffered video, for `SaveGif`. The footage is software decoded, thinned
+//! out to the requested frame rate, downscaled and quantized to a fixed 256 color palette as it
+//! goes, so only the frame being converted is held decoded at any time.
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use ffmpeg_next::{
+    self as ffmpeg,
+    codec::{self, Packet},
+    format::{self, context, Pixel},
+    frame,
+    software::scaling,
+    Rational,
+};
+
+use super::frame_extract::GopSnapshot;
+use crate::clips::naming::{move_into_place, partial_path};
+
+pub const MAX_GIF_SECONDS: u32 = 15;
+pub const MAX_GIF_FPS: u32 = 30;
+pub const MAX_GIF_WIDTH: u32 = 640;
+
+/// Frame delays in a GIF are hundredths of a second.
+const TICKS_PER_SECOND: i32 = 100;
+
+/// Progress is logged every this many frames.
+const PROGRESS_FRAMES: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GifOptions {
+    pub seconds: u32,
+    pub fps: u32,
+    pub width: u32,
+}
+
+impl GifOptions {
+    /// Checks the options against the limits which keep the time and memory a GIF takes in
+    /// bounds.
+    pub fn new(seconds: u32, fps: u32, width: u32) -> Result<Self> {
+        ensure!(
+            (1..=MAX_GIF_SECONDS).contains(&seconds),
+            "A GIF can be 1 to {MAX_GIF_SECONDS} seconds long, not {seconds}"
+        );
+        ensure!(
+            (1..=MAX_GIF_FPS).contains(&fps),
+            "A GIF can have 1 to {MAX_GIF_FPS} frames per second, not {fps}"
+        );
+        ensure!(
+            (2..=MAX_GIF_WIDTH).contains(&width),
+            "A GIF can be 2 to {MAX_GIF_WIDTH} pixels wide, not {width}"
+        );
+        Ok(Self {
+            seconds,
+            fps,
+            width,
+        })
+    }
+
+    /// Size of the GIF made from video of `width` x `height`. It is as wide as requested but
+    /// never upscaled, the height keeps the aspect ratio and both are even.
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let out_width = (self.width.min(width) & !1).max(2);
+        let out_height =
+            (u64::from(height) * u64::from(out_width) / u64::from(width.max(1))) as u32;
+        (out_width, (out_height & !1).max(2))
+    }
+}
+
+/// Picks the decoded frames which make it into a GIF at a lower frame rate than the capture.
+/// Timestamps are capture times in microseconds.
+pub struct FrameSampler {
+    interval: i64,
+    next: i64,
+}
+
+impl FrameSampler {
+    pub fn new(start: i64, fps: u32) -> Self {
+        Self {
+            interval: 1_000_000 / i64::from(fps.max(1)),
+            next: start,
+        }
+    }
+
+    /// Whether the frame presented at `pts` is kept. Frames before the start are skipped, after
+    /// that at most one per interval is kept and a gap in the capture doesn't make up for the
+    /// frames it missed.
+    pub fn keep(&mut self, pts: i64) -> bool {
+        if pts < self.next {
+            return false;
+        }
+        self.next += self.interval * ((pts - self.next) / self.interval + 1);
+        true
+    }
+}
+
+/// Writes the footage of `gop` from `start` (a capture time in microseconds) on to `path` as a
+/// GIF, returning how many frames it holds. The GIF is written next to `path` and only moved to it
+/// once complete, never replacing a file already there.
+pub fn write_gif(gop: &GopSnapshot, start: i64, options: GifOptions, path: &Path) -> Result<usize> {
+    let partial = partial_path(path);
+    let written = encode(gop, start, options, &partial)
+        .and_then(|frames| Ok(move_into_place(&partial, path).map(|_| frames)?));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    written.with_context(|| format!("Could not write the GIF {path:?}"))
+}
+
+fn encode(gop: &GopSnapshot, start: i64, options: GifOptions, path: &Path) -> Result<usize> {
+    let expected = (options.seconds * options.fps) as usize;
+    let mut sampler = FrameSampler::new(start, options.fps);
+    let mut writer: Option<GifWriter> = None;
+    gop.decode(|decoded| {
+        let Some(pts) = decoded.timestamp().or(decoded.pts()) else {
+            return Ok(());
+        };
+        if !sampler.keep(pts) {
+            return Ok(());
+        }
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => writer.insert(GifWriter::open(&decoded, options, path)?),
+        };
+        writer.push(&decoded, pts - start)?;
+        if writer.frames % PROGRESS_FRAMES == 0 {
+            log::info!("Encoded {} of about {expected} GIF frames", writer.frames);
+        }
+        Ok(())
+    })?;
+
+    let writer = writer.context("No video was buffered in that time")?;
+    writer.finish()
+}
+
+/// The GIF encoder and muxer, opened once the first frame tells the size of the video.
+struct GifWriter {
+    output: context::Output,
+    encoder: ffmpeg::encoder::video::Encoder,
+    scaler: scaling::Context,
+    stream_time_base: Rational,
+    /// Timestamp of the last frame, in hundredths of a second.
+    last_pts: Option<i64>,
+    frames: usize,
+}
+
+impl GifWriter {
+    fn open(first: &frame::Video, options: GifOptions, path: &Path) -> Result<Self> {
+        let (width, height) = options.output_size(first.width(), first.height());
+        // The 3-3-2 RGB palette is fixed, scaling to it dithers the colors down
+        let scaler = scaling::Context::get(
+            first.format(),
+            first.width(),
+            first.height(),
+            Pixel::RGB8,
+            width,
+            height,
+            scaling::Flags::BILINEAR,
+        )?;
+
+        let gif = ffmpeg::encoder::find(codec::Id::GIF).context("No GIF encoder available")?;
+        let mut encoder = codec::Context::new_with_codec(gif).encoder().video()?;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_format(Pixel::RGB8);
+        encoder.set_time_base(Rational::new(1, TICKS_PER_SECOND));
+        let encoder = encoder.open_as(gif)?;
+
+        let mut output = format::output(path)?;
+        let mut stream = output.add_stream(gif)?;
+        stream.set_parameters(&encoder);
+        stream.set_time_base(Rational::new(1, TICKS_PER_SECOND));
+        output.write_header()?;
+        // The muxer may pick its own time base while writing the header
+        let stream_time_base = output
+            .stream(0)
+            .map(|stream| stream.time_base())
+            .context("The GIF lost its stream")?;
+
+        Ok(Self {
+            output,
+            encoder,
+            scaler,
+            stream_time_base,
+            last_pts: None,
+            frames: 0,
+        })
+    }
+
+    /// Converts and encodes the frame shown `offset` microseconds into the GIF.
+    fn push(&mut self, decoded: &frame::Video, offset: i64) -> Result<()> {
+        let mut scaled = frame::Video::empty();
+        self.scaler.run(decoded, &mut scaled)?;
+        // Frames closer together than a hundredth of a second still need their own timestamp
+        let pts = (offset * i64::from(TICKS_PER_SECOND) / 1_000_000)
+            .max(self.last_pts.map_or(0, |last| last + 1));
+        scaled.set_pts(Some(pts));
+        self.last_pts = Some(pts);
+        self.encoder.send_frame(&scaled)?;
+        self.frames += 1;
+        self.write_packets()
+    }
+
+    fn write_packets(&mut self) -> Result<()> {
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(0);
+            packet.rescale_ts(Rational::new(1, TICKS_PER_SECOND), self.stream_time_base);
+            packet.write_interleaved(&mut self.output)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<usize> {
+        self.encoder.send_eof()?;
+        self.write_packets()?;
+        self.output.write_trailer()?;
+        log::info!("Encoded all {} GIF frames", self.frames);
+        Ok(self.frames)
+    }
+}
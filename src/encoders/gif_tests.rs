@@ -0,0 +1,52 @@
+use super::gif::*;
+
+#[test]
+fn test_gif_options_limits() {
+    assert!(GifOptions::new(MAX_GIF_SECONDS, MAX_GIF_FPS, MAX_GIF_WIDTH).is_ok());
+    assert!(GifOptions::new(0, 10, 320).is_err());
+    assert!(GifOptions::new(MAX_GIF_SECONDS + 1, 10, 320).is_err());
+    assert!(GifOptions::new(5, 0, 320).is_err());
+    assert!(GifOptions::new(5, MAX_GIF_FPS + 1, 320).is_err());
+    assert!(GifOptions::new(5, 10, 1).is_err());
+
+    let error = GifOptions::new(5, 10, 1920).unwrap_err().to_string();
+    assert!(error.contains("640"), "{error}");
+}
+
+#[test]
+fn test_gif_size_keeps_aspect_ratio() {
+    let options = GifOptions::new(5, 10, 480).unwrap();
+    assert_eq!(options.output_size(1920, 1080), (480, 270));
+    // An odd height is rounded down to an even one
+    assert_eq!(options.output_size(2560, 1080), (480, 202));
+    // Never upscaled
+    assert_eq!(options.output_size(320, 240), (320, 240));
+
+    let odd = GifOptions::new(5, 10, 333).unwrap();
+    assert_eq!(odd.output_size(1920, 1080), (332, 186));
+}
+
+#[test]
+fn test_frame_sampler_thins_out_to_the_frame_rate() {
+    // 60 fps capture for one second, starting half a second in
+    let mut sampler = FrameSampler::new(500_000, 10);
+    let kept: Vec<_> = (0..60)
+        .map(|index| index * 1_000_000 / 60)
+        .filter(|&pts| sampler.keep(pts))
+        .collect();
+    assert_eq!(kept.len(), 5);
+    assert!(kept[0] >= 500_000);
+    assert!(kept.windows(2).all(|pair| pair[1] - pair[0] >= 83_333));
+}
+
+#[test]
+fn test_frame_sampler_after_a_gap() {
+    let mut sampler = FrameSampler::new(0, 10);
+    assert!(sampler.keep(0));
+    assert!(!sampler.keep(50_000));
+    // Nothing was captured for a second, the next frame is kept once and not repeated
+    assert!(sampler.keep(1_050_000));
+    assert!(!sampler.keep(1_080_000));
+    // The frames after it stay on the 10 fps grid
+    assert!(sampler.keep(1_100_000));
+}
@@ -0,0 +1,102 @@
+//! What transcoding and joining clips share: one job of each kind at a time, started from the
+//! run loop and cancelled over dbus, on a thread at the priority and on the CPUs of the mux.
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use anyhow::Result;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::thread_priority::{spawn_mux_thread, ThreadsConfig};
+
+/// Shared between the run loop, which starts the jobs of one kind, and the dbus call cancelling
+/// them.
+#[derive(Debug, Default)]
+pub struct JobState {
+    running: AtomicBool,
+    cancel: AtomicBool,
+}
+
+impl JobState {
+    /// Claims the slot for a new job. Returns false if one is already running.
+    pub fn try_start(&self) -> bool {
+        let started = self
+            .running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if started {
+            self.cancel.store(false, Ordering::Release);
+        }
+        started
+    }
+
+    pub fn finish(&self) {
+        self.running.store(false, Ordering::Release);
+    }
+
+    /// Asks the running job to stop. Returns false if none is running.
+    pub fn cancel(&self) -> bool {
+        if !self.running.load(Ordering::Acquire) {
+            return false;
+        }
+        self.cancel.store(true, Ordering::Release);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Acquire)
+    }
+}
+
+/// A job [`spawn`] can run.
+pub trait Job: Send + 'static {
+    /// What [`Job::open`] hands over to [`Job::run`].
+    type Opened;
+    /// What the job reports while running, the last one being its outcome.
+    type Event: Send + 'static;
+
+    const THREAD_NAME: &'static str;
+
+    /// Opens and checks the inputs, returning them with the path the job writes to.
+    fn open(&self) -> Result<(Self::Opened, PathBuf)>;
+
+    /// Writes `output`, stopping early once `state` is cancelled. Returns the outcome.
+    fn run(
+        &self,
+        opened: Self::Opened,
+        output: &Path,
+        state: &JobState,
+        events: &mpsc::UnboundedSender<Self::Event>,
+    ) -> Self::Event;
+}
+
+/// Starts `job` on a thread running at the priority and on the CPUs of the mux. `reply` gets
+/// the output path once the job was opened, or why it can't run, the outcome is then sent to
+/// `events`. `state` must have been claimed with [`JobState::try_start`], the thread releases
+/// it once done.
+pub fn spawn<J: Job>(
+    job: J,
+    threads: &ThreadsConfig,
+    state: Arc<JobState>,
+    reply: oneshot::Sender<Result<String, String>>,
+    events: mpsc::UnboundedSender<J::Event>,
+) -> std::io::Result<JoinHandle<()>> {
+    spawn_mux_thread(J::THREAD_NAME, threads, move || {
+        match job.open() {
+            Ok((opened, output)) => {
+                let _ = reply.send(Ok(output.display().to_string()));
+                let event = job.run(opened, &output, &state, &events);
+                let _ = events.send(event);
+            }
+            Err(e) => {
+                let _ = reply.send(Err(format!("{e:#}")));
+            }
+        }
+        state.finish();
+    })
+}
@@ -0,0 +1,13 @@
+use super::job::JobState;
+
+#[test]
+fn test_one_job_at_a_time() {
+    let state = JobState::default();
+    assert!(!state.cancel());
+    assert!(state.try_start());
+    assert!(!state.try_start());
+    assert!(state.cancel());
+
+    state.finish();
+    assert!(state.try_start());
+}
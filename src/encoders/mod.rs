@@ -1,3 +1,34 @@
 pub mod buffer;
 #[cfg(test)]
+mod buffer_invariant_tests;
+#[cfg(test)]
 mod buffer_tests;
+pub mod concat;
+#[cfg(test)]
+mod concat_tests;
+pub mod frame_extract;
+pub mod gif;
+#[cfg(test)]
+mod gif_tests;
+pub mod job;
+#[cfg(test)]
+mod job_tests;
+pub mod muxer;
+#[cfg(test)]
+mod muxer_tests;
+pub mod recording;
+#[cfg(test)]
+mod recording_tests;
+pub mod spool;
+#[cfg(test)]
+mod spool_tests;
+pub mod staging;
+#[cfg(test)]
+mod staging_tests;
+pub mod streaming;
+#[cfg(test)]
+mod streaming_tests;
+pub mod tiering;
+pub mod transcode;
+#[cfg(test)]
+mod transcode_tests;
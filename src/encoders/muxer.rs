@@ -0,0 +1,730 @@
+use std::{
+    collections::VecDeque,
+    ops::Bound,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use ffmpeg_next::{self as ffmpeg, codec::Parameters, format::context::Output, Codec, Rational};
+
+use super::buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer};
+use crate::clips::markers::{chapters_for, Chapter, Marker};
+pub use crate::dbus_types::SaveReport;
+
+/// Sample rate of the Opus encoder, which is also its time base.
+const AUDIO_TIME_BASE_HZ: i64 = 48_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxStream {
+    Video,
+    Audio,
+}
+
+/// A packet ready to be written, with its timestamps already shifted so the clip starts at 0.
+#[derive(Debug, Clone)]
+pub struct MuxPacket {
+    pub stream: MuxStream,
+    pub data: Bytes,
+    pub pts: i64,
+    pub dts: i64,
+    /// Capture time in microseconds, used to order packets across streams.
+    pub capture_time: i64,
+}
+
+/// What is needed to add an encoder's stream to an output.
+#[derive(Clone)]
+pub struct StreamParams {
+    pub codec: Option<Codec>,
+    pub parameters: Parameters,
+    pub time_base: Rational,
+    /// Average frame rate written to the container, set by [`ClipMuxer`] from the frames it
+    /// writes.
+    pub frame_rate: Option<Rational>,
+}
+
+/// Where a [`ClipMuxer`] writes to. [`FileSink`] writes an actual file, tests can collect the
+/// packets in memory instead.
+pub trait PacketSink {
+    /// Adds a stream and returns its index.
+    fn add_stream(&mut self, params: &StreamParams) -> Result<usize>;
+    /// Adds a chapter, times being in microseconds from the start of the clip.
+    fn add_chapter(&mut self, chapter: &Chapter) -> Result<()>;
+    /// Sets a container level tag, before the header is written.
+    fn add_metadata(&mut self, key: &str, value: &str) -> Result<()>;
+    fn write_header(&mut self) -> Result<()>;
+    fn write_packet(&mut self, stream: usize, packet: &MuxPacket) -> Result<()>;
+    fn write_trailer(&mut self) -> Result<()>;
+}
+
+/// Writes a clip to disk through libav.
+pub struct FileSink {
+    output: Output,
+    movflags: Option<&'static str>,
+    metadata: ffmpeg::Dictionary<'static>,
+}
+
+impl FileSink {
+    pub fn create(path: &Path, faststart: bool) -> Result<Self> {
+        Ok(Self {
+            output: ffmpeg::format::output(path)?,
+            movflags: movflags_for(path, faststart),
+            metadata: ffmpeg::Dictionary::new(),
+        })
+    }
+}
+
+impl PacketSink for FileSink {
+    fn add_stream(&mut self, params: &StreamParams) -> Result<usize> {
+        let mut stream = self.output.add_stream(params.codec)?;
+        stream.set_time_base(params.time_base);
+        stream.set_parameters(params.parameters.clone());
+        if let Some(frame_rate) = params.frame_rate {
+            stream.set_avg_frame_rate(frame_rate);
+        }
+        Ok(stream.index())
+    }
+
+    fn add_chapter(&mut self, chapter: &Chapter) -> Result<()> {
+        self.output.add_chapter(
+            chapter.id as i64,
+            Rational::new(1, 1_000_000),
+            chapter.start,
+            chapter.end,
+            &chapter.title,
+        )?;
+        Ok(())
+    }
+
+    fn add_metadata(&mut self, key: &str, value: &str) -> Result<()> {
+        self.metadata.set(key, value);
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        self.output.set_metadata(std::mem::replace(
+            &mut self.metadata,
+            ffmpeg::Dictionary::new(),
+        ));
+        // Faststart moves the moov atom in front of the media data so players can start before
+        // the whole file is downloaded, at the cost of rewriting the file once the trailer is known
+        let mut options = ffmpeg::Dictionary::new();
+        if let Some(flags) = self.movflags {
+            options.set("movflags", flags);
+        }
+        self.output.write_header_with(options)?;
+        Ok(())
+    }
+
+    fn write_packet(&mut self, stream: usize, mux_packet: &MuxPacket) -> Result<()> {
+        let mut packet = ffmpeg::codec::packet::Packet::copy(&mux_packet.data);
+        packet.set_pts(Some(mux_packet.pts));
+        packet.set_dts(Some(mux_packet.dts));
+        packet.set_stream(stream);
+
+        packet
+            .write_interleaved(&mut self.output)
+            .context("Could not write packet interleaved")
+    }
+
+    fn write_trailer(&mut self) -> Result<()> {
+        self.output.write_trailer()?;
+        Ok(())
+    }
+}
+
+/// Returned by [`ClipMuxer::mux`] when the save was cancelled part way through.
+#[derive(Debug)]
+pub struct SaveCancelled;
+
+impl std::fmt::Display for SaveCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The save was cancelled")
+    }
+}
+
+impl std::error::Error for SaveCancelled {}
+
+/// Container level tags of a clip.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClipTags {
+    pub title: String,
+    /// Wall clock time at capture time 0, in milliseconds since the epoch, which dates the first
+    /// frame of the clip. No `creation_time` is written without it.
+    pub capture_epoch_ms: Option<i64>,
+}
+
+impl ClipTags {
+    /// Tags for the clip at `path`, titled after its file name.
+    pub fn new(path: &Path, capture_epoch_ms: Option<i64>) -> Self {
+        Self {
+            title: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            capture_epoch_ms,
+        }
+    }
+
+    /// The tags to write for a clip whose first frame was captured at `start_time` microseconds.
+    /// libav always replaces the `encoder` tag with its own version, so WayCap names itself in
+    /// the comment instead.
+    pub fn entries(&self, start_time: i64) -> Vec<(&'static str, String)> {
+        let mut entries = Vec::new();
+        if !self.title.is_empty() {
+            entries.push(("title", self.title.clone()));
+        }
+        if let Some(created) = self
+            .capture_epoch_ms
+            .and_then(|epoch| chrono::DateTime::from_timestamp_millis(epoch + start_time / 1000))
+        {
+            entries.push((
+                "creation_time",
+                created.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string(),
+            ));
+        }
+        entries.push((
+            "comment",
+            format!("Recorded with WayCap {}", env!("CARGO_PKG_VERSION")),
+        ));
+        entries
+    }
+}
+
+/// Turns the shadow buffers into a clip.
+pub struct ClipMuxer {
+    video: StreamParams,
+    audio: Option<StreamParams>,
+    window: ClipWindow,
+    audio_offset: i64,
+    cancel: Option<Arc<AtomicBool>>,
+    tags: Option<ClipTags>,
+}
+
+impl ClipMuxer {
+    pub fn new(video: StreamParams, audio: Option<StreamParams>) -> Self {
+        Self {
+            video,
+            audio,
+            window: ClipWindow::default(),
+            audio_offset: 0,
+            cancel: None,
+            tags: None,
+        }
+    }
+
+    /// Only saves the part of the buffer within `window`.
+    pub fn with_window(mut self, window: ClipWindow) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Delays the audio against the video by `offset_ms`, or moves it earlier if negative, to
+    /// make up for a fixed skew between the two.
+    pub fn with_audio_offset(mut self, offset_ms: i32) -> Self {
+        self.audio_offset = i64::from(offset_ms) * 1000;
+        self
+    }
+
+    /// Stops muxing with [`SaveCancelled`] once `cancel` is set. No trailer is written.
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Writes `tags` into the container, dated by the first frame actually written.
+    pub fn with_tags(mut self, tags: ClipTags) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+
+    /// Writes the buffered streams to `sink`, see [`interleave_packets`] for how they are lined
+    /// up. Audio is left out if there is no audio stream, and either stream if the window
+    /// excludes it. The `markers` within the clip are written as chapters and the tags, if any,
+    /// as container metadata.
+    pub fn mux(
+        &self,
+        video_buffer: &ShadowCaptureVideoBuffer,
+        audio_buffer: &ShadowCaptureAudioBuffer,
+        markers: &[Marker],
+        sink: &mut impl PacketSink,
+    ) -> Result<MuxPlan> {
+        if self.window.streams == ClipStreams::AudioOnly && self.audio.is_none() {
+            bail!("An audio-only clip can't be saved, no audio is being captured");
+        }
+        let mut plan =
+            interleave_packets(video_buffer, audio_buffer, self.window, self.audio_offset)?;
+        plan.chapters = chapters_for(markers, plan.start_time, plan.end_time);
+        plan.dts_corrections = enforce_increasing_dts(&mut plan.packets);
+        if plan.dts_corrections > 0 {
+            log::warn!(
+                "Moved the DTS of {} packets forward, they did not increase",
+                plan.dts_corrections
+            );
+        }
+
+        let video = StreamParams {
+            frame_rate: plan.frame_rate(),
+            ..self.video.clone()
+        };
+        let video_stream = match self.window.streams.video() {
+            true => Some(sink.add_stream(&video)?),
+            false => None,
+        };
+        let audio_stream = match &self.audio {
+            Some(params) if self.window.streams.audio() => Some(sink.add_stream(params)?),
+            _ => None,
+        };
+        for chapter in &plan.chapters {
+            sink.add_chapter(chapter)?;
+        }
+        if let Some(tags) = &self.tags {
+            for (key, value) in tags.entries(plan.start_time) {
+                sink.add_metadata(key, &value)?;
+            }
+        }
+        sink.write_header()?;
+
+        log::debug!("SAVE START");
+        for packet in &plan.packets {
+            if self
+                .cancel
+                .as_ref()
+                .is_some_and(|cancel| cancel.load(Ordering::Acquire))
+            {
+                log::debug!("SAVE CANCELLED");
+                return Err(SaveCancelled.into());
+            }
+            let stream = match packet.stream {
+                MuxStream::Video => video_stream,
+                MuxStream::Audio => audio_stream,
+            };
+            if let Some(stream) = stream {
+                sink.write_packet(stream, packet)?;
+            }
+        }
+        log::debug!("SAVE END");
+
+        sink.write_trailer()?;
+        Ok(plan)
+    }
+}
+
+/// Part of the buffer to save, as capture times in microseconds. `None` means from the oldest
+/// frame or up to the newest complete GOP respectively.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClipWindow {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub streams: ClipStreams,
+}
+
+/// Which of the buffered streams go into a clip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClipStreams {
+    #[default]
+    Both,
+    VideoOnly,
+    AudioOnly,
+}
+
+impl ClipStreams {
+    /// The streams to save, `None` if neither is included.
+    pub fn new(include_video: bool, include_audio: bool) -> Option<Self> {
+        match (include_video, include_audio) {
+            (true, true) => Some(Self::Both),
+            (true, false) => Some(Self::VideoOnly),
+            (false, true) => Some(Self::AudioOnly),
+            (false, false) => None,
+        }
+    }
+
+    pub fn video(self) -> bool {
+        self != Self::AudioOnly
+    }
+
+    pub fn audio(self) -> bool {
+        self != Self::VideoOnly
+    }
+}
+
+/// Every packet to write for a clip along with how many frames were left out to keep the streams
+/// in sync.
+#[derive(Debug, Clone, Default)]
+pub struct MuxPlan {
+    pub packets: Vec<MuxPacket>,
+    pub skipped_video_frames: usize,
+    pub skipped_audio_frames: usize,
+    /// Capture time of the first and last video frame written, in microseconds.
+    pub start_time: i64,
+    pub end_time: i64,
+    pub chapters: Vec<Chapter>,
+    /// State of the audio timestamps after the last audio packet, `None` if no audio was written.
+    pub audio_clock: Option<AudioClock>,
+    /// Packets whose DTS had to be moved forward to keep it increasing, see
+    /// [`enforce_increasing_dts`].
+    pub dts_corrections: usize,
+}
+
+impl MuxPlan {
+    pub fn video_frames(&self) -> usize {
+        self.packets
+            .iter()
+            .filter(|p| p.stream == MuxStream::Video)
+            .count()
+    }
+
+    pub fn audio_frames(&self) -> usize {
+        self.packets
+            .iter()
+            .filter(|p| p.stream == MuxStream::Audio)
+            .count()
+    }
+
+    /// Time between the first and last video frame, or audio frame for an audio-only clip, in
+    /// microseconds.
+    pub fn clip_duration_micros(&self) -> i64 {
+        let stream = match self.video_frames() {
+            0 => MuxStream::Audio,
+            _ => MuxStream::Video,
+        };
+        let mut frames = self.packets.iter().filter(|p| p.stream == stream);
+        let first = frames.next().map_or(0, |p| p.capture_time);
+        frames.last().map_or(0, |p| p.capture_time - first)
+    }
+
+    /// Average rate of the video frames, in thousandths of a frame per second, `None` without
+    /// two frames to measure it by. The timestamps stay variable, the rate only tells players
+    /// what to expect.
+    pub fn frame_rate(&self) -> Option<Rational> {
+        let frames = self.video_frames() as i64;
+        let duration = self.clip_duration_micros();
+        if frames < 2 || duration <= 0 {
+            return None;
+        }
+        let millihertz = (frames - 1) * 1_000_000_000 / duration;
+        Some(Rational::new(i32::try_from(millihertz).ok()?, 1000))
+    }
+}
+
+/// Returns the `movflags` to mux `filename` with. Faststart only applies to the ISO BMFF
+/// containers, other formats such as MKV/WebM get no flags at all.
+pub fn movflags_for(filename: &Path, faststart: bool) -> Option<&'static str> {
+    if !faststart {
+        return None;
+    }
+
+    let extension = filename.extension()?.to_str()?.to_ascii_lowercase();
+
+    match extension.as_str() {
+        "mp4" | "mov" | "m4v" | "m4a" => Some("+faststart"),
+        _ => None,
+    }
+}
+
+/// Lines the buffered streams up against each other and returns every packet to write, ordered
+/// by capture time so the muxer's interleave queue never has to hold more than a few packets.
+///
+/// Video is cut at the start of the last GOP and starts at the key frame of the GOP in which the
+/// audio starts, or the next key frame if that GOP isn't buffered, so the clip can be decoded
+/// from its first frame. Audio is trimmed to the span covered by the written video. The start of
+/// `window` is snapped back to the preceding key frame the same way.
+///
+/// A video-only clip starts at the first key frame of the window, an audio-only clip holds all
+/// the audio within the window.
+///
+/// `audio_offset` microseconds are added to the audio capture times before anything is lined up,
+/// audio moved before the first video frame is dropped like any other.
+pub fn interleave_packets(
+    video_buffer: &ShadowCaptureVideoBuffer,
+    audio_buffer: &ShadowCaptureAudioBuffer,
+    window: ClipWindow,
+    audio_offset: i64,
+) -> Result<MuxPlan> {
+    if window.streams == ClipStreams::AudioOnly {
+        return Ok(audio_only_packets(audio_buffer, window));
+    }
+    let last_keyframe = *video_buffer
+        .get_last_gop_start()
+        .context("Could not get last keyframe dts")?;
+    let start = match window
+        .start
+        .and_then(|start| video_buffer.key_frame_at_or_before(start))
+    {
+        Some(key_frame) => Bound::Included(key_frame),
+        None => Bound::Unbounded,
+    };
+    let end = window
+        .end
+        .map_or(last_keyframe, |end| end.min(last_keyframe));
+    if let Bound::Included(key_frame) = start {
+        if key_frame > end {
+            return Ok(MuxPlan::default());
+        }
+    }
+
+    let audio_capture_timestamps: VecDeque<_> = match window.streams.audio() {
+        true => audio_buffer
+            .get_capture_times()
+            .iter()
+            .map(|capture_time| capture_time + audio_offset)
+            .collect(),
+        false => VecDeque::new(),
+    };
+    let first_audio_capture = audio_capture_timestamps.front().copied();
+
+    let video_candidates: Vec<_> = video_buffer
+        .get_frames()
+        .range((start, Bound::Included(end)))
+        .collect();
+    // Start with the GOP in which the audio starts, the frames in it before the audio are
+    // written anyway since they can't be decoded without its key frame
+    let first_index = video_candidates
+        .iter()
+        .position(|(_, frame)| first_audio_capture.is_none_or(|first| frame.pts >= first))
+        .and_then(|aligned| {
+            video_candidates[..=aligned]
+                .iter()
+                .rposition(|(_, frame)| frame.is_keyframe)
+                .or_else(|| {
+                    video_candidates[aligned..]
+                        .iter()
+                        .position(|(_, frame)| frame.is_keyframe)
+                        .map(|offset| aligned + offset)
+                })
+        })
+        .unwrap_or(video_candidates.len());
+    if let Some((dts, frame)) = video_candidates.get(first_index) {
+        log::debug!(
+            "Starting the clip at key frame captured at: {:?}, DTS: {:?} (encoder PTS: {:?}, DTS: {:?}), skipping {first_index} video frames",
+            frame.pts,
+            dts,
+            frame.raw_pts,
+            frame.raw_dts,
+        );
+    }
+    let video_frames = &video_candidates[first_index..];
+    let skipped_video_frames = first_index;
+
+    let Some((_, first_frame)) = video_frames.first() else {
+        return Ok(MuxPlan {
+            skipped_video_frames,
+            skipped_audio_frames: audio_capture_timestamps.len(),
+            ..Default::default()
+        });
+    };
+    let first_pts_offset = first_frame.pts;
+    let newest_video_pts = video_frames.last().map_or(first_pts_offset, |(_, f)| f.pts);
+
+    // Don't write any audio past the newest video (clip to max video), and if audio starts
+    // before video try and catch up as much as possible (At worst a 20ms gap)
+    let audio_frames: Vec<_> = audio_buffer
+        .get_frames()
+        .iter()
+        .zip(audio_capture_timestamps.iter().copied())
+        .take_while(|(_, capture_time)| *capture_time <= newest_video_pts)
+        .filter(|((pts, _), capture_time)| {
+            let skip = *capture_time < first_pts_offset;
+            if skip {
+                log::debug!(
+                    "Skipping Audio Frame due to capture time being: {capture_time:?} while first video pts is: {first_pts_offset:?} pts: {pts:?}"
+                );
+            }
+            !skip
+        })
+        .collect();
+    let skipped_audio_frames = audio_capture_timestamps.len() - audio_frames.len();
+    // Audio starting after the key frame has to start after it in the clip as well
+    let mut audio_clock = AudioClock::starting_at(first_pts_offset);
+    let audio_pts: Vec<_> = audio_frames
+        .iter()
+        .map(|((pts, _), capture_time)| audio_clock.next(**pts, *capture_time))
+        .collect();
+    let audio_clock = (!audio_pts.is_empty()).then_some(audio_clock);
+
+    let mut video = video_frames
+        .iter()
+        .map(|&(&dts, frame)| MuxPacket {
+            stream: MuxStream::Video,
+            data: frame.data.clone(),
+            pts: frame.pts - first_pts_offset,
+            dts: dts - first_pts_offset,
+            capture_time: dts,
+        })
+        .peekable();
+    let mut audio = audio_frames
+        .into_iter()
+        .zip(audio_pts)
+        .map(|(((_, data), capture_time), pts)| MuxPacket {
+            stream: MuxStream::Audio,
+            data: data.clone(),
+            pts,
+            dts: pts,
+            capture_time,
+        })
+        .peekable();
+
+    let mut packets = Vec::with_capacity(video.len() + audio.len());
+    loop {
+        let next = match (video.peek(), audio.peek()) {
+            (Some(v), Some(a)) if a.capture_time < v.capture_time => audio.next(),
+            (Some(_), _) => video.next(),
+            (None, _) => audio.next(),
+        };
+        match next {
+            Some(packet) => packets.push(packet),
+            None => break,
+        }
+    }
+
+    Ok(MuxPlan {
+        packets,
+        skipped_video_frames,
+        skipped_audio_frames,
+        start_time: first_pts_offset,
+        end_time: newest_video_pts,
+        audio_clock,
+        ..Default::default()
+    })
+}
+
+/// The packets of an audio-only clip, every buffered audio frame captured within `window`.
+fn audio_only_packets(audio_buffer: &ShadowCaptureAudioBuffer, window: ClipWindow) -> MuxPlan {
+    let frames: Vec<_> = audio_buffer
+        .get_frames()
+        .iter()
+        .zip(audio_buffer.get_capture_times().iter().copied())
+        .filter(|(_, capture_time)| {
+            window.start.is_none_or(|start| *capture_time >= start)
+                && window.end.is_none_or(|end| *capture_time <= end)
+        })
+        .collect();
+    let skipped_audio_frames = audio_buffer.get_frames().len() - frames.len();
+    let (Some((_, start_time)), Some((_, end_time))) = (frames.first(), frames.last()) else {
+        return MuxPlan {
+            skipped_audio_frames,
+            ..Default::default()
+        };
+    };
+    let (start_time, end_time) = (*start_time, *end_time);
+
+    let mut audio_clock = AudioClock::starting_at(start_time);
+    let packets = frames
+        .into_iter()
+        .map(|((&encoder_pts, data), capture_time)| {
+            let pts = audio_clock.next(encoder_pts, capture_time);
+            MuxPacket {
+                stream: MuxStream::Audio,
+                data: data.clone(),
+                pts,
+                dts: pts,
+                capture_time,
+            }
+        })
+        .collect();
+    MuxPlan {
+        packets,
+        skipped_audio_frames,
+        start_time,
+        end_time,
+        audio_clock: Some(audio_clock),
+        ..Default::default()
+    }
+}
+
+/// Extension of an audio-only clip of `audio`, a container made for its codec.
+pub fn audio_only_extension(audio: &StreamParams) -> &'static str {
+    match audio.parameters.id() {
+        ffmpeg::codec::Id::OPUS => "opus",
+        ffmpeg::codec::Id::AAC => "m4a",
+        _ => "mka",
+    }
+}
+
+/// Moves every packet whose DTS doesn't increase over the previous one of its stream to one tick
+/// after it, shifting its PTS along, since libav refuses to write such packets and would fail the
+/// whole save. Returns how many packets were moved.
+pub fn enforce_increasing_dts(packets: &mut [MuxPacket]) -> usize {
+    let mut last_video = None;
+    let mut last_audio = None;
+    let mut corrections = 0;
+    for packet in packets {
+        let last = match packet.stream {
+            MuxStream::Video => &mut last_video,
+            MuxStream::Audio => &mut last_audio,
+        };
+        if let Some(last_dts) = *last {
+            if packet.dts <= last_dts {
+                let shift = last_dts + 1 - packet.dts;
+                packet.dts += shift;
+                packet.pts += shift;
+                corrections += 1;
+            }
+        }
+        *last = Some(packet.dts);
+    }
+    corrections
+}
+
+/// Builds the audio PTS, starting at 0, for frames given as `(encoder_pts, capture_time)`. See
+/// [`AudioClock`] for how gaps are handled.
+#[cfg(test)]
+pub fn audio_pts_from_capture_times(frames: &[(i64, i64)]) -> Vec<i64> {
+    let mut clock = AudioClock::default();
+    frames
+        .iter()
+        .map(|&(encoder_pts, capture_time)| clock.next(encoder_pts, capture_time))
+        .collect()
+}
+
+/// Turns the encoder PTS and capture times of consecutive audio frames into PTS counted from
+/// the first frame, or from the capture time given to [`AudioClock::starting_at`].
+///
+/// The encoder only counts samples, so every dropped or stalled input buffer would make the
+/// audio run ahead of the video for the rest of the clip. Frames keep the encoder's spacing
+/// unless their capture time is at least a whole frame later than that, in which case the PTS
+/// jumps forward to the capture time and leaves a gap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AudioClock {
+    first_capture: Option<i64>,
+    /// PTS and encoder PTS of the previous frame.
+    previous: Option<(i64, i64)>,
+}
+
+impl AudioClock {
+    /// A clock whose PTS 0 lies at `capture_time` rather than at the first frame.
+    pub fn starting_at(capture_time: i64) -> Self {
+        Self {
+            first_capture: Some(capture_time),
+            previous: None,
+        }
+    }
+
+    /// PTS of the next frame.
+    pub fn next(&mut self, encoder_pts: i64, capture_time: i64) -> i64 {
+        let first_capture = *self.first_capture.get_or_insert(capture_time);
+        let captured = (capture_time - first_capture) * AUDIO_TIME_BASE_HZ / 1_000_000;
+        let pts = match self.previous {
+            None => captured,
+            Some((previous_pts, previous_encoder_pts)) => {
+                let frame_len = encoder_pts - previous_encoder_pts;
+                let expected = previous_pts + frame_len;
+                if captured - expected >= frame_len {
+                    log::debug!(
+                        "Audio gap of {} samples at capture time {capture_time:?}",
+                        captured - expected
+                    );
+                    captured
+                } else {
+                    expected
+                }
+            }
+        };
+        self.previous = Some((pts, encoder_pts));
+        pts
+    }
+}
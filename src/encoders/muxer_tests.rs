@@ -0,0 +1,967 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use ffmpeg_next::{self as ffmpeg, codec::Parameters, Rational};
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+use super::{buffer::*, muxer::*};
+use crate::clips::markers::{Chapter, Marker};
+
+#[test]
+fn test_faststart_for_mp4() {
+    assert_eq!(
+        movflags_for(Path::new("clip_1.mp4"), true),
+        Some("+faststart")
+    );
+    assert_eq!(
+        movflags_for(Path::new("clips/clip_1.MOV"), true),
+        Some("+faststart")
+    );
+}
+
+#[test]
+fn test_faststart_disabled() {
+    assert_eq!(movflags_for(Path::new("clip_1.mp4"), false), None);
+}
+
+#[test]
+fn test_faststart_ignored_for_other_containers() {
+    assert_eq!(movflags_for(Path::new("clip_1.mkv"), true), None);
+    assert_eq!(movflags_for(Path::new("clip_1.webm"), true), None);
+    assert_eq!(movflags_for(Path::new("clip_1"), true), None);
+}
+
+fn video_frame(pts: i64, is_keyframe: bool) -> EncodedVideoFrame {
+    EncodedVideoFrame {
+        data: vec![0],
+        is_keyframe,
+        pts,
+        dts: pts,
+    }
+}
+
+/// 60fps video with a key frame every 30 frames next to 20ms audio frames at 48kHz, both
+/// starting at `start` microseconds.
+fn fill_buffers(
+    start: i64,
+    video_frames: i64,
+    audio_frames: i64,
+) -> (ShadowCaptureVideoBuffer, ShadowCaptureAudioBuffer) {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    for i in 0..video_frames {
+        let pts = start + i * 16_667;
+        video_buffer.insert(pts, video_frame(pts, i % 30 == 0));
+    }
+
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    for i in 0..audio_frames {
+        audio_buffer.insert_capture_time(start + i * 20_000);
+        audio_buffer.insert(i * 960, vec![0]);
+    }
+
+    (video_buffer, audio_buffer)
+}
+
+#[test]
+fn test_interleave_alternates_streams() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+
+    let packets = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0)
+        .unwrap()
+        .packets;
+
+    assert!(packets
+        .windows(2)
+        .all(|pair| pair[0].capture_time <= pair[1].capture_time));
+
+    // Neither stream should ever run more than a couple of packets ahead of the other
+    let longest_run = packets
+        .chunk_by(|a, b| a.stream == b.stream)
+        .map(|run| run.len())
+        .max()
+        .unwrap();
+    assert!(longest_run <= 2, "longest single stream run: {longest_run}");
+}
+
+#[test]
+fn test_interleave_offsets_start_at_zero() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+
+    let packets = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0)
+        .unwrap()
+        .packets;
+
+    let first_video = packets
+        .iter()
+        .find(|p| p.stream == MuxStream::Video)
+        .unwrap();
+    let first_audio = packets
+        .iter()
+        .find(|p| p.stream == MuxStream::Audio)
+        .unwrap();
+    assert_eq!((first_video.pts, first_video.dts), (0, 0));
+    assert_eq!((first_audio.pts, first_audio.dts), (0, 0));
+}
+
+#[test]
+fn test_interleave_cuts_at_last_gop_and_clips_audio() {
+    // Key frames at 0 and 30, so frames after the second key frame are left out
+    let (video_buffer, audio_buffer) = fill_buffers(0, 45, 40);
+
+    let packets = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0)
+        .unwrap()
+        .packets;
+
+    let video: Vec<_> = packets
+        .iter()
+        .filter(|p| p.stream == MuxStream::Video)
+        .collect();
+    assert_eq!(video.len(), 31);
+    let newest_video = video.last().unwrap().capture_time;
+    assert!(packets
+        .iter()
+        .filter(|p| p.stream == MuxStream::Audio)
+        .all(|p| p.capture_time <= newest_video));
+}
+
+/// Capture times of the video packets written for `frames` of `(dts, is_keyframe)` with the
+/// audio starting at `audio_start`.
+fn written_video(frames: &[(i64, bool)], audio_start: i64) -> Vec<i64> {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    for &(dts, is_keyframe) in frames {
+        video_buffer.insert(dts, video_frame(dts, is_keyframe));
+    }
+
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    audio_buffer.insert_capture_time(audio_start);
+    audio_buffer.insert(0, vec![0]);
+
+    interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0)
+        .unwrap()
+        .packets
+        .iter()
+        .filter(|p| p.stream == MuxStream::Video)
+        .map(|p| p.capture_time)
+        .collect()
+}
+
+#[test]
+fn test_interleave_snaps_back_to_key_frame_before_audio() {
+    let frames = [
+        (0, true),
+        (10, false),
+        (20, false),
+        (30, true),
+        (40, false),
+        (50, false),
+        (60, true),
+    ];
+
+    // The whole GOP the audio starts in is written, rather than dropping its first frames
+    assert_eq!(written_video(&frames, 15), vec![0, 10, 20, 30, 40, 50, 60]);
+    assert_eq!(written_video(&frames, 35), vec![30, 40, 50, 60]);
+}
+
+#[test]
+fn test_interleave_snaps_forward_without_key_frame_before_audio() {
+    // The key frame of the first GOP is no longer buffered
+    let frames = [(0, false), (10, false), (20, true), (30, false), (40, true)];
+
+    assert_eq!(written_video(&frames, 5), vec![20, 30, 40]);
+}
+
+#[test]
+fn test_interleave_delays_audio_after_key_frame() {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    video_buffer.insert(0, video_frame(0, true));
+    video_buffer.insert(20_000, video_frame(20_000, false));
+    video_buffer.insert(40_000, video_frame(40_000, true));
+
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    audio_buffer.insert_capture_time(20_000);
+    audio_buffer.insert(0, vec![0]);
+
+    let packets = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0)
+        .unwrap()
+        .packets;
+
+    let first_audio = packets
+        .iter()
+        .find(|p| p.stream == MuxStream::Audio)
+        .unwrap();
+    // 20ms at 48kHz
+    assert_eq!(first_audio.pts, 960);
+}
+
+#[test]
+fn test_interleave_across_encoder_reset() {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    // Both encoders start counting from zero again halfway through
+    for i in 0..121 {
+        let pts = if i < 60 {
+            1_000_000 + i * 16_667
+        } else {
+            (i - 60) * 16_667
+        };
+        video_buffer.insert(pts, video_frame(pts, i % 30 == 0));
+    }
+    for i in 0..100 {
+        audio_buffer.insert_capture_time(1_000_000 + i * 20_000);
+        audio_buffer.insert(i % 50 * 960, vec![0]);
+    }
+
+    let mut packets = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0)
+        .unwrap()
+        .packets;
+
+    let video: Vec<_> = packets
+        .iter()
+        .filter(|p| p.stream == MuxStream::Video)
+        .collect();
+    assert_eq!(video.len(), 121);
+    assert_eq!(video.last().unwrap().pts, 120 * 16_667);
+    assert_eq!(
+        packets
+            .iter()
+            .filter(|p| p.stream == MuxStream::Audio)
+            .count(),
+        100
+    );
+    assert_eq!(enforce_increasing_dts(&mut packets), 0);
+}
+
+fn first_packet(packets: &[MuxPacket], stream: MuxStream) -> &MuxPacket {
+    packets.iter().find(|p| p.stream == stream).unwrap()
+}
+
+#[test]
+fn test_interleave_delays_audio_by_offset() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+
+    let plan =
+        interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 100_000).unwrap();
+
+    // The clip still opens on the first key frame, the audio 100ms after it
+    assert_eq!(first_packet(&plan.packets, MuxStream::Video).pts, 0);
+    let first_audio = first_packet(&plan.packets, MuxStream::Audio);
+    assert_eq!((first_audio.pts, first_audio.dts), (4800, 4800));
+    assert_eq!(first_audio.capture_time, 1_100_000);
+    assert_eq!(plan.skipped_audio_frames, 60 - plan.audio_frames());
+}
+
+#[test]
+fn test_interleave_advances_audio_by_offset() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+
+    let plan = interleave_packets(
+        &video_buffer,
+        &audio_buffer,
+        ClipWindow::default(),
+        -100_000,
+    )
+    .unwrap();
+
+    // The first 100ms of audio now lie before the video and are dropped rather than written with
+    // negative timestamps, the audio captured 100ms in is written with the first frame
+    assert_eq!(plan.start_time, 1_000_000);
+    let first_audio = first_packet(&plan.packets, MuxStream::Audio);
+    assert_eq!((first_audio.pts, first_audio.dts), (0, 0));
+    assert_eq!(first_audio.capture_time, 1_000_000);
+    assert!(plan.packets.iter().all(|p| p.pts >= 0 && p.dts >= 0));
+    assert!(plan.skipped_audio_frames >= 5);
+}
+
+#[test]
+fn test_audio_pts_follow_encoder_without_gaps() {
+    // Capture times jitter a little around the 20ms frame spacing
+    let frames = [(960, 0), (1920, 20_500), (2880, 39_800), (3840, 60_100)];
+
+    assert_eq!(
+        audio_pts_from_capture_times(&frames),
+        vec![0, 960, 1920, 2880]
+    );
+}
+
+#[test]
+fn test_audio_pts_reflect_dropped_buffer() {
+    // The third input buffer never arrived so the encoder kept counting from where it was
+    let frames = [(0, 0), (960, 20_000), (1920, 60_000), (2880, 80_000)];
+
+    assert_eq!(
+        audio_pts_from_capture_times(&frames),
+        vec![0, 960, 2880, 3840]
+    );
+}
+
+#[test]
+fn test_audio_pts_empty() {
+    assert!(audio_pts_from_capture_times(&[]).is_empty());
+}
+
+#[test]
+fn test_mux_plan_counters() {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    video_buffer.insert(0, video_frame(0, true));
+    video_buffer.insert(10, video_frame(10, false));
+    video_buffer.insert(20, video_frame(20, false));
+    video_buffer.insert(30, video_frame(30, true));
+    // Past the last key frame so it is cut rather than skipped
+    video_buffer.insert(40, video_frame(40, false));
+
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    for (i, capture_time) in [15, 25, 35].into_iter().enumerate() {
+        audio_buffer.insert_capture_time(capture_time);
+        audio_buffer.insert(i as i64 * 960, vec![0]);
+    }
+
+    let plan = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0).unwrap();
+
+    // The audio starts within the first GOP, so all of it is written
+    assert_eq!(plan.video_frames(), 4);
+    assert_eq!(plan.skipped_video_frames, 0);
+    // The audio captured after the last written video frame is left out
+    assert_eq!(plan.audio_frames(), 2);
+    assert_eq!(plan.skipped_audio_frames, 1);
+    assert_eq!(plan.clip_duration_micros(), 30);
+}
+
+#[test]
+fn test_mux_plan_frame_rate() {
+    let (video_buffer, audio_buffer) = fill_buffers(0, 61, 0);
+    let plan = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0).unwrap();
+    // 60 frames 16.667ms apart
+    assert_eq!(plan.frame_rate(), Some(Rational::new(59_998, 1000)));
+
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    video_buffer.insert(0, video_frame(0, true));
+    let plan = interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0).unwrap();
+    assert_eq!(plan.frame_rate(), None);
+}
+
+/// Collects what the muxer writes instead of producing a file.
+#[derive(Default)]
+struct MemorySink {
+    streams: Vec<Rational>,
+    chapters: Vec<Chapter>,
+    metadata: Vec<(String, String)>,
+    header_written: bool,
+    trailer_written: bool,
+    packets: Vec<(usize, i64, i64)>,
+}
+
+impl PacketSink for MemorySink {
+    fn add_stream(&mut self, params: &StreamParams) -> anyhow::Result<usize> {
+        self.streams.push(params.time_base);
+        Ok(self.streams.len() - 1)
+    }
+
+    fn add_chapter(&mut self, chapter: &Chapter) -> anyhow::Result<()> {
+        assert!(!self.header_written);
+        self.chapters.push(chapter.clone());
+        Ok(())
+    }
+
+    fn add_metadata(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        assert!(!self.header_written);
+        self.metadata.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        self.header_written = true;
+        Ok(())
+    }
+
+    fn write_packet(&mut self, stream: usize, packet: &MuxPacket) -> anyhow::Result<()> {
+        assert!(self.header_written && !self.trailer_written);
+        self.packets.push((stream, packet.pts, packet.dts));
+        Ok(())
+    }
+
+    fn write_trailer(&mut self) -> anyhow::Result<()> {
+        self.trailer_written = true;
+        Ok(())
+    }
+}
+
+impl MemorySink {
+    fn stream_packets(&self, stream: usize) -> Vec<(i64, i64)> {
+        self.packets
+            .iter()
+            .filter(|(s, _, _)| *s == stream)
+            .map(|(_, pts, dts)| (*pts, *dts))
+            .collect()
+    }
+}
+
+fn clip_muxer() -> ClipMuxer {
+    let params = |time_base| StreamParams {
+        codec: None,
+        parameters: Parameters::new(),
+        time_base,
+        frame_rate: None,
+    };
+    ClipMuxer::new(
+        params(Rational::new(1, 1_000_000)),
+        Some(params(Rational::new(1, 48_000))),
+    )
+}
+
+#[test]
+fn test_muxer_audio_starts_before_video() {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    for pts in [100_000, 116_667, 133_334] {
+        video_buffer.insert(pts, video_frame(pts, pts == 100_000 || pts == 133_334));
+    }
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    for (i, capture_time) in [60_000, 80_000, 100_000, 120_000].into_iter().enumerate() {
+        audio_buffer.insert_capture_time(capture_time);
+        audio_buffer.insert(i as i64 * 960, vec![0]);
+    }
+
+    let mut sink = MemorySink::default();
+    clip_muxer()
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap();
+
+    assert_eq!(sink.streams.len(), 2);
+    assert!(sink.trailer_written);
+    // Audio captured before the first video frame is dropped and the rest starts at 0
+    assert_eq!(sink.stream_packets(1), vec![(0, 0), (960, 960)]);
+    assert_eq!(
+        sink.stream_packets(0),
+        vec![(0, 0), (16_667, 16_667), (33_334, 33_334)]
+    );
+}
+
+#[test]
+fn test_muxer_video_starts_before_audio() {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    video_buffer.insert(0, video_frame(0, false));
+    video_buffer.insert(16_667, video_frame(16_667, false));
+    video_buffer.insert(33_334, video_frame(33_334, true));
+    video_buffer.insert(50_001, video_frame(50_001, false));
+    video_buffer.insert(66_668, video_frame(66_668, true));
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    for (i, capture_time) in [30_000, 50_000].into_iter().enumerate() {
+        audio_buffer.insert_capture_time(capture_time);
+        audio_buffer.insert(i as i64 * 960, vec![0]);
+    }
+
+    let mut sink = MemorySink::default();
+    clip_muxer()
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap();
+
+    // Delta frames from before the audio started are dropped so the clip opens on a key frame
+    assert_eq!(
+        sink.stream_packets(0),
+        vec![(0, 0), (16_667, 16_667), (33_334, 33_334)]
+    );
+    // The first key frame comes after the first audio frame, which gets dropped in turn, and the
+    // next one keeps its 16.7ms distance to the key frame
+    assert_eq!(sink.stream_packets(1), vec![(799, 799)]);
+}
+
+#[test]
+fn test_muxer_clips_to_last_keyframe() {
+    let (video_buffer, audio_buffer) = fill_buffers(0, 45, 40);
+
+    let mut sink = MemorySink::default();
+    let plan = clip_muxer()
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap();
+
+    // Key frames at frame 0 and 30, the unfinished GOP after the second one is left out
+    let video = sink.stream_packets(0);
+    assert_eq!(video.len(), 31);
+    assert_eq!(video.last().unwrap().1, 30 * 16_667);
+    assert_eq!(sink.stream_packets(1).len(), plan.audio_frames());
+    assert!(plan.audio_frames() <= 26);
+}
+
+#[test]
+fn test_muxer_video_only_starts_at_key_frame() {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    video_buffer.insert(0, video_frame(0, false));
+    video_buffer.insert(16_667, video_frame(16_667, false));
+    video_buffer.insert(33_334, video_frame(33_334, true));
+    video_buffer.insert(50_001, video_frame(50_001, false));
+    video_buffer.insert(66_668, video_frame(66_668, true));
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    for (i, capture_time) in [60_000, 80_000].into_iter().enumerate() {
+        audio_buffer.insert_capture_time(capture_time);
+        audio_buffer.insert(i as i64 * 960, vec![0]);
+    }
+
+    let mut sink = MemorySink::default();
+    let plan = clip_muxer()
+        .with_window(ClipWindow {
+            streams: ClipStreams::VideoOnly,
+            ..Default::default()
+        })
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap();
+
+    // The audio starting late doesn't hold the video back
+    assert_eq!(sink.streams.len(), 1);
+    assert_eq!(
+        sink.stream_packets(0),
+        vec![(0, 0), (16_667, 16_667), (33_334, 33_334)]
+    );
+    assert_eq!(plan.audio_frames(), 0);
+    assert_eq!(plan.skipped_audio_frames, 0);
+}
+
+#[test]
+fn test_muxer_audio_only() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+
+    let mut sink = MemorySink::default();
+    let plan = clip_muxer()
+        .with_window(ClipWindow {
+            start: Some(1_500_000),
+            streams: ClipStreams::AudioOnly,
+            ..Default::default()
+        })
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap();
+
+    // Only the audio stream is added, so its packets go to stream 0
+    assert_eq!(sink.streams, vec![Rational::new(1, 48_000)]);
+    let audio = sink.stream_packets(0);
+    assert_eq!(audio.len(), 35);
+    assert_eq!(audio[0], (0, 0));
+    assert_eq!(audio[1], (960, 960));
+    assert_eq!(plan.video_frames(), 0);
+    assert_eq!(plan.clip_duration_micros(), 34 * 20_000);
+}
+
+#[test]
+fn test_muxer_audio_only_without_audio_stream() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+    let muxer = ClipMuxer::new(
+        StreamParams {
+            codec: None,
+            parameters: Parameters::new(),
+            time_base: Rational::new(1, 1_000_000),
+            frame_rate: None,
+        },
+        None,
+    )
+    .with_window(ClipWindow {
+        streams: ClipStreams::AudioOnly,
+        ..Default::default()
+    });
+
+    let mut sink = MemorySink::default();
+    assert!(muxer
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .is_err());
+    assert!(!sink.header_written);
+}
+
+#[test]
+fn test_muxer_writes_markers_as_chapters() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+    let marker = |id, timestamp| Marker {
+        id,
+        timestamp,
+        label: format!("marker {id}"),
+        automatic: false,
+    };
+    // Before the clip, inside it twice and after the last written frame
+    let markers = [
+        marker(0, 500_000),
+        marker(1, 1_200_000),
+        marker(2, 1_500_000),
+        marker(3, 5_000_000),
+    ];
+
+    let mut sink = MemorySink::default();
+    let plan = clip_muxer()
+        .mux(&video_buffer, &audio_buffer, &markers, &mut sink)
+        .unwrap();
+
+    let clip_end = plan.end_time - plan.start_time;
+    assert_eq!(
+        sink.chapters,
+        vec![
+            Chapter {
+                id: 1,
+                title: "marker 1".to_string(),
+                start: 200_000,
+                end: 500_000,
+            },
+            Chapter {
+                id: 2,
+                title: "marker 2".to_string(),
+                start: 500_000,
+                end: clip_end,
+            },
+        ]
+    );
+    assert_eq!(plan.chapters, sink.chapters);
+}
+
+#[test]
+fn test_interleave_window_snaps_to_key_frame() {
+    // Key frames every 30 frames, i.e. at 0, 500_010 and 1_000_020
+    let (video_buffer, audio_buffer) = fill_buffers(0, 61, 60);
+    let window = ClipWindow {
+        start: Some(600_000),
+        end: Some(800_000),
+        ..Default::default()
+    };
+
+    let plan = interleave_packets(&video_buffer, &audio_buffer, window, 0).unwrap();
+
+    assert_eq!(plan.start_time, 30 * 16_667);
+    assert!(plan.end_time <= 800_000);
+    assert_eq!(plan.end_time, 47 * 16_667);
+    assert!(plan
+        .packets
+        .iter()
+        .filter(|p| p.stream == MuxStream::Audio)
+        .all(|p| (plan.start_time..=plan.end_time).contains(&p.capture_time)));
+}
+
+#[test]
+fn test_interleave_window_before_buffer_start() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 31, 30);
+    let window = ClipWindow {
+        start: Some(0),
+        ..Default::default()
+    };
+
+    let plan = interleave_packets(&video_buffer, &audio_buffer, window, 0).unwrap();
+
+    // Nothing older is left so the clip starts at the oldest frame
+    assert_eq!(plan.start_time, 1_000_000);
+    assert_eq!(plan.video_frames(), 31);
+}
+
+/// Sets `cancel` once `after` packets were written, as if `CancelSave` arrived mid save.
+struct CancellingSink {
+    inner: MemorySink,
+    cancel: Arc<AtomicBool>,
+    after: usize,
+}
+
+impl PacketSink for CancellingSink {
+    fn add_stream(&mut self, params: &StreamParams) -> anyhow::Result<usize> {
+        self.inner.add_stream(params)
+    }
+
+    fn add_chapter(&mut self, chapter: &Chapter) -> anyhow::Result<()> {
+        self.inner.add_chapter(chapter)
+    }
+
+    fn add_metadata(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.inner.add_metadata(key, value)
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        self.inner.write_header()
+    }
+
+    fn write_packet(&mut self, stream: usize, packet: &MuxPacket) -> anyhow::Result<()> {
+        self.inner.write_packet(stream, packet)?;
+        if self.inner.packets.len() == self.after {
+            self.cancel.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn write_trailer(&mut self) -> anyhow::Result<()> {
+        self.inner.write_trailer()
+    }
+}
+
+#[test]
+fn test_muxer_stops_when_cancelled() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut sink = CancellingSink {
+        inner: MemorySink::default(),
+        cancel: Arc::clone(&cancel),
+        after: 10,
+    };
+
+    let err = clip_muxer()
+        .with_cancel(Arc::clone(&cancel))
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap_err();
+
+    assert!(err.is::<SaveCancelled>());
+    assert_eq!(sink.inner.packets.len(), 10);
+    assert!(!sink.inner.trailer_written);
+}
+
+/// Capture times of the `stream` packets of `plan`.
+fn capture_times(plan: &MuxPlan, stream: MuxStream) -> Vec<i64> {
+    plan.packets
+        .iter()
+        .filter(|p| p.stream == stream)
+        .map(|p| p.capture_time)
+        .collect()
+}
+
+#[test]
+fn test_no_frames_lost_across_a_slow_save() {
+    // Same layout as fill_buffers, carried on while the first clip is muxed
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    let insert_video = |buffer: &mut ShadowCaptureVideoBuffer, frames: std::ops::Range<i64>| {
+        for i in frames {
+            let pts = i * 16_667;
+            buffer.insert(pts, video_frame(pts, i % 30 == 0));
+        }
+    };
+    let insert_audio = |buffer: &mut ShadowCaptureAudioBuffer, frames: std::ops::Range<i64>| {
+        for i in frames {
+            buffer.insert_capture_time(i * 20_000);
+            buffer.insert(i * 960, vec![0]);
+        }
+    };
+    insert_video(&mut video_buffer, 0..75);
+    insert_audio(&mut audio_buffer, 0..62);
+
+    let taken_video = video_buffer.take();
+    let kept_from = video_buffer.oldest_pts().unwrap();
+    let taken_audio = audio_buffer.take(kept_from);
+    // Frames keep arriving while the taken ones are saved
+    insert_video(&mut video_buffer, 75..121);
+    insert_audio(&mut audio_buffer, 62..101);
+    let first = interleave_packets(&taken_video, &taken_audio, ClipWindow::default(), 0).unwrap();
+
+    let second =
+        interleave_packets(&video_buffer, &audio_buffer, ClipWindow::default(), 0).unwrap();
+    // The second clip starts on the key frame the first one ended on
+    assert_eq!(second.start_time, first.end_time);
+    let mut video = capture_times(&first, MuxStream::Video);
+    video.pop();
+    video.extend(capture_times(&second, MuxStream::Video));
+    assert_eq!(video, (0..=120).map(|i| i * 16_667).collect::<Vec<_>>());
+    let mut audio = capture_times(&first, MuxStream::Audio);
+    audio.extend(capture_times(&second, MuxStream::Audio));
+    // Up to the newest video frame of the second clip
+    assert_eq!(audio, (0..=100).map(|i| i * 20_000).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_buffers_survive_a_cancelled_save() {
+    let (video_buffer, audio_buffer) = fill_buffers(1_000_000, 61, 60);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut cancelled = CancellingSink {
+        inner: MemorySink::default(),
+        cancel: Arc::clone(&cancel),
+        after: 1,
+    };
+    assert!(clip_muxer()
+        .with_cancel(Arc::clone(&cancel))
+        .mux(&video_buffer, &audio_buffer, &[], &mut cancelled)
+        .is_err());
+
+    assert_eq!(video_buffer.get_frames().len(), 61);
+    assert_eq!(audio_buffer.get_frames().len(), 60);
+    assert_eq!(audio_buffer.get_capture_times().len(), 60);
+
+    // Saving again writes the same clip as if the first save had never happened
+    let (fresh_video, fresh_audio) = fill_buffers(1_000_000, 61, 60);
+    let mut expected = MemorySink::default();
+    clip_muxer()
+        .mux(&fresh_video, &fresh_audio, &[], &mut expected)
+        .unwrap();
+    cancel.store(false, Ordering::Release);
+    let mut retried = MemorySink::default();
+    clip_muxer()
+        .with_cancel(cancel)
+        .mux(&video_buffer, &audio_buffer, &[], &mut retried)
+        .unwrap();
+
+    assert!(retried.trailer_written);
+    assert_eq!(retried.packets, expected.packets);
+}
+
+#[test]
+fn test_muxer_tags_are_dated_by_the_first_frame() {
+    // Audio starts 100ms before the video so the clip starts at the first video frame
+    let (video_buffer, _) = fill_buffers(1_100_000, 61, 0);
+    let (_, audio_buffer) = fill_buffers(1_000_000, 0, 60);
+    let tags = ClipTags::new(
+        Path::new("clips/clip_1700000000.mp4"),
+        Some(1_700_000_000_000),
+    );
+
+    let mut sink = MemorySink::default();
+    let plan = clip_muxer()
+        .with_tags(tags)
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap();
+
+    assert_eq!(plan.start_time, 1_100_000);
+    let tag = |key: &str| {
+        sink.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    };
+    assert_eq!(tag("title"), Some("clip_1700000000"));
+    assert_eq!(tag("creation_time"), Some("2023-11-14T22:13:21.100000Z"));
+    assert_eq!(
+        tag("comment"),
+        Some(concat!("Recorded with WayCap ", env!("CARGO_PKG_VERSION")))
+    );
+
+    // Nothing to date the clip by without the wall clock
+    let undated = ClipTags::new(Path::new("clip_1.mp4"), None).entries(0);
+    assert!(undated.iter().all(|(key, _)| *key != "creation_time"));
+}
+
+/// Stream parameters of a small PNG encoder, which libav always comes with, streaming in
+/// `time_base`.
+fn png_stream_params(time_base: Rational) -> StreamParams {
+    ffmpeg::init().unwrap();
+    let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::PNG).unwrap();
+    let mut video = ffmpeg::codec::Context::new_with_codec(codec)
+        .encoder()
+        .video()
+        .unwrap();
+    video.set_width(16);
+    video.set_height(16);
+    video.set_format(ffmpeg::format::Pixel::RGB24);
+    video.set_time_base(Rational::new(1, 30));
+    let encoder = video.open_as(codec).unwrap();
+    StreamParams {
+        codec: Some(codec),
+        parameters: (&encoder).into(),
+        time_base,
+        frame_rate: None,
+    }
+}
+
+#[test]
+fn test_tags_read_back_from_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("clip_1.mkv");
+    let tags = ClipTags::new(&path, Some(1_700_000_000_000));
+    let mut sink = FileSink::create(&path, false).unwrap();
+    sink.add_stream(&png_stream_params(Rational::new(1, 30)))
+        .unwrap();
+    for (key, value) in tags.entries(0) {
+        sink.add_metadata(key, &value).unwrap();
+    }
+    sink.write_header().unwrap();
+    sink.write_trailer().unwrap();
+    drop(sink);
+
+    let input = ffmpeg::format::input(&path).unwrap();
+    let metadata = input.metadata();
+    assert_eq!(metadata.get("title"), Some(tags.title.as_str()));
+    assert_eq!(
+        metadata.get("creation_time"),
+        Some("2023-11-14T22:13:20.000000Z")
+    );
+    assert!(metadata
+        .get("comment")
+        .is_some_and(|comment| comment.starts_with("Recorded with WayCap")));
+}
+
+/// Types of the top level boxes of the MP4 at `path`, in the order they are in the file.
+fn top_level_boxes(path: &Path) -> Vec<String> {
+    let file = std::fs::read(path).unwrap();
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= file.len() {
+        let size = u32::from_be_bytes(file[offset..offset + 4].try_into().unwrap()) as usize;
+        boxes.push(String::from_utf8_lossy(&file[offset + 4..offset + 8]).into_owned());
+        offset += match size {
+            // The box runs to the end of the file
+            0 => break,
+            // A 64 bit size follows the type
+            1 => u64::from_be_bytes(file[offset + 8..offset + 16].try_into().unwrap()) as usize,
+            size => size,
+        };
+    }
+    boxes
+}
+
+/// Saves a second of video to an MP4 through [`FileSink`], returning its top level boxes.
+fn mux_mp4(faststart: bool) -> Vec<String> {
+    let (video_buffer, audio_buffer) = fill_buffers(0, 60, 0);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("clip_1.mp4");
+    let mut sink = FileSink::create(&path, faststart).unwrap();
+    ClipMuxer::new(png_stream_params(Rational::new(1, 1_000_000)), None)
+        .mux(&video_buffer, &audio_buffer, &[], &mut sink)
+        .unwrap();
+    drop(sink);
+
+    top_level_boxes(&path)
+}
+
+fn position(boxes: &[String], kind: &str) -> usize {
+    boxes
+        .iter()
+        .position(|found| found == kind)
+        .unwrap_or_else(|| panic!("No {kind} box in {boxes:?}"))
+}
+
+#[test]
+fn test_faststart_writes_moov_before_mdat() {
+    let boxes = mux_mp4(true);
+    assert!(
+        position(&boxes, "moov") < position(&boxes, "mdat"),
+        "{boxes:?}"
+    );
+
+    // Without it the moov box is only written with the trailer
+    let boxes = mux_mp4(false);
+    assert!(
+        position(&boxes, "moov") > position(&boxes, "mdat"),
+        "{boxes:?}"
+    );
+}
+
+fn packet(stream: MuxStream, dts: i64) -> MuxPacket {
+    MuxPacket {
+        stream,
+        data: Default::default(),
+        pts: dts + 2,
+        dts,
+        capture_time: dts,
+    }
+}
+
+#[test]
+fn test_enforce_increasing_dts_moves_repeats_forward() {
+    let mut packets = vec![
+        packet(MuxStream::Video, 0),
+        packet(MuxStream::Audio, 0),
+        packet(MuxStream::Video, 5),
+        packet(MuxStream::Video, 5),
+        packet(MuxStream::Audio, 10),
+        packet(MuxStream::Video, 3),
+    ];
+
+    assert_eq!(enforce_increasing_dts(&mut packets), 2);
+    let timestamps: Vec<_> = packets.iter().map(|p| (p.dts, p.pts)).collect();
+    assert_eq!(
+        timestamps,
+        vec![(0, 2), (0, 2), (5, 7), (6, 8), (10, 12), (7, 9)]
+    );
+}
+
+#[test]
+fn test_enforce_increasing_dts_leaves_ordered_packets() {
+    let mut packets = vec![packet(MuxStream::Video, 0), packet(MuxStream::Video, 1)];
+    assert_eq!(enforce_increasing_dts(&mut packets), 0);
+    assert_eq!(packets[1].dts, 1);
+}
@@ -0,0 +1,414 @@
+use anyhow::Result;
+
+use ffmpeg_next::Rational;
+
+use super::{
+    buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer},
+    muxer::{
+        interleave_packets, AudioClock, ClipWindow, MuxPacket, MuxStream, PacketSink, StreamParams,
+    },
+    streaming::{LivePacket, LiveStream},
+};
+
+/// How long past the segment length a recording waits for a key frame before asking for one.
+const KEY_FRAME_GRACE_MICROS: i64 = 5_000_000;
+
+/// One output file of a recording. Timestamps restart at 0 in every segment so each file plays
+/// on its own.
+struct Segment<S> {
+    sink: S,
+    video: usize,
+    audio: Option<usize>,
+    /// Capture time of the key frame the segment starts with.
+    start: i64,
+}
+
+/// Writes live packets to a series of outputs, starting a new one at the first key frame after
+/// every `segment_micros` of capture time.
+///
+/// Audio reaches the recorder slightly out of step with video, so when a segment is cut the
+/// previous one stays open until the first audio captured after the cut arrives. Audio
+/// belonging to the end of a segment lands in it instead of being dropped.
+pub struct SegmentedRecorder<S, F> {
+    video: StreamParams,
+    audio: Option<StreamParams>,
+    segment_micros: Option<i64>,
+    open: F,
+    next_sequence: u32,
+    current: Option<Segment<S>>,
+    closing: Option<Segment<S>>,
+}
+
+impl<S, F> SegmentedRecorder<S, F>
+where
+    S: PacketSink,
+    F: FnMut(u32) -> Result<S>,
+{
+    /// `open` creates the output for the given segment number, counting from 1. A
+    /// `segment_minutes` of 0 records everything into a single output.
+    pub fn new(
+        video: StreamParams,
+        audio: Option<StreamParams>,
+        segment_minutes: u32,
+        open: F,
+    ) -> Self {
+        Self {
+            video,
+            audio,
+            segment_micros: (segment_minutes > 0).then(|| segment_minutes as i64 * 60_000_000),
+            open,
+            next_sequence: 1,
+            current: None,
+            closing: None,
+        }
+    }
+
+    pub fn push(&mut self, packet: &LivePacket) -> Result<()> {
+        match packet.stream {
+            LiveStream::Video => self.push_video(packet),
+            LiveStream::Audio => self.push_audio(packet),
+        }
+    }
+
+    /// Whether the current segment is well past its length without a key frame to cut at.
+    pub fn key_frame_overdue(&self, capture_time: i64) -> bool {
+        match (&self.current, self.segment_micros) {
+            (Some(segment), Some(length)) => {
+                capture_time - segment.start >= length + KEY_FRAME_GRACE_MICROS
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the trailers of every open segment.
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(segment) = self.closing.take() {
+            Self::close(segment)?;
+        }
+        if let Some(segment) = self.current.take() {
+            Self::close(segment)?;
+        }
+        Ok(())
+    }
+
+    fn push_video(&mut self, packet: &LivePacket) -> Result<()> {
+        let cut = match &self.current {
+            // Nothing to decode the frame against until the first key frame
+            None if !packet.is_keyframe => return Ok(()),
+            None => true,
+            Some(segment) => {
+                packet.is_keyframe
+                    && self
+                        .segment_micros
+                        .is_some_and(|length| packet.capture_time - segment.start >= length)
+            }
+        };
+
+        if cut {
+            let segment = self.open_segment(packet.capture_time)?;
+            if let Some(previous) = self.current.replace(segment) {
+                if let Some(older) = self.closing.replace(previous) {
+                    Self::close(older)?;
+                }
+                if self.audio.is_none() {
+                    self.close_previous()?;
+                }
+            }
+        }
+
+        let segment = self.current.as_mut().expect("a segment was just opened");
+        Self::write(segment, self.video.time_base, segment.video, packet)
+    }
+
+    fn push_audio(&mut self, packet: &LivePacket) -> Result<()> {
+        let Some(time_base) = self.audio.as_ref().map(|audio| audio.time_base) else {
+            return Ok(());
+        };
+
+        let boundary = self.current.as_ref().map(|segment| segment.start);
+        if let Some(closing) = self.closing.as_mut() {
+            if boundary.is_some_and(|boundary| packet.capture_time < boundary) {
+                if let Some(stream) = closing.audio {
+                    return Self::write(closing, time_base, stream, packet);
+                }
+            }
+            self.close_previous()?;
+        }
+
+        match self.current.as_mut() {
+            Some(segment) => match segment.audio {
+                Some(stream) => Self::write(segment, time_base, stream, packet),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn open_segment(&mut self, start: i64) -> Result<Segment<S>> {
+        let mut sink = (self.open)(self.next_sequence)?;
+        self.next_sequence += 1;
+
+        let video = sink.add_stream(&self.video)?;
+        let audio = match &self.audio {
+            Some(params) => Some(sink.add_stream(params)?),
+            None => None,
+        };
+        sink.write_header()?;
+        Ok(Segment {
+            sink,
+            video,
+            audio,
+            start,
+        })
+    }
+
+    fn close_previous(&mut self) -> Result<()> {
+        match self.closing.take() {
+            Some(segment) => Self::close(segment),
+            None => Ok(()),
+        }
+    }
+
+    fn close(mut segment: Segment<S>) -> Result<()> {
+        segment.sink.write_trailer()
+    }
+
+    fn write(
+        segment: &mut Segment<S>,
+        time_base: Rational,
+        stream: usize,
+        packet: &LivePacket,
+    ) -> Result<()> {
+        let Some((pts, dts)) = packet.timestamps_since(segment.start, time_base) else {
+            return Ok(());
+        };
+        let mux_packet = MuxPacket {
+            stream: match packet.stream {
+                LiveStream::Video => MuxStream::Video,
+                LiveStream::Audio => MuxStream::Audio,
+            },
+            data: packet.data.clone(),
+            pts,
+            dts,
+            capture_time: packet.capture_time,
+        };
+        segment.sink.write_packet(stream, &mux_packet)
+    }
+}
+
+/// A single recording which starts with whatever the shadow buffers hold and carries on with
+/// live packets.
+///
+/// The buffered part is muxed exactly like a saved clip. Live video keeps counting from the
+/// first buffered frame and live audio continues the buffered audio's [`AudioClock`], so
+/// neither stream jumps at the boundary. Packets the buffers already covered are skipped, which
+/// lets the caller hand over the buffers and the live packets without worrying about overlap.
+pub struct PrerollRecorder<S> {
+    sink: S,
+    video_stream: usize,
+    video_time_base: Rational,
+    audio_stream: Option<usize>,
+    /// Capture time the video timestamps count from, the first written key frame.
+    start: Option<i64>,
+    audio_clock: Option<AudioClock>,
+    last_video_dts: Option<i64>,
+    last_audio_capture: Option<i64>,
+}
+
+impl<S: PacketSink> PrerollRecorder<S> {
+    /// Writes the header and the buffered footage to `sink`. Empty buffers start the recording
+    /// at the first live key frame instead.
+    pub fn start(
+        video: &StreamParams,
+        audio: Option<&StreamParams>,
+        video_buffer: &ShadowCaptureVideoBuffer,
+        audio_buffer: &ShadowCaptureAudioBuffer,
+        mut sink: S,
+    ) -> Result<Self> {
+        let video_stream = sink.add_stream(video)?;
+        let audio_stream = match audio {
+            Some(params) => Some(sink.add_stream(params)?),
+            None => None,
+        };
+        sink.write_header()?;
+
+        let mut recorder = Self {
+            sink,
+            video_stream,
+            video_time_base: video.time_base,
+            audio_stream,
+            start: None,
+            audio_clock: None,
+            last_video_dts: None,
+            last_audio_capture: None,
+        };
+        if video_buffer.get_last_gop_start().is_none() {
+            return Ok(recorder);
+        }
+
+        let plan = interleave_packets(video_buffer, audio_buffer, ClipWindow::default(), 0)?;
+        for packet in &plan.packets {
+            match packet.stream {
+                MuxStream::Video => {
+                    recorder.start.get_or_insert(plan.start_time);
+                    recorder.last_video_dts = Some(packet.capture_time);
+                    recorder.sink.write_packet(video_stream, packet)?;
+                }
+                MuxStream::Audio => {
+                    if let Some(stream) = audio_stream {
+                        recorder.last_audio_capture = Some(packet.capture_time);
+                        recorder.sink.write_packet(stream, packet)?;
+                    }
+                }
+            }
+        }
+        recorder.audio_clock = plan.audio_clock;
+
+        // The plan stops at the newest key frame, the rest of the buffer goes out like live
+        // packets with everything already written being skipped
+        for (&dts, frame) in video_buffer.get_frames() {
+            recorder.push(&LivePacket {
+                stream: LiveStream::Video,
+                data: frame.data.clone(),
+                is_keyframe: frame.is_keyframe,
+                pts: frame.pts,
+                dts,
+                capture_time: dts,
+            })?;
+        }
+        let audio_frames = audio_buffer
+            .get_frames()
+            .iter()
+            .zip(audio_buffer.get_capture_times());
+        for ((&pts, data), &capture_time) in audio_frames {
+            recorder.push(&LivePacket {
+                stream: LiveStream::Audio,
+                data: data.clone(),
+                is_keyframe: true,
+                pts,
+                dts: pts,
+                capture_time,
+            })?;
+        }
+        Ok(recorder)
+    }
+
+    pub fn push(&mut self, packet: &LivePacket) -> Result<()> {
+        match packet.stream {
+            LiveStream::Video => self.push_video(packet),
+            LiveStream::Audio => self.push_audio(packet),
+        }
+    }
+
+    /// Writes the trailer.
+    pub fn finish(mut self) -> Result<()> {
+        self.sink.write_trailer()
+    }
+
+    fn push_video(&mut self, packet: &LivePacket) -> Result<()> {
+        if self.last_video_dts.is_some_and(|last| packet.dts <= last) {
+            return Ok(());
+        }
+        let start = match self.start {
+            Some(start) => start,
+            // Nothing to decode the frame against until the first key frame
+            None if !packet.is_keyframe => return Ok(()),
+            None => *self.start.insert(packet.capture_time),
+        };
+        let Some((pts, dts)) = packet.timestamps_since(start, self.video_time_base) else {
+            return Ok(());
+        };
+
+        self.last_video_dts = Some(packet.dts);
+        self.sink.write_packet(
+            self.video_stream,
+            &MuxPacket {
+                stream: MuxStream::Video,
+                data: packet.data.clone(),
+                pts,
+                dts,
+                capture_time: packet.capture_time,
+            },
+        )
+    }
+
+    fn push_audio(&mut self, packet: &LivePacket) -> Result<()> {
+        let (Some(stream), Some(start)) = (self.audio_stream, self.start) else {
+            return Ok(());
+        };
+        if packet.capture_time < start
+            || self
+                .last_audio_capture
+                .is_some_and(|last| packet.capture_time <= last)
+        {
+            return Ok(());
+        }
+        let pts = self
+            .audio_clock
+            .get_or_insert_with(|| AudioClock::starting_at(start))
+            .next(packet.pts, packet.capture_time);
+
+        self.last_audio_capture = Some(packet.capture_time);
+        self.sink.write_packet(
+            stream,
+            &MuxPacket {
+                stream: MuxStream::Audio,
+                data: packet.data.clone(),
+                pts,
+                dts: pts,
+                capture_time: packet.capture_time,
+            },
+        )
+    }
+}
+
+/// A recording of the audio alone, written to a single audio-only output. Packets are stamped
+/// like the audio of saved clips by an [`AudioClock`] starting at the first one, video packets
+/// are ignored.
+pub struct AudioRecorder<S> {
+    sink: S,
+    stream: usize,
+    clock: AudioClock,
+    last_capture: Option<i64>,
+}
+
+impl<S: PacketSink> AudioRecorder<S> {
+    /// Writes the header of a file holding only `audio` to `sink`.
+    pub fn start(audio: &StreamParams, mut sink: S) -> Result<Self> {
+        let stream = sink.add_stream(audio)?;
+        sink.write_header()?;
+        Ok(Self {
+            sink,
+            stream,
+            clock: AudioClock::default(),
+            last_capture: None,
+        })
+    }
+
+    pub fn push(&mut self, packet: &LivePacket) -> Result<()> {
+        if packet.stream != LiveStream::Audio
+            || self
+                .last_capture
+                .is_some_and(|last| packet.capture_time <= last)
+        {
+            return Ok(());
+        }
+        self.last_capture = Some(packet.capture_time);
+        let pts = self.clock.next(packet.pts, packet.capture_time);
+        self.sink.write_packet(
+            self.stream,
+            &MuxPacket {
+                stream: MuxStream::Audio,
+                data: packet.data.clone(),
+                pts,
+                dts: pts,
+                capture_time: packet.capture_time,
+            },
+        )
+    }
+
+    /// Writes the trailer.
+    pub fn finish(mut self) -> Result<()> {
+        self.sink.write_trailer()
+    }
+}
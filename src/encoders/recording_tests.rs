@@ -0,0 +1,372 @@
+use std::{cell::RefCell, rc::Rc};
+
+use bytes::Bytes;
+use ffmpeg_next::{codec::Parameters, Rational};
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+use super::{buffer::*, muxer::*, recording::*, streaming::*};
+use crate::clips::markers::Chapter;
+
+const VIDEO_STREAM: usize = 0;
+const AUDIO_STREAM: usize = 1;
+
+#[derive(Default)]
+struct Recorded {
+    packets: Vec<(usize, i64, i64)>,
+    trailer_written: bool,
+}
+
+impl Recorded {
+    fn stream_pts(&self, stream: usize) -> Vec<i64> {
+        self.packets
+            .iter()
+            .filter(|(s, _, _)| *s == stream)
+            .map(|(_, pts, _)| *pts)
+            .collect()
+    }
+}
+
+/// Sink whose contents outlive the recorder so the tests can look at every segment.
+struct SharedSink {
+    recorded: Rc<RefCell<Recorded>>,
+    streams: usize,
+}
+
+impl PacketSink for SharedSink {
+    fn add_stream(&mut self, _params: &StreamParams) -> anyhow::Result<usize> {
+        self.streams += 1;
+        Ok(self.streams - 1)
+    }
+
+    fn add_chapter(&mut self, _chapter: &Chapter) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn add_metadata(&mut self, _key: &str, _value: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_packet(&mut self, stream: usize, packet: &MuxPacket) -> anyhow::Result<()> {
+        let mut recorded = self.recorded.borrow_mut();
+        assert!(!recorded.trailer_written);
+        recorded.packets.push((stream, packet.pts, packet.dts));
+        Ok(())
+    }
+
+    fn write_trailer(&mut self) -> anyhow::Result<()> {
+        self.recorded.borrow_mut().trailer_written = true;
+        Ok(())
+    }
+}
+
+type Segments = Rc<RefCell<Vec<Rc<RefCell<Recorded>>>>>;
+
+fn params(time_base: Rational) -> StreamParams {
+    StreamParams {
+        codec: None,
+        parameters: Parameters::new(),
+        time_base,
+        frame_rate: None,
+    }
+}
+
+fn recorder(
+    segment_minutes: u32,
+    segments: &Segments,
+) -> SegmentedRecorder<SharedSink, impl FnMut(u32) -> anyhow::Result<SharedSink>> {
+    let segments = Rc::clone(segments);
+    SegmentedRecorder::new(
+        params(Rational::new(1, 1_000_000)),
+        Some(params(Rational::new(1, 48_000))),
+        segment_minutes,
+        move |sequence| {
+            let mut segments = segments.borrow_mut();
+            assert_eq!(sequence as usize, segments.len() + 1);
+            let recorded = Rc::new(RefCell::new(Recorded::default()));
+            segments.push(Rc::clone(&recorded));
+            Ok(SharedSink {
+                recorded,
+                streams: 0,
+            })
+        },
+    )
+}
+
+fn video(capture_time: i64, is_keyframe: bool) -> LivePacket {
+    LivePacket {
+        stream: LiveStream::Video,
+        data: Bytes::new(),
+        is_keyframe,
+        pts: capture_time,
+        dts: capture_time,
+        capture_time,
+    }
+}
+
+fn audio(capture_time: i64) -> LivePacket {
+    encoded_audio(0, capture_time)
+}
+
+fn encoded_audio(pts: i64, capture_time: i64) -> LivePacket {
+    LivePacket {
+        stream: LiveStream::Audio,
+        data: Bytes::new(),
+        is_keyframe: true,
+        pts,
+        dts: 0,
+        capture_time,
+    }
+}
+
+#[test]
+fn test_recorder_cuts_at_key_frames() {
+    let segments = Segments::default();
+    let mut recorder = recorder(1, &segments);
+
+    // 150 seconds at 10 fps starting mid GOP, key frame every 2 seconds
+    let frames: Vec<_> = (0..1500).map(|i| 500_000 + i * 100_000).collect();
+    for &capture_time in &frames {
+        recorder
+            .push(&video(capture_time, capture_time % 2_000_000 == 1_000_000))
+            .unwrap();
+    }
+    recorder.finish().unwrap();
+
+    let segments = segments.borrow();
+    assert_eq!(segments.len(), 3);
+    let lengths: Vec<_> = segments
+        .iter()
+        .map(|segment| segment.borrow().stream_pts(VIDEO_STREAM).len())
+        .collect();
+    // Nothing but the frames before the first key frame is left out
+    assert_eq!(lengths.iter().sum::<usize>(), frames.len() - 5);
+    assert_eq!(lengths[0], 600);
+    assert_eq!(lengths[1], 600);
+
+    for segment in segments.iter() {
+        let segment = segment.borrow();
+        assert!(segment.trailer_written);
+        let pts = segment.stream_pts(VIDEO_STREAM);
+        assert_eq!(pts[0], 0);
+        assert!(pts.windows(2).all(|w| w[1] - w[0] == 100_000));
+    }
+}
+
+#[test]
+fn test_recorder_keeps_late_audio_in_previous_segment() {
+    let segments = Segments::default();
+    let mut recorder = recorder(1, &segments);
+
+    recorder.push(&video(0, true)).unwrap();
+    recorder.push(&audio(0)).unwrap();
+    recorder.push(&video(60_000_000, true)).unwrap();
+    // Captured before the cut but delivered after the key frame starting the next segment
+    recorder.push(&audio(59_980_000)).unwrap();
+    assert!(!segments.borrow()[0].borrow().trailer_written);
+    recorder.push(&audio(60_000_000)).unwrap();
+    assert!(segments.borrow()[0].borrow().trailer_written);
+
+    let segments = segments.borrow();
+    assert_eq!(
+        segments[0].borrow().stream_pts(AUDIO_STREAM),
+        vec![0, 59_980_000 * 48 / 1000]
+    );
+    assert_eq!(segments[1].borrow().stream_pts(AUDIO_STREAM), vec![0]);
+    assert_eq!(segments[1].borrow().stream_pts(VIDEO_STREAM), vec![0]);
+}
+
+#[test]
+fn test_recorder_without_segments() {
+    let segments = Segments::default();
+    let mut recorder = recorder(0, &segments);
+
+    for i in 0..100 {
+        let capture_time = i * 60_000_000;
+        recorder.push(&video(capture_time, true)).unwrap();
+        assert!(!recorder.key_frame_overdue(capture_time));
+    }
+    recorder.finish().unwrap();
+
+    assert_eq!(segments.borrow().len(), 1);
+}
+
+#[test]
+fn test_recorder_key_frame_overdue() {
+    let segments = Segments::default();
+    let mut recorder = recorder(1, &segments);
+
+    recorder.push(&video(0, true)).unwrap();
+    recorder.push(&video(60_000_000, false)).unwrap();
+    assert!(!recorder.key_frame_overdue(60_000_000));
+    assert!(recorder.key_frame_overdue(65_000_000));
+
+    recorder.push(&video(65_000_000, true)).unwrap();
+    assert!(!recorder.key_frame_overdue(65_000_000));
+    assert_eq!(segments.borrow().len(), 2);
+}
+
+const FRAME_MICROS: i64 = 16_667;
+const AUDIO_FRAME_MICROS: i64 = 20_000;
+
+/// Frame `i` of 60fps video with a key frame every 30 frames, starting at 1 second.
+fn video_at(i: i64) -> LivePacket {
+    video(1_000_000 + i * FRAME_MICROS, i % 30 == 0)
+}
+
+/// Frame `i` of 20ms audio at 48kHz, starting at 1 second.
+fn audio_at(i: i64) -> LivePacket {
+    encoded_audio(i * 960, 1_000_000 + i * AUDIO_FRAME_MICROS)
+}
+
+fn fill_buffers(
+    video_frames: i64,
+    audio_frames: i64,
+) -> (ShadowCaptureVideoBuffer, ShadowCaptureAudioBuffer) {
+    let mut video_buffer = ShadowCaptureVideoBuffer::new(60_000_000);
+    for i in 0..video_frames {
+        let packet = video_at(i);
+        video_buffer.insert(
+            packet.dts,
+            EncodedVideoFrame {
+                data: vec![0],
+                is_keyframe: packet.is_keyframe,
+                pts: packet.pts,
+                dts: packet.dts,
+            },
+        );
+    }
+
+    let mut audio_buffer = ShadowCaptureAudioBuffer::new(60_000_000);
+    for i in 0..audio_frames {
+        let packet = audio_at(i);
+        audio_buffer.insert_capture_time(packet.capture_time);
+        audio_buffer.insert(packet.pts, vec![0]);
+    }
+
+    (video_buffer, audio_buffer)
+}
+
+fn preroll_recorder(
+    video_buffer: &ShadowCaptureVideoBuffer,
+    audio_buffer: &ShadowCaptureAudioBuffer,
+    recorded: &Rc<RefCell<Recorded>>,
+) -> PrerollRecorder<SharedSink> {
+    PrerollRecorder::start(
+        &params(Rational::new(1, 1_000_000)),
+        Some(&params(Rational::new(1, 48_000))),
+        video_buffer,
+        audio_buffer,
+        SharedSink {
+            recorded: Rc::clone(recorded),
+            streams: 0,
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_preroll_timestamps_continue_into_live_packets() {
+    // Two and a half GOPs buffered, the last one incomplete
+    let (video_buffer, audio_buffer) = fill_buffers(75, 60);
+    let recorded = Rc::new(RefCell::new(Recorded::default()));
+    let mut recorder = preroll_recorder(&video_buffer, &audio_buffer, &recorded);
+
+    // Live packets start a few frames before the end of the buffer, as if they raced the handover
+    for i in 70..150 {
+        recorder.push(&video_at(i)).unwrap();
+    }
+    for i in 55..120 {
+        recorder.push(&audio_at(i)).unwrap();
+    }
+    recorder.finish().unwrap();
+
+    let recorded = recorded.borrow();
+    assert!(recorded.trailer_written);
+    let video_pts = recorded.stream_pts(VIDEO_STREAM);
+    assert_eq!(video_pts.len(), 150);
+    assert_eq!(video_pts[0], 0);
+    assert!(video_pts.windows(2).all(|w| w[1] - w[0] == FRAME_MICROS));
+
+    let audio_pts = recorded.stream_pts(AUDIO_STREAM);
+    assert_eq!(audio_pts.len(), 120);
+    assert_eq!(audio_pts[0], 0);
+    assert!(audio_pts.windows(2).all(|w| w[1] - w[0] == 960));
+}
+
+#[test]
+fn test_preroll_live_audio_after_a_gap() {
+    let (video_buffer, audio_buffer) = fill_buffers(31, 30);
+    let recorded = Rc::new(RefCell::new(Recorded::default()));
+    let mut recorder = preroll_recorder(&video_buffer, &audio_buffer, &recorded);
+
+    // The buffered audio ends at frame 25 with the video's last key frame, 10 frames go missing
+    recorder.push(&audio_at(40)).unwrap();
+    recorder.push(&audio_at(41)).unwrap();
+    recorder.finish().unwrap();
+
+    let audio_pts = recorded.borrow().stream_pts(AUDIO_STREAM);
+    assert_eq!(audio_pts.len(), 32);
+    assert_eq!(audio_pts[29], 29 * 960);
+    assert_eq!(audio_pts[30], 40 * 960);
+    assert_eq!(audio_pts[31], 41 * 960);
+}
+
+#[test]
+fn test_preroll_with_empty_buffers_starts_at_key_frame() {
+    let (video_buffer, audio_buffer) = fill_buffers(0, 0);
+    let recorded = Rc::new(RefCell::new(Recorded::default()));
+    let mut recorder = preroll_recorder(&video_buffer, &audio_buffer, &recorded);
+
+    for i in 20..40 {
+        recorder.push(&video_at(i)).unwrap();
+    }
+    for i in 0..30 {
+        recorder.push(&audio_at(i)).unwrap();
+    }
+    recorder.finish().unwrap();
+
+    let recorded = recorded.borrow();
+    let video_pts = recorded.stream_pts(VIDEO_STREAM);
+    assert_eq!(video_pts.len(), 10);
+    assert_eq!(video_pts[0], 0);
+    // Audio from before the key frame is left out and the rest lines up with it
+    let key_frame = video_at(30).capture_time;
+    let audio_pts = recorded.stream_pts(AUDIO_STREAM);
+    // The key frame lands at 500_010 microseconds in, just after audio frame 25
+    let first_audio = 26;
+    assert_eq!(audio_pts.len(), (30 - first_audio) as usize);
+    assert_eq!(
+        audio_pts[0],
+        (audio_at(first_audio).capture_time - key_frame) * 48 / 1000
+    );
+}
+
+#[test]
+fn test_audio_recorder_writes_only_audio() {
+    let recorded = Rc::new(RefCell::new(Recorded::default()));
+    let sink = SharedSink {
+        recorded: Rc::clone(&recorded),
+        streams: 0,
+    };
+    let mut recorder = AudioRecorder::start(&params(Rational::new(1, 48_000)), sink).unwrap();
+    for i in 0..10 {
+        recorder.push(&video_at(i)).unwrap();
+        recorder.push(&audio_at(i)).unwrap();
+    }
+    // Delivered twice, and a gap of 10 frames
+    recorder.push(&audio_at(9)).unwrap();
+    recorder.push(&audio_at(20)).unwrap();
+    recorder.finish().unwrap();
+
+    let recorded = recorded.borrow();
+    assert!(recorded.trailer_written);
+    // The audio is the only stream of the file
+    assert_eq!(recorded.packets.len(), 11);
+    let pts = recorded.stream_pts(0);
+    assert_eq!(pts[..10], (0..10).map(|i| i * 960).collect::<Vec<_>>()[..]);
+    assert_eq!(pts[10], 20 * 960);
+}
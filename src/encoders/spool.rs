@@ -0,0 +1,264 @@
+//! Keeping the shadow buffers across a restart. On shutdown the encoded frames are written to a
+//! spool file, the next start loads them back if they came from the same encoder setup. The
+//! capture of the new run starts its clocks over, the buffers then offset its frames to continue
+//! right after the restored ones, like after an encoder reset. The downtime in between is left
+//! out of the clips.
+//!
+//! The spool is a magic, a JSON [`SpoolHeader`] and then the frames, every number little endian:
+//!
+//! ```text
+//! b"WAYCAPSP" | u32 header length | header
+//! u64 video frames | (i64 dts | i64 pts | u8 key frame | u32 length | data)*
+//! u64 audio frames | (i64 pts | i64 capture time | u32 length | data)*
+//! ```
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+use super::{
+    buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer},
+    muxer::StreamParams,
+};
+
+const MAGIC: &[u8; 8] = b"WAYCAPSP";
+
+/// Version of the spool layout, bumped whenever it changes.
+const SPOOL_VERSION: u32 = 1;
+
+/// Headers are a few hundred bytes, anything far beyond that is not a spool.
+const MAX_HEADER_BYTES: u32 = 64 * 1024;
+
+/// Where the buffers are spooled to, in the state directory.
+pub fn spool_path() -> PathBuf {
+    ProjectDirs::from("com", "rust", "waycap")
+        .and_then(|dirs| dirs.state_dir().map(Path::to_path_buf))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("shadow_buffer.spool")
+}
+
+/// What the spooled frames were encoded with. Frames only fit into a clip with the new capture's
+/// stream parameters if all of this matches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpoolStreams {
+    pub video_codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub audio_codec: Option<String>,
+}
+
+impl SpoolStreams {
+    pub fn of(video: &StreamParams, audio: Option<&StreamParams>) -> Result<Self> {
+        let size = crate::preview::capture_size(video)?;
+        Ok(Self {
+            video_codec: format!("{:?}", video.parameters.id()),
+            width: size.width,
+            height: size.height,
+            audio_codec: audio.map(|audio| format!("{:?}", audio.parameters.id())),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpoolHeader {
+    pub spool_version: u32,
+    /// WayCap version which wrote the spool, a different build may encode differently.
+    pub waycap_version: String,
+    pub streams: SpoolStreams,
+}
+
+impl SpoolHeader {
+    pub fn new(streams: SpoolStreams) -> Self {
+        Self {
+            spool_version: SPOOL_VERSION,
+            waycap_version: env!("CARGO_PKG_VERSION").to_string(),
+            streams,
+        }
+    }
+
+    /// Why a spool with this header can't be loaded into buffers for `streams`, if it can't.
+    fn incompatibility(&self, streams: &SpoolStreams) -> Option<String> {
+        let current = Self::new(streams.clone());
+        if self.spool_version != current.spool_version {
+            Some(format!("it is spool version {}", self.spool_version))
+        } else if self.waycap_version != current.waycap_version {
+            Some(format!("it was written by WayCap {}", self.waycap_version))
+        } else if self.streams != current.streams {
+            Some(format!(
+                "it was encoded as {:?}, the capture is {:?}",
+                self.streams, current.streams
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Frames read from a spool, loaded into the buffers only once the whole file was read.
+#[derive(Debug, Default)]
+struct SpooledFrames {
+    video: Vec<EncodedVideoFrame>,
+    /// `(pts, capture time, data)` of every audio frame.
+    audio: Vec<(i64, i64, Vec<u8>)>,
+}
+
+/// Writes the buffered frames to `path`, replacing an older spool only once done.
+pub fn write_spool(
+    path: &Path,
+    header: &SpoolHeader,
+    video: &ShadowCaptureVideoBuffer,
+    audio: &ShadowCaptureAudioBuffer,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = path.with_extension("partial");
+    let written = File::create(&partial)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| {
+            let mut out = BufWriter::new(file);
+            out.write_all(MAGIC)?;
+            let header = serde_json::to_vec(header)?;
+            out.write_all(&(header.len() as u32).to_le_bytes())?;
+            out.write_all(&header)?;
+
+            out.write_all(&(video.get_frames().len() as u64).to_le_bytes())?;
+            for (&dts, frame) in video.get_frames() {
+                out.write_all(&dts.to_le_bytes())?;
+                out.write_all(&frame.pts.to_le_bytes())?;
+                out.write_all(&[u8::from(frame.is_keyframe)])?;
+                write_data(&mut out, &frame.data)?;
+            }
+
+            let frames = audio.get_frames().iter().zip(audio.get_capture_times());
+            out.write_all(&(frames.len() as u64).to_le_bytes())?;
+            for ((&pts, data), &capture_time) in frames {
+                out.write_all(&pts.to_le_bytes())?;
+                out.write_all(&capture_time.to_le_bytes())?;
+                write_data(&mut out, data)?;
+            }
+            out.into_inner()?.sync_all()?;
+            Ok(())
+        });
+    if let Err(e) = written.and_then(|_| Ok(fs::rename(&partial, path)?)) {
+        let _ = fs::remove_file(&partial);
+        return Err(e.context(format!("Could not write the spool {path:?}")));
+    }
+    Ok(())
+}
+
+/// Loads the spool at `path` into the buffers if it was written for `streams`, returning how many
+/// video and audio frames it held. The spool is removed whether or not it could be used, a
+/// corrupt or incompatible one is logged and nothing is loaded.
+pub fn restore_spool(
+    path: &Path,
+    streams: &SpoolStreams,
+    video: &mut ShadowCaptureVideoBuffer,
+    audio: &mut ShadowCaptureAudioBuffer,
+) -> Option<(usize, usize)> {
+    if !path.exists() {
+        return None;
+    }
+    let read = read_spool(path, streams);
+    if let Err(e) = fs::remove_file(path) {
+        log::error!("Could not remove the spool {path:?}: {e:?}");
+    }
+    let frames = match read {
+        Ok(frames) => frames,
+        Err(e) => {
+            log::warn!("Deleted the spool {path:?} without loading it: {e:#}");
+            return None;
+        }
+    };
+
+    let counts = (frames.video.len(), frames.audio.len());
+    for frame in frames.video {
+        video.insert(frame.dts, frame);
+    }
+    for (pts, capture_time, data) in frames.audio {
+        audio.insert_capture_time(capture_time);
+        audio.insert(pts, data);
+    }
+    Some(counts)
+}
+
+fn read_spool(path: &Path, streams: &SpoolStreams) -> Result<SpooledFrames> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    ensure!(&magic == MAGIC, "it is not a WayCap spool");
+
+    let header_len = read_u32(&mut input)?;
+    ensure!(header_len <= MAX_HEADER_BYTES, "its header is corrupt");
+    let mut header = vec![0; header_len as usize];
+    input.read_exact(&mut header)?;
+    let header: SpoolHeader = serde_json::from_slice(&header).context("its header is corrupt")?;
+    if let Some(reason) = header.incompatibility(streams) {
+        anyhow::bail!("{reason}");
+    }
+
+    let mut frames = SpooledFrames::default();
+    for _ in 0..read_u64(&mut input)? {
+        let dts = read_i64(&mut input)?;
+        let pts = read_i64(&mut input)?;
+        let mut is_keyframe = [0];
+        input.read_exact(&mut is_keyframe)?;
+        frames.video.push(EncodedVideoFrame {
+            data: read_data(&mut input)?,
+            is_keyframe: is_keyframe[0] != 0,
+            pts,
+            dts,
+        });
+    }
+    for _ in 0..read_u64(&mut input)? {
+        let pts = read_i64(&mut input)?;
+        let capture_time = read_i64(&mut input)?;
+        frames
+            .audio
+            .push((pts, capture_time, read_data(&mut input)?));
+    }
+    ensure!(
+        input.read(&mut [0])? == 0,
+        "it has data after the last frame"
+    );
+    Ok(frames)
+}
+
+fn write_data(out: &mut impl Write, data: &[u8]) -> Result<()> {
+    out.write_all(&(data.len() as u32).to_le_bytes())?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+fn read_data(input: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_u32(input)? as u64;
+    let mut data = Vec::new();
+    // A corrupt length ends the read at the end of the file instead of allocating it up front
+    input.take(len).read_to_end(&mut data)?;
+    ensure!(data.len() as u64 == len, "it ends in the middle of a frame");
+    Ok(data)
+}
+
+fn read_u32(input: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_i64(input: &mut impl Read) -> Result<i64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(i64::from_le_bytes(bytes))
+}
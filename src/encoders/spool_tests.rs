@@ -0,0 +1,177 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+use super::{
+    buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer},
+    spool::*,
+};
+
+const MAX_TIME: usize = 60_000_000;
+
+fn streams() -> SpoolStreams {
+    SpoolStreams {
+        video_codec: "H264".to_string(),
+        width: 1920,
+        height: 1080,
+        audio_codec: Some("OPUS".to_string()),
+    }
+}
+
+/// Spool path in a directory under `dir` which does not exist yet.
+fn spool_file(dir: &Path) -> PathBuf {
+    dir.join("spool").join("shadow_buffer.spool")
+}
+
+/// Two seconds of 10 fps video with a key frame every second, and 20ms audio frames.
+fn filled_buffers() -> (ShadowCaptureVideoBuffer, ShadowCaptureAudioBuffer) {
+    let mut video = ShadowCaptureVideoBuffer::new(MAX_TIME);
+    for index in 0..20 {
+        let pts = index * 100_000;
+        video.insert(
+            pts,
+            EncodedVideoFrame {
+                data: vec![index as u8; 8],
+                is_keyframe: index % 10 == 0,
+                pts,
+                dts: pts,
+            },
+        );
+    }
+    let mut audio = ShadowCaptureAudioBuffer::new(MAX_TIME);
+    for index in 0..100 {
+        audio.insert_capture_time(index * 20_000);
+        audio.insert(index * 960, vec![index as u8; 4]);
+    }
+    (video, audio)
+}
+
+fn empty_buffers() -> (ShadowCaptureVideoBuffer, ShadowCaptureAudioBuffer) {
+    (
+        ShadowCaptureVideoBuffer::new(MAX_TIME),
+        ShadowCaptureAudioBuffer::new(MAX_TIME),
+    )
+}
+
+#[test]
+fn test_spool_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = spool_file(dir.path());
+    let (video, audio) = filled_buffers();
+    write_spool(&path, &SpoolHeader::new(streams()), &video, &audio).unwrap();
+
+    let (mut restored_video, mut restored_audio) = empty_buffers();
+    let counts = restore_spool(&path, &streams(), &mut restored_video, &mut restored_audio);
+    assert_eq!(counts, Some((20, 100)));
+    assert!(!path.exists());
+
+    let frames = |buffer: &ShadowCaptureVideoBuffer| -> Vec<_> {
+        buffer
+            .get_frames()
+            .iter()
+            .map(|(&dts, frame)| (dts, frame.pts, frame.is_keyframe, frame.data.clone()))
+            .collect()
+    };
+    assert_eq!(frames(&restored_video), frames(&video));
+    assert_eq!(restored_video.key_frames(), video.key_frames());
+    assert_eq!(restored_audio.get_frames(), audio.get_frames());
+    assert_eq!(
+        restored_audio.get_capture_times(),
+        audio.get_capture_times()
+    );
+}
+
+#[test]
+fn test_new_capture_continues_after_the_spooled_frames() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = spool_file(dir.path());
+    let (video, audio) = filled_buffers();
+    write_spool(&path, &SpoolHeader::new(streams()), &video, &audio).unwrap();
+    let (mut video, mut audio) = empty_buffers();
+    restore_spool(&path, &streams(), &mut video, &mut audio).unwrap();
+
+    // The next run's capture starts its clocks at zero again
+    video.insert(
+        0,
+        EncodedVideoFrame {
+            data: vec![0xff],
+            is_keyframe: true,
+            pts: 0,
+            dts: 0,
+        },
+    );
+    audio.insert_capture_time(0);
+    audio.insert(0, vec![0xff]);
+
+    assert_eq!(video.oldest_pts(), Some(0));
+    assert!(video.newest_pts().unwrap() > 1_900_000);
+    assert_eq!(video.get_frames().len(), 21);
+    assert_eq!(audio.get_frames().len(), 101);
+    assert!(audio.get_capture_times().back().unwrap() > &1_980_000);
+}
+
+#[test]
+fn test_incompatible_spool_is_deleted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = spool_file(dir.path());
+    let (video, audio) = filled_buffers();
+    write_spool(&path, &SpoolHeader::new(streams()), &video, &audio).unwrap();
+
+    let other_size = SpoolStreams {
+        width: 2560,
+        height: 1440,
+        ..streams()
+    };
+    let (mut restored_video, mut restored_audio) = empty_buffers();
+    assert_eq!(
+        restore_spool(&path, &other_size, &mut restored_video, &mut restored_audio),
+        None
+    );
+    assert!(!path.exists());
+    assert!(restored_video.get_frames().is_empty());
+
+    let mut older = SpoolHeader::new(streams());
+    older.waycap_version = "0.0.1".to_string();
+    write_spool(&path, &older, &video, &audio).unwrap();
+    assert_eq!(
+        restore_spool(&path, &streams(), &mut restored_video, &mut restored_audio),
+        None
+    );
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_corrupt_spool_is_deleted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = spool_file(dir.path());
+    let (video, audio) = filled_buffers();
+    write_spool(&path, &SpoolHeader::new(streams()), &video, &audio).unwrap();
+
+    // Cut off in the middle of the audio frames, nothing of it is loaded
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() - 10]).unwrap();
+    let (mut restored_video, mut restored_audio) = empty_buffers();
+    assert_eq!(
+        restore_spool(&path, &streams(), &mut restored_video, &mut restored_audio),
+        None
+    );
+    assert!(!path.exists());
+    assert!(restored_video.get_frames().is_empty());
+    assert!(restored_audio.get_frames().is_empty());
+
+    fs::write(&path, b"not a spool at all").unwrap();
+    assert_eq!(
+        restore_spool(&path, &streams(), &mut restored_video, &mut restored_audio),
+        None
+    );
+    assert!(!path.exists());
+
+    // Without a spool there is nothing to do
+    assert_eq!(
+        restore_spool(&path, &streams(), &mut restored_video, &mut restored_audio),
+        None
+    );
+}
@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+
+use tokio::sync::Mutex;
+
+/// Frames a shadow worker received while its buffer was locked, e.g. by a save. They are flushed
+/// into the buffer on the next successful lock instead of being thrown away.
+///
+/// The queue is bounded so a very long save can't grow it without limit. Once full the oldest
+/// frame is dropped to make room, the newer footage being the one worth keeping.
+pub struct StagingQueue<T> {
+    frames: VecDeque<T>,
+    capacity: usize,
+}
+
+/// What happened to a frame handed to [`StagingQueue::insert_or_stage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageOutcome {
+    Inserted,
+    Staged,
+    /// The frame was staged but the queue was full so its oldest frame was dropped.
+    StagedDroppingOldest,
+}
+
+impl<T> StagingQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Inserts `frame` into `buffer` after any staged frames, or stages it if the buffer is busy.
+    pub fn insert_or_stage<B>(
+        &mut self,
+        buffer: &Mutex<B>,
+        frame: T,
+        mut insert: impl FnMut(&mut B, T),
+    ) -> StageOutcome {
+        match buffer.try_lock() {
+            Ok(mut buf) => {
+                for staged in self.frames.drain(..) {
+                    insert(&mut buf, staged);
+                }
+                insert(&mut buf, frame);
+                StageOutcome::Inserted
+            }
+            Err(_) => self.stage(frame),
+        }
+    }
+
+    /// Flushes the staged frames into `buffer` if it can be locked. Returns whether the queue is
+    /// empty afterwards.
+    pub fn flush<B>(&mut self, buffer: &Mutex<B>, mut insert: impl FnMut(&mut B, T)) -> bool {
+        if self.frames.is_empty() {
+            return true;
+        }
+
+        match buffer.try_lock() {
+            Ok(mut buf) => {
+                for staged in self.frames.drain(..) {
+                    insert(&mut buf, staged);
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn stage(&mut self, frame: T) -> StageOutcome {
+        let outcome = if self.frames.len() >= self.capacity {
+            self.frames.pop_front();
+            StageOutcome::StagedDroppingOldest
+        } else {
+            StageOutcome::Staged
+        };
+        self.frames.push_back(frame);
+        outcome
+    }
+}
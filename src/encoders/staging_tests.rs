@@ -0,0 +1,74 @@
+use tokio::sync::Mutex;
+
+use super::staging::*;
+
+fn push(buffer: &mut Vec<u32>, frame: u32) {
+    buffer.push(frame);
+}
+
+#[test]
+fn test_staging_inserts_directly_when_unlocked() {
+    let buffer = Mutex::new(Vec::new());
+    let mut staging = StagingQueue::new(8);
+
+    for frame in 0..5 {
+        assert_eq!(
+            staging.insert_or_stage(&buffer, frame, push),
+            StageOutcome::Inserted
+        );
+    }
+
+    assert!(staging.is_empty());
+    assert_eq!(*buffer.try_lock().unwrap(), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn test_staging_held_lock_keeps_newest_frames() {
+    let buffer = Mutex::new(vec![0]);
+    let mut staging = StagingQueue::new(300);
+
+    {
+        // Simulates a save holding the buffer for a long time
+        let _held = buffer.try_lock().unwrap();
+        let mut staged = 0;
+        let mut dropped = 0;
+        for frame in 1..=500 {
+            match staging.insert_or_stage(&buffer, frame, push) {
+                StageOutcome::Inserted => panic!("Frame {frame} inserted through a held lock"),
+                StageOutcome::Staged => staged += 1,
+                StageOutcome::StagedDroppingOldest => dropped += 1,
+            }
+        }
+        assert_eq!(staged, 300);
+        assert_eq!(dropped, 200);
+        assert_eq!(staging.len(), 300);
+    }
+
+    assert_eq!(
+        staging.insert_or_stage(&buffer, 501, push),
+        StageOutcome::Inserted
+    );
+    assert!(staging.is_empty());
+
+    let buffer = buffer.try_lock().unwrap();
+    assert_eq!(buffer.len(), 302);
+    assert_eq!(buffer[0], 0);
+    // The oldest staged frames made room for the newer ones, order is kept
+    assert!(buffer[1..].iter().copied().eq(201..=501));
+}
+
+#[test]
+fn test_staging_flush() {
+    let buffer = Mutex::new(Vec::new());
+    let mut staging = StagingQueue::new(8);
+
+    let held = buffer.try_lock().unwrap();
+    staging.insert_or_stage(&buffer, 1, push);
+    staging.insert_or_stage(&buffer, 2, push);
+    assert!(!staging.flush(&buffer, push));
+    drop(held);
+
+    assert!(staging.flush(&buffer, push));
+    assert!(staging.is_empty());
+    assert_eq!(*buffer.try_lock().unwrap(), vec![1, 2]);
+}
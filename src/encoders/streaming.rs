@@ -0,0 +1,362 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use ffmpeg_next::{self as ffmpeg, codec::Id, format::context::Output, Rational, Rescale};
+use waycap_rs::types::{audio_frame::EncodedAudioFrame, video_frame::EncodedVideoFrame};
+
+use super::muxer::StreamParams;
+
+/// Longest outage, in capture time, the stream catches up on once it reconnects.
+const BACKLOG_MICROS: i64 = 5_000_000;
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Capture times are in microseconds.
+const CAPTURE_TIME_BASE: Rational = Rational(1, 1_000_000);
+
+/// Returns the container to stream to `url` with, rejecting targets which can't carry the
+/// capture's audio codec.
+pub fn stream_format(url: &str, audio_codec: Option<Id>) -> Result<&'static str> {
+    let (scheme, _) = url
+        .split_once("://")
+        .with_context(|| format!("{url:?} is not a stream URL"))?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "rtmp" | "rtmps" => {
+            if let Some(codec) = audio_codec.filter(|&codec| codec != Id::AAC) {
+                bail!(
+                    "RTMP streams need AAC audio but the capture encodes {codec:?}, stream to an srt:// URL instead"
+                );
+            }
+            Ok("flv")
+        }
+        "srt" => Ok("mpegts"),
+        other => {
+            bail!("Unsupported stream URL scheme {other:?}, expected rtmp://, rtmps:// or srt://")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LiveStream {
+    Video,
+    Audio,
+}
+
+/// An encoded frame on its way to the stream, stamped with its capture time in microseconds.
+#[derive(Debug, Clone)]
+pub struct LivePacket {
+    pub stream: LiveStream,
+    pub data: Bytes,
+    pub is_keyframe: bool,
+    /// Encoder timestamps. For audio these only count samples, streams stamp it from its capture
+    /// time instead.
+    pub pts: i64,
+    pub dts: i64,
+    pub capture_time: i64,
+}
+
+impl LivePacket {
+    /// PTS and DTS in `time_base` counted from `start`, a capture time in microseconds. Video
+    /// keeps the encoder's timestamps while audio is stamped from its capture time. `None` for
+    /// audio captured before `start`, which has nothing to line up with.
+    pub fn timestamps_since(&self, start: i64, time_base: Rational) -> Option<(i64, i64)> {
+        match self.stream {
+            LiveStream::Video => {
+                let offset = start.rescale(CAPTURE_TIME_BASE, time_base);
+                Some((self.pts - offset, self.dts - offset))
+            }
+            LiveStream::Audio => {
+                if self.capture_time < start {
+                    return None;
+                }
+                let pts = (self.capture_time - start).rescale(CAPTURE_TIME_BASE, time_base);
+                Some((pts, pts))
+            }
+        }
+    }
+
+    /// Copies `frame` for when the frame itself is kept elsewhere, e.g. in the shadow buffer.
+    pub fn copy_video(frame: &EncodedVideoFrame) -> Self {
+        Self {
+            stream: LiveStream::Video,
+            data: Bytes::copy_from_slice(&frame.data),
+            is_keyframe: frame.is_keyframe,
+            pts: frame.pts,
+            dts: frame.dts,
+            capture_time: frame.dts,
+        }
+    }
+
+    /// Copies `frame` for when the frame itself is kept elsewhere, e.g. in the shadow buffer.
+    pub fn copy_audio(frame: &EncodedAudioFrame) -> Self {
+        Self {
+            stream: LiveStream::Audio,
+            data: Bytes::copy_from_slice(&frame.data),
+            is_keyframe: true,
+            pts: frame.pts,
+            dts: frame.pts,
+            capture_time: frame.timestamp,
+        }
+    }
+}
+
+impl From<EncodedVideoFrame> for LivePacket {
+    fn from(frame: EncodedVideoFrame) -> Self {
+        Self {
+            stream: LiveStream::Video,
+            data: Bytes::from(frame.data),
+            is_keyframe: frame.is_keyframe,
+            pts: frame.pts,
+            dts: frame.dts,
+            capture_time: frame.dts,
+        }
+    }
+}
+
+impl From<EncodedAudioFrame> for LivePacket {
+    fn from(frame: EncodedAudioFrame) -> Self {
+        Self {
+            stream: LiveStream::Audio,
+            data: Bytes::from(frame.data),
+            is_keyframe: true,
+            pts: frame.pts,
+            dts: frame.pts,
+            capture_time: frame.timestamp,
+        }
+    }
+}
+
+/// Packets waiting for the stream to (re)connect. Holds at most [`BACKLOG_MICROS`] of capture
+/// time and always starts at a video key frame, so whatever is written first decodes.
+#[derive(Debug, Default)]
+pub struct Backlog {
+    packets: VecDeque<LivePacket>,
+}
+
+impl Backlog {
+    pub fn push(&mut self, packet: LivePacket) {
+        let newest = packet.capture_time;
+        self.packets.push_back(packet);
+
+        while self
+            .packets
+            .front()
+            .is_some_and(|oldest| newest - oldest.capture_time > BACKLOG_MICROS)
+        {
+            self.packets.pop_front();
+        }
+        while self
+            .packets
+            .front()
+            .is_some_and(|oldest| oldest.stream != LiveStream::Video || !oldest.is_keyframe)
+        {
+            self.packets.pop_front();
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<LivePacket> {
+        self.packets.pop_front()
+    }
+
+    /// Puts back a packet which could not be written.
+    pub fn unpop(&mut self, packet: LivePacket) {
+        self.packets.push_front(packet);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+}
+
+/// Connection to the stream target. Timestamps start at 0 with the first packet written.
+struct LiveOutput {
+    output: Output,
+    video: StreamTarget,
+    audio: Option<StreamTarget>,
+    start: Option<i64>,
+}
+
+struct StreamTarget {
+    index: usize,
+    encoder_time_base: Rational,
+    stream_time_base: Rational,
+}
+
+impl LiveOutput {
+    fn connect(
+        url: &str,
+        format: &str,
+        video: &StreamParams,
+        audio: Option<&StreamParams>,
+    ) -> Result<Self> {
+        let mut output = ffmpeg::format::output_as(url, format)
+            .with_context(|| format!("Could not connect to {url}"))?;
+        let video_index = Self::add_stream(&mut output, video)?;
+        let audio_index = match audio {
+            Some(params) => Some(Self::add_stream(&mut output, params)?),
+            None => None,
+        };
+        output.write_header()?;
+
+        // The muxer picks its own time bases while writing the header
+        let target = |output: &Output, index: usize, params: &StreamParams| StreamTarget {
+            index,
+            encoder_time_base: params.time_base,
+            stream_time_base: output
+                .stream(index)
+                .map_or(params.time_base, |stream| stream.time_base()),
+        };
+        Ok(Self {
+            video: target(&output, video_index, video),
+            audio: audio_index.zip(audio).map(|(i, p)| target(&output, i, p)),
+            output,
+            start: None,
+        })
+    }
+
+    fn add_stream(output: &mut Output, params: &StreamParams) -> Result<usize> {
+        let mut stream = output.add_stream(params.codec)?;
+        stream.set_time_base(params.time_base);
+        stream.set_parameters(params.parameters.clone());
+        Ok(stream.index())
+    }
+
+    fn write(&mut self, live: &LivePacket) -> Result<()> {
+        let start = *self.start.get_or_insert(live.capture_time);
+        let target = match live.stream {
+            LiveStream::Video => &self.video,
+            LiveStream::Audio => match &self.audio {
+                Some(target) => target,
+                None => return Ok(()),
+            },
+        };
+        let Some((pts, dts)) = live.timestamps_since(start, target.encoder_time_base) else {
+            return Ok(());
+        };
+
+        let mut packet = ffmpeg::codec::packet::Packet::copy(&live.data);
+        packet.set_pts(Some(pts));
+        packet.set_dts(Some(dts));
+        packet.set_stream(target.index);
+        packet.rescale_ts(target.encoder_time_base, target.stream_time_base);
+        packet
+            .write_interleaved(&mut self.output)
+            .context("Could not write to the stream")
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.output.write_trailer()?;
+        Ok(())
+    }
+}
+
+/// Pushes live packets to a stream target, reconnecting with exponential backoff whenever the
+/// connection drops. Packets keep being backlogged meanwhile so a short outage only delays the
+/// stream instead of cutting it.
+pub struct Streamer {
+    url: String,
+    format: &'static str,
+    video: StreamParams,
+    audio: Option<StreamParams>,
+    output: Option<LiveOutput>,
+    backlog: Backlog,
+    backoff: Duration,
+    retry_at: Instant,
+}
+
+impl Streamer {
+    pub fn new(
+        url: String,
+        format: &'static str,
+        video: StreamParams,
+        audio: Option<StreamParams>,
+    ) -> Self {
+        Self {
+            url,
+            format,
+            video,
+            audio,
+            output: None,
+            backlog: Backlog::default(),
+            backoff: MIN_RECONNECT_BACKOFF,
+            retry_at: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, packet: LivePacket) {
+        match self.output.as_mut() {
+            Some(output) if self.backlog.is_empty() => {
+                if let Err(e) = output.write(&packet) {
+                    self.output = None;
+                    self.backlog.push(packet);
+                    self.schedule_reconnect(e);
+                }
+            }
+            _ => {
+                self.backlog.push(packet);
+                self.pump();
+            }
+        }
+    }
+
+    /// Connects if due and writes whatever is backlogged. Packets are written as the capture
+    /// delivers them, which paces the stream in real time.
+    pub fn pump(&mut self) {
+        if self.output.is_none() {
+            if self.backlog.is_empty() || Instant::now() < self.retry_at {
+                return;
+            }
+            match LiveOutput::connect(&self.url, self.format, &self.video, self.audio.as_ref()) {
+                Ok(output) => {
+                    log::info!("Streaming to {}", self.url);
+                    self.output = Some(output);
+                    self.backoff = MIN_RECONNECT_BACKOFF;
+                }
+                Err(e) => {
+                    self.schedule_reconnect(e);
+                    return;
+                }
+            }
+        }
+
+        let Some(output) = self.output.as_mut() else {
+            return;
+        };
+        while let Some(packet) = self.backlog.pop() {
+            if let Err(e) = output.write(&packet) {
+                self.output = None;
+                self.backlog.unpop(packet);
+                self.schedule_reconnect(e);
+                return;
+            }
+        }
+    }
+
+    /// Ends the stream cleanly.
+    pub fn finish(&mut self) {
+        if let Some(output) = self.output.take() {
+            if let Err(e) = output.finish() {
+                log::error!("Could not end the stream to {}: {e:?}", self.url);
+            }
+        }
+    }
+
+    fn schedule_reconnect(&mut self, error: anyhow::Error) {
+        log::warn!(
+            "Stream to {} unavailable, retrying in {:?}: {error:?}",
+            self.url,
+            self.backoff
+        );
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
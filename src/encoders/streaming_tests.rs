@@ -0,0 +1,63 @@
+use bytes::Bytes;
+use ffmpeg_next::codec::Id;
+
+use super::streaming::*;
+
+fn packet(stream: LiveStream, capture_time: i64, is_keyframe: bool) -> LivePacket {
+    LivePacket {
+        stream,
+        data: Bytes::new(),
+        is_keyframe,
+        pts: capture_time,
+        dts: capture_time,
+        capture_time,
+    }
+}
+
+#[test]
+fn test_stream_format() {
+    assert_eq!(
+        stream_format("srt://host:9000", Some(Id::OPUS)).unwrap(),
+        "mpegts"
+    );
+    assert_eq!(
+        stream_format("rtmp://host/live/key", Some(Id::AAC)).unwrap(),
+        "flv"
+    );
+    assert_eq!(stream_format("RTMPS://host/live/key", None).unwrap(), "flv");
+
+    let err = stream_format("rtmp://host/live/key", Some(Id::OPUS)).unwrap_err();
+    assert!(err.to_string().contains("AAC"), "{err}");
+    assert!(stream_format("http://host", None).is_err());
+    assert!(stream_format("not a url", None).is_err());
+}
+
+#[test]
+fn test_backlog_starts_at_key_frame() {
+    let mut backlog = Backlog::default();
+
+    backlog.push(packet(LiveStream::Audio, 0, true));
+    backlog.push(packet(LiveStream::Video, 10, false));
+    assert!(backlog.is_empty());
+
+    backlog.push(packet(LiveStream::Video, 20, true));
+    backlog.push(packet(LiveStream::Audio, 25, true));
+    backlog.push(packet(LiveStream::Video, 30, false));
+    assert_eq!(backlog.len(), 3);
+    assert_eq!(backlog.pop().unwrap().capture_time, 20);
+}
+
+#[test]
+fn test_backlog_drops_oldest_gops() {
+    let mut backlog = Backlog::default();
+
+    // 10 seconds of 30 fps video with a key frame every second
+    for frame in 0..300 {
+        backlog.push(packet(LiveStream::Video, frame * 33_333, frame % 30 == 0));
+    }
+
+    let first = backlog.pop().unwrap();
+    assert!(first.is_keyframe);
+    assert_eq!(first.capture_time, 150 * 33_333);
+    assert_eq!(backlog.len(), 149);
+}
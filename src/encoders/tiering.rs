@@ -0,0 +1,238 @@
+//! Re-encodes the oldest footage of the shadow buffer at a lower bitrate, trading its quality for
+//! a longer window in the same memory.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use ffmpeg_next::{
+    self as ffmpeg,
+    codec::{self, Packet},
+    frame, Dictionary, Rational,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::{
+    buffer::{BufferedVideoFrame, ShadowCaptureVideoBuffer},
+    frame_extract::GopSnapshot,
+    muxer::StreamParams,
+};
+use crate::thread_priority::{spawn_mux_thread, ThreadsConfig};
+
+/// How long the re-encoder waits before looking for old footage again when there is none.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many GOPs in a row may take longer to re-encode than they last before the re-encoder gives
+/// up, since it would never catch up with the capture.
+const MAX_SLOW_GOPS: u32 = 3;
+
+/// Frame rate assumed for a GOP too short to measure one.
+const FALLBACK_FPS: i32 = 60;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TieringConfig {
+    /// Re-encode the footage older than `age_seconds` at `bitrate_kbps` in the background.
+    pub enabled: bool,
+    pub age_seconds: u32,
+    pub bitrate_kbps: u32,
+}
+
+impl Default for TieringConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            age_seconds: 120,
+            bitrate_kbps: 2000,
+        }
+    }
+}
+
+/// Starts the thread re-encoding the GOPs of `buffer` older than the configured age, one at a
+/// time and oldest first, until `stop` is set. It runs at the priority and on the CPUs of the
+/// mux. Any failure, or not keeping up with the capture, stops it and leaves the rest of the
+/// footage at full quality.
+pub fn spawn_reencoder(
+    buffer: Arc<Mutex<ShadowCaptureVideoBuffer>>,
+    params: StreamParams,
+    config: TieringConfig,
+    threads: &ThreadsConfig,
+    stop: Arc<AtomicBool>,
+) -> std::io::Result<JoinHandle<()>> {
+    spawn_mux_thread("waycap-tiering", threads, move || {
+        if let Err(e) = run(&buffer, &params, &config, &stop) {
+            log::warn!("Stopped re-encoding old footage, it is kept at full quality: {e:#}");
+        }
+    })
+}
+
+fn run(
+    buffer: &Mutex<ShadowCaptureVideoBuffer>,
+    params: &StreamParams,
+    config: &TieringConfig,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let age = i64::from(config.age_seconds) * 1_000_000;
+    let bitrate = config.bitrate_kbps as usize * 1000;
+    let mut slow_gops = 0;
+    while !stop.load(Ordering::Acquire) {
+        let gop = {
+            let buffer = buffer.blocking_lock();
+            buffer
+                .newest_pts()
+                .and_then(|newest| buffer.oldest_original_gop(newest - age))
+        };
+        let Some(frames) = gop else {
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+
+        let started = Instant::now();
+        let original_bytes: usize = frames.iter().map(|(_, frame)| frame.data.len()).sum();
+        let duration = gop_duration(&frames);
+        let reencoded = reencode_gop(params, frames, bitrate)?;
+        let reencoded_bytes: usize = reencoded.iter().map(|(_, frame)| frame.data.len()).sum();
+        if buffer.blocking_lock().replace_gop(reencoded) {
+            log::debug!(
+                "Re-encoded a GOP from {original_bytes} to {reencoded_bytes} bytes in {:?}",
+                started.elapsed()
+            );
+        }
+
+        if started.elapsed() > duration {
+            slow_gops += 1;
+            ensure!(
+                slow_gops < MAX_SLOW_GOPS,
+                "Re-encoding is slower than the capture"
+            );
+        } else {
+            slow_gops = 0;
+        }
+    }
+    Ok(())
+}
+
+/// How long the frames of a GOP are presented for.
+fn gop_duration(frames: &[(i64, BufferedVideoFrame)]) -> Duration {
+    let (first, last) = pts_range(frames);
+    let micros = match frames.len() {
+        0 | 1 => 0,
+        count => (last - first) * count as i64 / (count as i64 - 1),
+    };
+    Duration::from_micros(micros.max(0) as u64)
+}
+
+fn pts_range(frames: &[(i64, BufferedVideoFrame)]) -> (i64, i64) {
+    let first = frames.iter().map(|(_, frame)| frame.pts).min().unwrap_or(0);
+    let last = frames.iter().map(|(_, frame)| frame.pts).max().unwrap_or(0);
+    (first, last)
+}
+
+/// Decodes `frames` and encodes them again at `bitrate` with the software encoder for their
+/// codec. The re-encoded frames take over the DTS of the originals so the GOP keeps its place in
+/// the buffer.
+fn reencode_gop(
+    params: &StreamParams,
+    frames: Vec<(i64, BufferedVideoFrame)>,
+    bitrate: usize,
+) -> Result<Vec<(i64, BufferedVideoFrame)>> {
+    let encoder_name = match params.parameters.id() {
+        codec::Id::H264 => "libx264",
+        codec::Id::HEVC => "libx265",
+        id => bail!("There is no software encoder to re-encode {id:?} with"),
+    };
+    let codec = ffmpeg::encoder::find_by_name(encoder_name)
+        .with_context(|| format!("{encoder_name} is not available"))?;
+
+    let frame_count = frames.len();
+    let frame_rate = match gop_duration(&frames).as_micros() {
+        0 => FALLBACK_FPS,
+        micros => (frame_count as u128 * 1_000_000 / micros) as i32,
+    };
+    let mut encoder = None;
+    let mut packets = Vec::with_capacity(frame_count);
+    let snapshot = GopSnapshot::new(params.clone(), frames.clone());
+    snapshot.decode(|mut decoded| {
+        let encoder = match &mut encoder {
+            Some(encoder) => encoder,
+            None => encoder.insert(open_encoder(
+                codec,
+                &decoded,
+                params.time_base,
+                Rational::new(frame_rate.max(1), 1),
+                bitrate,
+                frame_count,
+            )?),
+        };
+        let pts = decoded.timestamp();
+        decoded.set_pts(pts);
+        encoder.send_frame(&decoded)?;
+        receive_packets(encoder, &mut packets);
+        Ok(())
+    })?;
+    let mut encoder = encoder.context("The decoder did not return any frame")?;
+    encoder.send_eof()?;
+    receive_packets(&mut encoder, &mut packets);
+    ensure!(
+        packets.len() == frame_count,
+        "Re-encoding {frame_count} frames returned {} packets",
+        packets.len()
+    );
+
+    // Without B-frames the packets come out in presentation order, so the n-th packet is never
+    // presented before the n-th original DTS
+    Ok(frames
+        .into_iter()
+        .zip(packets)
+        .map(|((dts, original), packet)| {
+            let pts = packet.pts().unwrap_or(original.pts);
+            let frame = BufferedVideoFrame {
+                data: Bytes::copy_from_slice(packet.data().unwrap_or_default()),
+                is_keyframe: packet.is_key(),
+                pts,
+                raw_pts: pts - (original.pts - original.raw_pts),
+                raw_dts: original.raw_dts,
+                reencoded: true,
+            };
+            (dts, frame)
+        })
+        .collect())
+}
+
+fn open_encoder(
+    codec: codec::Codec,
+    decoded: &frame::Video,
+    time_base: Rational,
+    frame_rate: Rational,
+    bitrate: usize,
+    gop_size: usize,
+) -> Result<ffmpeg::encoder::video::Encoder> {
+    let mut encoder = codec::Context::new_with_codec(codec).encoder().video()?;
+    encoder.set_width(decoded.width());
+    encoder.set_height(decoded.height());
+    encoder.set_format(decoded.format());
+    encoder.set_time_base(time_base);
+    encoder.set_frame_rate(Some(frame_rate));
+    encoder.set_bit_rate(bitrate);
+    // One key frame at the start, just like the GOP being replaced
+    encoder.set_gop(gop_size as u32 + 1);
+    encoder.set_max_b_frames(0);
+
+    let mut options = Dictionary::new();
+    options.set("preset", "veryfast");
+    Ok(encoder.open_as_with(codec, options)?)
+}
+
+fn receive_packets(encoder: &mut ffmpeg::encoder::video::Encoder, packets: &mut Vec<Packet>) {
+    let mut packet = Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packets.push(std::mem::replace(&mut packet, Packet::empty()));
+    }
+}
@@ -0,0 +1,695 @@
+//! Remuxes or re-encodes a clip which was already saved, for `TranscodeClip`. Only one job runs
+//! at a time, at the priority and on the CPUs of the mux, so it doesn't compete with the live
+//! capture.
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use ffmpeg_next::{
+    self as ffmpeg,
+    codec::{self, Packet},
+    format::{self, context},
+    frame, media, Dictionary, Rational,
+};
+use tokio::sync::mpsc;
+use zbus::Connection;
+
+use super::job::{Job, JobState};
+
+use crate::{
+    application_config::EncoderToUse,
+    clips::naming::{partial_path, transcoded_path},
+    dbus::{ClipService, GameClip},
+    dbus_types::TranscodeOptions,
+};
+
+/// Progress is only reported once it moved on by at least this much.
+const PROGRESS_STEP: f64 = 0.01;
+
+/// Worst constant quality any of the encoders accept.
+const MAX_QUALITY: u32 = 63;
+/// A key frame every 2 seconds keeps seeking in a transcoded clip quick.
+const KEYFRAME_SECONDS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Webm,
+}
+
+impl Container {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Webm => "webm",
+        }
+    }
+
+    pub fn holds_video(self, codec: VideoCodec) -> bool {
+        match self {
+            Container::Mp4 | Container::Mkv => true,
+            Container::Webm => matches!(codec, VideoCodec::Vp9 | VideoCodec::Av1),
+        }
+    }
+
+    pub fn holds_audio(self, id: codec::Id) -> bool {
+        match self {
+            Container::Mkv => true,
+            Container::Mp4 => matches!(id, codec::Id::AAC | codec::Id::OPUS | codec::Id::MP3),
+            Container::Webm => matches!(id, codec::Id::OPUS | codec::Id::VORBIS),
+        }
+    }
+
+    /// Codec the video is encoded with when the container can't hold the one of the input.
+    fn default_video_codec(self) -> VideoCodec {
+        match self {
+            Container::Webm => VideoCodec::Vp9,
+            Container::Mp4 | Container::Mkv => VideoCodec::H264,
+        }
+    }
+}
+
+impl FromStr for Container {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(Container::Mp4),
+            "mkv" => Ok(Container::Mkv),
+            "webm" => Ok(Container::Webm),
+            other => Err(format!(
+                "Unknown container: {other:?}, Valid values: mp4, mkv, webm"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+    Av1,
+    Vp9,
+}
+
+impl VideoCodec {
+    pub fn from_id(id: codec::Id) -> Option<Self> {
+        match id {
+            codec::Id::H264 => Some(VideoCodec::H264),
+            codec::Id::HEVC => Some(VideoCodec::Hevc),
+            codec::Id::AV1 => Some(VideoCodec::Av1),
+            codec::Id::VP9 => Some(VideoCodec::Vp9),
+            _ => None,
+        }
+    }
+
+    fn software_encoder(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "libx264",
+            VideoCodec::Hevc => "libx265",
+            VideoCodec::Av1 => "libsvtav1",
+            VideoCodec::Vp9 => "libvpx-vp9",
+        }
+    }
+
+    fn nvenc_encoder(self) -> Option<&'static str> {
+        match self {
+            VideoCodec::H264 => Some("h264_nvenc"),
+            VideoCodec::Hevc => Some("hevc_nvenc"),
+            VideoCodec::Av1 => Some("av1_nvenc"),
+            VideoCodec::Vp9 => None,
+        }
+    }
+
+    /// Quality used when none is asked for, about where each encoder's own default lands.
+    fn default_quality(self) -> u32 {
+        match self {
+            VideoCodec::H264 => 23,
+            VideoCodec::Hevc => 28,
+            VideoCodec::Av1 => 35,
+            VideoCodec::Vp9 => 31,
+        }
+    }
+}
+
+impl FromStr for VideoCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "h264" | "avc" => Ok(VideoCodec::H264),
+            "hevc" | "h265" => Ok(VideoCodec::Hevc),
+            "av1" => Ok(VideoCodec::Av1),
+            "vp9" => Ok(VideoCodec::Vp9),
+            other => Err(format!(
+                "Unknown video codec: {other:?}, Valid values: h264, hevc, av1, vp9"
+            )),
+        }
+    }
+}
+
+/// What happens to the video of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoStep {
+    /// The packets are copied as they are.
+    Copy,
+    Encode {
+        codec: VideoCodec,
+        encoder: &'static str,
+        quality: u32,
+    },
+}
+
+/// The codecs of the input which decide how it is transcoded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Probe {
+    /// Index and codec of the video stream, only the first one is kept.
+    pub video: Option<(usize, codec::Id)>,
+    /// Codecs of the audio streams, which are all kept.
+    pub audio: Vec<codec::Id>,
+}
+
+impl Probe {
+    fn new(input: &context::Input) -> Self {
+        let mut probe = Self::default();
+        for stream in input.streams() {
+            let parameters = stream.parameters();
+            match parameters.medium() {
+                media::Type::Video if probe.video.is_none() => {
+                    probe.video = Some((stream.index(), parameters.id()))
+                }
+                media::Type::Audio => probe.audio.push(parameters.id()),
+                _ => {}
+            }
+        }
+        probe
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscodePlan {
+    pub container: Container,
+    /// `None` for inputs without video.
+    pub video: Option<VideoStep>,
+}
+
+impl TranscodePlan {
+    /// Works out how to turn `input`, holding what `probe` found, into what `options` ask for.
+    /// The video is only re-encoded if its codec changes or a quality was asked for, the audio
+    /// never is. Hardware encoding goes through the encoder the capture is configured with.
+    pub fn new(
+        options: &TranscodeOptions,
+        input: &Path,
+        probe: &Probe,
+        encoder: EncoderToUse,
+    ) -> Result<Self> {
+        let container = match &options.container {
+            Some(container) => container.parse().map_err(anyhow::Error::msg)?,
+            None => Container::from_path(input)
+                .with_context(|| format!("Can't tell the container of {input:?}, pick one"))?,
+        };
+        if let Some(id) = probe.audio.iter().find(|&&id| !container.holds_audio(id)) {
+            bail!(
+                "{id:?} audio can't be put in {}, and the audio is never re-encoded",
+                container.extension()
+            );
+        }
+
+        let target = options
+            .video_codec
+            .as_deref()
+            .map(VideoCodec::from_str)
+            .transpose()
+            .map_err(anyhow::Error::msg)?;
+        let video = match probe.video {
+            Some((_, id)) => Some(video_step(container, id, target, options, encoder)?),
+            None => {
+                ensure!(target.is_none(), "{input:?} has no video to encode");
+                None
+            }
+        };
+        Ok(Self { container, video })
+    }
+
+    /// Whether the clip is only remuxed, which is quick and loses nothing.
+    pub fn is_remux(&self) -> bool {
+        matches!(self.video, None | Some(VideoStep::Copy))
+    }
+}
+
+fn video_step(
+    container: Container,
+    input: codec::Id,
+    target: Option<VideoCodec>,
+    options: &TranscodeOptions,
+    encoder: EncoderToUse,
+) -> Result<VideoStep> {
+    let current = VideoCodec::from_id(input);
+    let codec = match (target, current) {
+        (Some(target), _) => target,
+        (None, Some(current)) if container.holds_video(current) => current,
+        (None, _) => container.default_video_codec(),
+    };
+    ensure!(
+        container.holds_video(codec),
+        "{codec:?} video can't be put in {}",
+        container.extension()
+    );
+    if current == Some(codec) && options.quality.is_none() {
+        return Ok(VideoStep::Copy);
+    }
+
+    let quality = options.quality.unwrap_or(codec.default_quality());
+    ensure!(
+        quality <= MAX_QUALITY,
+        "The quality must be at most {MAX_QUALITY}, got {quality}"
+    );
+    let encoder = if options.hardware.unwrap_or(false) {
+        match encoder {
+            EncoderToUse::H264Nvenc => codec
+                .nvenc_encoder()
+                .with_context(|| format!("NVENC can't encode {codec:?}"))?,
+            EncoderToUse::H264Vaapi => bail!(
+                "Hardware transcoding is only supported with NVENC, leave hardware off to encode on the CPU"
+            ),
+        }
+    } else {
+        codec.software_encoder()
+    };
+    Ok(VideoStep::Encode {
+        codec,
+        encoder,
+        quality,
+    })
+}
+
+/// Options making `encoder` encode at the constant `quality`.
+pub fn quality_options(encoder: &str, quality: u32) -> Dictionary<'static> {
+    let quality = quality.to_string();
+    let mut options = Dictionary::new();
+    if encoder.ends_with("_nvenc") {
+        options.set("rc", "vbr");
+        options.set("cq", &quality);
+        options.set("preset", "p5");
+        return options;
+    }
+    options.set("crf", &quality);
+    match encoder {
+        "libsvtav1" => options.set("preset", "8"),
+        "libvpx-vp9" => options.set("row-mt", "1"),
+        _ => options.set("preset", "medium"),
+    }
+    options
+}
+
+/// Resolves the clip `input` names, relative to `output_dir` unless absolute. It must be a file
+/// inside `output_dir`, following symlinks.
+pub fn resolve_input(output_dir: &Path, input: &Path) -> Result<PathBuf> {
+    let output_dir = output_dir
+        .canonicalize()
+        .with_context(|| format!("Could not open the output directory {output_dir:?}"))?;
+    let resolved = output_dir
+        .join(input)
+        .canonicalize()
+        .with_context(|| format!("Could not find {input:?}"))?;
+    ensure!(
+        resolved.starts_with(&output_dir),
+        "{input:?} is not inside the output directory {output_dir:?}"
+    );
+    ensure!(resolved.is_file(), "{input:?} is not a file");
+    Ok(resolved)
+}
+
+/// Turns the position of the latest packet into the progress to report, only once it moved on
+/// by [`PROGRESS_STEP`].
+#[derive(Debug)]
+pub struct ProgressTracker {
+    duration: f64,
+    reported: f64,
+}
+
+impl ProgressTracker {
+    /// `duration` is the length of the input in seconds, progress is never reported for inputs
+    /// of unknown length.
+    pub fn new(duration: f64) -> Self {
+        Self {
+            duration,
+            reported: 0.0,
+        }
+    }
+
+    /// Returns the progress between 0 and 1 to report for having reached `position` seconds.
+    pub fn update(&mut self, position: f64) -> Option<f64> {
+        if self.duration <= 0.0 {
+            return None;
+        }
+        let progress = (position / self.duration).clamp(0.0, 1.0);
+        if progress - self.reported < PROGRESS_STEP {
+            return None;
+        }
+        self.reported = progress;
+        Some(progress)
+    }
+}
+
+pub struct TranscodeJob {
+    pub input: PathBuf,
+    pub options: TranscodeOptions,
+    pub encoder: EncoderToUse,
+}
+
+/// What a running job reports, turned into dbus signals by [`publish`].
+#[derive(Debug)]
+pub enum TranscodeEvent {
+    Progress(f64),
+    Done(PathBuf),
+    Failed(String),
+}
+
+impl Job for TranscodeJob {
+    type Opened = (context::Input, TranscodePlan);
+    type Event = TranscodeEvent;
+
+    const THREAD_NAME: &'static str = "waycap-transcode";
+
+    /// Probes the input and plans the transcode, the output is named after the container.
+    fn open(&self) -> Result<(Self::Opened, PathBuf)> {
+        let (input, plan) =
+            open(self).inspect_err(|e| log::error!("Not transcoding {:?}: {e:#}", self.input))?;
+        let output = transcoded_path(&self.input, plan.container.extension());
+        Ok(((input, plan), output))
+    }
+
+    fn run(
+        &self,
+        (mut input, plan): Self::Opened,
+        output: &Path,
+        state: &JobState,
+        events: &mpsc::UnboundedSender<TranscodeEvent>,
+    ) -> TranscodeEvent {
+        let action = if plan.is_remux() {
+            "Remuxing"
+        } else {
+            "Re-encoding"
+        };
+        log::info!("{action} {:?} to {output:?} with {plan:?}", self.input);
+        match transcode(&mut input, &plan, output, state, events) {
+            Ok(()) => {
+                log::info!("Transcoded {:?} to {output:?}", self.input);
+                TranscodeEvent::Done(output.to_path_buf())
+            }
+            Err(e) => {
+                log::error!("Could not transcode {:?}: {e:#}", self.input);
+                TranscodeEvent::Failed(format!("{e:#}"))
+            }
+        }
+    }
+}
+
+/// Frames between key frames at `frame_rate` for one every `seconds`, `None` when the rate is
+/// unknown, e.g. the 0/1 of a stream with no average rate.
+pub fn gop_size(frame_rate: Rational, seconds: u32) -> Option<u32> {
+    let (frames, per) = (frame_rate.numerator(), frame_rate.denominator());
+    if frames <= 0 || per <= 0 {
+        return None;
+    }
+    let gop = (i64::from(frames) * i64::from(seconds) + i64::from(per) / 2) / i64::from(per);
+    u32::try_from(gop.max(1)).ok()
+}
+
+fn open(job: &TranscodeJob) -> Result<(context::Input, TranscodePlan)> {
+    let input =
+        format::input(&job.input).with_context(|| format!("Could not open {:?}", job.input))?;
+    let plan = TranscodePlan::new(&job.options, &job.input, &Probe::new(&input), job.encoder)?;
+    Ok((input, plan))
+}
+
+/// Writes the transcode to a partial file which only replaces `output` once complete, and which
+/// is deleted if anything fails or the job is cancelled.
+fn transcode(
+    input: &mut context::Input,
+    plan: &TranscodePlan,
+    output: &Path,
+    state: &JobState,
+    events: &mpsc::UnboundedSender<TranscodeEvent>,
+) -> Result<()> {
+    let partial = partial_path(output);
+    let result = write(input, plan, &partial, state, events).and_then(|()| {
+        std::fs::rename(&partial, output)
+            .with_context(|| format!("Could not move the transcode to {output:?}"))
+    });
+    if result.is_err() {
+        if let Err(e) = std::fs::remove_file(&partial) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::error!("Could not remove the partial transcode {partial:?}: {e:?}");
+            }
+        }
+    }
+    result
+}
+
+/// Where the packets of an input stream go.
+enum Route {
+    Copy { output: usize, time_base: Rational },
+    Encode,
+    Skip,
+}
+
+fn write(
+    input: &mut context::Input,
+    plan: &TranscodePlan,
+    path: &Path,
+    state: &JobState,
+    events: &mpsc::UnboundedSender<TranscodeEvent>,
+) -> Result<()> {
+    let probe = Probe::new(input);
+    let mut output = format::output(path)?;
+    let global_header = output
+        .format()
+        .flags()
+        .contains(format::Flags::GLOBAL_HEADER);
+
+    let mut routes = Vec::new();
+    let mut video = None;
+    for stream in input.streams() {
+        let is_video = probe
+            .video
+            .is_some_and(|(index, _)| index == stream.index());
+        let route = match (&plan.video, stream.parameters().medium()) {
+            (
+                Some(VideoStep::Encode {
+                    encoder, quality, ..
+                }),
+                _,
+            ) if is_video => {
+                video = Some(VideoTranscoder::open(
+                    &stream,
+                    encoder,
+                    *quality,
+                    global_header,
+                    &mut output,
+                )?);
+                Route::Encode
+            }
+            (Some(VideoStep::Copy), _) if is_video => copy_stream(&stream, &mut output)?,
+            (_, media::Type::Audio) => copy_stream(&stream, &mut output)?,
+            _ => Route::Skip,
+        };
+        routes.push(route);
+    }
+    output.write_header()?;
+
+    // The muxer may pick its own time bases while writing the header
+    let output_time_base = |output: &context::Output, index: usize| {
+        output
+            .stream(index)
+            .map(|stream| stream.time_base())
+            .context("The output lost a stream")
+    };
+    for route in &mut routes {
+        if let Route::Copy {
+            output: index,
+            time_base,
+        } = route
+        {
+            *time_base = output_time_base(&output, *index)?;
+        }
+    }
+    if let Some(video) = &mut video {
+        video.output_time_base = output_time_base(&output, video.output)?;
+    }
+
+    // The duration of the input is in microseconds
+    let mut progress = ProgressTracker::new(input.duration() as f64 / 1_000_000.0);
+    for (stream, mut packet) in input.packets() {
+        ensure!(!state.is_cancelled(), "The transcode was cancelled");
+        let input_time_base = stream.time_base();
+        if let Some(position) = packet.pts().or(packet.dts()) {
+            let seconds = position as f64 * f64::from(input_time_base);
+            if let Some(progress) = progress.update(seconds) {
+                let _ = events.send(TranscodeEvent::Progress(progress));
+            }
+        }
+
+        match routes.get(stream.index()) {
+            Some(Route::Copy {
+                output: index,
+                time_base,
+            }) => {
+                packet.rescale_ts(input_time_base, *time_base);
+                packet.set_position(-1);
+                packet.set_stream(*index);
+                packet.write_interleaved(&mut output)?;
+            }
+            Some(Route::Encode) => {
+                if let Some(video) = &mut video {
+                    video.decoder.send_packet(&packet)?;
+                    video.encode_frames(&mut output)?;
+                }
+            }
+            Some(Route::Skip) | None => {}
+        }
+    }
+
+    if let Some(video) = &mut video {
+        video.decoder.send_eof()?;
+        video.encode_frames(&mut output)?;
+        video.encoder.send_eof()?;
+        video.write_packets(&mut output)?;
+    }
+    output.write_trailer()?;
+    Ok(())
+}
+
+fn copy_stream(stream: &format::stream::Stream, output: &mut context::Output) -> Result<Route> {
+    let mut copied = output.add_stream(ffmpeg::encoder::find(codec::Id::None))?;
+    copied.set_parameters(stream.parameters());
+    Ok(Route::Copy {
+        output: copied.index(),
+        time_base: stream.time_base(),
+    })
+}
+
+/// Decodes the video stream and encodes it again, keeping the timestamps in the time base of the
+/// input stream.
+struct VideoTranscoder {
+    decoder: ffmpeg::decoder::Video,
+    encoder: ffmpeg::encoder::video::Encoder,
+    input_time_base: Rational,
+    output: usize,
+    output_time_base: Rational,
+}
+
+impl VideoTranscoder {
+    fn open(
+        stream: &format::stream::Stream,
+        encoder_name: &str,
+        quality: u32,
+        global_header: bool,
+        output: &mut context::Output,
+    ) -> Result<Self> {
+        let decoder = codec::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()
+            .context("Could not open a decoder for the video")?;
+        let codec = ffmpeg::encoder::find_by_name(encoder_name)
+            .with_context(|| format!("{encoder_name} is not available"))?;
+
+        let input_time_base = stream.time_base();
+        let mut encoder = codec::Context::new_with_codec(codec).encoder().video()?;
+        encoder.set_width(decoder.width());
+        encoder.set_height(decoder.height());
+        encoder.set_format(decoder.format());
+        encoder.set_time_base(input_time_base);
+        let frame_rate = stream.avg_frame_rate();
+        if let Some(gop) = gop_size(frame_rate, KEYFRAME_SECONDS) {
+            encoder.set_frame_rate(Some(frame_rate));
+            encoder.set_gop(gop);
+        }
+        // Constant quality, the bitrate is whatever it takes
+        encoder.set_bit_rate(0);
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let encoder = encoder
+            .open_as_with(codec, quality_options(encoder_name, quality))
+            .with_context(|| format!("Could not open {encoder_name}"))?;
+
+        let mut encoded = output.add_stream(codec)?;
+        encoded.set_parameters(&encoder);
+        Ok(Self {
+            decoder,
+            encoder,
+            input_time_base,
+            output: encoded.index(),
+            output_time_base: input_time_base,
+        })
+    }
+
+    /// Hands the frames the decoder has ready to the encoder.
+    fn encode_frames(&mut self, output: &mut context::Output) -> Result<()> {
+        let mut decoded = frame::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = decoded.timestamp();
+            decoded.set_pts(timestamp);
+            self.encoder.send_frame(&decoded)?;
+            self.write_packets(output)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the packets the encoder has ready.
+    fn write_packets(&mut self, output: &mut context::Output) -> Result<()> {
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(self.output);
+            packet.rescale_ts(self.input_time_base, self.output_time_base);
+            packet.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns the events of the job transcoding `input` into dbus signals until it ends.
+pub async fn publish(
+    conn: Connection,
+    input: String,
+    mut events: mpsc::UnboundedReceiver<TranscodeEvent>,
+) {
+    while let Some(event) = events.recv().await {
+        let iface = match conn
+            .object_server()
+            .interface::<_, ClipService>("/com/rust/WayCap")
+            .await
+        {
+            Ok(iface) => iface,
+            Err(e) => {
+                log::debug!("Stopped publishing the transcode of {input}: {e:?}");
+                return;
+            }
+        };
+        let emitter = iface.signal_emitter();
+        let result = match event {
+            TranscodeEvent::Progress(progress) => {
+                ClipService::transcode_progress(emitter, input.clone(), progress).await
+            }
+            TranscodeEvent::Done(path) => {
+                ClipService::transcode_done(emitter, path.display().to_string()).await
+            }
+            TranscodeEvent::Failed(error) => {
+                ClipService::transcode_failed(emitter, input.clone(), error).await
+            }
+        };
+        if let Err(e) = result {
+            log::error!("Could not emit the transcode signal: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::{fs, path::Path};
+
+use ffmpeg_next::{codec, Rational};
+
+use super::transcode::*;
+use crate::{
+    application_config::EncoderToUse, clips::naming::transcoded_path, dbus_types::TranscodeOptions,
+};
+
+fn h264_clip() -> Probe {
+    Probe {
+        video: Some((0, codec::Id::H264)),
+        audio: vec![codec::Id::OPUS],
+    }
+}
+
+fn plan(options: TranscodeOptions, encoder: EncoderToUse) -> anyhow::Result<TranscodePlan> {
+    TranscodePlan::new(&options, Path::new("clip_1.mp4"), &h264_clip(), encoder)
+}
+
+#[test]
+fn test_container_change_only_remuxes() {
+    let plan = plan(
+        TranscodeOptions {
+            container: Some("mkv".to_string()),
+            ..Default::default()
+        },
+        EncoderToUse::H264Vaapi,
+    )
+    .unwrap();
+    assert_eq!(plan.container, Container::Mkv);
+    assert_eq!(plan.video, Some(VideoStep::Copy));
+    assert!(plan.is_remux());
+}
+
+#[test]
+fn test_webm_re_encodes_what_it_cant_hold() {
+    let plan = plan(
+        TranscodeOptions {
+            container: Some("webm".to_string()),
+            ..Default::default()
+        },
+        EncoderToUse::H264Vaapi,
+    )
+    .unwrap();
+    assert_eq!(
+        plan.video,
+        Some(VideoStep::Encode {
+            codec: VideoCodec::Vp9,
+            encoder: "libvpx-vp9",
+            quality: 31,
+        })
+    );
+
+    let aac = Probe {
+        audio: vec![codec::Id::AAC],
+        ..h264_clip()
+    };
+    let options = TranscodeOptions {
+        container: Some("webm".to_string()),
+        ..Default::default()
+    };
+    assert!(TranscodePlan::new(
+        &options,
+        Path::new("clip_1.mp4"),
+        &aac,
+        EncoderToUse::H264Vaapi
+    )
+    .is_err());
+}
+
+#[test]
+fn test_codec_and_quality_pick_the_encoder() {
+    let hevc = |hardware, encoder| {
+        plan(
+            TranscodeOptions {
+                video_codec: Some("h265".to_string()),
+                quality: Some(30),
+                hardware: Some(hardware),
+                ..Default::default()
+            },
+            encoder,
+        )
+    };
+    let encoder_of = |plan: TranscodePlan| match plan.video {
+        Some(VideoStep::Encode { encoder, .. }) => encoder,
+        step => panic!("Expected an encode, got {step:?}"),
+    };
+    assert_eq!(
+        encoder_of(hevc(false, EncoderToUse::H264Vaapi).unwrap()),
+        "libx265"
+    );
+    assert_eq!(
+        encoder_of(hevc(true, EncoderToUse::H264Nvenc).unwrap()),
+        "hevc_nvenc"
+    );
+    assert!(hevc(true, EncoderToUse::H264Vaapi).is_err());
+
+    // The same codec is re-encoded once a quality is asked for
+    let shrunk = plan(
+        TranscodeOptions {
+            quality: Some(28),
+            ..Default::default()
+        },
+        EncoderToUse::H264Vaapi,
+    )
+    .unwrap();
+    assert_eq!(encoder_of(shrunk), "libx264");
+
+    let too_low = TranscodeOptions {
+        quality: Some(80),
+        ..Default::default()
+    };
+    assert!(plan(too_low, EncoderToUse::H264Vaapi).is_err());
+}
+
+#[test]
+fn test_quality_options() {
+    let x264 = quality_options("libx264", 23);
+    assert_eq!(x264.get("crf"), Some("23"));
+    let nvenc = quality_options("hevc_nvenc", 30);
+    assert_eq!(nvenc.get("cq"), Some("30"));
+    assert_eq!(nvenc.get("crf"), None);
+}
+
+#[test]
+fn test_input_must_be_inside_the_output_dir() {
+    let root = tempfile::tempdir().unwrap();
+    let output_dir = root.path().join("clips");
+    fs::create_dir_all(&output_dir).unwrap();
+    fs::write(output_dir.join("clip_1.mp4"), b"").unwrap();
+    fs::write(root.path().join("secret.mp4"), b"").unwrap();
+
+    let resolved = resolve_input(&output_dir, Path::new("clip_1.mp4")).unwrap();
+    assert_eq!(
+        resolved,
+        output_dir.join("clip_1.mp4").canonicalize().unwrap()
+    );
+    assert!(resolve_input(&output_dir, Path::new("../secret.mp4")).is_err());
+    assert!(resolve_input(&output_dir, &root.path().join("secret.mp4")).is_err());
+    assert!(resolve_input(&output_dir, Path::new("missing.mp4")).is_err());
+    assert!(resolve_input(&output_dir, Path::new(".")).is_err());
+}
+
+#[test]
+fn test_transcoded_path_never_replaces_the_input() {
+    let input = Path::new("clips/clip_1.mp4");
+    assert_eq!(
+        transcoded_path(input, "mp4"),
+        Path::new("clips/clip_1_transcoded.mp4")
+    );
+    assert_eq!(
+        transcoded_path(input, "webm"),
+        Path::new("clips/clip_1_transcoded.webm")
+    );
+}
+
+#[test]
+fn test_progress_is_reported_in_steps() {
+    let mut progress = ProgressTracker::new(100.0);
+    assert_eq!(progress.update(0.5), None);
+    assert_eq!(progress.update(1.0), Some(0.01));
+    assert_eq!(progress.update(1.5), None);
+    assert_eq!(progress.update(250.0), Some(1.0));
+
+    assert_eq!(ProgressTracker::new(0.0).update(10.0), None);
+}
+
+#[test]
+fn test_gop_follows_the_frame_rate() {
+    assert_eq!(gop_size(Rational::new(60, 1), 2), Some(120));
+    assert_eq!(gop_size(Rational::new(30000, 1001), 2), Some(60));
+    assert_eq!(gop_size(Rational::new(1, 10), 2), Some(1));
+    assert_eq!(gop_size(Rational::new(0, 1), 2), None);
+}
@@ -0,0 +1,123 @@
+//! Keeps the session from suspending or going idle while a mode is writing continuously.
+use std::collections::HashMap;
+
+use anyhow::Result;
+use zbus::{
+    proxy,
+    zvariant::{OwnedObjectPath, Value},
+    Connection,
+};
+
+use crate::portal::{unique_token, RequestProxy};
+
+const INHIBIT_SUSPEND: u32 = 4;
+const INHIBIT_IDLE: u32 = 8;
+
+#[proxy(
+    interface = "org.freedesktop.portal.Inhibit",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+)]
+trait Inhibit {
+    fn inhibit(
+        &self,
+        window: &str,
+        flags: u32,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<OwnedObjectPath>;
+}
+
+/// Suspend and idle inhibitor taken through the desktop portal. The modes ask for it in `init`
+/// and give it up in `on_exit`, pausing the capture releases it until the capture resumes.
+/// Failures are only logged, a desktop without the portal just keeps its power settings.
+pub struct Inhibitor {
+    conn: Connection,
+    /// Why the inhibitor is wanted, `None` when it isn't.
+    reason: Option<&'static str>,
+    paused: bool,
+    /// Request handle of the held inhibitor, closing it releases the inhibitor.
+    handle: Option<OwnedObjectPath>,
+}
+
+impl Inhibitor {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            reason: None,
+            paused: false,
+            handle: None,
+        }
+    }
+
+    /// Takes the inhibitor, unless the capture is paused in which case resuming takes it.
+    pub async fn inhibit(&mut self, reason: &'static str) {
+        if self.reason == Some(reason) {
+            return;
+        }
+        self.release().await;
+        self.reason = Some(reason);
+        if !self.paused {
+            self.take().await;
+        }
+    }
+
+    pub async fn uninhibit(&mut self) {
+        self.reason = None;
+        self.release().await;
+    }
+
+    pub async fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if paused {
+            self.release().await;
+        } else if self.handle.is_none() {
+            self.take().await;
+        }
+    }
+
+    async fn take(&mut self) {
+        let Some(reason) = self.reason else {
+            return;
+        };
+        match self.request(reason).await {
+            Ok(handle) => {
+                log::debug!("Inhibiting suspend and idle: {reason}");
+                self.handle = Some(handle);
+            }
+            Err(e) => log::warn!("Could not inhibit suspend and idle: {e:#}"),
+        }
+    }
+
+    async fn request(&self, reason: &str) -> Result<OwnedObjectPath> {
+        let portal = InhibitProxy::new(&self.conn).await?;
+        let handle = portal
+            .inhibit(
+                "",
+                INHIBIT_SUSPEND | INHIBIT_IDLE,
+                HashMap::from([
+                    ("handle_token", Value::from(unique_token())),
+                    ("reason", Value::from(reason)),
+                ]),
+            )
+            .await?;
+        Ok(handle)
+    }
+
+    async fn release(&mut self) {
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        let closed = async {
+            RequestProxy::builder(&self.conn)
+                .path(handle)?
+                .build()
+                .await?
+                .close()
+                .await
+        };
+        match closed.await {
+            Ok(()) => log::debug!("Released the suspend and idle inhibitor"),
+            Err(e) => log::warn!("Could not release the suspend and idle inhibitor: {e}"),
+        }
+    }
+}
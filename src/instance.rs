@@ -0,0 +1,98 @@
+use std::{
+    fs::{self, File, TryLockError},
+    io::{ErrorKind, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use directories::ProjectDirs;
+
+const LOCK_FILE: &str = "waycap.lock";
+/// Times a lock removed by an exiting instance while we waited on it is retried.
+const ATTEMPTS: usize = 3;
+
+/// Where the lock of the running instance lives, the user's runtime dir when there is one.
+pub fn lock_path() -> PathBuf {
+    ProjectDirs::from("com", "rust", "waycap")
+        .and_then(|dirs| dirs.runtime_dir().map(Path::to_path_buf))
+        .unwrap_or_else(std::env::temp_dir)
+        .join(LOCK_FILE)
+}
+
+/// Makes sure only one WayCap runs at a time. Holds an exclusive `flock` on a file with the PID
+/// of the running instance, which is removed again when dropped.
+///
+/// The kernel releases the `flock` with the process, so a lock file left behind by a crash is
+/// simply taken over whatever it holds. Deleting the file of a running instance would let a
+/// second one lock a new file, which is why the errors never suggest it.
+#[derive(Debug)]
+pub struct InstanceLock {
+    path: PathBuf,
+    /// Holds the `flock` until the lock is dropped.
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Takes the lock at `path` or fails with the PID of the instance holding it.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        for _ in 0..ATTEMPTS {
+            let mut file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .with_context(|| format!("Could not open the lock {path:?}"))?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let pid = fs::read_to_string(path).unwrap_or_default();
+                    match pid.trim() {
+                        "" => bail!("Another instance of WayCap is starting at the same time"),
+                        pid => bail!("WayCap is already running (PID {pid}), stop it first"),
+                    }
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Could not lock {path:?}"))
+                }
+            }
+            // An exiting instance removes the file before releasing it, the lock taken on it
+            // then guards nothing and a new file has to be locked instead
+            if !is_same_file(&file, path)? {
+                continue;
+            }
+
+            file.set_len(0)?;
+            write!(file, "{}", std::process::id())?;
+            return Ok(Self {
+                path: path.to_path_buf(),
+                _file: file,
+            });
+        }
+        bail!("Could not take the lock {path:?}, other instances keep starting and exiting")
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Removed while still locked, the flock goes with the file right after
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::error!("Could not remove the lock {:?}: {e:?}", self.path);
+        }
+    }
+}
+
+/// Whether `file` is still the one at `path`.
+fn is_same_file(file: &File, path: &Path) -> Result<bool> {
+    let locked = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(locked.dev() == current.dev() && locked.ino() == current.ino()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
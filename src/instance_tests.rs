@@ -0,0 +1,71 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::instance::*;
+
+/// Lock path in a directory under `dir` which does not exist yet.
+fn lock_path(dir: &Path) -> PathBuf {
+    dir.join("runtime").join("waycap.lock")
+}
+
+#[test]
+fn test_lock_is_released_on_drop() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = lock_path(dir.path());
+
+    let lock = InstanceLock::acquire(&path).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        std::process::id().to_string()
+    );
+    drop(lock);
+    assert!(!path.exists());
+
+    InstanceLock::acquire(&path).unwrap();
+}
+
+#[test]
+fn test_second_instance_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = lock_path(dir.path());
+
+    let _lock = InstanceLock::acquire(&path).unwrap();
+    let err = InstanceLock::acquire(&path).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains(&format!("PID {}", std::process::id())));
+    // The running instance keeps its lock
+    assert!(path.exists());
+}
+
+#[test]
+fn test_held_lock_without_a_pid_is_not_stale() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = lock_path(dir.path());
+
+    // The holder has taken the lock but not written its PID yet
+    let _lock = InstanceLock::acquire(&path).unwrap();
+    fs::write(&path, "").unwrap();
+    let err = InstanceLock::acquire(&path).unwrap_err();
+    assert!(err.to_string().contains("starting"), "{err}");
+
+    fs::write(&path, "not a pid").unwrap();
+    assert!(InstanceLock::acquire(&path).is_err());
+}
+
+#[test]
+fn test_lock_left_by_a_crash_is_taken_over() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = lock_path(dir.path());
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+    // A crashed instance leaves its file with its PID behind, but nothing holds the flock
+    fs::write(&path, "4194305").unwrap();
+    let _lock = InstanceLock::acquire(&path).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        std::process::id().to_string()
+    );
+}
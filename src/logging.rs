@@ -0,0 +1,317 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{
+    kv::{Key, Value, VisitSource},
+    Level, LevelFilter, Log, Metadata, Record,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogDestination {
+    File,
+    Stderr,
+    Journald,
+}
+
+/// Socket journald accepts native protocol messages on.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_IDENTIFIER: &str = "waycap";
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `RUST_LOG` takes precedence when set to a level.
+    pub level: LogLevel,
+    /// Defaults to journald when running as a systemd service and to `file` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<LogDestination>,
+    /// Relative paths are resolved from where waycap is started.
+    pub file: PathBuf,
+    /// The log file is rotated once it grows past this, 0 lets it grow forever.
+    pub max_size_mb: u32,
+    /// How many rotated files (`logs.txt.1`, `logs.txt.2`, ...) are kept.
+    pub max_files: u32,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            destination: None,
+            file: PathBuf::from("logs.txt"),
+            max_size_mb: 10,
+            max_files: 3,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn destination(&self) -> LogDestination {
+        self.destination.unwrap_or_else(|| {
+            // systemd sets these for the processes it starts
+            let under_systemd = std::env::var_os("JOURNAL_STREAM").is_some()
+                || std::env::var_os("INVOCATION_ID").is_some();
+            if under_systemd {
+                LogDestination::Journald
+            } else {
+                LogDestination::File
+            }
+        })
+    }
+}
+
+/// Installs the global logger. A log file or journal which can't be opened falls back to stderr
+/// rather than keeping the application from starting.
+pub fn init(config: &LoggingConfig) {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or_else(|| config.level.into());
+
+    let (output, open_error) = match config.destination() {
+        LogDestination::Stderr => (Output::Stderr, None),
+        LogDestination::File => {
+            let max_bytes = config.max_size_mb as u64 * 1024 * 1024;
+            match RotatingFile::open(&config.file, max_bytes, config.max_files) {
+                Ok(file) => (Output::File(file), None),
+                Err(e) => (Output::Stderr, Some(("log file", e))),
+            }
+        }
+        LogDestination::Journald => match UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(JOURNALD_SOCKET).map(|()| socket))
+        {
+            Ok(socket) => (Output::Journald(socket), None),
+            Err(e) => (Output::Stderr, Some(("journal", e))),
+        },
+    };
+
+    let logger = Logger {
+        output: Mutex::new(output),
+    };
+    // The logger lives for the rest of the process
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(level);
+    }
+    if let Some((what, e)) = open_error {
+        log::error!("Could not open the {what}, logging to stderr instead: {e:?}");
+    }
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+    Journald(UnixDatagram),
+}
+
+struct Logger {
+    output: Mutex<Output>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let Ok(mut output) = self.output.lock() else {
+            return;
+        };
+        let result = match &mut *output {
+            Output::Stderr => io::stderr().write_all(format_line(record).as_bytes()),
+            Output::File(file) => file.write_line(&format_line(record)),
+            Output::Journald(socket) => socket.send(&journal_message(record)).map(|_| ()),
+        };
+        // Nowhere left to report it but stderr
+        if let Err(e) = result {
+            eprintln!("Could not write log line: {e:?}");
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(Output::File(file)) = self.output.lock().as_deref_mut() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+fn format_line(record: &Record) -> String {
+    format!(
+        "{} {:<5} [{}] {}\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// Syslog priority journald files `level` under.
+pub fn journal_priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Encodes `record` in journald's native protocol. Key values logged with the record, e.g.
+/// `log::info!(clip_path = path; "...")`, become fields of their own named in upper case.
+pub fn journal_message(record: &Record) -> Vec<u8> {
+    let mut message = Vec::new();
+    push_journal_field(
+        &mut message,
+        "PRIORITY",
+        &journal_priority(record.level()).to_string(),
+    );
+    push_journal_field(&mut message, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    push_journal_field(&mut message, "CODE_MODULE", record.target());
+    push_journal_field(&mut message, "MESSAGE", &record.args().to_string());
+
+    struct Fields<'a>(&'a mut Vec<u8>);
+    impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+            push_journal_field(
+                self.0,
+                &journal_field_name(key.as_str()),
+                &value.to_string(),
+            );
+            Ok(())
+        }
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut message));
+    message
+}
+
+/// Journald field names may only hold upper case letters, digits and underscores and can't
+/// start with an underscore, which is reserved for fields journald sets itself.
+fn journal_field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9') => c,
+            _ => '_',
+        })
+        .collect();
+    name.trim_start_matches('_').to_string()
+}
+
+/// Values spanning several lines are sent length prefixed, everything else as `NAME=value`.
+fn push_journal_field(message: &mut Vec<u8>, name: &str, value: &str) {
+    message.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+/// Log file which is moved to `<path>.1` once it reaches `max_bytes`, shifting the older ones to
+/// `<path>.2` and so on. Only the newest `max_files` rotated files are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, rotating it right away if it is already full.
+    pub fn open(path: &Path, max_bytes: u64, max_files: u32) -> io::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::options().create(true).append(true).open(path)?;
+        let mut rotating = Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_bytes,
+            max_files,
+        };
+        if rotating.is_full(0) {
+            rotating.rotate()?;
+        }
+        Ok(rotating)
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        // An empty file takes the line even if it is longer than the limit on its own
+        if self.size > 0 && self.is_full(line.len() as u64) {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn is_full(&self, incoming: u64) -> bool {
+        self.max_bytes > 0 && self.size + incoming > self.max_bytes
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs;
+
+use super::logging::*;
+
+#[test]
+fn test_log_file_rotates_once_full() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("logs.txt");
+    let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+
+    for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+        file.write_line(line).unwrap();
+    }
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+    assert_eq!(
+        fs::read_to_string(dir.path().join("logs.txt.1")).unwrap(),
+        "third\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("logs.txt.2")).unwrap(),
+        "second\n"
+    );
+    // Only max_files rotated files are kept
+    assert!(!dir.path().join("logs.txt.3").exists());
+}
+
+#[test]
+fn test_full_log_file_rotates_on_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("logs.txt");
+    fs::write(&path, "left over from last run\n").unwrap();
+
+    let mut file = RotatingFile::open(&path, 10, 1).unwrap();
+    file.write_line("new\n").unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+    assert_eq!(
+        fs::read_to_string(dir.path().join("logs.txt.1")).unwrap(),
+        "left over from last run\n"
+    );
+}
+
+#[test]
+fn test_log_file_without_limit_appends() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("logs.txt");
+    fs::write(&path, "earlier\n").unwrap();
+
+    let mut file = RotatingFile::open(&path, 0, 3).unwrap();
+    file.write_line("later\n").unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "earlier\nlater\n");
+    assert!(!dir.path().join("logs.txt.1").exists());
+}
+
+#[test]
+fn test_logging_config_from_toml() {
+    let config: LoggingConfig =
+        toml::from_str("level = \"debug\"\ndestination = \"stderr\"").unwrap();
+    assert_eq!(config.level, LogLevel::Debug);
+    assert_eq!(config.destination, Some(LogDestination::Stderr));
+    // Everything else keeps its default
+    assert_eq!(config.max_files, LoggingConfig::default().max_files);
+}
+
+fn journal_fields(message: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(message)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn test_journal_message_fields() {
+    let message = journal_message(
+        &log::Record::builder()
+            .level(log::Level::Warn)
+            .target("waycap::waycap")
+            .args(format_args!("Saved clip"))
+            .key_values(&[("clip_path", "/clips/clip_1.mp4"), ("mode", "Shadow")])
+            .build(),
+    );
+
+    assert_eq!(
+        journal_fields(&message),
+        vec![
+            "PRIORITY=4",
+            "SYSLOG_IDENTIFIER=waycap",
+            "CODE_MODULE=waycap::waycap",
+            "MESSAGE=Saved clip",
+            "CLIP_PATH=/clips/clip_1.mp4",
+            "MODE=Shadow",
+        ]
+    );
+}
+
+#[test]
+fn test_journal_multiline_values_are_length_prefixed() {
+    let message = journal_message(
+        &log::Record::builder()
+            .level(log::Level::Error)
+            .args(format_args!("two\nlines"))
+            .build(),
+    );
+
+    let mut expected = b"MESSAGE\n".to_vec();
+    expected.extend_from_slice(&9u64.to_le_bytes());
+    expected.extend_from_slice(b"two\nlines\n");
+    assert!(message.ends_with(&expected));
+}
+
+#[test]
+fn test_journal_priorities() {
+    assert_eq!(journal_priority(log::Level::Error), 3);
+    assert_eq!(journal_priority(log::Level::Info), 6);
+    assert_eq!(journal_priority(log::Level::Trace), 7);
+}
@@ -8,169 +8,237 @@
 
 mod app_context;
 mod application_config;
+mod audio_levels;
+#[cfg(test)]
+mod audio_levels_tests;
+mod capture_watch;
+#[cfg(test)]
+mod capture_watch_tests;
+mod cli;
+#[cfg(test)]
+mod cli_tests;
+mod clips;
+mod config_migration;
+#[cfg(test)]
+mod config_migration_tests;
 mod dbus;
+#[cfg(test)]
+mod dbus_tests;
+mod dbus_types;
+mod diagnostics;
+#[cfg(test)]
+mod diagnostics_tests;
 mod encoders;
+mod inhibit;
+mod instance;
+#[cfg(test)]
+mod instance_tests;
+mod logging;
+#[cfg(test)]
+mod logging_tests;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(all(test, feature = "metrics"))]
+mod metrics_tests;
 mod modes;
+mod portal;
+mod preview;
+#[cfg(test)]
+mod preview_tests;
+mod shortcuts;
+#[cfg(test)]
+mod shortcuts_tests;
+mod stats;
+#[cfg(test)]
+mod stats_tests;
+mod thread_priority;
+#[cfg(test)]
+mod thread_priority_tests;
 mod waycap;
+#[cfg(test)]
+mod waycap_tests;
+
+use std::{
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+    time::Instant,
+};
 
 use anyhow::{Context, Error, Result};
-use application_config::load_or_create_config;
-use encoders::buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer};
+use app_context::AppContext;
+use application_config::ConfigSource;
+use clap::Parser;
+use cli::Cli;
+use clips::{
+    events::{events_for, write_events, LoggedEvent},
+    markers::{write_sidecar, Chapter, Marker},
+    naming::{move_into_place, partial_path},
+    retention::remove_partial_files,
+};
+use encoders::{
+    buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer},
+    muxer::{ClipMuxer, ClipTags, ClipWindow, FileSink, SaveReport, StreamParams},
+};
 use ffmpeg_next::{self as ffmpeg};
-use modes::{app_mode_variant::AppModeVariant, shadow_cap::ShadowCapMode};
+use instance::InstanceLock;
+use modes::shadow_cap::ShadowCapMode;
 use pipewire::{self as pw};
 use waycap::WayCap;
-use waycap_rs::Capture;
-
-const VIDEO_STREAM: usize = 0;
-const AUDIO_STREAM: usize = 1;
 
 pub struct Terminate;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
+    let config_source = ConfigSource::new(cli.config, cli.overrides);
+    let (config, config_error) = match config_source.load() {
+        Ok(config) => (config, None),
+        Err(e) if cli.ignore_config_errors => {
+            eprintln!("{e:#}, starting with the defaults");
+            (config_source.defaults(), Some(e))
+        }
+        Err(e) => return Err(e.context("Fix the config or pass --ignore-config-errors")),
+    };
+    if cli.print_config {
+        print!("{}", toml::to_string_pretty(&config)?);
+        return Ok(());
+    }
+
+    logging::init(&config.logging);
+    if let Some(e) = config_error {
+        log::warn!("{e:#}, started with the defaults");
+    }
+    // Before the capture is built so a second instance never opens the screen share picker
+    let _lock = InstanceLock::acquire(&instance::lock_path())?;
+    if let Err(e) = remove_partial_files(&config.output_dir) {
+        log::warn!(
+            "Could not delete unfinished clips in {:?}: {e:#}",
+            config.output_dir
+        );
+    }
     pw::init();
     ffmpeg::init()?;
-    let config = load_or_create_config();
     log::debug!("Config: {config:?}");
-    let mode = AppModeVariant::Shadow(ShadowCapMode::new(config.max_seconds).await?);
+    let mode = Box::new(ShadowCapMode::new(&config).await?);
 
-    let mut app = WayCap::new(mode, config).await?;
+    let mut app = WayCap::new(mode, config, config_source).await?;
 
     app.run().await?;
     log::debug!("Shutdown successfully");
     Ok(())
 }
 
-fn save_buffer(
-    filename: &str,
-    video_buffer: &ShadowCaptureVideoBuffer,
-    audio_buffer: &ShadowCaptureAudioBuffer,
-    capture: &Capture,
-) -> Result<()> {
-    let mut output = ffmpeg::format::output(&filename)?;
-
-    capture.with_video_encoder(|enc| {
-        if let Some(encoder) = enc {
-            let video_codec = encoder.codec().unwrap();
-            let mut video_stream = output.add_stream(video_codec).unwrap();
-            video_stream.set_time_base(encoder.time_base());
-            video_stream.set_parameters(encoder);
-        }
-    });
-
-    capture.with_audio_encoder(|enc| {
-        if let Some(encoder) = enc {
-            let audio_codec = encoder.codec().unwrap();
-            let mut audio_stream = output.add_stream(audio_codec).unwrap();
-            audio_stream.set_time_base(encoder.time_base());
-            audio_stream.set_parameters(encoder);
-        }
-    });
-
-    output.write_header()?;
-
-    let last_keyframe = video_buffer
-        .get_last_gop_start()
-        .context("Could not get last keyframe dts")?;
-
-    let mut newest_video_pts = 0;
-    let audio_capture_timestamps = audio_buffer.get_capture_times();
-
-    // Write video
-    let mut first_pts_offset: i64 = 0;
-    let mut first_offset = false;
-    log::debug!("VIDEO SAVE START");
-    for (dts, frame_data) in video_buffer.get_frames().range(..=last_keyframe) {
-        // If video starts before audio try and catch up as much as possible
-        // (At worst a 20ms gap)
-        if audio_capture_timestamps[0] > frame_data.pts && !frame_data.is_keyframe {
-            log::debug!(
-                "Skipping Video Frame Captured at: {:?}, DTS: {:?}",
-                frame_data.pts,
-                dts,
-            );
-            continue;
-        }
+/// Stream parameters of the capture's video encoder.
+fn video_stream_params(ctx: &AppContext) -> Result<StreamParams> {
+    ctx.capture
+        .video_params()
+        .context("No video encoder to save with")
+}
 
-        if !first_offset {
-            first_pts_offset = frame_data.pts;
-            first_offset = true;
-        }
+/// Stream parameters of the capture's audio encoder, if it records audio.
+fn audio_stream_params(ctx: &AppContext) -> Option<StreamParams> {
+    if !ctx.has_audio {
+        return None;
+    }
+    ctx.capture.audio_params()
+}
 
-        let pts_offset = frame_data.pts - first_pts_offset;
-        let dts_offset = dts - first_pts_offset;
+/// A clip written by [`save_buffer`].
+pub struct SavedClip {
+    pub report: SaveReport,
+    /// Capture time of the first and last video frame of the clip, in microseconds.
+    pub start_time: i64,
+    pub end_time: i64,
+    pub chapters: Vec<Chapter>,
+}
 
-        let mut packet = ffmpeg::codec::packet::Packet::copy(&frame_data.data);
-        packet.set_pts(Some(pts_offset));
-        packet.set_dts(Some(dts_offset));
+/// The buffered footage [`save_buffer`] writes and what it is annotated with.
+pub struct ClipSource<'a> {
+    pub video_buffer: &'a ShadowCaptureVideoBuffer,
+    pub audio_buffer: &'a ShadowCaptureAudioBuffer,
+    pub markers: &'a [Marker],
+    pub events: &'a [LoggedEvent],
+}
 
-        packet.set_stream(VIDEO_STREAM);
+/// How [`save_buffer`] writes a clip.
+pub struct SaveSettings {
+    /// Stream parameters of the capture's encoders.
+    pub video: StreamParams,
+    pub audio: Option<StreamParams>,
+    pub window: ClipWindow,
+    pub audio_offset_ms: i32,
+    pub faststart: bool,
+    /// Dates the clip, see [`ClipTags`].
+    pub capture_epoch_ms: Option<i64>,
+    pub cancel: Arc<AtomicBool>,
+}
 
-        packet
-            .write_interleaved(&mut output)
-            .expect("Could not write video interleaved");
-        newest_video_pts = frame_data.pts;
-    }
-    log::debug!("VIDEO SAVE END");
-
-    // Write audio
-    let mut oldest_frame_offset = 0;
-    let mut first_offset = false;
-    log::debug!("AUDIO SAVE START");
-    let mut iter = 0;
-    for (pts, frame) in audio_buffer.get_frames() {
-        // Don't write any more audio if we would exceed video (clip to max video)
-        if audio_capture_timestamps[iter] > newest_video_pts {
-            log::debug!(
-                "Oldest capture time {:?}, in time scale: {:?}",
-                audio_capture_timestamps[iter],
-                pts
-            );
-            break;
+/// Saves the shadow buffers of `source` to `filename`. The clip is written to a partial file
+/// first which is only moved to `filename` once the trailer is written, never replacing a clip
+/// already there, and which is deleted if the mux fails or `settings.cancel` is set during the
+/// save. The events of `source` within the clip are written next to it.
+fn save_buffer(filename: &Path, source: ClipSource, settings: SaveSettings) -> Result<SavedClip> {
+    let started = Instant::now();
+
+    // The sink is dropped, closing the file, before it is renamed or removed
+    let partial = partial_path(filename);
+    let muxed = FileSink::create(&partial, settings.faststart).and_then(|mut sink| {
+        ClipMuxer::new(settings.video, settings.audio)
+            .with_window(settings.window)
+            .with_audio_offset(settings.audio_offset_ms)
+            .with_cancel(settings.cancel)
+            .with_tags(ClipTags::new(filename, settings.capture_epoch_ms))
+            .mux(
+                source.video_buffer,
+                source.audio_buffer,
+                source.markers,
+                &mut sink,
+            )
+    });
+    let plan = match muxed.and_then(|plan| {
+        move_into_place(&partial, filename)
+            .with_context(|| format!("Could not move the clip to {filename:?}"))?;
+        Ok(plan)
+    }) {
+        Ok(plan) => plan,
+        Err(e) => {
+            if let Err(e) = std::fs::remove_file(&partial) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::error!("Could not remove the partial clip {partial:?}: {e:?}");
+                }
+            }
+            return Err(e);
         }
-
-        // If audio starts before video try and catch up as much as possible
-        // (At worst a 20ms gap)
-        if audio_capture_timestamps[iter] < first_pts_offset {
-            log::debug!(
-                "Skipping Audio Frame due to capture time being: {:?} while first video pts is: {:?} pts: {:?}",
-                &audio_capture_timestamps[iter],
-                &first_pts_offset,
-                pts
-            );
-            iter += 1;
-            continue;
+    };
+    if !plan.chapters.is_empty() {
+        if let Err(e) = write_sidecar(filename, &plan.chapters) {
+            log::error!("Could not write the markers of {filename:?}: {e:?}");
         }
-
-        if !first_offset {
-            oldest_frame_offset = *pts;
-            first_offset = true;
+    }
+    let clip_events = events_for(source.events, plan.start_time, plan.end_time);
+    if !clip_events.is_empty() {
+        if let Err(e) = write_events(filename, &clip_events) {
+            log::error!("Could not write the events of {filename:?}: {e:?}");
         }
-
-        let offset = pts - oldest_frame_offset;
-
-        log::debug!(
-            "PTS IN MICROS: {:?}, PTS IN TIME SCALE: {:?}",
-            audio_capture_timestamps[iter],
-            offset
-        );
-
-        let mut packet = ffmpeg::codec::packet::Packet::copy(frame);
-        packet.set_pts(Some(offset));
-        packet.set_dts(Some(offset));
-
-        packet.set_stream(AUDIO_STREAM);
-
-        packet
-            .write_interleaved(&mut output)
-            .expect("Could not write audio interleaved");
-
-        iter += 1;
     }
-    log::debug!("AUDIO SAVE END");
 
-    output.write_trailer()?;
-
-    Ok(())
+    let report = SaveReport {
+        path: filename.display().to_string(),
+        save_duration_ms: started.elapsed().as_millis() as u64,
+        clip_duration_ms: (plan.clip_duration_micros() / 1000) as u64,
+        frame_rate: plan.frame_rate().map_or(0.0, f64::from),
+        video_frames: plan.video_frames() as u64,
+        audio_frames: plan.audio_frames() as u64,
+        bytes_on_disk: std::fs::metadata(filename)?.len(),
+        skipped_video_frames: plan.skipped_video_frames as u64,
+        skipped_audio_frames: plan.skipped_audio_frames as u64,
+        dropped_video_frames: 0,
+        dropped_audio_frames: 0,
+    };
+    Ok(SavedClip {
+        report,
+        start_time: plan.start_time,
+        end_time: plan.end_time,
+        chapters: plan.chapters,
+    })
 }
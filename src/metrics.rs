@@ -0,0 +1,158 @@
+//! Serves the GetStats counters in the Prometheus text exposition format.
+use std::{fmt::Write, sync::Arc};
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    dbus::AppStatus,
+    stats::{DropCounters, EncodeCounters, PipelineStats, SAVE_DURATION_BOUNDS},
+};
+
+/// The counters a scrape reads, the same ones `GetStats` returns.
+pub struct MetricsSource {
+    pub encode: Arc<EncodeCounters>,
+    pub drops: Arc<DropCounters>,
+    pub status_tx: mpsc::Sender<oneshot::Sender<AppStatus>>,
+}
+
+/// Answers every request on `address` with the current metrics until the process exits. Failing
+/// to bind is logged and leaves the capture running without metrics.
+pub async fn serve(address: String, source: MetricsSource) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::warn!("Could not serve metrics on {address}: {e}");
+            return;
+        }
+    };
+    log::info!("Serving metrics on http://{address}/metrics");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, &source).await {
+                    log::debug!("Could not answer a metrics request: {e:?}");
+                }
+            }
+            Err(e) => log::warn!("Could not accept a metrics connection: {e}"),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, source: &MetricsSource) -> Result<()> {
+    // Every path gets the metrics, the request only has to be read far enough to be answered
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request).await?;
+
+    let (status_tx, status_rx) = oneshot::channel();
+    source.status_tx.send(status_tx).await?;
+    let status = status_rx.await?;
+    let body = render(&source.encode.snapshot(), source.drops.totals(), &status);
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The exposition text for one scrape. `drops` are the video and audio frames dropped since
+/// startup.
+pub fn render(stats: &PipelineStats, drops: (u64, u64), status: &AppStatus) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+        let _ = writeln!(out, "# HELP waycap_{name} {help}");
+        let _ = writeln!(out, "# TYPE waycap_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "waycap_{name}{labels} {value}");
+        }
+    };
+
+    metric(
+        "frames_encoded_total",
+        "counter",
+        "Encoded video frames received from the capture.",
+        &[("", stats.video_frames_encoded.to_string())],
+    );
+    metric(
+        "frames_dropped_total",
+        "counter",
+        "Frames dropped because the shadow buffer could not keep up.",
+        &[
+            ("{stream=\"video\"}", drops.0.to_string()),
+            ("{stream=\"audio\"}", drops.1.to_string()),
+        ],
+    );
+    metric(
+        "buffer_seconds",
+        "gauge",
+        "Length of the footage in the shadow buffer.",
+        &[("", status.buffered_seconds.to_string())],
+    );
+    metric(
+        "buffer_bytes",
+        "gauge",
+        "Memory held by the shadow buffers.",
+        &[("", status.buffered_bytes.to_string())],
+    );
+    metric(
+        "last_frame_age_seconds",
+        "gauge",
+        "Time since the newest frame arrived, grows while the capture is frozen.",
+        &[
+            (
+                "{stream=\"video\"}",
+                (status.last_video_frame_age_ms as f64 / 1000.0).to_string(),
+            ),
+            (
+                "{stream=\"audio\"}",
+                (status.last_audio_frame_age_ms as f64 / 1000.0).to_string(),
+            ),
+        ],
+    );
+    metric(
+        "saves_total",
+        "counter",
+        "Clips saved successfully.",
+        &[("", stats.saves().to_string())],
+    );
+
+    let mut cumulative = 0;
+    let mut buckets: Vec<_> = SAVE_DURATION_BOUNDS
+        .iter()
+        .zip(stats.save_durations)
+        .map(|(bound, count)| {
+            cumulative += count;
+            (format!("_bucket{{le=\"{bound}\"}}"), cumulative.to_string())
+        })
+        .collect();
+    buckets.push((
+        "_bucket{le=\"+Inf\"}".to_string(),
+        stats.saves().to_string(),
+    ));
+    buckets.push(("_sum".to_string(), stats.save_duration_seconds.to_string()));
+    buckets.push(("_count".to_string(), stats.saves().to_string()));
+    let samples: Vec<_> = buckets
+        .iter()
+        .map(|(suffix, value)| (suffix.as_str(), value.clone()))
+        .collect();
+    metric(
+        "save_duration_seconds",
+        "histogram",
+        "Time taken to write a clip.",
+        &samples,
+    );
+
+    out
+}
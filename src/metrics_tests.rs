@@ -0,0 +1,42 @@
+use super::{dbus::AppStatus, metrics::*, stats::EncodeCounters};
+
+#[test]
+fn test_render_exposition() {
+    let counters = EncodeCounters::default();
+    counters.record_video_at(0, 1000, 0);
+    counters.record_save(800);
+    counters.record_save(12_000);
+    let status = AppStatus {
+        buffered_seconds: 42.5,
+        buffered_bytes: 1_048_576,
+        last_video_frame_age_ms: 2_500,
+        last_audio_frame_age_ms: 20,
+        ..Default::default()
+    };
+
+    let text = render(&counters.snapshot(), (3, 1), &status);
+    for line in [
+        "# TYPE waycap_frames_encoded_total counter",
+        "waycap_frames_encoded_total 1",
+        "waycap_frames_dropped_total{stream=\"video\"} 3",
+        "waycap_frames_dropped_total{stream=\"audio\"} 1",
+        "waycap_buffer_seconds 42.5",
+        "waycap_buffer_bytes 1048576",
+        "waycap_last_frame_age_seconds{stream=\"video\"} 2.5",
+        "waycap_last_frame_age_seconds{stream=\"audio\"} 0.02",
+        "waycap_saves_total 2",
+        "# TYPE waycap_save_duration_seconds histogram",
+        "waycap_save_duration_seconds_bucket{le=\"0.5\"} 0",
+        "waycap_save_duration_seconds_bucket{le=\"1\"} 1",
+        "waycap_save_duration_seconds_bucket{le=\"10\"} 1",
+        "waycap_save_duration_seconds_bucket{le=\"30\"} 2",
+        "waycap_save_duration_seconds_bucket{le=\"+Inf\"} 2",
+        "waycap_save_duration_seconds_sum 12.8",
+        "waycap_save_duration_seconds_count 2",
+    ] {
+        assert!(
+            text.lines().any(|l| l == line),
+            "{line:?} missing from\n{text}"
+        );
+    }
+}
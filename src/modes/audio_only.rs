@@ -0,0 +1,317 @@
+use std::{
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context};
+use async_trait::async_trait;
+
+use crate::{
+    app_context::AppContext,
+    application_config::{AppConfig, AppModeDbus},
+    audio_levels::LevelMeter,
+    audio_stream_params,
+    capture_watch::CaptureEvent,
+    clips::naming::audio_recording_path,
+    dbus::AppStatus,
+    encoders::{
+        muxer::{audio_only_extension, ClipWindow, FileSink, SaveReport},
+        recording::AudioRecorder,
+        streaming::LivePacket,
+    },
+};
+
+use super::{shadow_cap::ShadowCapMode, AppMode, CaptureFeed, WORKER_POLL_INTERVAL};
+
+/// Keeps only the audio of the capture, either buffered like shadow mode to be saved as clips or,
+/// with `audio_only_record`, recorded to a file for as long as the mode is active. waycap-rs
+/// can't build a capture without video, so the video is still encoded but dropped on arrival.
+pub struct AudioOnlyMode {
+    /// Buffers the audio, `None` when recording it instead.
+    shadow: Option<ShadowCapMode>,
+    worker: Option<JoinHandle<()>>,
+    /// Set by the record worker while it is writing, cleared if the recording failed.
+    recording: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl AppMode for AudioOnlyMode {
+    fn to_dbus(&self) -> AppModeDbus {
+        AppModeDbus::AudioOnly
+    }
+
+    async fn init(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        match &mut self.shadow {
+            Some(shadow) => shadow.init(ctx).await,
+            None => self.start_recording(ctx).await,
+        }
+    }
+
+    async fn on_save(&mut self, ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        self.shadow()?.on_save(ctx).await
+    }
+
+    async fn on_save_window(
+        &mut self,
+        ctx: &mut AppContext,
+        window: ClipWindow,
+    ) -> anyhow::Result<SaveReport> {
+        self.shadow()?.on_save_window(ctx, window).await
+    }
+
+    async fn on_shutdown(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        if let Some(shadow) = &mut self.shadow {
+            return shadow.on_shutdown(ctx).await;
+        }
+        // The worker writes the trailer once it sees the stop flag
+        ctx.stop.store(true, std::sync::atomic::Ordering::Release);
+        ctx.inhibitor.uninhibit().await;
+        Ok(())
+    }
+
+    async fn on_exit(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        if let Some(shadow) = &mut self.shadow {
+            return shadow.on_exit(ctx).await;
+        }
+        ctx.stop.store(true, std::sync::atomic::Ordering::Release);
+        ctx.capture.pause()?;
+        if let Some(worker) = self.worker.take() {
+            if let Err(e) = worker.join() {
+                log::error!("Error in audio record worker thread: {e:?}");
+            }
+        }
+        ctx.inhibitor.uninhibit().await;
+        Ok(())
+    }
+
+    async fn on_config_update(
+        &mut self,
+        ctx: &mut AppContext,
+        old: &AppConfig,
+        new: &AppConfig,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut pending = match &mut self.shadow {
+            Some(shadow) => shadow.on_config_update(ctx, old, new).await?,
+            None => {
+                let mut pending = old.fields_requiring_rebuild(new);
+                // The running recording keeps its settings, re-entering the mode picks these up
+                if old.output_dir != new.output_dir {
+                    pending.push("output_dir".to_string());
+                }
+                pending
+            }
+        };
+        if old.audio_only_record != new.audio_only_record {
+            pending.push("audio_only_record".to_string());
+        }
+        Ok(pending)
+    }
+
+    async fn add_marker(&mut self, ctx: &mut AppContext, label: String) -> anyhow::Result<u32> {
+        self.shadow()?.add_marker(ctx, label).await
+    }
+
+    async fn marker_window(
+        &mut self,
+        ctx: &mut AppContext,
+        marker_id: u32,
+        before_secs: u32,
+        after_secs: u32,
+    ) -> anyhow::Result<(ClipWindow, Duration)> {
+        self.shadow()?
+            .marker_window(ctx, marker_id, before_secs, after_secs)
+            .await
+    }
+
+    async fn recent_window(
+        &mut self,
+        ctx: &mut AppContext,
+        seconds: u32,
+    ) -> anyhow::Result<ClipWindow> {
+        self.shadow()?.recent_window(ctx, seconds).await
+    }
+
+    async fn set_recording(
+        &mut self,
+        _ctx: &mut AppContext,
+        _enabled: bool,
+    ) -> anyhow::Result<String> {
+        bail!("Audio-only mode records when audio_only_record is set, switch modes to stop it")
+    }
+
+    async fn on_pause(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        match &mut self.shadow {
+            Some(shadow) => shadow.on_pause(ctx).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn on_resume(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        match &mut self.shadow {
+            Some(shadow) => shadow.on_resume(ctx).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn on_capture_event(
+        &mut self,
+        ctx: &mut AppContext,
+        event: CaptureEvent,
+    ) -> anyhow::Result<()> {
+        match &mut self.shadow {
+            Some(shadow) => shadow.on_capture_event(ctx, event).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn on_tick(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        match &mut self.shadow {
+            Some(shadow) => shadow.on_tick(ctx).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn fill_status(&mut self, ctx: &mut AppContext, status: &mut AppStatus) {
+        match &mut self.shadow {
+            Some(shadow) => shadow.fill_status(ctx, status).await,
+            None => status.recording = self.recording.load(std::sync::atomic::Ordering::Acquire),
+        }
+    }
+}
+
+impl AudioOnlyMode {
+    pub async fn new(ctx: &AppContext) -> anyhow::Result<Self> {
+        ensure!(
+            ctx.has_audio,
+            "Audio-only mode needs audio, set audio = true in the config and restart WayCap"
+        );
+        let shadow = match ctx.config.audio_only_record {
+            true => None,
+            false => Some(ShadowCapMode::audio_only(&ctx.config).await?),
+        };
+        Ok(Self {
+            shadow,
+            worker: None,
+            recording: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// The buffering side of the mode, which clips and markers need.
+    fn shadow(&mut self) -> anyhow::Result<&mut ShadowCapMode> {
+        self.shadow.as_mut().context(
+            "Clips can't be saved while audio-only mode records, everything is already being \
+             recorded",
+        )
+    }
+
+    async fn start_recording(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        log::debug!("Initializing context for Audio Only Mode");
+        let audio = audio_stream_params(ctx).context("No audio encoder to record with")?;
+        std::fs::create_dir_all(&ctx.config.output_dir)?;
+        let path = audio_recording_path(
+            &ctx.config.output_dir,
+            chrono::Local::now().timestamp(),
+            audio_only_extension(&audio),
+        );
+        let sink = FileSink::create(&path, ctx.config.faststart)
+            .with_context(|| format!("Could not create {path:?}"))?;
+        let recorder = AudioRecorder::start(&audio, sink)
+            .with_context(|| format!("Could not start recording to {path:?}"))?;
+        log::info!("Recording audio to {path:?}");
+
+        self.recording
+            .store(true, std::sync::atomic::Ordering::Release);
+        self.worker = Some(Self::create_record_worker(
+            path,
+            recorder,
+            ctx.level_meter(),
+            CaptureFeed::new(ctx)?,
+            Arc::clone(&self.recording),
+        ));
+
+        ctx.start_capture()?;
+        ctx.inhibitor.inhibit("Recording the audio").await;
+        log::debug!("Successfully initialized Audio Only Mode");
+        Ok(())
+    }
+
+    fn create_record_worker(
+        path: PathBuf,
+        recorder: AudioRecorder<FileSink>,
+        mut meter: Option<LevelMeter>,
+        feed: CaptureFeed,
+        recording: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        let CaptureFeed {
+            video_recv,
+            audio_recv,
+            stop,
+            last_video_frame,
+            last_audio_frame,
+            encode,
+        } = feed;
+        std::thread::spawn(move || {
+            let mut recorder = Some(recorder);
+            loop {
+                if stop.load(std::sync::atomic::Ordering::Acquire) {
+                    while video_recv.try_recv().is_ok() {}
+                    while audio_recv.try_recv().is_ok() {}
+                    break;
+                }
+
+                // The video is only taken off the channel, its timestamp keeps the stall
+                // watchdog from restarting a capture which is running fine
+                crossbeam::channel::select! {
+                    recv(video_recv) -> frame => match frame {
+                        Ok(frame) => {
+                            last_video_frame.store(
+                                chrono::Local::now().timestamp_millis(),
+                                std::sync::atomic::Ordering::Release,
+                            );
+                            encode.record_video(frame.data.len(), video_recv.len());
+                        }
+                        // Nothing will arrive anymore, wait for the stop flag without spinning
+                        Err(_) => std::thread::sleep(WORKER_POLL_INTERVAL),
+                    },
+                    recv(audio_recv) -> frame => {
+                        let Ok(frame) = frame else {
+                            std::thread::sleep(WORKER_POLL_INTERVAL);
+                            continue;
+                        };
+                        last_audio_frame.store(
+                            chrono::Local::now().timestamp_millis(),
+                            std::sync::atomic::Ordering::Release,
+                        );
+                        encode.record_audio_queue(audio_recv.len());
+                        if let Some(meter) = &mut meter {
+                            meter.measure(&frame.data);
+                        }
+                        // Frames are still received after a failure so the capture doesn't back up
+                        let Some(active) = recorder.as_mut() else {
+                            continue;
+                        };
+                        if let Err(e) = active.push(&LivePacket::from(frame)) {
+                            log::error!("Stopping the recording of {path:?}, could not write to it: {e:?}");
+                            if let Some(failed) = recorder.take() {
+                                if let Err(e) = failed.finish() {
+                                    log::error!("Could not finish the recording: {e:?}");
+                                }
+                            }
+                            recording.store(false, std::sync::atomic::Ordering::Release);
+                        }
+                    },
+                    default(WORKER_POLL_INTERVAL) => {},
+                }
+            }
+
+            if let Some(recorder) = recorder.take() {
+                if let Err(e) = recorder.finish() {
+                    log::error!("Could not finish the recording of {path:?}: {e:?}");
+                }
+            }
+            recording.store(false, std::sync::atomic::Ordering::Release);
+        })
+    }
+}
@@ -0,0 +1,219 @@
+use std::{path::PathBuf, thread::JoinHandle, time::Duration};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+
+use crate::{
+    app_context::AppContext,
+    application_config::{AppConfig, AppModeDbus},
+    audio_stream_params,
+    capture_watch::CaptureEvent,
+    clips::naming::recording_path,
+    dbus::AppStatus,
+    encoders::{
+        frame_extract::GopSnapshot,
+        muxer::{ClipWindow, FileSink, SaveReport},
+        recording::PrerollRecorder,
+    },
+    video_stream_params,
+};
+
+use super::{shadow_cap::ShadowCapMode, AppMode};
+
+/// A recording started in hybrid mode, written by its own thread.
+struct ActiveRecording {
+    path: PathBuf,
+    writer: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Shadow capture which can also record. A recording starts with whatever the shadow buffer
+/// holds, so the file begins up to `max_seconds` before it was requested, and then continues
+/// live until stopped.
+pub struct HybridMode {
+    shadow: ShadowCapMode,
+    recording: Option<ActiveRecording>,
+}
+
+#[async_trait]
+impl AppMode for HybridMode {
+    fn to_dbus(&self) -> AppModeDbus {
+        AppModeDbus::Hybrid
+    }
+
+    async fn init(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.shadow.init(ctx).await
+    }
+
+    async fn on_save(&mut self, ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        // The capture keeps running through a full save, so it doesn't interrupt a recording
+        self.shadow.on_save(ctx).await
+    }
+
+    async fn on_save_window(
+        &mut self,
+        ctx: &mut AppContext,
+        window: ClipWindow,
+    ) -> anyhow::Result<SaveReport> {
+        self.shadow.on_save_window(ctx, window).await
+    }
+
+    async fn on_shutdown(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.finish_recording(ctx).await;
+        self.shadow.on_shutdown(ctx).await
+    }
+
+    async fn on_exit(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.finish_recording(ctx).await;
+        self.shadow.on_exit(ctx).await
+    }
+
+    async fn on_config_update(
+        &mut self,
+        ctx: &mut AppContext,
+        old: &AppConfig,
+        new: &AppConfig,
+    ) -> anyhow::Result<Vec<String>> {
+        self.shadow.on_config_update(ctx, old, new).await
+    }
+
+    async fn add_marker(&mut self, ctx: &mut AppContext, label: String) -> anyhow::Result<u32> {
+        self.shadow.add_marker(ctx, label).await
+    }
+
+    async fn marker_window(
+        &mut self,
+        ctx: &mut AppContext,
+        marker_id: u32,
+        before_secs: u32,
+        after_secs: u32,
+    ) -> anyhow::Result<(ClipWindow, Duration)> {
+        self.shadow
+            .marker_window(ctx, marker_id, before_secs, after_secs)
+            .await
+    }
+
+    async fn recent_window(
+        &mut self,
+        ctx: &mut AppContext,
+        seconds: u32,
+    ) -> anyhow::Result<ClipWindow> {
+        self.shadow.recent_window(ctx, seconds).await
+    }
+
+    async fn latest_gop(&mut self, ctx: &mut AppContext) -> anyhow::Result<GopSnapshot> {
+        self.shadow.latest_gop(ctx).await
+    }
+
+    async fn recent_video(
+        &mut self,
+        ctx: &mut AppContext,
+        seconds: u32,
+    ) -> anyhow::Result<(GopSnapshot, i64)> {
+        self.shadow.recent_video(ctx, seconds).await
+    }
+
+    async fn set_recording(
+        &mut self,
+        ctx: &mut AppContext,
+        enabled: bool,
+    ) -> anyhow::Result<String> {
+        if enabled {
+            self.start_recording(ctx).await
+        } else {
+            self.stop_recording(ctx).await
+        }
+    }
+
+    async fn on_pause(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.shadow.on_pause(ctx).await
+    }
+
+    async fn on_resume(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.shadow.on_resume(ctx).await
+    }
+
+    async fn on_capture_event(
+        &mut self,
+        ctx: &mut AppContext,
+        event: CaptureEvent,
+    ) -> anyhow::Result<()> {
+        self.shadow.on_capture_event(ctx, event).await
+    }
+
+    async fn on_tick(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.shadow.on_tick(ctx).await
+    }
+
+    async fn fill_status(&mut self, ctx: &mut AppContext, status: &mut AppStatus) {
+        self.shadow.fill_status(ctx, status).await;
+        status.recording = self
+            .recording
+            .as_ref()
+            .is_some_and(|recording| !recording.writer.is_finished());
+    }
+}
+
+impl HybridMode {
+    pub fn new(shadow: ShadowCapMode) -> Self {
+        Self {
+            shadow: shadow.with_clip_mode(AppModeDbus::Hybrid),
+            recording: None,
+        }
+    }
+
+    async fn start_recording(&mut self, ctx: &mut AppContext) -> anyhow::Result<String> {
+        if let Some(recording) = &self.recording {
+            bail!("Already recording to {}", recording.path.display());
+        }
+
+        let video = video_stream_params(ctx)?;
+        let audio = audio_stream_params(ctx);
+        std::fs::create_dir_all(&ctx.config.output_dir)?;
+        let path = recording_path(&ctx.config.output_dir, chrono::Local::now().timestamp(), 1);
+        let sink = FileSink::create(&path, ctx.config.faststart)
+            .with_context(|| format!("Could not create {path:?}"))?;
+
+        // Live packets queue up in the channel while the buffered footage is written
+        let (packets_tx, packets_rx) = crossbeam::channel::unbounded();
+        let (video_buffer, audio_buffer) = self.shadow.tap_frames(packets_tx).await;
+        let writer = std::thread::spawn(move || {
+            let mut recorder =
+                PrerollRecorder::start(&video, audio.as_ref(), &video_buffer, &audio_buffer, sink)?;
+            drop((video_buffer, audio_buffer));
+            for packet in packets_rx {
+                recorder.push(&packet)?;
+            }
+            recorder.finish()
+        });
+
+        ctx.inhibitor.inhibit("Recording the screen").await;
+        log::info!("Recording to {path:?}");
+        let path_name = path.display().to_string();
+        self.recording = Some(ActiveRecording { path, writer });
+        Ok(path_name)
+    }
+
+    async fn stop_recording(&mut self, ctx: &mut AppContext) -> anyhow::Result<String> {
+        let recording = self.recording.take().context("Not recording")?;
+        self.shadow.untap_frames();
+        ShadowCapMode::inhibit_if_configured(ctx).await;
+        let path = recording.path.display().to_string();
+        tokio::task::spawn_blocking(move || recording.writer.join())
+            .await?
+            .map_err(|e| anyhow::anyhow!("Recording thread panicked: {e:?}"))?
+            .with_context(|| format!("Could not finish the recording {path}"))?;
+
+        log::info!("Finished recording {path}");
+        Ok(path)
+    }
+
+    /// Stops a running recording, if any, making sure its trailer is written.
+    async fn finish_recording(&mut self, ctx: &mut AppContext) {
+        if self.recording.is_none() {
+            return;
+        }
+        if let Err(e) = self.stop_recording(ctx).await {
+            log::error!("{e:?}");
+        }
+    }
+}
@@ -1,11 +1,161 @@
-pub mod app_mode_variant;
+pub mod audio_only;
+pub mod hybrid;
+pub mod record;
+pub mod registry;
+#[cfg(test)]
+mod registry_tests;
 pub mod shadow_cap;
-use crate::app_context::AppContext;
-use anyhow::Result;
+pub mod stream;
+use crate::{
+    app_context::AppContext,
+    application_config::{AppConfig, AppModeDbus},
+    capture_watch::CaptureEvent,
+    dbus::AppStatus,
+    encoders::{
+        frame_extract::GopSnapshot,
+        muxer::{ClipWindow, SaveReport},
+    },
+    stats::EncodeCounters,
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use crossbeam::channel::Receiver;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI64},
+        Arc,
+    },
+    time::Duration,
+};
+use waycap_rs::types::{audio_frame::EncodedAudioFrame, video_frame::EncodedVideoFrame};
 
+/// A way of handling the capture. `WayCap` holds the running one as a `Box<dyn AppMode>`, which
+/// one to start for each [`AppModeDbus`] is looked up in the [`registry::ModeRegistry`]. Markers,
+/// screenshots, GIFs, streaming and recording fail unless a mode implements them.
+#[async_trait]
 pub trait AppMode: Send + 'static {
+    /// The identifier of the mode as sent over dbus.
+    fn to_dbus(&self) -> AppModeDbus;
     async fn init(&mut self, ctx: &mut AppContext) -> Result<()>;
-    async fn on_save(&mut self, ctx: &mut AppContext) -> Result<()>;
+    async fn on_save(&mut self, ctx: &mut AppContext) -> Result<SaveReport>;
+    /// Saves only `window` of the buffered footage, leaving the rest of it in place.
+    async fn on_save_window(
+        &mut self,
+        ctx: &mut AppContext,
+        window: ClipWindow,
+    ) -> Result<SaveReport>;
     async fn on_shutdown(&mut self, ctx: &mut AppContext) -> Result<()>;
     async fn on_exit(&mut self, ctx: &mut AppContext) -> Result<()>;
+    /// Applies a new configuration to the running mode. Returns the names of the fields which
+    /// could not be applied live and need a restart to take effect, by default the ones baked
+    /// into the capture pipeline.
+    async fn on_config_update(
+        &mut self,
+        _ctx: &mut AppContext,
+        old: &AppConfig,
+        new: &AppConfig,
+    ) -> Result<Vec<String>> {
+        Ok(old.fields_requiring_rebuild(new))
+    }
+    /// Records a marker at the current capture time and returns its id.
+    async fn add_marker(&mut self, _ctx: &mut AppContext, _label: String) -> Result<u32> {
+        bail!("Markers are only available in shadow and hybrid mode")
+    }
+    /// Works out the window around a marker and how long to wait until its end is captured.
+    async fn marker_window(
+        &mut self,
+        _ctx: &mut AppContext,
+        _marker_id: u32,
+        _before_secs: u32,
+        _after_secs: u32,
+    ) -> Result<(ClipWindow, Duration)> {
+        bail!("Markers are only available in shadow and hybrid mode")
+    }
+    /// Window covering the last `seconds` of buffered footage.
+    async fn recent_window(&mut self, _ctx: &mut AppContext, _seconds: u32) -> Result<ClipWindow> {
+        bail!("Clips can only be saved in shadow and hybrid mode")
+    }
+    /// Snapshot of the newest GOP to decode a screenshot from.
+    async fn latest_gop(&mut self, _ctx: &mut AppContext) -> Result<GopSnapshot> {
+        bail!("Screenshots are only available in shadow and hybrid mode")
+    }
+    /// Snapshot of the video buffered over the last `seconds` to make a GIF of, starting at the
+    /// key frame before them, and the capture time the GIF starts at.
+    async fn recent_video(
+        &mut self,
+        _ctx: &mut AppContext,
+        _seconds: u32,
+    ) -> Result<(GopSnapshot, i64)> {
+        bail!("GIFs are only available in shadow and hybrid mode")
+    }
+    /// Starts or stops pushing the capture to the configured stream URL.
+    async fn set_streaming(&mut self, _ctx: &mut AppContext, _enabled: bool) -> Result<()> {
+        bail!("Streaming is only available in stream mode")
+    }
+    /// Starts or stops a recording, returning the path of the recorded file.
+    async fn set_recording(&mut self, _ctx: &mut AppContext, _enabled: bool) -> Result<String> {
+        bail!("Recording is only available in hybrid mode")
+    }
+    /// Called once the capture was paused over dbus.
+    async fn on_pause(&mut self, _ctx: &mut AppContext) -> Result<()> {
+        Ok(())
+    }
+    /// Called right before the paused capture starts again.
+    async fn on_resume(&mut self, _ctx: &mut AppContext) -> Result<()> {
+        Ok(())
+    }
+    /// Called when the capture was restarted, lost, recovered or reconnected.
+    async fn on_capture_event(
+        &mut self,
+        _ctx: &mut AppContext,
+        _event: CaptureEvent,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Called about once a second from the run loop.
+    async fn on_tick(&mut self, _ctx: &mut AppContext) -> Result<()> {
+        Ok(())
+    }
+    /// Fills in the parts of the status which belong to the mode.
+    async fn fill_status(&mut self, _ctx: &mut AppContext, _status: &mut AppStatus) {}
+}
+
+impl std::fmt::Debug for dyn AppMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_dbus() {
+            AppModeDbus::Shadow => write!(f, "Shadow Capture Mode"),
+            AppModeDbus::Stream => write!(f, "Stream Mode"),
+            AppModeDbus::Record => write!(f, "Record Mode"),
+            AppModeDbus::Hybrid => write!(f, "Hybrid Mode"),
+            AppModeDbus::AudioOnly => write!(f, "Audio Only Mode"),
+        }
+    }
+}
+
+/// How long the mode workers wait for a frame before checking the stop flag again.
+pub const WORKER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The capture's frame channels and what a recording or streaming worker reports back to the
+/// run loop.
+pub struct CaptureFeed {
+    pub video_recv: Receiver<EncodedVideoFrame>,
+    pub audio_recv: Receiver<EncodedAudioFrame>,
+    pub stop: Arc<AtomicBool>,
+    pub last_video_frame: Arc<AtomicI64>,
+    pub last_audio_frame: Arc<AtomicI64>,
+    pub encode: Arc<EncodeCounters>,
+}
+
+impl CaptureFeed {
+    /// The receivers of the capture in `ctx` and its shared counters.
+    pub fn new(ctx: &mut AppContext) -> Result<Self> {
+        Ok(Self {
+            video_recv: ctx.capture.video_receiver(),
+            audio_recv: ctx.audio_receiver()?,
+            stop: Arc::clone(&ctx.stop),
+            last_video_frame: Arc::clone(&ctx.last_video_frame),
+            last_audio_frame: Arc::clone(&ctx.last_audio_frame),
+            encode: Arc::clone(&ctx.encode),
+        })
+    }
 }
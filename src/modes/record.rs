@@ -0,0 +1,267 @@
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    thread::JoinHandle,
+};
+
+use anyhow::bail;
+use async_trait::async_trait;
+
+use crate::{
+    app_context::AppContext,
+    application_config::{AppConfig, AppModeDbus},
+    audio_levels::LevelMeter,
+    audio_stream_params,
+    clips::naming::recording_path,
+    dbus::AppStatus,
+    encoders::{
+        muxer::{ClipWindow, FileSink, SaveReport, StreamParams},
+        recording::SegmentedRecorder,
+        streaming::LivePacket,
+    },
+    video_stream_params,
+};
+
+use super::{AppMode, CaptureFeed, WORKER_POLL_INTERVAL};
+
+/// Records the capture to disk for as long as the mode is active, split into segments of
+/// `segment_minutes`.
+pub struct RecordMode {
+    worker: Option<JoinHandle<()>>,
+    /// Set by the worker while it is writing, cleared if the recording failed.
+    recording: Arc<AtomicBool>,
+    /// Set by the worker when a segment can't be cut because no key frame arrives.
+    key_frame_wanted: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl AppMode for RecordMode {
+    fn to_dbus(&self) -> AppModeDbus {
+        AppModeDbus::Record
+    }
+
+    async fn init(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        log::debug!("Initializing context for Record Mode");
+        std::fs::create_dir_all(&ctx.config.output_dir)?;
+        let video = video_stream_params(ctx)?;
+        let audio = audio_stream_params(ctx);
+
+        self.recording
+            .store(true, std::sync::atomic::Ordering::Release);
+        self.worker = Some(Self::create_record_worker(
+            RecordSettings {
+                video,
+                audio,
+                meter: ctx.level_meter(),
+                config: ctx.config.clone(),
+            },
+            CaptureFeed::new(ctx)?,
+            Arc::clone(&self.recording),
+            Arc::clone(&self.key_frame_wanted),
+        ));
+
+        ctx.start_capture()?;
+        ctx.inhibitor.inhibit("Recording the screen").await;
+        log::debug!("Successfully initialized Record Mode");
+        Ok(())
+    }
+
+    async fn on_save(&mut self, _ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        bail!("Clips can't be saved in record mode, everything is already being recorded")
+    }
+
+    async fn on_save_window(
+        &mut self,
+        _ctx: &mut AppContext,
+        _window: ClipWindow,
+    ) -> anyhow::Result<SaveReport> {
+        bail!("Clips can't be saved in record mode, everything is already being recorded")
+    }
+
+    async fn on_shutdown(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        // The worker writes the trailer once it sees the stop flag
+        ctx.stop.store(true, std::sync::atomic::Ordering::Release);
+        ctx.inhibitor.uninhibit().await;
+        Ok(())
+    }
+
+    async fn on_exit(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        ctx.stop.store(true, std::sync::atomic::Ordering::Release);
+        ctx.capture.pause()?;
+        if let Some(worker) = self.worker.take() {
+            if let Err(e) = worker.join() {
+                log::error!("Error in record worker thread: {e:?}");
+            }
+        }
+        ctx.inhibitor.uninhibit().await;
+        Ok(())
+    }
+
+    async fn on_config_update(
+        &mut self,
+        _ctx: &mut AppContext,
+        old: &AppConfig,
+        new: &AppConfig,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut pending = old.fields_requiring_rebuild(new);
+        // The running recording keeps its settings, re-entering the mode picks these up
+        if old.segment_minutes != new.segment_minutes {
+            pending.push("segment_minutes".to_string());
+        }
+        if old.output_dir != new.output_dir {
+            pending.push("output_dir".to_string());
+        }
+        Ok(pending)
+    }
+
+    async fn recent_window(
+        &mut self,
+        _ctx: &mut AppContext,
+        _seconds: u32,
+    ) -> anyhow::Result<ClipWindow> {
+        bail!("Clips can't be saved in record mode, everything is already being recorded")
+    }
+
+    async fn set_recording(
+        &mut self,
+        _ctx: &mut AppContext,
+        _enabled: bool,
+    ) -> anyhow::Result<String> {
+        bail!("Record mode is always recording, switch modes to stop it")
+    }
+
+    async fn on_tick(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        if !self
+            .key_frame_wanted
+            .swap(false, std::sync::atomic::Ordering::AcqRel)
+        {
+            return Ok(());
+        }
+
+        // The capture has no way to request a single key frame, a fresh encoder starts with one
+        log::warn!("No key frame to cut the recording segment at, restarting the encoders");
+        ctx.capture.finish()?;
+        ctx.capture.reset()?;
+        ctx.start_capture()?;
+        ctx.last_video_frame.store(
+            chrono::Local::now().timestamp_millis(),
+            std::sync::atomic::Ordering::Release,
+        );
+        Ok(())
+    }
+
+    async fn fill_status(&mut self, _ctx: &mut AppContext, status: &mut AppStatus) {
+        status.recording = self.recording.load(std::sync::atomic::Ordering::Acquire);
+    }
+}
+
+/// What the record worker needs to open its segments.
+struct RecordSettings {
+    video: StreamParams,
+    audio: Option<StreamParams>,
+    meter: Option<LevelMeter>,
+    config: AppConfig,
+}
+
+impl RecordMode {
+    pub fn new() -> Self {
+        Self {
+            worker: None,
+            recording: Arc::new(AtomicBool::new(false)),
+            key_frame_wanted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn create_record_worker(
+        settings: RecordSettings,
+        feed: CaptureFeed,
+        recording: Arc<AtomicBool>,
+        key_frame_wanted: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        let CaptureFeed {
+            video_recv,
+            audio_recv,
+            stop,
+            last_video_frame,
+            last_audio_frame,
+            encode,
+        } = feed;
+        std::thread::spawn(move || {
+            let RecordSettings {
+                video,
+                audio,
+                mut meter,
+                config,
+            } = settings;
+            let started = chrono::Local::now().timestamp();
+            let mut recorder = Some(SegmentedRecorder::new(
+                video,
+                audio,
+                config.segment_minutes,
+                move |sequence| {
+                    let path = recording_path(&config.output_dir, started, sequence);
+                    log::info!("Recording to {path:?}");
+                    FileSink::create(&path, config.faststart)
+                },
+            ));
+
+            loop {
+                if stop.load(std::sync::atomic::Ordering::Acquire) {
+                    while video_recv.try_recv().is_ok() {}
+                    while audio_recv.try_recv().is_ok() {}
+                    break;
+                }
+
+                // Frames are still received after a failure so the capture doesn't back up
+                let packet = crossbeam::channel::select! {
+                    recv(video_recv) -> frame => frame.ok().map(|frame| {
+                        last_video_frame.store(
+                            chrono::Local::now().timestamp_millis(),
+                            std::sync::atomic::Ordering::Release,
+                        );
+                        encode.record_video(frame.data.len(), video_recv.len());
+                        LivePacket::from(frame)
+                    }),
+                    recv(audio_recv) -> frame => frame.ok().map(|frame| {
+                        last_audio_frame.store(
+                            chrono::Local::now().timestamp_millis(),
+                            std::sync::atomic::Ordering::Release,
+                        );
+                        encode.record_audio_queue(audio_recv.len());
+                        if let Some(meter) = &mut meter {
+                            meter.measure(&frame.data);
+                        }
+                        LivePacket::from(frame)
+                    }),
+                    default(WORKER_POLL_INTERVAL) => continue,
+                };
+                let (Some(packet), Some(active)) = (packet, recorder.as_mut()) else {
+                    // Either the recording failed or a receiver is gone, don't spin on it
+                    if recorder.is_some() {
+                        std::thread::sleep(WORKER_POLL_INTERVAL);
+                    }
+                    continue;
+                };
+
+                if let Err(e) = active.push(&packet) {
+                    log::error!("Stopping the recording, could not write to it: {e:?}");
+                    if let Err(e) = active.finish() {
+                        log::error!("Could not finish the recording: {e:?}");
+                    }
+                    recorder = None;
+                    recording.store(false, std::sync::atomic::Ordering::Release);
+                    continue;
+                }
+                if active.key_frame_overdue(packet.capture_time) {
+                    key_frame_wanted.store(true, std::sync::atomic::Ordering::Release);
+                }
+            }
+
+            if let Some(mut recorder) = recorder.take() {
+                if let Err(e) = recorder.finish() {
+                    log::error!("Could not finish the recording: {e:?}");
+                }
+            }
+            recording.store(false, std::sync::atomic::Ordering::Release);
+        })
+    }
+}
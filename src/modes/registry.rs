@@ -0,0 +1,96 @@
+//! Which [`AppMode`] to start for each [`AppModeDbus`], adding a mode only takes registering
+//! its constructor here.
+use std::{future::Future, pin::Pin};
+
+use anyhow::{Context, Result};
+
+use super::{
+    audio_only::AudioOnlyMode, hybrid::HybridMode, record::RecordMode, shadow_cap::ShadowCapMode,
+    stream::StreamMode, AppMode,
+};
+use crate::{app_context::AppContext, application_config::AppModeDbus};
+
+/// What a mode constructor returns, the mode ready to be initialized.
+pub type ModeFuture<'a> = Pin<Box<dyn Future<Output = Result<Box<dyn AppMode>>> + Send + 'a>>;
+
+type Constructor = Box<dyn for<'a> Fn(&'a AppContext) -> ModeFuture<'a> + Send + Sync>;
+
+pub struct ModeRegistry {
+    constructors: Vec<(AppModeDbus, Constructor)>,
+}
+
+impl ModeRegistry {
+    /// A registry without any modes.
+    pub fn empty() -> Self {
+        Self {
+            constructors: Vec::new(),
+        }
+    }
+
+    /// Makes `constructor` build the mode started for `mode`, replacing the one registered before.
+    pub fn register<F>(&mut self, mode: AppModeDbus, constructor: F)
+    where
+        F: for<'a> Fn(&'a AppContext) -> ModeFuture<'a> + Send + Sync + 'static,
+    {
+        self.constructors
+            .retain(|(registered, _)| *registered != mode);
+        self.constructors.push((mode, Box::new(constructor)));
+    }
+
+    #[cfg(test)]
+    pub fn is_registered(&self, mode: AppModeDbus) -> bool {
+        self.constructors
+            .iter()
+            .any(|(registered, _)| *registered == mode)
+    }
+
+    /// Builds the mode registered for `mode`. It is not initialized yet, that happens once the
+    /// current mode exited.
+    pub async fn create(&self, mode: AppModeDbus, ctx: &AppContext) -> Result<Box<dyn AppMode>> {
+        let (_, constructor) = self
+            .constructors
+            .iter()
+            .find(|(registered, _)| *registered == mode)
+            .with_context(|| format!("No mode is registered for {mode:?}"))?;
+        constructor(ctx).await
+    }
+}
+
+impl Default for ModeRegistry {
+    /// The modes WayCap comes with.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(AppModeDbus::Shadow, |ctx| {
+            Box::pin(async move {
+                let mode: Box<dyn AppMode> = Box::new(ShadowCapMode::new(&ctx.config).await?);
+                Ok(mode)
+            })
+        });
+        registry.register(AppModeDbus::Stream, |ctx| {
+            Box::pin(async move {
+                let mode: Box<dyn AppMode> = Box::new(StreamMode::new(ctx)?);
+                Ok(mode)
+            })
+        });
+        registry.register(AppModeDbus::Record, |_| {
+            Box::pin(async move {
+                let mode: Box<dyn AppMode> = Box::new(RecordMode::new());
+                Ok(mode)
+            })
+        });
+        registry.register(AppModeDbus::Hybrid, |ctx| {
+            Box::pin(async move {
+                let shadow = ShadowCapMode::new(&ctx.config).await?;
+                let mode: Box<dyn AppMode> = Box::new(HybridMode::new(shadow));
+                Ok(mode)
+            })
+        });
+        registry.register(AppModeDbus::AudioOnly, |ctx| {
+            Box::pin(async move {
+                let mode: Box<dyn AppMode> = Box::new(AudioOnlyMode::new(ctx).await?);
+                Ok(mode)
+            })
+        });
+        registry
+    }
+}
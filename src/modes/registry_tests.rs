@@ -0,0 +1,36 @@
+use super::registry::*;
+use crate::application_config::AppModeDbus;
+
+const ALL_MODES: [AppModeDbus; 5] = [
+    AppModeDbus::Shadow,
+    AppModeDbus::Stream,
+    AppModeDbus::Record,
+    AppModeDbus::Hybrid,
+    AppModeDbus::AudioOnly,
+];
+
+#[test]
+fn test_every_mode_is_registered() {
+    let registry = ModeRegistry::default();
+    for mode in ALL_MODES {
+        assert!(registry.is_registered(mode), "{mode:?} is not registered");
+    }
+
+    let registry = ModeRegistry::empty();
+    assert!(ALL_MODES.iter().all(|&mode| !registry.is_registered(mode)));
+}
+
+#[test]
+fn test_register_adds_only_that_mode() {
+    let mut registry = ModeRegistry::empty();
+    for _ in 0..2 {
+        registry.register(AppModeDbus::Record, |_| {
+            Box::pin(async { anyhow::bail!("not buildable in a test") })
+        });
+    }
+    assert!(registry.is_registered(AppModeDbus::Record));
+    assert!(!registry.is_registered(AppModeDbus::Shadow));
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ModeRegistry>();
+}
@@ -1,79 +1,215 @@
 use std::{
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicI64},
+        Arc,
+    },
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
-use crossbeam::channel::Receiver;
+use anyhow::Context;
+use async_trait::async_trait;
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
 use tokio::sync::Mutex;
 use waycap_rs::types::{audio_frame::EncodedAudioFrame, video_frame::EncodedVideoFrame};
 
 use crate::{
     app_context::AppContext,
-    encoders::buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer},
+    application_config::{AppConfig, AppConfigDbus, AppModeDbus},
+    audio_levels::LevelMeter,
+    audio_stream_params,
+    capture_watch::CaptureEvent,
+    clips::{
+        audio_events::{AudioEventDetector, AUDIO_PEAK_LABEL},
+        events::{EventKind, EventLog},
+        hooks::{post_save_argv, run_post_save},
+        index,
+        markers::Markers,
+        naming::{audio_clip_path, clip_path, mixed_quality_clip_path},
+        retention::prune_clips,
+    },
+    dbus::AppStatus,
+    dbus_types::{ClipInfo, ClipMarker},
+    encoders::{
+        buffer::{ShadowCaptureAudioBuffer, ShadowCaptureVideoBuffer},
+        frame_extract::GopSnapshot,
+        muxer::{audio_only_extension, ClipStreams, ClipWindow, SaveReport},
+        spool::{restore_spool, spool_path, write_spool, SpoolHeader, SpoolStreams},
+        staging::{StageOutcome, StagingQueue},
+        streaming::LivePacket,
+        tiering::spawn_reencoder,
+    },
+    preview::{Preview, PreviewTap},
     save_buffer,
+    stats::{DropCounters, DropWarning, EncodeCounters},
+    thread_priority::{pin_current_thread, spawn_mux_thread},
+    video_stream_params, ClipSource, SaveSettings, SavedClip,
 };
 
-use super::AppMode;
+use super::{AppMode, WORKER_POLL_INTERVAL};
+
+/// 1/`AUDIO_BUFFER_SHARE` of `max_buffer_mb` is reserved for the audio buffer. Opus is a tiny
+/// fraction of the video bitrate so this keeps the audio window at least as long as the video one.
+const AUDIO_BUFFER_SHARE: usize = 20;
+
+/// How long the save on shutdown may take before it is cancelled.
+const EXIT_SAVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a cancelled save on shutdown gets to stop before the application exits without it.
+const EXIT_CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many frames a shadow worker parks while its buffer is busy before dropping the oldest,
+/// about 10 seconds of video at 60 fps and of 20ms Opus frames.
+const VIDEO_STAGING_CAPACITY: usize = 600;
+const AUDIO_STAGING_CAPACITY: usize = 500;
+
+/// Where the shadow workers forward every frame they buffer while a recording is running.
+type FrameTap = Arc<std::sync::Mutex<Option<Sender<LivePacket>>>>;
+
+/// Capture times the audio worker found loud events at, waiting to be added as markers.
+type AutoMarkers = Arc<std::sync::Mutex<Vec<i64>>>;
+
+/// The event log, shared with the workers so they can record the frames they drop.
+type SharedEvents = Arc<std::sync::Mutex<EventLog>>;
 
 pub struct ShadowCapMode {
     video_buffer: Arc<Mutex<ShadowCaptureVideoBuffer>>,
     audio_buffer: Arc<Mutex<ShadowCaptureAudioBuffer>>,
     shadow_workers: Vec<JoinHandle<()>>,
+    markers: Markers,
+    auto_markers: AutoMarkers,
+    events: SharedEvents,
+    tap: FrameTap,
+    /// Set while the capture is paused, the workers then leave out the frames still in flight.
+    paused: Arc<AtomicBool>,
+    preview: Option<Preview>,
+    /// Mode recorded in the clip index, hybrid mode saves through this one too.
+    clip_mode: AppModeDbus,
+    /// Only the audio is buffered and saved, see [`Self::audio_only`].
+    audio_only: bool,
 }
 
+#[async_trait]
 impl AppMode for ShadowCapMode {
+    fn to_dbus(&self) -> AppModeDbus {
+        AppModeDbus::Shadow
+    }
+
     async fn init(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
         log::debug!("Initializing context for Shadow Capture Mode");
-        let video_owned_recv = ctx.capture.get_video_receiver();
+        if ctx.config.preview_stream && !self.audio_only {
+            // The clips don't depend on the preview, so it failing to start is not fatal
+            self.preview = video_stream_params(ctx)
+                .and_then(Preview::start)
+                .inspect_err(|e| log::error!("Could not start the preview stream: {e:#}"))
+                .ok();
+        }
+        self.paused
+            .store(ctx.paused, std::sync::atomic::Ordering::Release);
+        if ctx.config.persist_buffer && !self.audio_only {
+            self.load_spool(ctx).await;
+        }
+        let video_owned_recv = ctx.capture.video_receiver();
 
+        let shared = WorkerShared {
+            stop: Arc::clone(&ctx.stop),
+            paused: Arc::clone(&self.paused),
+            drops: Arc::clone(&ctx.drops),
+            encode: Arc::clone(&ctx.encode),
+            events: Arc::clone(&self.events),
+            tap: Arc::clone(&self.tap),
+            cpus: ctx.config.threads.worker_cpus(),
+        };
         let shadow_worker = Self::create_shadow_video_worker(
             video_owned_recv,
             Arc::clone(&self.video_buffer),
-            Arc::clone(&ctx.stop),
+            !self.audio_only,
+            Arc::clone(&ctx.last_video_frame),
+            self.preview.as_ref().map(Preview::tap),
+            shared.clone(),
         );
         self.shadow_workers.push(shadow_worker);
 
-        let audio_owned_recv = ctx.capture.get_audio_receiver()?;
+        let audio_owned_recv = ctx.audio_receiver()?;
 
+        let analysis = AudioAnalysis {
+            meter: ctx.level_meter(),
+            detector: ctx
+                .config
+                .audio_events
+                .enabled
+                .then(|| AudioEventDetector::new(&ctx.config.audio_events)),
+            auto_markers: Arc::clone(&self.auto_markers),
+        };
         let audio_shadow_worker = Self::create_shadow_audio_worker(
             audio_owned_recv,
             Arc::clone(&self.audio_buffer),
-            Arc::clone(&ctx.stop),
+            Arc::clone(&ctx.last_audio_frame),
+            analysis,
+            shared,
         );
         self.shadow_workers.push(audio_shadow_worker);
 
-        ctx.capture.start()?;
+        ctx.start_capture()?;
+        if ctx.config.tiering.enabled && !self.audio_only {
+            let reencoder = spawn_reencoder(
+                Arc::clone(&self.video_buffer),
+                video_stream_params(ctx)?,
+                ctx.config.tiering.clone(),
+                &ctx.config.threads,
+                Arc::clone(&ctx.stop),
+            )?;
+            self.shadow_workers.push(reencoder);
+        }
+        Self::inhibit_if_configured(ctx).await;
         log::debug!("Successfully initialized Shadow Capture Mode");
         Ok(())
     }
 
-    async fn on_save(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
-        ctx.saving.store(true, std::sync::atomic::Ordering::Release);
-        ctx.capture.finish()?;
-        log::info!("Saving clip...");
-
-        let (mut video_buffer, mut audio_buffer) =
-            tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
-        let filename = format!("clip_{}.mp4", chrono::Local::now().timestamp());
-
-        save_buffer(&filename, &video_buffer, &audio_buffer, &ctx.capture)?;
+    async fn on_save(&mut self, ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        Self::begin_save(ctx);
+        let result = self.save_and_reset(ctx).await;
+        // Cleared however the save went so a failure can't block config or mode changes
+        ctx.saving
+            .store(false, std::sync::atomic::Ordering::Release);
+        result
+    }
 
-        video_buffer.reset();
-        audio_buffer.reset();
-        ctx.capture.reset()?;
+    async fn on_save_window(
+        &mut self,
+        ctx: &mut AppContext,
+        window: ClipWindow,
+    ) -> anyhow::Result<SaveReport> {
+        // Only part of the buffer is saved, so unlike a full save the buffer is left intact
+        log::info!("Saving clip window {window:?}...");
+        Self::begin_save(ctx);
+        self.update_markers().await;
+        let (video_snapshot, audio_snapshot) = self.snapshot().await;
+        let result = self
+            .write_clip(ctx, window, video_snapshot, audio_snapshot)
+            .await;
         ctx.saving
             .store(false, std::sync::atomic::Ordering::Release);
-        ctx.capture.start()?;
+        let mut report = result?.report;
+        let drops = ctx.drops.snapshot();
+        report.dropped_video_frames = drops.video_frames_dropped;
+        report.dropped_audio_frames = drops.audio_frames_dropped;
 
-        log::info!("Done saving!");
-        Ok(())
+        log::info!("Done saving! {report:?}");
+        Ok(report)
     }
 
     async fn on_shutdown(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
         log::info!("Shutting down");
+        if ctx.config.save_on_exit {
+            self.save_on_exit(ctx).await;
+        }
+        if ctx.config.persist_buffer && !self.audio_only {
+            self.spool(ctx).await;
+        }
         // Stop processing new frames and exit worker threads
         ctx.stop.store(true, std::sync::atomic::Ordering::Release);
+        ctx.inhibitor.uninhibit().await;
         Ok(())
     }
 
@@ -89,73 +225,892 @@ impl AppMode for ShadowCapMode {
                 }
             }
         }
+        if let Some(preview) = self.preview.take() {
+            preview.stop();
+        }
+        ctx.inhibitor.uninhibit().await;
+        Ok(())
+    }
+
+    async fn on_config_update(
+        &mut self,
+        _ctx: &mut AppContext,
+        old: &AppConfig,
+        new: &AppConfig,
+    ) -> anyhow::Result<Vec<String>> {
+        if old.max_seconds != new.max_seconds {
+            let actual_max = Self::max_time_micros(new.max_seconds)?;
+            let (mut video_buffer, mut audio_buffer) =
+                tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+            video_buffer.set_max_time(actual_max);
+            audio_buffer.set_max_time(actual_max);
+            log::info!(
+                "Resized shadow buffers from {} to {} seconds",
+                old.max_seconds,
+                new.max_seconds
+            );
+        }
+
+        if old.max_buffer_mb != new.max_buffer_mb {
+            let (video_max_bytes, audio_max_bytes) =
+                Self::max_bytes(new.max_buffer_mb, self.audio_only);
+            let (mut video_buffer, mut audio_buffer) =
+                tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+            video_buffer.set_max_bytes(video_max_bytes);
+            audio_buffer.set_max_bytes(audio_max_bytes);
+            log::info!(
+                "Shadow buffer memory limit set to {:?} MB",
+                new.max_buffer_mb
+            );
+        }
+
+        // The capture pipeline is built once at startup so these need a restart
+        Ok(old.fields_requiring_rebuild(new))
+    }
+
+    async fn add_marker(&mut self, ctx: &mut AppContext, label: String) -> anyhow::Result<u32> {
+        let timestamp = self
+            .capture_now(ctx)
+            .await
+            .context("No footage has been buffered yet")?;
+
+        self.update_markers().await;
+        let id = self.markers.add(timestamp, label.clone());
+        record_event(
+            &self.events,
+            timestamp,
+            EventKind::Marker {
+                id,
+                label: label.clone(),
+                automatic: false,
+            },
+        );
+        log::info!("Added marker {id} {label:?} at {timestamp}");
+        Ok(id)
+    }
+
+    async fn marker_window(
+        &mut self,
+        ctx: &mut AppContext,
+        marker_id: u32,
+        before_secs: u32,
+        after_secs: u32,
+    ) -> anyhow::Result<(ClipWindow, Duration)> {
+        self.update_markers().await;
+        let marker = match self.markers.get(marker_id) {
+            Some(marker) => marker.clone(),
+            None => {
+                let history = self.buffered_range().await.map_or(0.0, |(oldest, newest)| {
+                    (newest - oldest) as f64 / 1_000_000.0
+                });
+                anyhow::bail!(
+                    "Marker {marker_id} is not in the buffer, only {history:.1} seconds of history remain"
+                );
+            }
+        };
+
+        let window = ClipWindow {
+            start: Some(marker.timestamp - before_secs as i64 * 1_000_000),
+            end: Some(marker.timestamp + after_secs as i64 * 1_000_000),
+            ..Default::default()
+        };
+        if let Some((oldest, _)) = self.buffered_range().await {
+            if window.start.is_some_and(|start| start < oldest) {
+                log::warn!(
+                    "Only {:.1} of the requested {before_secs} seconds before marker {marker_id} are still buffered",
+                    (marker.timestamp - oldest) as f64 / 1_000_000.0
+                );
+            }
+        }
+
+        let now = self.capture_now(ctx).await.unwrap_or(marker.timestamp);
+        let remaining = window.end.unwrap_or(now) - now;
+        let wait = Duration::from_micros(remaining.clamp(0, after_secs as i64 * 1_000_000) as u64);
+        Ok((window, wait))
+    }
+
+    async fn recent_window(
+        &mut self,
+        ctx: &mut AppContext,
+        seconds: u32,
+    ) -> anyhow::Result<ClipWindow> {
+        let now = self
+            .capture_now(ctx)
+            .await
+            .context("No footage has been buffered yet")?;
+        Ok(ClipWindow {
+            start: Some(now - seconds as i64 * 1_000_000),
+            ..Default::default()
+        })
+    }
+
+    async fn latest_gop(&mut self, ctx: &mut AppContext) -> anyhow::Result<GopSnapshot> {
+        if self.audio_only {
+            anyhow::bail!("Screenshots need video, only the audio is being buffered");
+        }
+        let video_buffer = self.video_buffer.lock().await;
+        let start = *video_buffer
+            .get_last_gop_start()
+            .context("No complete GOP has been buffered yet")?;
+        let frames = video_buffer
+            .get_frames()
+            .range(start..)
+            .map(|(&dts, frame)| (dts, frame.clone()))
+            .collect();
+        Ok(GopSnapshot::new(video_stream_params(ctx)?, frames))
+    }
+
+    async fn recent_video(
+        &mut self,
+        ctx: &mut AppContext,
+        seconds: u32,
+    ) -> anyhow::Result<(GopSnapshot, i64)> {
+        if self.audio_only {
+            anyhow::bail!("GIFs need video, only the audio is being buffered");
+        }
+        let video_buffer = self.video_buffer.lock().await;
+        let (oldest, newest) = video_buffer
+            .oldest_pts()
+            .zip(video_buffer.newest_pts())
+            .context("No footage has been buffered yet")?;
+        let start = (newest - seconds as i64 * 1_000_000).max(oldest);
+        // The frames from the key frame on are needed to decode the ones from `start`
+        let key_frame = video_buffer
+            .key_frame_at_or_before(start)
+            .or(video_buffer.get_last_gop_start().copied())
+            .context("No complete GOP has been buffered yet")?;
+        let frames = video_buffer
+            .get_frames()
+            .range(key_frame..)
+            .map(|(&dts, frame)| (dts, frame.clone()))
+            .collect();
+        Ok((GopSnapshot::new(video_stream_params(ctx)?, frames), start))
+    }
+
+    async fn on_pause(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.paused
+            .store(true, std::sync::atomic::Ordering::Release);
+        self.record_now(ctx, EventKind::Paused).await;
         Ok(())
     }
+
+    async fn on_resume(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.paused
+            .store(false, std::sync::atomic::Ordering::Release);
+        self.record_now(ctx, EventKind::Resumed).await;
+        Ok(())
+    }
+
+    async fn on_capture_event(
+        &mut self,
+        ctx: &mut AppContext,
+        event: CaptureEvent,
+    ) -> anyhow::Result<()> {
+        let kind = match event {
+            CaptureEvent::Restarted { stalled_seconds } => {
+                EventKind::CaptureRestarted { stalled_seconds }
+            }
+            CaptureEvent::Lost(loss) => EventKind::CaptureLost {
+                reason: loss.reason().to_string(),
+            },
+            CaptureEvent::Recovered => EventKind::CaptureRecovered,
+            CaptureEvent::Reconnected => EventKind::CaptureReconnected,
+        };
+        self.record_now(ctx, kind).await;
+        Ok(())
+    }
+
+    async fn fill_status(&mut self, _ctx: &mut AppContext, status: &mut AppStatus) {
+        self.update_markers().await;
+        status.buffered_seconds = self.buffered_range().await.map_or(0.0, |(oldest, newest)| {
+            (newest - oldest) as f64 / 1_000_000.0
+        });
+        let (video_buffer, audio_buffer) =
+            tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+        status.buffered_bytes = (video_buffer.size_bytes() + audio_buffer.size_bytes()) as u64;
+        status.marker_count = self.markers.len() as u32;
+        status.auto_marker_count = self.markers.automatic_len() as u32;
+    }
 }
 
 impl ShadowCapMode {
-    pub async fn new(max_seconds: u32) -> anyhow::Result<Self> {
+    pub async fn new(config: &AppConfig) -> anyhow::Result<Self> {
+        Self::build(config, false)
+    }
+
+    /// Buffers and saves only the audio. The video frames the capture still encodes are taken
+    /// off its channel and dropped, markers and the buffered length go by the audio instead.
+    pub async fn audio_only(config: &AppConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            clip_mode: AppModeDbus::AudioOnly,
+            ..Self::build(config, true)?
+        })
+    }
+
+    fn build(config: &AppConfig, audio_only: bool) -> anyhow::Result<Self> {
+        let actual_max = Self::max_time_micros(config.max_seconds)?;
+        let (video_max_bytes, audio_max_bytes) = Self::max_bytes(config.max_buffer_mb, audio_only);
+
+        let mut video_buffer = ShadowCaptureVideoBuffer::new(actual_max);
+        video_buffer.set_max_bytes(video_max_bytes);
+        let mut audio_buffer = ShadowCaptureAudioBuffer::new(actual_max);
+        audio_buffer.set_max_bytes(audio_max_bytes);
+
+        Ok(Self {
+            video_buffer: Arc::new(Mutex::new(video_buffer)),
+            audio_buffer: Arc::new(Mutex::new(audio_buffer)),
+            shadow_workers: Vec::new(),
+            markers: Markers::default(),
+            auto_markers: AutoMarkers::default(),
+            events: SharedEvents::default(),
+            tap: FrameTap::default(),
+            paused: Arc::default(),
+            preview: None,
+            clip_mode: AppModeDbus::Shadow,
+            audio_only,
+        })
+    }
+
+    pub fn with_clip_mode(mut self, mode: AppModeDbus) -> Self {
+        self.clip_mode = mode;
+        self
+    }
+
+    /// Starts forwarding every frame buffered from now on to `sender` and returns a snapshot of
+    /// what is buffered so far. The workers insert and forward under the buffer locks, so each
+    /// frame ends up in exactly one of the two.
+    pub async fn tap_frames(
+        &self,
+        sender: Sender<LivePacket>,
+    ) -> (ShadowCaptureVideoBuffer, ShadowCaptureAudioBuffer) {
+        let (video_buffer, audio_buffer) =
+            tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+        if let Ok(mut tap) = self.tap.lock() {
+            *tap = Some(sender);
+        }
+        (video_buffer.clone(), audio_buffer.clone())
+    }
+
+    /// Takes the suspend inhibitor if `inhibit_suspend_in_shadow` asks for it, otherwise releases
+    /// whatever inhibitor is held.
+    pub async fn inhibit_if_configured(ctx: &mut AppContext) {
+        if ctx.config.inhibit_suspend_in_shadow {
+            ctx.inhibitor.inhibit("Buffering the screen").await;
+        } else {
+            ctx.inhibitor.uninhibit().await;
+        }
+    }
+
+    /// Stops forwarding frames, which disconnects the receiving end of [`Self::tap_frames`].
+    pub fn untap_frames(&self) {
+        if let Ok(mut tap) = self.tap.lock() {
+            *tap = None;
+        }
+    }
+
+    /// Saves the whole buffer. The buffers are swapped for fresh ones holding just the GOP in
+    /// progress, which the clip ends on and the next one starts with, and the capture keeps
+    /// running into them while the taken footage is muxed. If anything fails the taken footage
+    /// is put back in front of what was buffered meanwhile and stays for another try.
+    async fn save_and_reset(&mut self, ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        log::info!("Saving clip...");
+        // Before the swap, which would drop the markers of the taken footage
+        self.update_markers().await;
+        let (video, audio) = {
+            let (mut video_buffer, mut audio_buffer) =
+                tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+            let video = video_buffer.take();
+            let keep_audio_from = match self.audio_only {
+                // Audio-only clips end on their last frame
+                true => i64::MAX,
+                // The clip takes the audio up to the key frame it ends on, shifted like the
+                // muxer does
+                false => video_buffer
+                    .get_frames()
+                    .first_key_value()
+                    .map_or(i64::MAX, |(_, frame)| {
+                        frame.pts - i64::from(ctx.config.audio_offset_ms) * 1000
+                    }),
+            };
+            (
+                Arc::new(video),
+                Arc::new(audio_buffer.take(keep_audio_from)),
+            )
+        };
+
+        let saved = match self
+            .write_clip(
+                ctx,
+                ClipWindow::default(),
+                Arc::clone(&video),
+                Arc::clone(&audio),
+            )
+            .await
+        {
+            Ok(saved) => saved,
+            Err(e) => {
+                let (mut video_buffer, mut audio_buffer) =
+                    tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+                video_buffer.restore(Arc::unwrap_or_clone(video));
+                audio_buffer.restore(Arc::unwrap_or_clone(audio));
+                return Err(e);
+            }
+        };
+        let mut report = saved.report;
+        // The buffers were swapped for this clip so the drops so far all fall within it
+        let drops = ctx.drops.take();
+        report.dropped_video_frames = drops.video_frames_dropped;
+        report.dropped_audio_frames = drops.audio_frames_dropped;
+        // Drops the markers of the saved footage, the ones after it carry over
+        self.update_markers().await;
+
+        log::info!("Done saving! {report:?}");
+        Ok(report)
+    }
+
+    /// Copies the buffers for a save which leaves them intact. Snapshots only bump the reference
+    /// counts of the encoded frames so the locks are held just long enough to clone the indexes,
+    /// not for the whole mux.
+    async fn snapshot(&self) -> (Arc<ShadowCaptureVideoBuffer>, Arc<ShadowCaptureAudioBuffer>) {
+        let (video_buffer, audio_buffer) =
+            tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+        (
+            Arc::new(video_buffer.clone()),
+            Arc::new(audio_buffer.clone()),
+        )
+    }
+
+    /// Saves `window` of the buffered footage to a new clip in the output directory.
+    async fn write_clip(
+        &mut self,
+        ctx: &mut AppContext,
+        window: ClipWindow,
+        video_snapshot: Arc<ShadowCaptureVideoBuffer>,
+        audio_snapshot: Arc<ShadowCaptureAudioBuffer>,
+    ) -> anyhow::Result<SavedClip> {
+        let window = match (self.audio_only, window.streams) {
+            (false, _) => window,
+            (true, ClipStreams::VideoOnly) => {
+                anyhow::bail!("A video-only clip can't be saved, only the audio is being buffered")
+            }
+            (true, _) => ClipWindow {
+                streams: ClipStreams::AudioOnly,
+                ..window
+            },
+        };
+        let markers: Vec<_> = self.markers.markers().iter().cloned().collect();
+        let events: Vec<_> = self
+            .events
+            .lock()
+            .map(|events| events.events().iter().cloned().collect())
+            .unwrap_or_default();
+        std::fs::create_dir_all(&ctx.config.output_dir)?;
+        let timestamp = chrono::Local::now().timestamp_millis();
+        let filename = if window.streams == ClipStreams::AudioOnly {
+            let audio = audio_stream_params(ctx)
+                .context("An audio-only clip can't be saved, no audio is being captured")?;
+            audio_clip_path(
+                &ctx.config.output_dir,
+                timestamp,
+                audio_only_extension(&audio),
+            )
+        } else if video_snapshot.has_reencoded_frames() {
+            mixed_quality_clip_path(&ctx.config.output_dir, timestamp)
+        } else {
+            clip_path(&ctx.config.output_dir, timestamp)
+        };
+        log::debug!(
+            "Buffered {} bytes of video and {} bytes of audio",
+            video_snapshot.size_bytes(),
+            audio_snapshot.size_bytes()
+        );
+
+        // Maps capture times to the wall clock for the clip's tags and the clip index
+        let newest_frame_ms = self
+            .last_frame(ctx)
+            .load(std::sync::atomic::Ordering::Acquire);
+        let newest_capture = match self.audio_only {
+            true => audio_snapshot.get_capture_times().back().copied(),
+            false => video_snapshot.newest_pts(),
+        };
+        let capture_epoch_ms = newest_capture.map(|newest| newest_frame_ms - newest / 1000);
+
+        // The mux gets a thread of its own so it can run at a lower priority than the capture
+        let settings = SaveSettings {
+            video: video_stream_params(ctx)?,
+            audio: audio_stream_params(ctx),
+            window,
+            audio_offset_ms: ctx.config.audio_offset_ms,
+            faststart: ctx.config.faststart,
+            capture_epoch_ms,
+            cancel: Arc::clone(&ctx.cancel_save),
+        };
+        let mux_filename = filename.clone();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        spawn_mux_thread("waycap-mux", &ctx.config.threads, move || {
+            let source = ClipSource {
+                video_buffer: &video_snapshot,
+                audio_buffer: &audio_snapshot,
+                markers: &markers,
+                events: &events,
+            };
+            let _ = done_tx.send(save_buffer(&mux_filename, source, settings));
+        })?;
+        let saved = done_rx.await.context("The mux thread panicked")??;
+
+        let started_at_ms =
+            capture_epoch_ms.map_or(newest_frame_ms, |epoch| epoch + saved.start_time / 1000);
+        let settings = AppConfigDbus::from(&ctx.config);
+        let record = ClipInfo {
+            path: saved.report.path.clone(),
+            saved_at_ms: chrono::Local::now().timestamp_millis(),
+            started_at_ms,
+            duration_ms: saved.report.clip_duration_ms,
+            bytes_on_disk: saved.report.bytes_on_disk,
+            mode: format!("{:?}", self.clip_mode),
+            encoder: settings.encoder,
+            quality: settings.quality,
+            markers: saved
+                .chapters
+                .iter()
+                .map(|chapter| ClipMarker {
+                    label: chapter.title.clone(),
+                    offset_ms: (chapter.start / 1000).max(0) as u64,
+                })
+                .collect(),
+        };
+        if let Err(e) = index::append(&ctx.config.output_dir, &record) {
+            log::error!("Could not add {filename:?} to the clip index: {e:?}");
+        }
+
+        if let Some(command) = &ctx.config.post_save_command {
+            match post_save_argv(command, ctx.config.post_save_shell, &filename) {
+                Ok(argv) => {
+                    let timeout = Duration::from_secs(ctx.config.post_save_timeout_seconds as u64);
+                    tokio::spawn(run_post_save(argv, timeout));
+                }
+                Err(e) => log::error!("Invalid post_save_command {command:?}: {e:?}"),
+            }
+        }
+
+        if let Some(retention) = ctx.config.retention.clone() {
+            let output_dir = ctx.config.output_dir.clone();
+            tokio::task::spawn_blocking(move || {
+                match prune_clips(&output_dir, &retention, SystemTime::now()) {
+                    Ok(deleted) if !deleted.is_empty() => {
+                        if let Err(e) = index::remove(&output_dir, &deleted) {
+                            log::error!("Could not drop the pruned clips from the index: {e:?}");
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::error!("Could not prune old clips in {output_dir:?}: {e:?}"),
+                }
+            });
+        }
+        Ok(saved)
+    }
+
+    /// Flags a save as running. A cancel left over from an earlier save is cleared first so only
+    /// the ones sent from here on apply.
+    fn begin_save(ctx: &AppContext) {
+        ctx.cancel_save
+            .store(false, std::sync::atomic::Ordering::Release);
+        ctx.saving.store(true, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Best estimate of the current capture time. Frames are stamped by the capture's own clock
+    /// so this is the newest frame plus the time since it arrived.
+    async fn capture_now(&self, ctx: &AppContext) -> Option<i64> {
+        let (_, newest) = self.buffered_range().await?;
+        let since_last_frame = chrono::Local::now().timestamp_millis()
+            - self
+                .last_frame(ctx)
+                .load(std::sync::atomic::Ordering::Acquire);
+        Some(newest + since_last_frame.max(0) * 1000)
+    }
+
+    /// Capture times of the oldest and newest frame of the stream clips are cut by, the video or
+    /// in audio-only mode the audio.
+    async fn buffered_range(&self) -> Option<(i64, i64)> {
+        if self.audio_only {
+            let audio_buffer = self.audio_buffer.lock().await;
+            let capture_times = audio_buffer.get_capture_times();
+            Some((*capture_times.front()?, *capture_times.back()?))
+        } else {
+            let video_buffer = self.video_buffer.lock().await;
+            Some((video_buffer.oldest_pts()?, video_buffer.newest_pts()?))
+        }
+    }
+
+    /// When the newest frame of the stream [`Self::buffered_range`] goes by arrived.
+    fn last_frame<'a>(&self, ctx: &'a AppContext) -> &'a AtomicI64 {
+        match self.audio_only {
+            true => &ctx.last_audio_frame,
+            false => &ctx.last_video_frame,
+        }
+    }
+
+    /// Adds an event at the current capture time, if anything was buffered to tell it from.
+    async fn record_now(&self, ctx: &AppContext, kind: EventKind) {
+        if let Some(timestamp) = self.capture_now(ctx).await {
+            record_event(&self.events, timestamp, kind);
+        }
+    }
+
+    /// Adds the markers found by the audio event detector and drops the markers and events whose
+    /// footage was trimmed from the buffer.
+    async fn update_markers(&mut self) {
+        let found = self
+            .auto_markers
+            .lock()
+            .map(|mut found| std::mem::take(&mut *found))
+            .unwrap_or_default();
+        for timestamp in found {
+            let id = self
+                .markers
+                .add_automatic(timestamp, AUDIO_PEAK_LABEL.to_string());
+            record_event(
+                &self.events,
+                timestamp,
+                EventKind::Marker {
+                    id,
+                    label: AUDIO_PEAK_LABEL.to_string(),
+                    automatic: true,
+                },
+            );
+            log::info!("Added marker {id} {AUDIO_PEAK_LABEL:?} at {timestamp}");
+        }
+        if let Some((oldest, _)) = self.buffered_range().await {
+            self.markers.trim_before(oldest);
+            if let Ok(mut events) = self.events.lock() {
+                events.trim_before(oldest);
+            }
+        }
+    }
+
+    /// Saves the buffer one last time, skipping it if there is no complete GOP to save. A save
+    /// still running after [`EXIT_SAVE_TIMEOUT`] is cancelled, which removes its partial clip, and
+    /// given [`EXIT_CANCEL_TIMEOUT`] more to stop before shutdown carries on without it.
+    async fn save_on_exit(&mut self, ctx: &mut AppContext) {
+        let empty = match self.audio_only {
+            true => self.audio_buffer.lock().await.get_frames().is_empty(),
+            false => self
+                .video_buffer
+                .lock()
+                .await
+                .get_last_gop_start()
+                .is_none(),
+        };
+        if empty {
+            log::debug!("Shadow buffer is empty, skipping save on exit");
+            return;
+        }
+
+        log::info!("Saving shadow buffer before exiting");
+        let cancel = Arc::clone(&ctx.cancel_save);
+        let output_dir = ctx.config.output_dir.clone();
+        let mut save = self.on_save(ctx);
+        let saved = match tokio::time::timeout(EXIT_SAVE_TIMEOUT, &mut save).await {
+            Ok(saved) => saved,
+            Err(_) => {
+                log::error!(
+                    "Save on exit did not finish within {EXIT_SAVE_TIMEOUT:?}, cancelling it"
+                );
+                cancel.store(true, std::sync::atomic::Ordering::Release);
+                // The save is left waiting on the mux thread, which stops at its next packet
+                match tokio::time::timeout(EXIT_CANCEL_TIMEOUT, &mut save).await {
+                    Ok(saved) => saved,
+                    Err(_) => {
+                        log::error!(
+                            "Save on exit did not stop within {EXIT_CANCEL_TIMEOUT:?} of being \
+                             cancelled, exiting without it. Its partial clip in {output_dir:?} \
+                             is deleted on the next start"
+                        );
+                        return;
+                    }
+                }
+            }
+        };
+        match saved {
+            Ok(report) => log::info!("Save on exit succeeded, clip written to {}", report.path),
+            Err(e) => log::error!("Save on exit failed: {e:?}"),
+        }
+    }
+
+    /// Writes the buffers to the spool for the next start to pick up.
+    async fn spool(&self, ctx: &AppContext) {
+        let streams = match Self::spool_streams(ctx) {
+            Ok(streams) => streams,
+            Err(e) => {
+                log::error!("Could not spool the shadow buffer: {e:#}");
+                return;
+            }
+        };
+        let (video_snapshot, audio_snapshot) = {
+            let (video_buffer, audio_buffer) =
+                tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+            (video_buffer.clone(), audio_buffer.clone())
+        };
+        if video_snapshot.get_last_gop_start().is_none() {
+            log::debug!("Shadow buffer is empty, nothing to spool");
+            return;
+        }
+
+        let path = spool_path();
+        let started = std::time::Instant::now();
+        let header = SpoolHeader::new(streams);
+        let spool_to = path.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            write_spool(&spool_to, &header, &video_snapshot, &audio_snapshot)
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => log::info!(
+                "Spooled the shadow buffer to {path:?} in {:?}",
+                started.elapsed()
+            ),
+            Ok(Err(e)) => log::error!("{e:#}"),
+            Err(e) => log::error!("The spool thread panicked: {e:?}"),
+        }
+    }
+
+    /// Loads the buffer spooled by the previous run, if it fits the current capture.
+    async fn load_spool(&self, ctx: &AppContext) {
+        let streams = match Self::spool_streams(ctx) {
+            Ok(streams) => streams,
+            Err(e) => {
+                log::warn!("Not loading the spooled shadow buffer: {e:#}");
+                return;
+            }
+        };
+        let path = spool_path();
+        let (mut video_buffer, mut audio_buffer) =
+            tokio::join!(self.video_buffer.lock(), self.audio_buffer.lock());
+        if let Some((video_frames, audio_frames)) =
+            restore_spool(&path, &streams, &mut video_buffer, &mut audio_buffer)
+        {
+            log::info!(
+                "Loaded {video_frames} video and {audio_frames} audio frames spooled by the last run"
+            );
+        }
+    }
+
+    fn spool_streams(ctx: &AppContext) -> anyhow::Result<SpoolStreams> {
+        let video = video_stream_params(ctx)?;
+        SpoolStreams::of(&video, audio_stream_params(ctx).as_ref())
+    }
+
+    /// Splits the configured memory limit into the (video, audio) byte limits.
+    fn max_bytes(max_buffer_mb: Option<u32>, audio_only: bool) -> (Option<usize>, Option<usize>) {
+        match max_buffer_mb {
+            // The audio gets all of it when nothing else is buffered
+            Some(mb) if audio_only => (None, Some(mb as usize * 1024 * 1024)),
+            Some(mb) => {
+                let total = mb as usize * 1024 * 1024;
+                let audio = total / AUDIO_BUFFER_SHARE;
+                (Some(total - audio), Some(audio))
+            }
+            None => (None, None),
+        }
+    }
+
+    fn max_time_micros(max_seconds: u32) -> anyhow::Result<usize> {
         anyhow::ensure!(
             max_seconds <= 86400,
             "Max seconds is above 24 hours. This is too much time for shadow capture"
         );
 
-        let actual_max = max_seconds * 1_000_000_u32;
-        Ok(Self {
-            video_buffer: Arc::new(Mutex::new(ShadowCaptureVideoBuffer::new(
-                actual_max as usize,
-            ))),
-            audio_buffer: Arc::new(Mutex::new(ShadowCaptureAudioBuffer::new(
-                actual_max as usize,
-            ))),
-            shadow_workers: Vec::new(),
-        })
+        Ok(max_seconds as usize * 1_000_000)
     }
 
     fn create_shadow_video_worker(
         recv: Receiver<EncodedVideoFrame>,
         buffer: Arc<Mutex<ShadowCaptureVideoBuffer>>,
-        stop: Arc<AtomicBool>,
+        buffer_video: bool,
+        last_video_frame: Arc<AtomicI64>,
+        preview: Option<PreviewTap>,
+        shared: WorkerShared,
     ) -> std::thread::JoinHandle<()> {
-        std::thread::spawn(move || loop {
-            if stop.load(std::sync::atomic::Ordering::Acquire) {
-                while recv.try_recv().is_ok() {} // Drain any remaining frames to avoid error
-                                                 // logging
-                break;
-            }
+        let WorkerShared {
+            stop,
+            paused,
+            drops,
+            encode,
+            events,
+            tap,
+            cpus,
+        } = shared;
+        std::thread::spawn(move || {
+            pin_current_thread(&cpus);
+            let mut drop_warning = DropWarning::new("video");
+            let mut staging = StagingQueue::new(VIDEO_STAGING_CAPACITY);
+            let insert = |buf: &mut ShadowCaptureVideoBuffer, frame: EncodedVideoFrame| {
+                forward(&tap, || LivePacket::copy_video(&frame));
+                buf.insert(frame.dts, frame)
+            };
+            loop {
+                if stop.load(std::sync::atomic::Ordering::Acquire) {
+                    while recv.try_recv().is_ok() {} // Drain any remaining frames to avoid error
+                                                     // logging
+                    break;
+                }
 
-            while let Ok(encoded_frame) = recv.try_recv() {
-                // Still receive but discard any frames received if we cannot acquire the lock
-                if let Ok(mut buf) = buffer.try_lock() {
-                    buf.insert(encoded_frame.dts, encoded_frame);
+                match recv.recv_timeout(WORKER_POLL_INTERVAL) {
+                    // Whatever was encoded before the capture paused stays out of the buffer
+                    Ok(_) if paused.load(std::sync::atomic::Ordering::Acquire) => {}
+                    Ok(encoded_frame) => {
+                        last_video_frame.store(
+                            chrono::Local::now().timestamp_millis(),
+                            std::sync::atomic::Ordering::Release,
+                        );
+                        encode.record_video(encoded_frame.data.len(), recv.len());
+                        // Audio-only mode keeps the frames flowing but has no use for them
+                        if !buffer_video {
+                            continue;
+                        }
+                        if let Some(preview) = &preview {
+                            preview.offer(&encoded_frame);
+                        }
+                        let dts = encoded_frame.dts;
+                        match staging.insert_or_stage(&buffer, encoded_frame, insert) {
+                            StageOutcome::Inserted => {}
+                            StageOutcome::Staged => drops.record_video_staged(),
+                            StageOutcome::StagedDroppingOldest => {
+                                drops.record_video_staged();
+                                drops.record_video();
+                                drop_warning.record();
+                                record_event(&events, dts, EventKind::VideoFrameDropped);
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        staging.flush(&buffer, insert);
+                    }
+                    // Nothing will arrive anymore, wait for the stop flag without spinning
+                    Err(RecvTimeoutError::Disconnected) => std::thread::sleep(WORKER_POLL_INTERVAL),
                 }
+                drop_warning.check();
             }
-
-            std::thread::sleep(Duration::from_millis(100));
         })
     }
 
     fn create_shadow_audio_worker(
         recv: Receiver<EncodedAudioFrame>,
         audio_buffer: Arc<Mutex<ShadowCaptureAudioBuffer>>,
-        stop: Arc<AtomicBool>,
+        last_audio_frame: Arc<AtomicI64>,
+        analysis: AudioAnalysis,
+        shared: WorkerShared,
     ) -> std::thread::JoinHandle<()> {
-        std::thread::spawn(move || loop {
-            if stop.load(std::sync::atomic::Ordering::Acquire) {
-                while recv.try_recv().is_ok() {} // Drain any remaining frames to avoid error
-                                                 // logging
-                break;
-            }
+        let WorkerShared {
+            stop,
+            paused,
+            drops,
+            encode,
+            events,
+            tap,
+            cpus,
+        } = shared;
+        let AudioAnalysis {
+            mut meter,
+            mut detector,
+            auto_markers,
+        } = analysis;
+        std::thread::spawn(move || {
+            pin_current_thread(&cpus);
+            let mut drop_warning = DropWarning::new("audio");
+            let mut staging = StagingQueue::new(AUDIO_STAGING_CAPACITY);
+            let insert = |buf: &mut ShadowCaptureAudioBuffer, frame: EncodedAudioFrame| {
+                forward(&tap, || LivePacket::copy_audio(&frame));
+                buf.insert_capture_time(frame.timestamp);
+                buf.insert(frame.pts, frame.data);
+            };
+            loop {
+                if stop.load(std::sync::atomic::Ordering::Acquire) {
+                    while recv.try_recv().is_ok() {} // Drain any remaining frames to avoid error
+                                                     // logging
+                    break;
+                }
 
-            while let Ok(encoded_frame) = recv.try_recv() {
-                // Still receive but discard any frames received if we cannot acquire the lock
-                if let Ok(mut buf) = audio_buffer.try_lock() {
-                    buf.insert_capture_time(encoded_frame.timestamp);
-                    buf.insert(encoded_frame.pts, encoded_frame.data);
+                match recv.recv_timeout(WORKER_POLL_INTERVAL) {
+                    Ok(_) if paused.load(std::sync::atomic::Ordering::Acquire) => {}
+                    Ok(encoded_frame) => {
+                        last_audio_frame.store(
+                            chrono::Local::now().timestamp_millis(),
+                            std::sync::atomic::Ordering::Release,
+                        );
+                        encode.record_audio_queue(recv.len());
+                        if let Some(meter) = &mut meter {
+                            let chunks = meter.measure(&encoded_frame.data);
+                            if let Some(detector) = &mut detector {
+                                // Every chunk has to be observed to keep the baseline up to date
+                                let mut loud = false;
+                                for chunk in &chunks {
+                                    loud |= detector.observe(encoded_frame.timestamp, chunk);
+                                }
+                                if loud {
+                                    if let Ok(mut found) = auto_markers.lock() {
+                                        found.push(encoded_frame.timestamp);
+                                    }
+                                }
+                            }
+                        }
+                        let timestamp = encoded_frame.timestamp;
+                        match staging.insert_or_stage(&audio_buffer, encoded_frame, insert) {
+                            StageOutcome::Inserted => {}
+                            StageOutcome::Staged => drops.record_audio_staged(),
+                            StageOutcome::StagedDroppingOldest => {
+                                drops.record_audio_staged();
+                                drops.record_audio();
+                                drop_warning.record();
+                                record_event(&events, timestamp, EventKind::AudioFrameDropped);
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        staging.flush(&audio_buffer, insert);
+                    }
+                    Err(RecvTimeoutError::Disconnected) => std::thread::sleep(WORKER_POLL_INTERVAL),
                 }
+                drop_warning.check();
             }
-
-            std::thread::sleep(Duration::from_millis(100));
         })
     }
 }
+
+/// What the video and audio workers share with the mode and the run loop.
+#[derive(Clone)]
+struct WorkerShared {
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    drops: Arc<DropCounters>,
+    encode: Arc<EncodeCounters>,
+    events: SharedEvents,
+    tap: FrameTap,
+    /// CPUs the workers are pinned to.
+    cpus: Vec<usize>,
+}
+
+/// What the audio worker measures the audio with as it comes in.
+struct AudioAnalysis {
+    meter: Option<LevelMeter>,
+    detector: Option<AudioEventDetector>,
+    auto_markers: AutoMarkers,
+}
+
+/// Adds an event at capture time `timestamp` to the log.
+fn record_event(events: &SharedEvents, timestamp: i64, kind: EventKind) {
+    if let Ok(mut events) = events.lock() {
+        events.record(timestamp, kind);
+    }
+}
+
+/// Sends the packet built by `packet` to the tap if a recording is running. The buffer keeps the
+/// frame itself so the recording gets a copy.
+fn forward(tap: &FrameTap, packet: impl FnOnce() -> LivePacket) {
+    if let Ok(tap) = tap.lock() {
+        if let Some(sender) = tap.as_ref() {
+            // The recording went away on its own, it is cleaned up once stopped
+            let _ = sender.send(packet());
+        }
+    }
+}
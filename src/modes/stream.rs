@@ -0,0 +1,262 @@
+use std::thread::JoinHandle;
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use crossbeam::channel::{Receiver, Sender};
+
+use crate::{
+    app_context::AppContext,
+    application_config::{AppConfig, AppModeDbus},
+    audio_levels::LevelMeter,
+    audio_stream_params,
+    dbus::AppStatus,
+    encoders::{
+        muxer::{ClipWindow, SaveReport},
+        streaming::{stream_format, LivePacket, Streamer},
+    },
+    video_stream_params,
+};
+
+use super::{AppMode, CaptureFeed, WORKER_POLL_INTERVAL};
+
+enum StreamCommand {
+    Start(Box<Streamer>),
+    Stop,
+}
+
+/// Pushes the encoded capture to `stream_url` instead of buffering it. Streaming is started and
+/// stopped over dbus, the capture keeps running in between.
+pub struct StreamMode {
+    url: String,
+    commands: Option<Sender<StreamCommand>>,
+    worker: Option<JoinHandle<()>>,
+    streaming: bool,
+}
+
+#[async_trait]
+impl AppMode for StreamMode {
+    fn to_dbus(&self) -> AppModeDbus {
+        AppModeDbus::Stream
+    }
+
+    async fn init(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        log::debug!("Initializing context for Stream Mode");
+        let (commands_tx, commands_rx) = crossbeam::channel::unbounded();
+        self.commands = Some(commands_tx);
+        self.worker = Some(Self::create_stream_worker(
+            CaptureFeed::new(ctx)?,
+            commands_rx,
+            ctx.level_meter(),
+        ));
+
+        ctx.start_capture()?;
+        ctx.inhibitor.inhibit("Streaming the screen").await;
+        log::debug!("Successfully initialized Stream Mode");
+        Ok(())
+    }
+
+    async fn on_save(&mut self, _ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        bail!("Clips can't be saved in stream mode, switch to shadow mode first")
+    }
+
+    async fn on_save_window(
+        &mut self,
+        _ctx: &mut AppContext,
+        _window: ClipWindow,
+    ) -> anyhow::Result<SaveReport> {
+        bail!("Clips can't be saved in stream mode, switch to shadow mode first")
+    }
+
+    async fn on_shutdown(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        // The worker ends the stream once it sees the stop flag
+        ctx.stop.store(true, std::sync::atomic::Ordering::Release);
+        ctx.inhibitor.uninhibit().await;
+        Ok(())
+    }
+
+    async fn on_exit(&mut self, ctx: &mut AppContext) -> anyhow::Result<()> {
+        ctx.stop.store(true, std::sync::atomic::Ordering::Release);
+        ctx.capture.pause()?;
+        if let Some(worker) = self.worker.take() {
+            if let Err(e) = worker.join() {
+                log::error!("Error in stream worker thread: {e:?}");
+            }
+        }
+        self.commands = None;
+        self.streaming = false;
+        ctx.inhibitor.uninhibit().await;
+        Ok(())
+    }
+
+    async fn on_config_update(
+        &mut self,
+        _ctx: &mut AppContext,
+        old: &AppConfig,
+        new: &AppConfig,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut pending = old.fields_requiring_rebuild(new);
+        if old.stream_url != new.stream_url {
+            match &new.stream_url {
+                Some(url) => self.url = url.clone(),
+                None => log::warn!(
+                    "stream_url was removed, keeping {} for stream mode",
+                    self.url
+                ),
+            }
+            // A running stream keeps its connection, the new URL is used from the next start
+            if self.streaming {
+                pending.push("stream_url".to_string());
+            }
+        }
+        Ok(pending)
+    }
+
+    async fn recent_window(
+        &mut self,
+        _ctx: &mut AppContext,
+        _seconds: u32,
+    ) -> anyhow::Result<ClipWindow> {
+        bail!("Clips can't be saved in stream mode, switch to shadow mode first")
+    }
+
+    async fn set_streaming(&mut self, ctx: &mut AppContext, enabled: bool) -> anyhow::Result<()> {
+        let commands = self
+            .commands
+            .as_ref()
+            .context("Stream mode is not initialized")?;
+        if enabled == self.streaming {
+            return Ok(());
+        }
+
+        let command = if enabled {
+            let format = stream_format(&self.url, Self::audio_codec(ctx))?;
+            StreamCommand::Start(Box::new(Streamer::new(
+                self.url.clone(),
+                format,
+                video_stream_params(ctx)?,
+                audio_stream_params(ctx),
+            )))
+        } else {
+            StreamCommand::Stop
+        };
+        commands
+            .send(command)
+            .context("The stream worker is not running")?;
+        self.streaming = enabled;
+        log::info!(
+            "Streaming to {} {}",
+            self.url,
+            if enabled { "started" } else { "stopped" }
+        );
+        Ok(())
+    }
+
+    async fn fill_status(&mut self, _ctx: &mut AppContext, status: &mut AppStatus) {
+        status.streaming = self.streaming;
+    }
+}
+
+impl StreamMode {
+    /// Validates the configured stream target against the capture so an unusable setup is
+    /// reported before switching modes.
+    pub fn new(ctx: &AppContext) -> anyhow::Result<Self> {
+        let url = ctx
+            .config
+            .stream_url
+            .clone()
+            .context("Set stream_url in the config to use stream mode")?;
+        stream_format(&url, Self::audio_codec(ctx))?;
+
+        Ok(Self {
+            url,
+            commands: None,
+            worker: None,
+            streaming: false,
+        })
+    }
+
+    fn audio_codec(ctx: &AppContext) -> Option<ffmpeg_next::codec::Id> {
+        audio_stream_params(ctx).map(|params| params.parameters.id())
+    }
+
+    fn create_stream_worker(
+        feed: CaptureFeed,
+        commands: Receiver<StreamCommand>,
+        mut meter: Option<LevelMeter>,
+    ) -> JoinHandle<()> {
+        let CaptureFeed {
+            video_recv,
+            audio_recv,
+            stop,
+            last_video_frame,
+            last_audio_frame,
+            encode,
+        } = feed;
+        std::thread::spawn(move || {
+            let mut streamer: Option<Box<Streamer>> = None;
+            loop {
+                if stop.load(std::sync::atomic::Ordering::Acquire) {
+                    while video_recv.try_recv().is_ok() {}
+                    while audio_recv.try_recv().is_ok() {}
+                    break;
+                }
+
+                // Frames are still received while not streaming so the capture doesn't back up
+                crossbeam::channel::select! {
+                    recv(commands) -> command => match command {
+                        Ok(StreamCommand::Start(new_streamer)) => {
+                            if let Some(mut old) = streamer.replace(new_streamer) {
+                                old.finish();
+                            }
+                        }
+                        Ok(StreamCommand::Stop) => {
+                            if let Some(mut old) = streamer.take() {
+                                old.finish();
+                            }
+                        }
+                        Err(_) => std::thread::sleep(WORKER_POLL_INTERVAL),
+                    },
+                    recv(video_recv) -> frame => match frame {
+                        Ok(frame) => {
+                            last_video_frame.store(
+                                chrono::Local::now().timestamp_millis(),
+                                std::sync::atomic::Ordering::Release,
+                            );
+                            encode.record_video(frame.data.len(), video_recv.len());
+                            if let Some(streamer) = streamer.as_mut() {
+                                streamer.push(LivePacket::from(frame));
+                            }
+                        }
+                        Err(_) => std::thread::sleep(WORKER_POLL_INTERVAL),
+                    },
+                    recv(audio_recv) -> frame => match frame {
+                        Ok(frame) => {
+                            last_audio_frame.store(
+                                chrono::Local::now().timestamp_millis(),
+                                std::sync::atomic::Ordering::Release,
+                            );
+                            encode.record_audio_queue(audio_recv.len());
+                            if let Some(meter) = &mut meter {
+                                meter.measure(&frame.data);
+                            }
+                            if let Some(streamer) = streamer.as_mut() {
+                                streamer.push(LivePacket::from(frame));
+                            }
+                        }
+                        Err(_) => std::thread::sleep(WORKER_POLL_INTERVAL),
+                    },
+                    default(WORKER_POLL_INTERVAL) => {
+                        // Lets a dropped connection come back even while no frames arrive
+                        if let Some(streamer) = streamer.as_mut() {
+                            streamer.pump();
+                        }
+                    },
+                }
+            }
+
+            if let Some(mut streamer) = streamer.take() {
+                streamer.finish();
+            }
+        })
+    }
+}
@@ -0,0 +1,72 @@
+//! Helpers for the request/response pattern of the XDG desktop portals.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use zbus::{
+    proxy,
+    zvariant::{OwnedObjectPath, OwnedValue},
+    Connection,
+};
+
+#[proxy(
+    interface = "org.freedesktop.portal.Request",
+    default_service = "org.freedesktop.portal.Desktop"
+)]
+pub trait Request {
+    /// Ends the request. For requests which hold something, like an inhibitor, this releases it.
+    fn close(&self) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn response(&self, response: u32, results: HashMap<String, OwnedValue>) -> zbus::Result<()>;
+}
+
+/// Makes a portal call which takes a `handle_token` and waits for the `Response` of the request
+/// it creates. The request path is known up front so its response can't be missed.
+pub async fn portal_request<F, Fut>(conn: &Connection, call: F) -> Result<()>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = zbus::Result<OwnedObjectPath>>,
+{
+    let token = unique_token();
+    let path = format!(
+        "/org/freedesktop/portal/desktop/request/{}/{token}",
+        sender_path_element(conn)?
+    );
+    let request = RequestProxy::builder(conn).path(path)?.build().await?;
+    let mut responses = request.receive_response().await?;
+
+    call(token).await?;
+    let response = responses
+        .next()
+        .await
+        .context("The portal closed the request without responding")?;
+    let args = response.args()?;
+    match args.response {
+        0 => Ok(()),
+        1 => bail!("The request was cancelled"),
+        code => bail!("The request failed with response code {code}"),
+    }
+}
+
+pub fn sender_path_element(conn: &Connection) -> Result<String> {
+    let name = conn
+        .unique_name()
+        .context("The dbus connection has no unique name")?;
+    Ok(path_element(name.as_str()))
+}
+
+/// A unique bus name as the portal puts it in object paths, `:1.42` becoming `1_42`.
+pub fn path_element(unique_name: &str) -> String {
+    unique_name.trim_start_matches(':').replace('.', "_")
+}
+
+/// Token for a request or session, unique within this process.
+pub fn unique_token() -> String {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    format!("waycap_{}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
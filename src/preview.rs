@@ -0,0 +1,611 @@
+//! Exports the capture as a PipeWire `Video/Source` node, so OBS or a preview window can show
+//! what WayCap is capturing without a second screen share. waycap-rs only hands out encoded
+//! video, so the preview is decoded from the frames going into the shadow buffer, and only while
+//! a consumer is streaming from the node.
+use std::{
+    io::Cursor,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use ffmpeg_next::{
+    self as ffmpeg,
+    codec::{self, Packet},
+    format::Pixel,
+    frame,
+    software::scaling,
+};
+use pipewire::{
+    self as pw,
+    context::Context as PwContext,
+    main_loop::MainLoop,
+    properties::properties,
+    spa::{
+        buffer::DataType,
+        param::{
+            format::{FormatProperties, MediaSubtype, MediaType},
+            format_utils::parse_format,
+            video::{VideoFormat, VideoInfoRaw},
+            ParamType,
+        },
+        pod::{serialize::PodSerializer, ChoiceValue, Object, Pod, Property, PropertyFlags, Value},
+        utils::{Choice, ChoiceEnum, ChoiceFlags, Direction, Fraction, Rectangle, SpaTypes},
+    },
+    stream::{Stream, StreamFlags, StreamRef, StreamState},
+};
+use waycap_rs::types::video_frame::EncodedVideoFrame;
+
+use crate::encoders::{muxer::StreamParams, streaming::LivePacket};
+
+/// Rate the preview is converted and offered at, whatever the capture runs at.
+pub const PREVIEW_FPS: u32 = 10;
+
+/// How many encoded frames may wait for the decoder before the preview skips ahead to the next
+/// key frame, about two seconds at 60 fps.
+const PACKET_QUEUE: usize = 120;
+
+/// How long the decoder waits for a frame before checking whether the preview was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Buffers PipeWire allocates for the node, as (default, min, max).
+const BUFFER_COUNT: (i32, i32, i32) = (4, 2, 8);
+
+/// The raw format a consumer agreed on. Both offered formats are 4 bytes per pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreviewFormat {
+    pub pixel: Pixel,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PreviewFormat {
+    /// The format for a negotiated PipeWire video format, `None` for one that was never offered.
+    pub fn new(format: VideoFormat, width: u32, height: u32) -> Option<Self> {
+        let pixel = match format {
+            VideoFormat::BGRx => Pixel::BGRZ,
+            VideoFormat::RGBx => Pixel::RGBZ,
+            _ => return None,
+        };
+        Some(Self {
+            pixel,
+            width,
+            height,
+        })
+    }
+
+    pub fn stride(&self) -> usize {
+        self.width as usize * 4
+    }
+
+    pub fn frame_bytes(&self) -> usize {
+        self.stride() * self.height as usize
+    }
+}
+
+/// Lets through one frame per interval so the preview runs at [`PREVIEW_FPS`] however fast the
+/// capture is. The schedule advances by whole intervals so it doesn't drift slower than asked.
+#[derive(Debug)]
+pub struct FrameGate {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl FrameGate {
+    pub fn new(fps: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / fps.max(1),
+            next: None,
+        }
+    }
+
+    /// Whether the frame arriving at `now` should be shown.
+    pub fn admit(&mut self, now: Instant) -> bool {
+        match self.next {
+            Some(next) if now < next => false,
+            // After a gap the schedule restarts instead of letting a burst through
+            Some(next) if now < next + self.interval => {
+                self.next = Some(next + self.interval);
+                true
+            }
+            _ => {
+                self.next = Some(now + self.interval);
+                true
+            }
+        }
+    }
+}
+
+/// Copies `rows` rows of `row_bytes` each out of an image whose rows are `stride` bytes apart,
+/// so the frame handed to PipeWire has no padding.
+pub fn pack_rows(data: &[u8], stride: usize, row_bytes: usize, rows: usize) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(row_bytes * rows);
+    for row in data.chunks(stride.max(1)).take(rows) {
+        packed.extend_from_slice(&row[..row_bytes.min(row.len())]);
+    }
+    packed
+}
+
+/// Sent to the node thread's loop, which is the only thread allowed to touch the stream.
+enum PreviewEvent {
+    /// A new frame was converted and the consumers should get it.
+    Frame,
+    Terminate,
+}
+
+/// State shared between the shadow video worker, the decoder and the node.
+#[derive(Default)]
+struct Shared {
+    /// Set while a consumer is streaming from the node. Nothing is copied or decoded otherwise.
+    connected: AtomicBool,
+    /// Set when frames were skipped, the decoder then starts over at the next key frame.
+    resync: AtomicBool,
+    stopped: AtomicBool,
+    format: Mutex<Option<PreviewFormat>>,
+    /// The latest converted frame, in `format` without any row padding.
+    frame: Mutex<Option<Vec<u8>>>,
+}
+
+/// Where the shadow video worker hands its frames to.
+#[derive(Clone)]
+pub struct PreviewTap {
+    shared: Arc<Shared>,
+    packets: Sender<LivePacket>,
+}
+
+impl PreviewTap {
+    /// Queues a copy of `frame` for the decoder if a consumer is connected. The capture never
+    /// waits on the preview, when the decoder falls behind frames are skipped.
+    pub fn offer(&self, frame: &EncodedVideoFrame) {
+        if !self.shared.connected.load(Ordering::Acquire) {
+            return;
+        }
+        if let Err(TrySendError::Full(_)) = self.packets.try_send(LivePacket::copy_video(frame)) {
+            self.shared.resync.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// The running preview node and the thread decoding frames for it.
+pub struct Preview {
+    tap: PreviewTap,
+    events: pw::channel::Sender<PreviewEvent>,
+    node: JoinHandle<()>,
+    decoder: JoinHandle<()>,
+}
+
+impl Preview {
+    /// Creates the node and starts decoding for it. `params` are those of the capture's video
+    /// encoder, the node offers the capture size or anything smaller.
+    pub fn start(params: StreamParams) -> Result<Self> {
+        let size = capture_size(&params)?;
+        let shared = Arc::new(Shared::default());
+        let (packet_tx, packet_rx) = crossbeam::channel::bounded(PACKET_QUEUE);
+        let (events, event_rx) = pw::channel::channel();
+
+        let node = {
+            let shared = Arc::clone(&shared);
+            std::thread::Builder::new()
+                .name("waycap-preview".to_string())
+                .spawn(move || {
+                    if let Err(e) = run_node(&shared, size, event_rx) {
+                        log::error!("The preview stream stopped: {e:#}");
+                    }
+                    // Whatever happened nobody is watching anymore
+                    shared.connected.store(false, Ordering::Release);
+                })?
+        };
+        let decoder = {
+            let shared = Arc::clone(&shared);
+            let events = events.clone();
+            std::thread::Builder::new()
+                .name("waycap-preview-decode".to_string())
+                .spawn(move || PreviewDecoder::new(params).run(&packet_rx, &shared, &events))?
+        };
+        log::info!(
+            "Exporting the capture as a PipeWire video source at up to {}x{}",
+            size.width,
+            size.height
+        );
+
+        Ok(Self {
+            tap: PreviewTap {
+                shared,
+                packets: packet_tx,
+            },
+            events,
+            node,
+            decoder,
+        })
+    }
+
+    pub fn tap(&self) -> PreviewTap {
+        self.tap.clone()
+    }
+
+    /// Removes the node from the graph and waits for both threads to exit.
+    pub fn stop(self) {
+        self.tap.shared.stopped.store(true, Ordering::Release);
+        if self.events.send(PreviewEvent::Terminate).is_err() {
+            log::debug!("The preview node had already stopped");
+        }
+        drop(self.tap);
+        for (name, thread) in [("node", self.node), ("decoder", self.decoder)] {
+            if let Err(e) = thread.join() {
+                log::error!("Error in the preview {name} thread: {e:?}");
+            }
+        }
+    }
+}
+
+/// Size of the captured video, read from the stream parameters by opening a decoder for them.
+pub fn capture_size(params: &StreamParams) -> Result<Rectangle> {
+    let decoder = codec::Context::from_parameters(params.parameters.clone())?
+        .decoder()
+        .video()
+        .context("Could not open a decoder for the captured video")?;
+    anyhow::ensure!(
+        decoder.width() > 0 && decoder.height() > 0,
+        "The size of the captured video is not known yet"
+    );
+    Ok(Rectangle {
+        width: decoder.width(),
+        height: decoder.height(),
+    })
+}
+
+/// A scaler along with the input format and size, and the output format it was set up for.
+type Scaler = ((Pixel, u32, u32, PreviewFormat), scaling::Context);
+
+/// Decodes the tapped frames and converts them to the negotiated format at [`PREVIEW_FPS`].
+/// Every frame has to be decoded since the later ones depend on it, only the conversion is
+/// skipped for those the gate holds back.
+struct PreviewDecoder {
+    params: StreamParams,
+    decoder: Option<ffmpeg::decoder::Video>,
+    scaler: Option<Scaler>,
+    gate: FrameGate,
+}
+
+impl PreviewDecoder {
+    fn new(params: StreamParams) -> Self {
+        Self {
+            params,
+            decoder: None,
+            scaler: None,
+            gate: FrameGate::new(PREVIEW_FPS),
+        }
+    }
+
+    fn run(
+        mut self,
+        packets: &Receiver<LivePacket>,
+        shared: &Shared,
+        events: &pw::channel::Sender<PreviewEvent>,
+    ) {
+        while !shared.stopped.load(Ordering::Acquire) {
+            let packet = match packets.recv_timeout(POLL_INTERVAL) {
+                Ok(packet) => packet,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if shared.resync.swap(false, Ordering::AcqRel) {
+                self.decoder = None;
+            }
+            match self.decode(&packet, shared) {
+                Ok(false) => {}
+                Ok(true) => {
+                    let _ = events.send(PreviewEvent::Frame);
+                }
+                Err(e) => {
+                    log::debug!("Could not decode a preview frame: {e:#}");
+                    self.decoder = None;
+                }
+            }
+        }
+    }
+
+    /// Feeds one frame to the decoder, returning whether a new preview frame was stored.
+    fn decode(&mut self, packet: &LivePacket, shared: &Shared) -> Result<bool> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            // Frames before the first key frame can't be decoded
+            None if !packet.is_keyframe => return Ok(false),
+            None => self.decoder.insert(
+                codec::Context::from_parameters(self.params.parameters.clone())?
+                    .decoder()
+                    .video()?,
+            ),
+        };
+        let mut encoded = Packet::copy(&packet.data);
+        encoded.set_pts(Some(packet.pts));
+        encoded.set_dts(Some(packet.dts));
+        decoder.send_packet(&encoded)?;
+
+        let mut stored = false;
+        let mut decoded = frame::Video::empty();
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            if !self.gate.admit(Instant::now()) {
+                continue;
+            }
+            let Some(format) = shared.format.lock().ok().and_then(|format| *format) else {
+                continue;
+            };
+            let converted = convert(&mut self.scaler, &decoded, format)?;
+            if let Ok(mut frame) = shared.frame.lock() {
+                *frame = Some(converted);
+                stored = true;
+            }
+        }
+        Ok(stored)
+    }
+}
+
+/// Scales `decoded` into `format`, reusing the scaler while the input and output don't change.
+fn convert(
+    scaler: &mut Option<Scaler>,
+    decoded: &frame::Video,
+    format: PreviewFormat,
+) -> Result<Vec<u8>> {
+    let key = (decoded.format(), decoded.width(), decoded.height(), format);
+    let context = match scaler {
+        Some((current, context)) if *current == key => context,
+        _ => {
+            let context = scaling::Context::get(
+                decoded.format(),
+                decoded.width(),
+                decoded.height(),
+                format.pixel,
+                format.width,
+                format.height,
+                scaling::Flags::BILINEAR,
+            )?;
+            &mut scaler.insert((key, context)).1
+        }
+    };
+    let mut converted = frame::Video::empty();
+    context.run(decoded, &mut converted)?;
+    Ok(pack_rows(
+        converted.data(0),
+        converted.stride(0),
+        format.stride(),
+        format.height as usize,
+    ))
+}
+
+/// Runs the node until [`PreviewEvent::Terminate`] arrives.
+fn run_node(
+    shared: &Arc<Shared>,
+    size: Rectangle,
+    events: pw::channel::Receiver<PreviewEvent>,
+) -> Result<()> {
+    let main_loop = MainLoop::new(None)?;
+    let context = PwContext::new(&main_loop)?;
+    let core = context.connect(None)?;
+    let stream = Rc::new(Stream::new(
+        &core,
+        "waycap-preview",
+        properties! {
+            *pw::keys::MEDIA_CLASS => "Video/Source",
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+            *pw::keys::NODE_DESCRIPTION => "WayCap preview",
+        },
+    )?);
+
+    let _listener = stream
+        .add_local_listener_with_user_data(())
+        .state_changed({
+            let shared = Arc::clone(shared);
+            move |_, _, old, new| {
+                log::debug!("Preview stream state changed: {old:?} -> {new:?}");
+                let streaming = new == StreamState::Streaming;
+                if streaming {
+                    // Frames were skipped while nobody watched
+                    shared.resync.store(true, Ordering::Release);
+                }
+                shared.connected.store(streaming, Ordering::Release);
+            }
+        })
+        .param_changed({
+            let shared = Arc::clone(shared);
+            move |stream, _, id, param| {
+                if id != ParamType::Format.as_raw() {
+                    return;
+                }
+                let format = param.and_then(|param| match negotiated_format(param) {
+                    Ok(format) => Some(format),
+                    Err(e) => {
+                        log::warn!("Could not use the preview format a consumer asked for: {e:#}");
+                        None
+                    }
+                });
+                if let Ok(mut frame) = shared.frame.lock() {
+                    *frame = None;
+                }
+                if let Ok(mut current) = shared.format.lock() {
+                    *current = format;
+                }
+                if let Some(format) = format {
+                    log::info!(
+                        "Preview consumer connected at {}x{} {:?}",
+                        format.width,
+                        format.height,
+                        format.pixel
+                    );
+                    if let Err(e) = update_buffers(stream, format) {
+                        log::warn!("Could not set up the preview buffers: {e:#}");
+                    }
+                }
+            }
+        })
+        .process({
+            let shared = Arc::clone(shared);
+            move |stream, _| fill_buffer(stream, &shared)
+        })
+        .register()?;
+
+    let enum_format = serialize(enum_format(size))?;
+    let mut params = [Pod::from_bytes(&enum_format).context("Invalid preview format")?];
+    stream.connect(
+        Direction::Output,
+        None,
+        StreamFlags::DRIVER | StreamFlags::MAP_BUFFERS,
+        &mut params,
+    )?;
+
+    // Nothing else drives the graph, so every converted frame triggers a cycle of its own
+    let _events = events.attach(main_loop.loop_(), {
+        let main_loop = main_loop.clone();
+        let stream = Rc::clone(&stream);
+        move |event| match event {
+            PreviewEvent::Frame => {
+                if let Err(e) = stream.trigger_process() {
+                    log::debug!("Could not trigger the preview stream: {e:?}");
+                }
+            }
+            PreviewEvent::Terminate => main_loop.quit(),
+        }
+    });
+    main_loop.run();
+
+    stream.disconnect()?;
+    log::debug!("Preview stream removed");
+    Ok(())
+}
+
+/// The formats offered to consumers: 32 bit RGB at up to the capture size.
+fn enum_format(size: Rectangle) -> Object {
+    pw::spa::pod::object!(
+        SpaTypes::ObjectParamFormat,
+        ParamType::EnumFormat,
+        pw::spa::pod::property!(FormatProperties::MediaType, Id, MediaType::Video),
+        pw::spa::pod::property!(FormatProperties::MediaSubtype, Id, MediaSubtype::Raw),
+        pw::spa::pod::property!(
+            FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            VideoFormat::BGRx,
+            VideoFormat::BGRx,
+            VideoFormat::RGBx,
+        ),
+        pw::spa::pod::property!(
+            FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            size,
+            Rectangle {
+                width: 1,
+                height: 1
+            },
+            size
+        ),
+        pw::spa::pod::property!(
+            FormatProperties::VideoFramerate,
+            Fraction,
+            Fraction {
+                num: PREVIEW_FPS,
+                denom: 1
+            }
+        ),
+    )
+}
+
+fn negotiated_format(param: &Pod) -> Result<PreviewFormat> {
+    let (media_type, media_subtype) =
+        parse_format(param).map_err(|e| anyhow!("Invalid format: {e:?}"))?;
+    anyhow::ensure!(
+        media_type == MediaType::Video && media_subtype == MediaSubtype::Raw,
+        "Only raw video is offered"
+    );
+    let mut info = VideoInfoRaw::new();
+    info.parse(param)
+        .map_err(|e| anyhow!("Invalid video format: {e:?}"))?;
+    let size = info.size();
+    PreviewFormat::new(info.format(), size.width, size.height)
+        .with_context(|| format!("{:?} was never offered", info.format()))
+}
+
+/// Tells PipeWire how big the buffers for `format` have to be.
+fn update_buffers(stream: &StreamRef, format: PreviewFormat) -> Result<()> {
+    let property = |key, value| Property {
+        key,
+        flags: PropertyFlags::empty(),
+        value,
+    };
+    let (default, min, max) = BUFFER_COUNT;
+    let buffers = serialize(Object {
+        type_: SpaTypes::ObjectParamBuffers.as_raw(),
+        id: ParamType::Buffers.as_raw(),
+        properties: vec![
+            property(
+                pw::spa::sys::SPA_PARAM_BUFFERS_buffers,
+                Value::Choice(ChoiceValue::Int(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Range { default, min, max },
+                ))),
+            ),
+            property(pw::spa::sys::SPA_PARAM_BUFFERS_blocks, Value::Int(1)),
+            property(
+                pw::spa::sys::SPA_PARAM_BUFFERS_size,
+                Value::Int(format.frame_bytes() as i32),
+            ),
+            property(
+                pw::spa::sys::SPA_PARAM_BUFFERS_stride,
+                Value::Int(format.stride() as i32),
+            ),
+            property(
+                pw::spa::sys::SPA_PARAM_BUFFERS_dataType,
+                Value::Int(1 << DataType::MemPtr.as_raw()),
+            ),
+        ],
+    })?;
+    let mut params = [Pod::from_bytes(&buffers).context("Invalid buffer parameters")?];
+    stream.update_params(&mut params)?;
+    Ok(())
+}
+
+fn serialize(object: Object) -> Result<Vec<u8>> {
+    Ok(
+        PodSerializer::serialize(Cursor::new(Vec::new()), &Value::Object(object))
+            .map_err(|e| anyhow!("Could not serialize a stream parameter: {e:?}"))?
+            .0
+            .into_inner(),
+    )
+}
+
+/// Copies the latest frame into the next free buffer. Without a frame of the negotiated size
+/// yet the buffer goes out empty.
+fn fill_buffer(stream: &StreamRef, shared: &Shared) {
+    let Some(mut buffer) = stream.dequeue_buffer() else {
+        log::debug!("The preview stream is out of buffers");
+        return;
+    };
+    let Some(data) = buffer.datas_mut().first_mut() else {
+        return;
+    };
+    let format = shared.format.lock().ok().and_then(|format| *format);
+    let frame = shared.frame.lock();
+    let written = match (data.data(), frame.as_deref(), format) {
+        (Some(out), Ok(Some(frame)), Some(format))
+            if frame.len() == format.frame_bytes() && out.len() >= frame.len() =>
+        {
+            out[..frame.len()].copy_from_slice(frame);
+            Some(format)
+        }
+        _ => None,
+    };
+    let chunk = data.chunk_mut();
+    *chunk.offset_mut() = 0;
+    *chunk.stride_mut() = written.map_or(0, |format| format.stride() as i32);
+    *chunk.size_mut() = written.map_or(0, |format| format.frame_bytes() as u32);
+}
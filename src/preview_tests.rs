@@ -0,0 +1,52 @@
+use std::time::{Duration, Instant};
+
+use ffmpeg_next::format::Pixel;
+use pipewire::spa::param::video::VideoFormat;
+
+use super::preview::*;
+
+#[test]
+fn test_gate_keeps_the_preview_rate() {
+    let mut gate = FrameGate::new(10);
+    let start = Instant::now();
+    // Two seconds of 60 fps frames
+    let admitted = (0..120)
+        .filter(|frame| gate.admit(start + Duration::from_micros(frame * 16_667)))
+        .count();
+    assert_eq!(admitted, 20);
+}
+
+#[test]
+fn test_gate_restarts_after_a_gap() {
+    let mut gate = FrameGate::new(10);
+    let start = Instant::now();
+    assert!(gate.admit(start));
+    assert!(!gate.admit(start + Duration::from_millis(50)));
+
+    // No burst of catch-up frames after nothing arrived for a while
+    let resumed = start + Duration::from_secs(5);
+    assert!(gate.admit(resumed));
+    assert!(!gate.admit(resumed + Duration::from_millis(10)));
+    assert!(gate.admit(resumed + Duration::from_millis(100)));
+}
+
+#[test]
+fn test_pack_rows_drops_the_padding() {
+    let data = [1, 2, 3, 0, 4, 5, 6, 0, 7, 8, 9, 0];
+    assert_eq!(pack_rows(&data, 4, 3, 3), vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    assert_eq!(pack_rows(&data, 4, 3, 2), vec![1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn test_only_offered_formats_are_accepted() {
+    let format = PreviewFormat::new(VideoFormat::BGRx, 1280, 720).unwrap();
+    assert_eq!(format.pixel, Pixel::BGRZ);
+    assert_eq!(format.stride(), 5120);
+    assert_eq!(format.frame_bytes(), 5120 * 720);
+
+    assert_eq!(
+        PreviewFormat::new(VideoFormat::RGBx, 1, 1).map(|format| format.pixel),
+        Some(Pixel::RGBZ)
+    );
+    assert!(PreviewFormat::new(VideoFormat::BGRA, 1280, 720).is_none());
+}
@@ -0,0 +1,220 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use zbus::{
+    proxy,
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+    Connection,
+};
+
+use crate::{
+    dbus::{AppStatus, MarkerReply, PauseReply, SaveQueue, SaveQueued},
+    portal::{portal_request, sender_path_element, unique_token},
+};
+
+const SAVE_CLIP: &str = "save-clip";
+const TOGGLE_PAUSE: &str = "toggle-pause";
+const ADD_MARKER: &str = "add-marker";
+/// Label of the markers added through the shortcut.
+const MARKER_LABEL: &str = "Shortcut";
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShortcutsConfig {
+    pub enabled: bool,
+    /// Triggers suggested to the desktop, e.g. `CTRL+ALT+S`. The desktop decides on the final
+    /// binding and may ask first. Empty leaves the choice to it entirely.
+    pub save_clip: String,
+    pub toggle_pause: String,
+    pub add_marker: String,
+}
+
+impl Default for ShortcutsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            save_clip: "CTRL+ALT+S".to_string(),
+            toggle_pause: "CTRL+ALT+P".to_string(),
+            add_marker: "CTRL+ALT+M".to_string(),
+        }
+    }
+}
+
+impl ShortcutsConfig {
+    /// Id, description and preferred trigger of every shortcut.
+    pub fn shortcuts(&self) -> [(&'static str, &'static str, &str); 3] {
+        [
+            (SAVE_CLIP, "Save clip", &self.save_clip),
+            (TOGGLE_PAUSE, "Pause/Resume", &self.toggle_pause),
+            (ADD_MARKER, "Add marker", &self.add_marker),
+        ]
+    }
+}
+
+#[proxy(
+    interface = "org.freedesktop.portal.GlobalShortcuts",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+)]
+trait GlobalShortcuts {
+    fn create_session(&self, options: HashMap<&str, Value<'_>>) -> zbus::Result<OwnedObjectPath>;
+
+    fn bind_shortcuts(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        shortcuts: &[(&str, HashMap<&str, Value<'_>>)],
+        parent_window: &str,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(signal)]
+    fn activated(
+        &self,
+        session_handle: OwnedObjectPath,
+        shortcut_id: String,
+        timestamp: u64,
+        options: HashMap<String, OwnedValue>,
+    ) -> zbus::Result<()>;
+}
+
+/// The channels the dbus methods use, so a shortcut does exactly what the matching call does.
+pub struct ShortcutActions {
+    pub saves: Arc<SaveQueue>,
+    pub pause_tx: mpsc::Sender<(bool, PauseReply)>,
+    pub status_tx: mpsc::Sender<oneshot::Sender<AppStatus>>,
+    pub marker_tx: mpsc::Sender<(String, MarkerReply)>,
+}
+
+impl ShortcutActions {
+    async fn activate(&self, shortcut_id: &str) -> Result<()> {
+        match shortcut_id {
+            SAVE_CLIP => match self.saves.queue() {
+                (_, SaveQueued::Queued) => {}
+                (id, SaveQueued::AlreadyPending) => log::info!("Save {id} is already pending"),
+                (_, SaveQueued::Failed) => bail!("The run loop is gone"),
+            },
+            TOGGLE_PAUSE => {
+                let (status_tx, status_rx) = oneshot::channel();
+                self.status_tx.send(status_tx).await?;
+                let paused = status_rx.await?.paused;
+
+                let (reply_tx, reply_rx) = oneshot::channel();
+                self.pause_tx.send((!paused, reply_tx)).await?;
+                reply_rx.await?.map_err(anyhow::Error::msg)?;
+            }
+            ADD_MARKER => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                self.marker_tx
+                    .send((MARKER_LABEL.to_string(), reply_tx))
+                    .await?;
+                reply_rx.await?.map_err(anyhow::Error::msg)?;
+            }
+            other => log::debug!("Ignoring unknown shortcut {other:?}"),
+        }
+        Ok(())
+    }
+}
+
+/// Registers the shortcuts with the desktop portal and routes their activations until the
+/// connection closes. The shortcuts are registered again whenever the portal restarts, a portal
+/// without global shortcuts support only gets logged.
+pub async fn run(conn: Connection, config: ShortcutsConfig, actions: ShortcutActions) {
+    if let Err(e) = serve(&conn, &config, &actions).await {
+        log::warn!("Global shortcuts stopped: {e:?}");
+    }
+}
+
+async fn serve(
+    conn: &Connection,
+    config: &ShortcutsConfig,
+    actions: &ShortcutActions,
+) -> Result<()> {
+    let portal = GlobalShortcutsProxy::new(conn).await?;
+    let mut owner_changes = portal.inner().receive_owner_changed().await?;
+    let mut activations = portal.receive_activated().await?;
+
+    loop {
+        let session = match bind(conn, &portal, config).await {
+            Ok(session) => Some(session),
+            Err(e) => {
+                log::warn!(
+                    "Global shortcuts are unavailable, bind the dbus calls in your compositor instead: {e:#}"
+                );
+                None
+            }
+        };
+
+        loop {
+            tokio::select! {
+                Some(signal) = activations.next() => {
+                    let args = signal.args()?;
+                    if session.as_ref() != Some(&args.session_handle) {
+                        continue;
+                    }
+                    log::debug!("Shortcut {:?} activated", args.shortcut_id);
+                    if let Err(e) = actions.activate(&args.shortcut_id).await {
+                        log::error!("Could not run shortcut {:?}: {e:?}", args.shortcut_id);
+                    }
+                },
+                Some(owner) = owner_changes.next() => {
+                    // The session died with the old portal
+                    if owner.is_some() {
+                        log::info!("The desktop portal restarted, registering the shortcuts again");
+                        break;
+                    }
+                },
+                else => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Creates a global shortcuts session and binds the shortcuts to it, returning its handle.
+async fn bind(
+    conn: &Connection,
+    portal: &GlobalShortcutsProxy<'_>,
+    config: &ShortcutsConfig,
+) -> Result<OwnedObjectPath> {
+    let sender = sender_path_element(conn)?;
+    let session_token = unique_token();
+    let session = OwnedObjectPath::try_from(format!(
+        "/org/freedesktop/portal/desktop/session/{sender}/{session_token}"
+    ))?;
+
+    portal_request(conn, |handle_token| {
+        portal.create_session(HashMap::from([
+            ("handle_token", Value::from(handle_token)),
+            ("session_handle_token", Value::from(session_token.clone())),
+        ]))
+    })
+    .await
+    .context("Could not create a global shortcuts session")?;
+
+    let shortcuts: Vec<_> = config
+        .shortcuts()
+        .into_iter()
+        .map(|(id, description, trigger)| {
+            let mut properties = HashMap::from([("description", Value::from(description))]);
+            if !trigger.is_empty() {
+                properties.insert("preferred_trigger", Value::from(trigger));
+            }
+            (id, properties)
+        })
+        .collect();
+    portal_request(conn, |handle_token| {
+        portal.bind_shortcuts(
+            &session,
+            &shortcuts,
+            "",
+            HashMap::from([("handle_token", Value::from(handle_token))]),
+        )
+    })
+    .await
+    .context("Could not bind the global shortcuts")?;
+
+    log::info!("Registered the global shortcuts with the desktop portal");
+    Ok(session)
+}
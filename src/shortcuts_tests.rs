@@ -0,0 +1,28 @@
+use super::{portal::path_element, shortcuts::*};
+
+#[test]
+fn test_portal_path_element() {
+    assert_eq!(path_element(":1.42"), "1_42");
+    assert_eq!(path_element(":1.2.3"), "1_2_3");
+}
+
+#[test]
+fn test_preferred_triggers() {
+    let config: ShortcutsConfig = toml::from_str(
+        r#"
+        save_clip = "SUPER+F9"
+        add_marker = ""
+        "#,
+    )
+    .unwrap();
+
+    assert!(config.enabled);
+    assert_eq!(
+        config.shortcuts(),
+        [
+            ("save-clip", "Save clip", "SUPER+F9"),
+            ("toggle-pause", "Pause/Resume", "CTRL+ALT+P"),
+            ("add-marker", "Add marker", ""),
+        ]
+    );
+}
@@ -0,0 +1,294 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+/// Frames the shadow workers dropped in one minute before a warning is logged.
+const DROP_WARNING_THRESHOLD: u64 = 30;
+const DROP_WARNING_WINDOW: Duration = Duration::from_secs(60);
+
+/// Seconds of encoded packets the rates in [`PipelineStats`] are averaged over.
+const RATE_WINDOW_SECONDS: u64 = 10;
+
+/// Upper bounds in seconds of the save duration histogram buckets, the last one catches the rest.
+pub const SAVE_DURATION_BOUNDS: [f64; 7] = [0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Frames which were received from the capture but never made it into the shadow buffers
+/// because the buffer stayed busy for too long, and frames which had to be staged while it was.
+#[derive(Debug, Default)]
+pub struct DropCounters {
+    video: AtomicU64,
+    audio: AtomicU64,
+    video_staged: AtomicU64,
+    audio_staged: AtomicU64,
+    /// Never reset by [`Self::take`].
+    video_total: AtomicU64,
+    audio_total: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Type, Serialize, Deserialize)]
+pub struct DropStats {
+    pub video_frames_dropped: u64,
+    pub audio_frames_dropped: u64,
+    pub video_frames_staged: u64,
+    pub audio_frames_staged: u64,
+}
+
+impl DropCounters {
+    pub fn record_video(&self) {
+        self.video.fetch_add(1, Ordering::Relaxed);
+        self.video_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_audio(&self) {
+        self.audio.fetch_add(1, Ordering::Relaxed);
+        self.audio_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_video_staged(&self) {
+        self.video_staged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_audio_staged(&self) {
+        self.audio_staged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> DropStats {
+        DropStats {
+            video_frames_dropped: self.video.load(Ordering::Relaxed),
+            audio_frames_dropped: self.audio.load(Ordering::Relaxed),
+            video_frames_staged: self.video_staged.load(Ordering::Relaxed),
+            audio_frames_staged: self.audio_staged.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Video and audio frames dropped since startup.
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.video_total.load(Ordering::Relaxed),
+            self.audio_total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Returns the counts and starts counting from 0 again.
+    pub fn take(&self) -> DropStats {
+        DropStats {
+            video_frames_dropped: self.video.swap(0, Ordering::Relaxed),
+            audio_frames_dropped: self.audio.swap(0, Ordering::Relaxed),
+            video_frames_staged: self.video_staged.swap(0, Ordering::Relaxed),
+            audio_frames_staged: self.audio_staged.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Logs at most one warning per minute for a worker which keeps dropping frames.
+pub struct DropWarning {
+    stream: &'static str,
+    window_start: Instant,
+    drops: u64,
+}
+
+impl DropWarning {
+    pub fn new(stream: &'static str) -> Self {
+        Self {
+            stream,
+            window_start: Instant::now(),
+            drops: 0,
+        }
+    }
+
+    pub fn record(&mut self) {
+        self.drops += 1;
+    }
+
+    pub fn check(&mut self) {
+        if self.window_start.elapsed() < DROP_WARNING_WINDOW {
+            return;
+        }
+
+        if self.drops >= DROP_WARNING_THRESHOLD {
+            log::warn!(
+                "Dropped {} {} frames in the last minute, the shadow buffer could not keep up",
+                self.drops,
+                self.stream
+            );
+        }
+        self.window_start = Instant::now();
+        self.drops = 0;
+    }
+}
+
+/// Packets and bytes of encoded video received during one second of uptime.
+#[derive(Debug, Default)]
+struct RateBucket {
+    second: AtomicU64,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// What the workers see of the encode path: every encoded video packet they receive, how many
+/// frames wait in the capture's channels, and the last error of the run loop, along with how
+/// long the saves took. Recording a packet costs a handful of relaxed atomic ops.
+#[derive(Debug)]
+pub struct EncodeCounters {
+    started: Instant,
+    video_packets: AtomicU64,
+    /// One more bucket than the window so the running second never overwrites the oldest one.
+    buckets: [RateBucket; RATE_WINDOW_SECONDS as usize + 1],
+    video_queue_depth: AtomicU64,
+    audio_queue_depth: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    /// Saves per [`SAVE_DURATION_BOUNDS`] bucket, not cumulative.
+    save_durations: [AtomicU64; SAVE_DURATION_BOUNDS.len() + 1],
+    save_duration_ms: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PipelineStats {
+    pub uptime_seconds: u64,
+    pub video_frames_encoded: u64,
+    /// Rates over the last [`RATE_WINDOW_SECONDS`] complete seconds.
+    pub encode_fps: f64,
+    pub average_packet_bytes: u64,
+    pub bitrate_kbps: f64,
+    /// Encoded frames waiting in the capture's channels for a worker.
+    pub video_queue_depth: u64,
+    pub audio_queue_depth: u64,
+    pub last_error: Option<String>,
+    /// Saves per [`SAVE_DURATION_BOUNDS`] bucket, not cumulative.
+    pub save_durations: [u64; SAVE_DURATION_BOUNDS.len() + 1],
+    pub save_duration_seconds: f64,
+}
+
+impl PipelineStats {
+    pub fn saves(&self) -> u64 {
+        self.save_durations.iter().sum()
+    }
+}
+
+impl Default for EncodeCounters {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            video_packets: AtomicU64::new(0),
+            buckets: Default::default(),
+            video_queue_depth: AtomicU64::new(0),
+            audio_queue_depth: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            save_durations: Default::default(),
+            save_duration_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+impl EncodeCounters {
+    /// Records a video packet of `bytes` with `queued` more frames waiting behind it.
+    pub fn record_video(&self, bytes: usize, queued: usize) {
+        self.record_video_at(self.started.elapsed().as_secs(), bytes, queued);
+    }
+
+    pub fn record_audio_queue(&self, queued: usize) {
+        self.audio_queue_depth
+            .store(queued as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, error: &anyhow::Error) {
+        if let Ok(mut last_error) = self.last_error.lock() {
+            *last_error = Some(format!("{error:#}"));
+        }
+    }
+
+    pub fn record_save(&self, duration_ms: u64) {
+        let seconds = duration_ms as f64 / 1000.0;
+        let bucket = SAVE_DURATION_BOUNDS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(SAVE_DURATION_BOUNDS.len());
+        self.save_durations[bucket].fetch_add(1, Ordering::Relaxed);
+        self.save_duration_ms
+            .fetch_add(duration_ms, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> PipelineStats {
+        self.snapshot_at(self.started.elapsed().as_secs())
+    }
+
+    /// [`Self::record_video`] at `second` of uptime. Only the video worker of the active mode
+    /// records, so a bucket is never reset by two threads at once.
+    pub fn record_video_at(&self, second: u64, bytes: usize, queued: usize) {
+        let bucket = &self.buckets[(second % (RATE_WINDOW_SECONDS + 1)) as usize];
+        if bucket.second.load(Ordering::Relaxed) != second {
+            bucket.packets.store(0, Ordering::Relaxed);
+            bucket.bytes.store(0, Ordering::Relaxed);
+            bucket.second.store(second, Ordering::Relaxed);
+        }
+        bucket.packets.fetch_add(1, Ordering::Relaxed);
+        bucket.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.video_packets.fetch_add(1, Ordering::Relaxed);
+        self.video_queue_depth
+            .store(queued as u64, Ordering::Relaxed);
+    }
+
+    /// Video packets received during the last `seconds` of uptime, including the running one.
+    pub fn recent_video_packets(&self, seconds: u64) -> u64 {
+        self.recent_video_packets_at(self.started.elapsed().as_secs(), seconds)
+    }
+
+    /// [`Self::recent_video_packets`] at `second` of uptime. Only the buckets of the rate window
+    /// are kept, so `seconds` is capped to it.
+    pub fn recent_video_packets_at(&self, second: u64, seconds: u64) -> u64 {
+        self.buckets
+            .iter()
+            .filter(|bucket| {
+                let age = second.wrapping_sub(bucket.second.load(Ordering::Relaxed));
+                age < seconds.min(RATE_WINDOW_SECONDS + 1)
+            })
+            .map(|bucket| bucket.packets.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn snapshot_at(&self, second: u64) -> PipelineStats {
+        // The running second is still filling up and would pull the rates down
+        let window = second.min(RATE_WINDOW_SECONDS);
+        let (packets, bytes) = self
+            .buckets
+            .iter()
+            .filter(|bucket| {
+                let age = second.wrapping_sub(bucket.second.load(Ordering::Relaxed));
+                (1..=window).contains(&age)
+            })
+            .fold((0, 0), |(packets, bytes), bucket| {
+                (
+                    packets + bucket.packets.load(Ordering::Relaxed),
+                    bytes + bucket.bytes.load(Ordering::Relaxed),
+                )
+            });
+
+        let (encode_fps, bitrate_kbps) = if window == 0 {
+            (0.0, 0.0)
+        } else {
+            (
+                packets as f64 / window as f64,
+                bytes as f64 * 8.0 / 1000.0 / window as f64,
+            )
+        };
+        PipelineStats {
+            uptime_seconds: second,
+            video_frames_encoded: self.video_packets.load(Ordering::Relaxed),
+            encode_fps,
+            average_packet_bytes: bytes.checked_div(packets).unwrap_or(0),
+            bitrate_kbps,
+            video_queue_depth: self.video_queue_depth.load(Ordering::Relaxed),
+            audio_queue_depth: self.audio_queue_depth.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().ok().and_then(|error| error.clone()),
+            save_durations: std::array::from_fn(|i| self.save_durations[i].load(Ordering::Relaxed)),
+            save_duration_seconds: self.save_duration_ms.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use super::stats::*;
+
+#[test]
+fn test_rates_cover_the_last_complete_seconds() {
+    let counters = EncodeCounters::default();
+    // 60 fps of 1000 byte packets for 12 seconds, then the running second is half done
+    for second in 0..12 {
+        for _ in 0..60 {
+            counters.record_video_at(second, 1000, 2);
+        }
+    }
+    for _ in 0..30 {
+        counters.record_video_at(12, 4000, 0);
+    }
+
+    let stats = counters.snapshot_at(12);
+    assert_eq!(stats.uptime_seconds, 12);
+    assert_eq!(stats.video_frames_encoded, 12 * 60 + 30);
+    assert_eq!(stats.encode_fps, 60.0);
+    assert_eq!(stats.average_packet_bytes, 1000);
+    assert_eq!(stats.bitrate_kbps, 480.0);
+    assert_eq!(stats.video_queue_depth, 0);
+}
+
+#[test]
+fn test_rates_after_a_stall() {
+    let counters = EncodeCounters::default();
+    for _ in 0..30 {
+        counters.record_video_at(1, 500, 0);
+    }
+
+    // Only the first two seconds of uptime are averaged over
+    assert_eq!(counters.snapshot_at(2).encode_fps, 15.0);
+    // The packets fell out of the window while nothing arrived
+    let stats = counters.snapshot_at(20);
+    assert_eq!(stats.encode_fps, 0.0);
+    assert_eq!(stats.average_packet_bytes, 0);
+    assert_eq!(stats.video_frames_encoded, 30);
+}
+
+#[test]
+fn test_recent_video_packets() {
+    let counters = EncodeCounters::default();
+    for second in 0..8 {
+        for _ in 0..10 {
+            counters.record_video_at(second, 100, 0);
+        }
+    }
+
+    // Seconds 3 to 7, the running one included
+    assert_eq!(counters.recent_video_packets_at(7, 5), 50);
+    assert_eq!(counters.recent_video_packets_at(9, 5), 30);
+    assert_eq!(counters.recent_video_packets_at(30, 5), 0);
+}
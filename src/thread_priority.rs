@@ -0,0 +1,149 @@
+//! Keeps the clip muxing out of the way of the game: a lower priority for the mux thread and CPU
+//! pinning for the mux and the shadow workers. Everything here only logs when the kernel refuses.
+use std::thread::JoinHandle;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadsConfig {
+    /// Niceness of the thread writing clips, 0 runs it at the priority of the rest of WayCap.
+    pub mux_nice: i32,
+    /// Run the mux under `SCHED_IDLE`, only getting the CPU time nothing else wants.
+    pub mux_idle: bool,
+    /// CPUs the shadow workers are pinned to, in the `taskset -c` format, e.g. `0-3,8`. Empty
+    /// leaves them unpinned.
+    pub worker_cpus: String,
+    /// CPUs the mux thread is pinned to, in the same format.
+    pub mux_cpus: String,
+}
+
+impl Default for ThreadsConfig {
+    fn default() -> Self {
+        Self {
+            mux_nice: 10,
+            mux_idle: false,
+            worker_cpus: String::new(),
+            mux_cpus: String::new(),
+        }
+    }
+}
+
+impl ThreadsConfig {
+    pub fn worker_cpus(&self) -> Vec<usize> {
+        config_cpus("worker_cpus", &self.worker_cpus)
+    }
+
+    pub fn mux_cpus(&self) -> Vec<usize> {
+        config_cpus("mux_cpus", &self.mux_cpus)
+    }
+}
+
+fn config_cpus(name: &str, list: &str) -> Vec<usize> {
+    parse_cpu_list(list).unwrap_or_else(|e| {
+        log::warn!("Ignoring {name} = {list:?}: {e:#}");
+        Vec::new()
+    })
+}
+
+/// Parses a CPU list like `0-3,8,10-11` into the sorted CPUs it names.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_cpu(first)?, parse_cpu(last)?),
+            None => {
+                let cpu = parse_cpu(part)?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            bail!("{part:?} is not an ascending range");
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+fn parse_cpu(cpu: &str) -> Result<usize> {
+    let cpu: usize = cpu
+        .trim()
+        .parse()
+        .with_context(|| format!("{cpu:?} is not a CPU number"))?;
+    if cpu >= libc::CPU_SETSIZE as usize {
+        bail!("CPU {cpu} is out of range");
+    }
+    Ok(cpu)
+}
+
+/// Pins the calling thread to `cpus`, doing nothing if there are none.
+pub fn pin_current_thread(cpus: &[usize]) {
+    if cpus.is_empty() {
+        return;
+    }
+
+    // SAFETY: the set is plain data, every CPU was range checked by parse_cpu_list and pid 0
+    // is the calling thread
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        log::warn!(
+            "Could not pin a thread to CPUs {cpus:?}: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Lowers the priority of the calling thread to `nice`, and to `SCHED_IDLE` if `idle` is set.
+pub fn lower_current_thread(nice: i32, idle: bool) {
+    // SAFETY: both calls only take plain values and act on the calling thread
+    unsafe {
+        if nice != 0
+            && libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice) != 0
+        {
+            log::warn!(
+                "Could not set the mux thread niceness to {nice}: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let param = libc::sched_param { sched_priority: 0 };
+        if idle && libc::sched_setscheduler(0, libc::SCHED_IDLE, &param) != 0 {
+            log::warn!(
+                "Could not run the mux thread under SCHED_IDLE: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Runs `f` on a thread named `name`, at the priority and on the CPUs `threads` gives the mux.
+pub fn spawn_mux_thread<T, F>(
+    name: &str,
+    threads: &ThreadsConfig,
+    f: F,
+) -> std::io::Result<JoinHandle<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (nice, idle, cpus) = (threads.mux_nice, threads.mux_idle, threads.mux_cpus());
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            lower_current_thread(nice, idle);
+            pin_current_thread(&cpus);
+            f()
+        })
+}
@@ -0,0 +1,27 @@
+use super::thread_priority::*;
+
+#[test]
+fn test_parse_cpu_list() {
+    assert_eq!(parse_cpu_list("").unwrap(), Vec::<usize>::new());
+    assert_eq!(parse_cpu_list("3").unwrap(), vec![3]);
+    assert_eq!(
+        parse_cpu_list("8, 0-3,10-11").unwrap(),
+        vec![0, 1, 2, 3, 8, 10, 11]
+    );
+    // Overlapping entries name each CPU once
+    assert_eq!(parse_cpu_list("0-2,1").unwrap(), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_parse_invalid_cpu_list() {
+    assert!(parse_cpu_list("3-1").is_err());
+    assert!(parse_cpu_list("0-").is_err());
+    assert!(parse_cpu_list("0xf").is_err());
+    assert!(parse_cpu_list("4096").is_err());
+    assert!(ThreadsConfig {
+        worker_cpus: "a,b".to_string(),
+        ..Default::default()
+    }
+    .worker_cpus()
+    .is_empty());
+}
@@ -1,95 +1,464 @@
 use crate::{
-    app_context::AppContext,
-    application_config::{update_config, AppConfig, AppModeDbus},
-    dbus,
-    modes::{app_mode_variant::AppModeVariant, shadow_cap::ShadowCapMode, AppMode},
+    app_context::{AppContext, CaptureControl},
+    application_config::{AppConfig, AppConfigDbus, AppModeDbus, ConfigSource},
+    audio_levels,
+    capture_watch::{self, CaptureEvent, CaptureLoss, StallAction, StallWatch},
+    clips::{
+        index,
+        naming::{gif_path, screenshot_path},
+    },
+    dbus::{
+        AppStatus, ClipChannels, ClipIndexQuery, ClipIndexReply, ClipService, ClipState,
+        ConcatReply, ConcatRequest, ConfigUpdateReply, GameClip, GifReply, GifRequest, MarkerReply,
+        MarkerSaveReply, MarkerSaveRequest, ModeChangeReply, PauseReply, RecentSaveReply,
+        RecentSaveRequest, ReconnectReply, RecordingReply, SaveQueue, SaveQueued, ScreenshotReply,
+        StreamingReply, TranscodeReply, TranscodeRequest,
+    },
+    dbus_types::Diagnostics,
+    diagnostics::{self, RECENT_SECONDS},
+    encoders::{
+        concat::{self, ConcatJob},
+        frame_extract::write_png,
+        gif::write_gif,
+        job::{self, JobState},
+        muxer::{ClipWindow, SaveCancelled, SaveReport},
+        transcode::{self, TranscodeJob},
+    },
+    inhibit::Inhibitor,
+    modes::{registry::ModeRegistry, AppMode},
+    shortcuts::{self, ShortcutActions},
+};
+use anyhow::{Context, Result};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicI64},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
 };
-use anyhow::Result;
-use std::sync::{atomic::AtomicBool, Arc};
-use tokio::sync::mpsc;
 use waycap_rs::pipeline::builder::CaptureBuilder;
-use zbus::{connection, Connection};
+use zbus::{connection, object_server::InterfaceRef, Connection};
+
+/// The run loop's ends of the [`ClipChannels`], and of the channel the portal watch reports
+/// losses of the screencast through.
+pub struct Requests {
+    save_rx: mpsc::Receiver<u32>,
+    config_rx: mpsc::Receiver<(AppConfigDbus, ConfigUpdateReply)>,
+    change_mode_rx: mpsc::Receiver<(AppModeDbus, ModeChangeReply)>,
+    marker_rx: mpsc::Receiver<(String, MarkerReply)>,
+    marker_save_rx: mpsc::Receiver<(MarkerSaveRequest, MarkerSaveReply)>,
+    status_rx: mpsc::Receiver<oneshot::Sender<AppStatus>>,
+    screenshot_rx: mpsc::Receiver<ScreenshotReply>,
+    gif_rx: mpsc::Receiver<(GifRequest, GifReply)>,
+    streaming_rx: mpsc::Receiver<(bool, StreamingReply)>,
+    recording_rx: mpsc::Receiver<(bool, RecordingReply)>,
+    recent_save_rx: mpsc::Receiver<(RecentSaveRequest, RecentSaveReply)>,
+    pause_rx: mpsc::Receiver<(bool, PauseReply)>,
+    config_request_rx: mpsc::Receiver<oneshot::Sender<AppConfigDbus>>,
+    transcode_rx: mpsc::Receiver<(TranscodeRequest, TranscodeReply)>,
+    concat_rx: mpsc::Receiver<(ConcatRequest, ConcatReply)>,
+    clip_index_rx: mpsc::Receiver<(ClipIndexQuery, ClipIndexReply)>,
+    diagnose_rx: mpsc::Receiver<oneshot::Sender<Diagnostics>>,
+    reconnect_rx: mpsc::Receiver<ReconnectReply>,
+    quit_rx: mpsc::Receiver<()>,
+    /// Losses of the screencast noticed outside the run loop, see [`capture_watch`].
+    capture_lost_rx: mpsc::Receiver<CaptureLoss>,
+}
+
+/// The channels between a [`ClipService`] sharing `state` and the run loop, and the sender for
+/// losses of the screencast.
+pub fn channels(state: &ClipState) -> (ClipChannels, mpsc::Sender<CaptureLoss>, Requests) {
+    let (save_tx, save_rx) = mpsc::channel(1);
+    let (config_tx, config_rx) = mpsc::channel(1);
+    let (change_mode_tx, change_mode_rx) = mpsc::channel(1);
+    let (marker_tx, marker_rx) = mpsc::channel(8);
+    let (marker_save_tx, marker_save_rx) = mpsc::channel(8);
+    let (status_tx, status_rx) = mpsc::channel(8);
+    let (screenshot_tx, screenshot_rx) = mpsc::channel(8);
+    let (gif_tx, gif_rx) = mpsc::channel(8);
+    let (streaming_tx, streaming_rx) = mpsc::channel(8);
+    let (recording_tx, recording_rx) = mpsc::channel(8);
+    let (recent_save_tx, recent_save_rx) = mpsc::channel(8);
+    let (pause_tx, pause_rx) = mpsc::channel(8);
+    let (config_request_tx, config_request_rx) = mpsc::channel(8);
+    let (transcode_tx, transcode_rx) = mpsc::channel(8);
+    let (concat_tx, concat_rx) = mpsc::channel(8);
+    let (clip_index_tx, clip_index_rx) = mpsc::channel(8);
+    let (diagnose_tx, diagnose_rx) = mpsc::channel(8);
+    let (reconnect_tx, reconnect_rx) = mpsc::channel(1);
+    let (quit_tx, quit_rx) = mpsc::channel(1);
+    let (capture_lost_tx, capture_lost_rx) = mpsc::channel(4);
+
+    let channels = ClipChannels {
+        saves: Arc::new(SaveQueue::new(save_tx, Arc::clone(&state.ids))),
+        config_tx,
+        change_mode_tx,
+        marker_tx,
+        marker_save_tx,
+        status_tx,
+        screenshot_tx,
+        gif_tx,
+        streaming_tx,
+        recording_tx,
+        recent_save_tx,
+        pause_tx,
+        config_request_tx,
+        transcode_tx,
+        concat_tx,
+        clip_index_tx,
+        diagnose_tx,
+        reconnect_tx,
+        quit_tx,
+    };
+    let requests = Requests {
+        save_rx,
+        config_rx,
+        change_mode_rx,
+        marker_rx,
+        marker_save_rx,
+        status_rx,
+        screenshot_rx,
+        gif_rx,
+        streaming_rx,
+        recording_rx,
+        recent_save_rx,
+        pause_rx,
+        config_request_rx,
+        transcode_rx,
+        concat_rx,
+        clip_index_rx,
+        diagnose_rx,
+        reconnect_rx,
+        quit_rx,
+        capture_lost_rx,
+    };
+    (channels, capture_lost_tx, requests)
+}
 
 pub struct WayCap {
     context: AppContext,
     dbus_conn: Option<Connection>,
-    dbus_save_rx: mpsc::Receiver<()>,
-    dbus_config_rx: mpsc::Receiver<AppConfig>,
-    dbus_change_mode_rx: mpsc::Receiver<AppModeDbus>,
-    mode: AppModeVariant,
+    requests: Requests,
+    /// Windowed saves which are due once their end has been captured, with their request ids.
+    window_save_tx: mpsc::Sender<(u32, ClipWindow)>,
+    window_save_rx: mpsc::Receiver<(u32, ClipWindow)>,
+    /// Set while a GIF is being encoded, only one is made at a time to bound the memory it takes.
+    making_gif: Arc<AtomicBool>,
+    stall_watch: StallWatch,
+    transcodes: Arc<JobState>,
+    /// Thread of the last transcode, joined on shutdown so it can clean up after itself.
+    transcode_handle: Option<JoinHandle<()>>,
+    concats: Arc<JobState>,
+    /// Thread of the last join of clips, joined on shutdown like the transcode.
+    concat_handle: Option<JoinHandle<()>>,
+    mode: Box<dyn AppMode>,
+    /// Builds the mode requested through `ChangeMode`.
+    modes: ModeRegistry,
+    config_source: ConfigSource,
+    /// Last status sent through `StatusChanged`.
+    published_status: Option<AppStatus>,
 }
 
 impl WayCap {
-    pub async fn new(mut mode: AppModeVariant, config: AppConfig) -> Result<Self> {
-        simple_logging::log_to_file("logs.txt", log::LevelFilter::Info)?;
-        let saving = Arc::new(AtomicBool::new(false));
-        let stop = Arc::new(AtomicBool::new(false));
-        let join_handles: Vec<std::thread::JoinHandle<()>> = Vec::new();
-
-        let (dbus_save_tx, dbus_save_rx) = mpsc::channel(1);
-        let (dbus_config_tx, dbus_config_rx): (mpsc::Sender<AppConfig>, mpsc::Receiver<AppConfig>) =
-            mpsc::channel(1);
-        let (dbus_change_mode_tx, dbus_change_mode_rx): (
-            mpsc::Sender<AppModeDbus>,
-            mpsc::Receiver<AppModeDbus>,
-        ) = mpsc::channel(1);
-
-        let clip_service =
-            dbus::ClipService::new(dbus_save_tx, dbus_config_tx, dbus_change_mode_tx);
+    pub async fn new(
+        mode: Box<dyn AppMode>,
+        config: AppConfig,
+        config_source: ConfigSource,
+    ) -> Result<Self> {
+        let state = ClipState::default();
+        let (channels, capture_lost_tx, requests) = channels(&state);
+        let shortcut_actions = ShortcutActions {
+            saves: Arc::clone(&channels.saves),
+            pause_tx: channels.pause_tx.clone(),
+            status_tx: channels.status_tx.clone(),
+            marker_tx: channels.marker_tx.clone(),
+        };
+        #[cfg(feature = "metrics")]
+        let metrics_status_tx = channels.status_tx.clone();
+        let clip_service = ClipService::new(channels, state.clone());
 
         log::debug!("Creating dbus connection");
         let connection = connection::Builder::session()?
             .name("com.rust.WayCap")?
             .serve_at("/com/rust/WayCap", clip_service)?
             .build()
-            .await?;
+            .await
+            .context("Could not claim com.rust.WayCap on the session bus")?;
 
-        let mut capture = CaptureBuilder::new()
-            .with_audio()
-            .with_quality_preset(waycap_rs::types::config::QualityPreset::Medium)
-            .with_cursor_shown()
-            .with_audio_encoder(waycap_rs::types::config::AudioEncoder::Opus)
-            .build()?;
+        if let Some(address) = config.metrics_address.clone() {
+            #[cfg(feature = "metrics")]
+            tokio::spawn(crate::metrics::serve(
+                address,
+                crate::metrics::MetricsSource {
+                    encode: Arc::clone(&state.encode),
+                    drops: Arc::clone(&state.drops),
+                    status_tx: metrics_status_tx,
+                },
+            ));
+            #[cfg(not(feature = "metrics"))]
+            log::warn!(
+                "Not serving metrics on {address}, WayCap was built without the metrics feature"
+            );
+        }
+
+        if config.audio {
+            tokio::spawn(audio_levels::publish(
+                connection.clone(),
+                Arc::clone(&state.levels),
+            ));
+        }
+
+        if config.shortcuts.enabled {
+            tokio::spawn(shortcuts::run(
+                connection.clone(),
+                config.shortcuts.clone(),
+                shortcut_actions,
+            ));
+        }
+
+        tokio::spawn(capture_watch::watch_portal(
+            connection.clone(),
+            capture_lost_tx,
+        ));
 
+        let capture = capture_builder(&config).build()?;
+        Self::start(
+            mode,
+            config,
+            config_source,
+            state,
+            requests,
+            Box::new(capture),
+            connection,
+        )
+        .await
+    }
+
+    /// Starts `mode` on `capture`, the run loop reading `requests` and serving signals through
+    /// `connection`.
+    pub async fn start(
+        mut mode: Box<dyn AppMode>,
+        config: AppConfig,
+        config_source: ConfigSource,
+        state: ClipState,
+        requests: Requests,
+        mut capture: Box<dyn CaptureControl>,
+        connection: Connection,
+    ) -> Result<Self> {
         capture.start()?;
         let mut ctx = AppContext {
-            saving,
-            stop,
-            join_handles,
+            saving: state.saving,
+            cancel_save: state.cancel_save,
+            stop: Arc::new(AtomicBool::new(false)),
+            last_video_frame: Arc::new(AtomicI64::new(chrono::Local::now().timestamp_millis())),
+            last_audio_frame: Arc::new(AtomicI64::new(chrono::Local::now().timestamp_millis())),
+            drops: state.drops,
+            encode: state.encode,
+            levels: state.levels,
+            join_handles: Vec::new(),
             capture,
+            has_audio: config.audio,
             config,
+            paused: false,
+            capture_lost: None,
+            inhibitor: Inhibitor::new(connection.clone()),
         };
 
         mode.init(&mut ctx).await?;
 
+        let (window_save_tx, window_save_rx) = mpsc::channel(8);
         Ok(Self {
             context: ctx,
-            dbus_save_rx,
-            dbus_config_rx,
-            dbus_change_mode_rx,
+            requests,
+            window_save_tx,
+            window_save_rx,
+            making_gif: Arc::new(AtomicBool::new(false)),
+            stall_watch: StallWatch::default(),
+            transcodes: state.transcodes,
+            transcode_handle: None,
+            concats: state.concats,
+            concat_handle: None,
             mode,
+            modes: ModeRegistry::default(),
+            config_source,
+            published_status: None,
             dbus_conn: Some(connection),
         })
     }
 
+    /// Builds the modes requested through `ChangeMode` with `modes` instead of the ones WayCap
+    /// comes with.
+    #[cfg(test)]
+    pub fn with_modes(mut self, modes: ModeRegistry) -> Self {
+        self.modes = modes;
+        self
+    }
+
     pub async fn run(&mut self) -> Result<()> {
+        let mut watchdog = tokio::time::interval(Duration::from_secs(1));
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sighup = signal(SignalKind::hangup())?;
         loop {
             tokio::select! {
-                _ = self.dbus_save_rx.recv() => {
-                    log::debug!("Saving...");
-                    self.mode.on_save(&mut self.context).await?;
+                Some(id) = self.requests.save_rx.recv() => {
+                    log::debug!("Saving for request {id}...");
+                    self.publish_saving().await;
+                    match self.save_default_clip().await {
+                        Ok(report) => self.emit_clip_saved(id, report).await,
+                        Err(e) if e.is::<SaveCancelled>() => {
+                            log::info!("Save {id} cancelled, the buffer is kept");
+                            self.emit_save_cancelled(id).await;
+                        }
+                        Err(e) => {
+                            log::error!("Could not save clip: {e:?}");
+                            self.emit_save_failed(id, &e).await;
+                        }
+                    }
                 },
-                Some(cfg) = self.dbus_config_rx.recv() => {
-                    update_config(cfg);
+                Some((request, reply)) = self.requests.marker_save_rx.recv() => {
+                    let result = self.schedule_marker_save(request).await;
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
                 },
-                Some(new_mode) = self.dbus_change_mode_rx.recv() => {
-                    self.try_switch_mode(new_mode).await?;
+                Some((id, window)) = self.window_save_rx.recv() => {
+                    self.publish_saving().await;
+                    match self.mode.on_save_window(&mut self.context, window).await {
+                        Ok(report) => self.emit_clip_saved(id, report).await,
+                        Err(e) if e.is::<SaveCancelled>() => {
+                            log::info!("Save {id} of clip window {window:?} cancelled");
+                            self.emit_save_cancelled(id).await;
+                        }
+                        Err(e) => {
+                            log::error!("Could not save clip window {window:?}: {e:?}");
+                            self.emit_save_failed(id, &e).await;
+                        }
+                    }
                 },
-                _ = tokio::signal::ctrl_c() => {
-                    log::debug!("Shutting down");
-                    self.mode.on_shutdown(&mut self.context).await?;
-                    break;
-                }
+                Some((request, reply)) = self.requests.recent_save_rx.recv() => {
+                    let window = match request.seconds {
+                        Some(seconds) => self.mode.recent_window(&mut self.context, seconds).await,
+                        None => Ok(ClipWindow::default()),
+                    };
+                    let result = window
+                        .map(|window| self.queue_window_save(request.id, ClipWindow { streams: request.streams, ..window }))
+                        .map_err(|e| e.to_string());
+                    let _ = reply.send(result);
+                },
+                Some(reply) = self.requests.config_request_rx.recv() => {
+                    let _ = reply.send(AppConfigDbus::from(&self.context.config));
+                },
+                Some((paused, reply)) = self.requests.pause_rx.recv() => {
+                    let result = self.set_paused(paused).await;
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                },
+                Some((cfg, reply)) = self.requests.config_rx.recv() => {
+                    let result = match cfg.apply_to(&self.context.config) {
+                        Ok(new_config) => self.apply_config(new_config).await.map_err(|e| e.to_string()),
+                        Err(e) => Err(e),
+                    };
+                    let _ = reply.send(result);
+                },
+                Some((new_mode, reply)) = self.requests.change_mode_rx.recv() => {
+                    self.try_switch_mode(new_mode, reply).await;
+                },
+                Some((enabled, reply)) = self.requests.streaming_rx.recv() => {
+                    let result = self.mode.set_streaming(&mut self.context, enabled).await;
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                },
+                Some((enabled, reply)) = self.requests.recording_rx.recv() => {
+                    let result = self.mode.set_recording(&mut self.context, enabled).await;
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                },
+                Some((label, reply)) = self.requests.marker_rx.recv() => {
+                    let result = self.mode.add_marker(&mut self.context, label).await;
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                },
+                Some(reply) = self.requests.status_rx.recv() => {
+                    let _ = reply.send(self.status().await);
+                },
+                Some(reply) = self.requests.screenshot_rx.recv() => {
+                    self.take_screenshot(reply).await;
+                },
+                Some((request, reply)) = self.requests.gif_rx.recv() => {
+                    self.save_gif(request, reply).await;
+                },
+                Some((request, reply)) = self.requests.transcode_rx.recv() => {
+                    self.start_transcode(request, reply);
+                },
+                Some((request, reply)) = self.requests.concat_rx.recv() => {
+                    self.start_concat(request, reply);
+                },
+                Some((query, reply)) = self.requests.clip_index_rx.recv() => {
+                    let output_dir = self.context.config.output_dir.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let clips = match query {
+                            ClipIndexQuery::Recent(limit) => index::recent(&output_dir, limit),
+                            ClipIndexQuery::Find(path) => index::find(&output_dir, Path::new(&path))
+                                .map(|clip| clip.into_iter().collect()),
+                        };
+                        let _ = reply.send(clips.map_err(|e| format!("{e:#}")));
+                    });
+                },
+                Some(reply) = self.requests.diagnose_rx.recv() => {
+                    let _ = reply.send(self.diagnose());
+                },
+                Some(loss) = self.requests.capture_lost_rx.recv() => {
+                    self.on_capture_lost(loss).await;
+                },
+                Some(reply) = self.requests.reconnect_rx.recv() => {
+                    let result = self.reconnect().await;
+                    let _ = reply.send(result.map_err(|e| format!("{e:#}")));
+                },
+                _ = watchdog.tick() => {
+                    self.restart_stalled_capture().await;
+                    if let Err(e) = self.mode.on_tick(&mut self.context).await {
+                        log::error!("Error in {:?}: {e:?}", self.mode);
+                        self.context.encode.record_error(&e);
+                    }
+                },
+                _ = sighup.recv() => {
+                    log::info!("Received SIGHUP, reloading config");
+                    let reloaded = match self.config_source.load() {
+                        Ok(config) => self.apply_config(config).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = reloaded {
+                        log::error!("Could not reload config: {e:?}");
+                    }
+                },
+                Some(()) = self.requests.quit_rx.recv() => break,
+                _ = sigterm.recv() => break,
+                _ = tokio::signal::ctrl_c() => break,
+            }
+
+            let status = self.status().await;
+            self.publish_status(status).await;
+        }
+
+        // The signal streams stay registered until they are dropped so a repeated signal from
+        // here on is ignored instead of killing the process mid shutdown
+        log::debug!("Shutting down");
+        self.mode.on_shutdown(&mut self.context).await?;
+
+        if self.transcodes.cancel() {
+            log::info!("Cancelling the running transcode");
+        }
+        if let Some(handle) = self.transcode_handle.take() {
+            if let Err(e) = handle.join() {
+                log::error!("Error shutting down the transcode: {e:?}");
+            }
+        }
+        if self.concats.cancel() {
+            log::info!("Cancelling the running join of clips");
+        }
+        if let Some(handle) = self.concat_handle.take() {
+            if let Err(e) = handle.join() {
+                log::error!("Error shutting down the join of clips: {e:?}");
             }
         }
 
@@ -108,34 +477,665 @@ impl WayCap {
         Ok(())
     }
 
-    async fn try_switch_mode(&mut self, new_mode: AppModeDbus) -> anyhow::Result<()> {
-        let current_mode = self.mode.to_dbus();
-        if new_mode == current_mode {
-            log::info!("Already in {:?}. Not switching", self.mode);
+    /// Validates the new config, hands it to the active mode, then persists it. Returns the
+    /// fields which could not be applied without a restart.
+    async fn apply_config(&mut self, new_config: AppConfig) -> Result<Vec<String>> {
+        new_config.validate()?;
+        let old_config = self.context.config.clone();
+        let pending = self
+            .mode
+            .on_config_update(&mut self.context, &old_config, &new_config)
+            .await?;
+
+        if let Err(e) = self.config_source.save(&new_config) {
+            log::error!("{e:#}, the new config only lasts until a restart");
+        }
+        self.context.config = new_config;
+
+        if pending.is_empty() {
+            log::info!("Applied new config: {:?}", self.context.config);
+        } else {
+            log::warn!("Config saved but {pending:?} will only take effect after a restart");
+        }
+
+        Ok(pending)
+    }
+
+    async fn set_paused(&mut self, paused: bool) -> Result<()> {
+        if paused == self.context.paused {
             return Ok(());
         }
 
-        log::info!("Exiting {:?}", self.mode);
+        if paused {
+            self.context.capture.pause()?;
+            self.mode.on_pause(&mut self.context).await?;
+            log::info!("Capture paused");
+        } else {
+            self.mode.on_resume(&mut self.context).await?;
+            self.context.capture.start()?;
+            // No frames arrived while paused, don't let the watchdog count that as a stall
+            self.context.last_video_frame.store(
+                chrono::Local::now().timestamp_millis(),
+                std::sync::atomic::Ordering::Release,
+            );
+            log::info!("Capture resumed");
+        }
+        self.context.paused = paused;
+        self.context.inhibitor.set_paused(paused).await;
+        Ok(())
+    }
+
+    /// Restarts the capture if it stopped delivering video frames, e.g. after the monitor went to
+    /// sleep. A capture which stays silent after the restart is taken for lost. The shadow buffers
+    /// are left untouched either way so earlier footage can still be saved. A restart which
+    /// fails also takes the capture for lost rather than stopping the daemon.
+    async fn restart_stalled_capture(&mut self) {
+        match self.context.capture_lost {
+            // A stream which only looked stopped, e.g. while the monitor slept, comes back by
+            // itself
+            Some(CaptureLoss::StreamStopped) if self.context.encode.recent_video_packets(1) > 0 => {
+                log::info!("Video frames are arriving again, the capture recovered");
+                self.context.capture_lost = None;
+                self.stall_watch = StallWatch::default();
+                self.capture_event(CaptureEvent::Recovered).await;
+                return;
+            }
+            Some(_) => return,
+            None => {}
+        }
+        if self.context.paused
+            || self
+                .context
+                .saving
+                .load(std::sync::atomic::Ordering::Acquire)
+        {
+            return;
+        }
+
+        let now = chrono::Local::now().timestamp_millis();
+        let last_frame = self
+            .context
+            .last_video_frame
+            .load(std::sync::atomic::Ordering::Acquire);
+        let stalled_seconds = match self.stall_watch.check(
+            now,
+            last_frame,
+            self.context.config.stall_timeout_seconds,
+        ) {
+            StallAction::Wait => return,
+            StallAction::Restart { stalled_seconds } => stalled_seconds,
+            StallAction::Lost { stalled_seconds } => {
+                log::warn!("Still no video frames after restarting the capture, {stalled_seconds} seconds without any");
+                self.on_capture_lost(CaptureLoss::StreamStopped).await;
+                return;
+            }
+        };
+
+        log::warn!("No video frames received for {stalled_seconds} seconds, restarting capture");
+        let restarted = self
+            .context
+            .capture
+            .finish()
+            .and_then(|()| self.context.capture.reset())
+            .and_then(|()| self.context.capture.start());
+        if let Err(e) = restarted {
+            let e = e.context("Could not restart the stalled capture");
+            log::error!("{e:?}");
+            self.context.encode.record_error(&e);
+            self.on_capture_lost(CaptureLoss::StreamStopped).await;
+            return;
+        }
+        // Give the restarted capture a full timeout before trying again
+        self.context
+            .last_video_frame
+            .store(now, std::sync::atomic::Ordering::Release);
+        self.capture_event(CaptureEvent::Restarted { stalled_seconds })
+            .await;
+
+        if let Some(iface) = self.clip_service().await {
+            if let Err(e) =
+                ClipService::capture_restarted(iface.signal_emitter(), stalled_seconds).await
+            {
+                log::error!("Could not emit capture restarted signal: {e:?}");
+            }
+        }
+    }
+
+    /// Tells the user to pick the screen again. waycap-rs asks the portal for a new session
+    /// without a restore token, so there is nothing to re-establish the screencast with silently.
+    /// The buffers keep their footage, nothing is trimmed until frames arrive again.
+    async fn on_capture_lost(&mut self, loss: CaptureLoss) {
+        if self.context.capture_lost.is_some() {
+            return;
+        }
+        log::error!(
+            "The screencast was lost as {}, call Reconnect to pick the screen again",
+            loss.reason()
+        );
+        self.context.capture_lost = Some(loss);
+        self.capture_event(CaptureEvent::Lost(loss)).await;
+
+        if let Some(iface) = self.clip_service().await {
+            if let Err(e) =
+                ClipService::capture_lost(iface.signal_emitter(), loss.reason().to_string()).await
+            {
+                log::error!("Could not emit capture lost signal: {e:?}");
+            }
+        }
+    }
+
+    /// Replaces the lost capture with a new one, which has the portal ask for the screen again.
+    /// The mode is started over on the new capture with its buffers intact. The run loop waits
+    /// for the portal meanwhile, like it does at startup.
+    async fn reconnect(&mut self) -> Result<()> {
+        if self.context.capture_lost.is_none() {
+            anyhow::bail!("The capture is running, there is nothing to reconnect");
+        }
+        if self
+            .context
+            .saving
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            anyhow::bail!("A clip is being saved, try again once it is done");
+        }
+
+        log::info!("Building a new capture, pick the screen to share");
         self.mode.on_exit(&mut self.context).await?;
+        let rebuilt = capture_builder(&self.context.config).build();
+        let result = match rebuilt {
+            Ok(capture) => {
+                let mut lost = std::mem::replace(&mut self.context.capture, Box::new(capture));
+                if let Err(e) = lost.close() {
+                    log::warn!("Could not close the lost capture: {e:?}");
+                }
+                self.context.has_audio = self.context.config.audio;
+                self.context.capture_lost = None;
+                self.stall_watch = StallWatch::default();
+                Ok(())
+            }
+            // The mode goes back onto the lost capture so its buffers can still be saved
+            Err(e) => Err(anyhow::Error::from(e).context("Could not build a new capture")),
+        };
+
+        self.context
+            .stop
+            .store(false, std::sync::atomic::Ordering::Release);
+        self.context.last_video_frame.store(
+            chrono::Local::now().timestamp_millis(),
+            std::sync::atomic::Ordering::Release,
+        );
+        self.mode.init(&mut self.context).await?;
+        if result.is_ok() {
+            log::info!("Capture reconnected");
+            self.capture_event(CaptureEvent::Reconnected).await;
+        }
+        result
+    }
 
-        let mode = match new_mode {
-            AppModeDbus::Shadow => {
-                AppModeVariant::Shadow(ShadowCapMode::new(self.context.config.max_seconds).await?)
+    /// Tells the mode about `event`. The capture carries on whatever the mode makes of it, so a
+    /// failure is only recorded.
+    async fn capture_event(&mut self, event: CaptureEvent) {
+        if let Err(e) = self.mode.on_capture_event(&mut self.context, event).await {
+            log::error!("Error in {:?} handling {event:?}: {e:?}", self.mode);
+            self.context.encode.record_error(&e);
+        }
+    }
+
+    /// Queues the save of the window around a marker for once its end has been captured.
+    async fn schedule_marker_save(&mut self, request: MarkerSaveRequest) -> Result<SaveQueued> {
+        let (window, wait) = self
+            .mode
+            .marker_window(
+                &mut self.context,
+                request.marker_id,
+                request.before_secs,
+                request.after_secs,
+            )
+            .await?;
+
+        log::info!(
+            "Saving the clip around marker {} in {wait:?} for request {}",
+            request.marker_id,
+            request.id
+        );
+        let window_save_tx = self.window_save_tx.clone();
+        let id = request.id;
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            if window_save_tx.send((id, window)).await.is_err() {
+                log::error!("Could not queue save {id}, the run loop is gone");
+            }
+        });
+        Ok(SaveQueued::Queued)
+    }
+
+    /// Queues a windowed save behind the saves already waiting, which makes it `busy`.
+    fn queue_window_save(&self, id: u32, window: ClipWindow) -> SaveQueued {
+        let waiting = !self.window_save_rx.is_empty() || !self.requests.save_rx.is_empty();
+        match self.window_save_tx.try_send((id, window)) {
+            Ok(()) if waiting => SaveQueued::AlreadyPending,
+            Ok(()) => SaveQueued::Queued,
+            Err(e) => {
+                log::error!("Could not queue save {id}: {e}");
+                SaveQueued::Failed
+            }
+        }
+    }
+
+    /// Saves what `SaveClip` asks for, the last `default_clip_seconds` if set and otherwise the
+    /// whole buffer. A shorter clip leaves the buffer in place like any other windowed save.
+    async fn save_default_clip(&mut self) -> Result<SaveReport> {
+        let Some(seconds) = self.context.config.default_clip_seconds else {
+            return self.mode.on_save(&mut self.context).await;
+        };
+        let window = self.mode.recent_window(&mut self.context, seconds).await?;
+        self.mode.on_save_window(&mut self.context, window).await
+    }
+
+    /// Decodes the newest frame on a blocking thread so the run loop keeps serving requests
+    /// meanwhile.
+    async fn take_screenshot(&mut self, reply: ScreenshotReply) {
+        let gop = match self.mode.latest_gop(&mut self.context).await {
+            Ok(gop) => gop,
+            Err(e) => {
+                let _ = reply.send(Err(e.to_string()));
+                return;
+            }
+        };
+
+        let output_dir = self.context.config.output_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let path = screenshot_path(&output_dir, chrono::Local::now().timestamp());
+            let result = std::fs::create_dir_all(&output_dir)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| gop.decode_last_frame())
+                .and_then(|frame| write_png(&frame, &path));
+            match result {
+                Ok(()) => {
+                    log::info!("Saved screenshot to {path:?}");
+                    let _ = reply.send(Ok(path.display().to_string()));
+                }
+                Err(e) => {
+                    log::error!("Could not take a screenshot: {e:?}");
+                    let _ = reply.send(Err(e.to_string()));
+                }
+            }
+        });
+    }
+
+    /// Encodes a GIF of the recent footage on a blocking thread, replying with its path once
+    /// written. Requests made while one is being encoded are turned down.
+    async fn save_gif(&mut self, request: GifRequest, reply: GifReply) {
+        let GifRequest { id, options } = request;
+        if self
+            .making_gif
+            .swap(true, std::sync::atomic::Ordering::AcqRel)
+        {
+            let _ = reply.send(Err(format!(
+                "A GIF is already being made, turning down request {id}"
+            )));
+            return;
+        }
+        let (gop, start) = match self
+            .mode
+            .recent_video(&mut self.context, options.seconds)
+            .await
+        {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.making_gif
+                    .store(false, std::sync::atomic::Ordering::Release);
+                let _ = reply.send(Err(e.to_string()));
+                return;
+            }
+        };
+
+        let output_dir = self.context.config.output_dir.clone();
+        let making_gif = Arc::clone(&self.making_gif);
+        tokio::task::spawn_blocking(move || {
+            let path = gif_path(&output_dir, chrono::Local::now().timestamp());
+            log::info!(
+                "Making a {}s GIF at {} fps, {} pixels wide for request {id}",
+                options.seconds,
+                options.fps,
+                options.width
+            );
+            let result = std::fs::create_dir_all(&output_dir)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| write_gif(&gop, start, options, &path));
+            making_gif.store(false, std::sync::atomic::Ordering::Release);
+            match result {
+                Ok(frames) => {
+                    log::info!("Saved a GIF of {frames} frames to {path:?}");
+                    let _ = reply.send(Ok(path.display().to_string()));
+                }
+                Err(e) => {
+                    log::error!("Could not save a GIF: {e:?}");
+                    let _ = reply.send(Err(format!("{e:#}")));
+                }
+            }
+        });
+    }
+
+    /// Starts transcoding a clip from the output directory on a thread of its own, unless
+    /// another transcode is still running.
+    fn start_transcode(&mut self, request: TranscodeRequest, reply: TranscodeReply) {
+        let input = match transcode::resolve_input(
+            &self.context.config.output_dir,
+            Path::new(&request.input_path),
+        ) {
+            Ok(input) => input,
+            Err(e) => {
+                let _ = reply.send(Err(format!("{e:#}")));
+                return;
+            }
+        };
+        if !self.transcodes.try_start() {
+            let _ = reply.send(Err(
+                "A transcode is already running, cancel it or wait for it to finish".to_string(),
+            ));
+            return;
+        }
+
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        if let Some(conn) = &self.dbus_conn {
+            tokio::spawn(transcode::publish(
+                conn.clone(),
+                input.display().to_string(),
+                events_rx,
+            ));
+        }
+        let job = TranscodeJob {
+            input,
+            options: request.options,
+            encoder: self.context.config.encoder,
+        };
+        match job::spawn(
+            job,
+            &self.context.config.threads,
+            Arc::clone(&self.transcodes),
+            reply,
+            events_tx,
+        ) {
+            Ok(handle) => self.transcode_handle = Some(handle),
+            Err(e) => {
+                log::error!("Could not start the transcode: {e:?}");
+                self.transcodes.finish();
+            }
+        }
+    }
+
+    /// Starts joining clips from the output directory on a thread of its own, unless another
+    /// join is still running.
+    fn start_concat(&mut self, request: ConcatRequest, reply: ConcatReply) {
+        let output_dir = &self.context.config.output_dir;
+        let job = request
+            .paths
+            .iter()
+            .map(|path| transcode::resolve_input(output_dir, Path::new(path)))
+            .collect::<Result<Vec<_>>>()
+            .and_then(|inputs| {
+                let extension = inputs
+                    .first()
+                    .and_then(|input| input.extension())
+                    .map_or("mp4".into(), |extension| extension.to_string_lossy());
+                let output = concat::resolve_output(output_dir, &request.output_name, &extension)?;
+                Ok(ConcatJob { inputs, output })
+            });
+        let job = match job {
+            Ok(job) => job,
+            Err(e) => {
+                let _ = reply.send(Err(format!("{e:#}")));
+                return;
             }
         };
+        if !self.concats.try_start() {
+            let _ = reply.send(Err(
+                "Clips are already being joined, cancel it or wait for it to finish".to_string(),
+            ));
+            return;
+        }
+
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        if let Some(conn) = &self.dbus_conn {
+            tokio::spawn(concat::publish(
+                conn.clone(),
+                request.id,
+                job.output.display().to_string(),
+                events_rx,
+            ));
+        }
+        match job::spawn(
+            job,
+            &self.context.config.threads,
+            Arc::clone(&self.concats),
+            reply,
+            events_tx,
+        ) {
+            Ok(handle) => self.concat_handle = Some(handle),
+            Err(e) => {
+                log::error!("Could not start joining the clips: {e:?}");
+                self.concats.finish();
+            }
+        }
+    }
+
+    async fn emit_clip_saved(&self, request_id: u32, report: SaveReport) {
+        self.context.encode.record_save(report.save_duration_ms);
+        // MODE and CLIP_PATH become fields of their own in the journal
+        log::info!(
+            mode = format!("{:?}", self.mode).as_str(), clip_path = report.path.as_str();
+            "Clip saved to {}", report.path
+        );
+        if let Some(iface) = self.clip_service().await {
+            if let Err(e) =
+                ClipService::clip_saved(iface.signal_emitter(), report, request_id).await
+            {
+                log::error!("Could not emit clip saved signal: {e:?}");
+            }
+        }
+    }
+
+    async fn emit_save_cancelled(&self, request_id: u32) {
+        if let Some(iface) = self.clip_service().await {
+            if let Err(e) = ClipService::save_cancelled(iface.signal_emitter(), request_id).await {
+                log::error!("Could not emit save cancelled signal: {e:?}");
+            }
+        }
+    }
+
+    async fn emit_save_failed(&self, request_id: u32, error: &anyhow::Error) {
+        self.context.encode.record_error(error);
+        if let Some(iface) = self.clip_service().await {
+            if let Err(e) =
+                ClipService::save_failed(iface.signal_emitter(), format!("{error:#}"), request_id)
+                    .await
+            {
+                log::error!("Could not emit save failed signal: {e:?}");
+            }
+        }
+    }
+
+    /// Announces the save which is about to run, the save itself blocks the run loop until it is
+    /// done.
+    async fn publish_saving(&mut self) {
+        let mut status = self.status().await;
+        status.saving = true;
+        self.publish_status(status).await;
+    }
+
+    /// Emits `StatusChanged` if anything but the buffered length changed since the last one.
+    async fn publish_status(&mut self, status: AppStatus) {
+        let changed = self.published_status.as_ref().is_none_or(|last| {
+            last.mode != status.mode
+                || last.saving != status.saving
+                || last.paused != status.paused
+                || last.capture_lost != status.capture_lost
+                || last.streaming != status.streaming
+                || last.recording != status.recording
+        });
+        if !changed {
+            return;
+        }
+
+        if let Some(iface) = self.clip_service().await {
+            if let Err(e) =
+                ClipService::status_changed(iface.signal_emitter(), status.clone()).await
+            {
+                log::error!("Could not emit status changed signal: {e:?}");
+            }
+        }
+        self.published_status = Some(status);
+    }
+
+    async fn status(&mut self) -> AppStatus {
+        let now = chrono::Local::now().timestamp_millis();
+        let age_ms = |last: &AtomicI64| {
+            (now - last.load(std::sync::atomic::Ordering::Acquire)).max(0) as u64
+        };
+        let mut status = AppStatus {
+            mode: format!("{:?}", self.mode),
+            paused: self.context.paused,
+            capture_lost: self.context.capture_lost.is_some(),
+            fps: self.context.encode.snapshot().encode_fps,
+            max_fps: self.context.config.max_fps,
+            default_clip_seconds: self.context.config.default_clip_seconds.unwrap_or(0),
+            saving: self
+                .context
+                .saving
+                .load(std::sync::atomic::Ordering::Acquire),
+            last_video_frame_age_ms: age_ms(&self.context.last_video_frame),
+            last_audio_frame_age_ms: match self.context.has_audio {
+                true => age_ms(&self.context.last_audio_frame),
+                false => 0,
+            },
+            ..Default::default()
+        };
+        self.mode.fill_status(&mut self.context, &mut status).await;
+        status
+    }
+
+    fn diagnose(&self) -> Diagnostics {
+        let last_frame_age_ms = chrono::Local::now().timestamp_millis()
+            - self
+                .context
+                .last_video_frame
+                .load(std::sync::atomic::Ordering::Acquire);
+        Diagnostics {
+            capture_lost: self
+                .context
+                .capture_lost
+                .map_or_else(String::new, |loss| loss.reason().to_string()),
+            ..diagnostics::capture_report(
+                &self.context.config,
+                format!("{:?}", self.mode),
+                self.context.paused,
+                self.context.encode.recent_video_packets(RECENT_SECONDS),
+                last_frame_age_ms.max(0) as u64,
+            )
+        }
+    }
+
+    /// The served dbus interface, used to emit signals.
+    async fn clip_service(&self) -> Option<InterfaceRef<ClipService>> {
+        let conn = self.dbus_conn.as_ref()?;
+        match conn
+            .object_server()
+            .interface::<_, ClipService>("/com/rust/WayCap")
+            .await
+        {
+            Ok(iface) => Some(iface),
+            Err(e) => {
+                log::error!("Could not get the dbus interface: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Switches to `new_mode`. The new mode is created before the current one is torn down so a
+    /// misconfigured mode is reported through `reply` and the current one keeps running. `reply`
+    /// is only answered once the new mode started. If it fails to, the error is sent back instead
+    /// and the previous mode is started again.
+    async fn try_switch_mode(&mut self, new_mode: AppModeDbus, reply: ModeChangeReply) {
+        let current_mode = self.mode.to_dbus();
+        if new_mode == current_mode {
+            log::info!("Already in {:?}. Not switching", self.mode);
+            let _ = reply.send(Ok(()));
+            return;
+        }
+
+        let mode = match self.modes.create(new_mode, &self.context).await {
+            Ok(mode) => mode,
+            Err(e) => {
+                log::error!("Not switching modes: {e:?}");
+                let _ = reply.send(Err(e.to_string()));
+                return;
+            }
+        };
+
+        log::info!("Exiting {:?}", self.mode);
+        if let Err(e) = self.mode.on_exit(&mut self.context).await {
+            log::error!("Could not exit {:?}, not switching modes: {e:?}", self.mode);
+            let _ = reply.send(Err(format!("{e:#}")));
+            self.restart_mode().await;
+            return;
+        }
 
         log::info!("Initializing {mode:?}");
-        self.mode = mode;
+        let previous = std::mem::replace(&mut self.mode, mode);
+        self.reset_mode_state();
+        match self.mode.init(&mut self.context).await {
+            Ok(()) => {
+                let _ = reply.send(Ok(()));
+            }
+            Err(e) => {
+                log::error!(
+                    "Could not start {:?}, going back to {previous:?}: {e:?}",
+                    self.mode
+                );
+                let _ = reply.send(Err(format!("{e:#}")));
+                // Whatever the failed mode got to start is stopped again
+                if let Err(e) = self.mode.on_exit(&mut self.context).await {
+                    log::warn!("Could not clean up after {:?}: {e:?}", self.mode);
+                }
+                self.mode = previous;
+                self.restart_mode().await;
+            }
+        }
+    }
+
+    /// Starts the current mode over after it was exited. A failure leaves the daemon running
+    /// without a working mode, which is recorded for `Status` and `Diagnose`.
+    async fn restart_mode(&mut self) {
+        self.reset_mode_state();
+        if let Err(e) = self.mode.init(&mut self.context).await {
+            let e = e.context(format!("Could not restart {:?}", self.mode));
+            log::error!("{e:?}");
+            self.context.encode.record_error(&e);
+        }
+    }
 
-        // Reset internal states
+    /// Clears the flags the exited mode may have left set.
+    fn reset_mode_state(&mut self) {
         self.context
             .stop
             .store(false, std::sync::atomic::Ordering::Release);
         self.context
             .saving
             .store(false, std::sync::atomic::Ordering::Release);
+    }
+}
 
-        self.mode.init(&mut self.context).await?;
-        Ok(())
+/// The capture as configured, built at startup and again by `Reconnect`.
+fn capture_builder(config: &AppConfig) -> CaptureBuilder {
+    let builder = CaptureBuilder::new()
+        .with_quality_preset(config.quality.into())
+        .with_target_fps(config.target_fps())
+        .with_cursor_shown();
+    match config.audio {
+        true => builder
+            .with_audio()
+            .with_audio_encoder(waycap_rs::types::config::AudioEncoder::Opus),
+        false => builder,
     }
 }
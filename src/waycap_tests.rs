@@ -0,0 +1,477 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use crossbeam::channel::Receiver;
+use futures_util::StreamExt;
+use tokio::sync::{Notify, Semaphore};
+use waycap_rs::types::{audio_frame::EncodedAudioFrame, video_frame::EncodedVideoFrame};
+use zbus::{connection, message::Type, Connection, Guid, MessageStream};
+
+use super::{
+    app_context::{AppContext, CaptureControl},
+    application_config::{AppConfig, AppModeDbus, ConfigSource},
+    dbus::{AppStatus, ClipService, ClipState},
+    encoders::muxer::{ClipWindow, SaveCancelled, SaveReport, StreamParams},
+    modes::{registry::ModeRegistry, AppMode},
+    waycap::{self, WayCap},
+};
+
+const PATH: &str = "/com/rust/WayCap";
+const INTERFACE: &str = "com.rust.WayCap";
+
+/// A capture which never delivers any frames.
+struct IdleCapture;
+
+impl CaptureControl for IdleCapture {
+    fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn pause(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn video_receiver(&mut self) -> Receiver<EncodedVideoFrame> {
+        crossbeam::channel::never()
+    }
+
+    fn audio_receiver(&mut self) -> anyhow::Result<Receiver<EncodedAudioFrame>> {
+        Ok(crossbeam::channel::never())
+    }
+
+    fn video_params(&self) -> Option<StreamParams> {
+        None
+    }
+
+    fn audio_params(&self) -> Option<StreamParams> {
+        None
+    }
+}
+
+/// The hooks the modes of a test were called with, in order.
+type Hooks = Arc<Mutex<Vec<String>>>;
+
+/// A mode which only notes down its hooks and leaves the capture alone. Everything it doesn't
+/// implement comes from the defaults of [`AppMode`].
+struct HookMode {
+    mode: AppModeDbus,
+    hooks: Hooks,
+    fail_init: bool,
+}
+
+impl HookMode {
+    fn new(mode: AppModeDbus, hooks: &Hooks) -> Self {
+        Self {
+            mode,
+            hooks: Arc::clone(hooks),
+            fail_init: false,
+        }
+    }
+
+    fn note(&self, hook: &str) {
+        self.hooks
+            .lock()
+            .unwrap()
+            .push(format!("{:?} {hook}", self.mode));
+    }
+}
+
+#[async_trait]
+impl AppMode for HookMode {
+    fn to_dbus(&self) -> AppModeDbus {
+        self.mode
+    }
+
+    async fn init(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.note("init");
+        anyhow::ensure!(!self.fail_init, "{:?} does not start", self.mode);
+        Ok(())
+    }
+
+    async fn on_save(&mut self, _ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        self.note("on_save");
+        anyhow::bail!("Nothing is buffered")
+    }
+
+    async fn on_save_window(
+        &mut self,
+        _ctx: &mut AppContext,
+        _window: ClipWindow,
+    ) -> anyhow::Result<SaveReport> {
+        self.note("on_save_window");
+        anyhow::bail!("Nothing is buffered")
+    }
+
+    async fn on_shutdown(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.note("on_shutdown");
+        Ok(())
+    }
+
+    async fn on_exit(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.note("on_exit");
+        Ok(())
+    }
+
+    async fn on_pause(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.note("on_pause");
+        Ok(())
+    }
+
+    async fn on_resume(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        self.note("on_resume");
+        Ok(())
+    }
+}
+
+/// A mode whose saves only finish once the test hands out a permit for them, or are cancelled.
+/// The run loop is stuck in the save meanwhile, like it is while a long clip is written.
+struct SlowMode {
+    /// Notified whenever a save started.
+    started: Arc<Notify>,
+    /// One permit per save allowed to finish.
+    release: Arc<Semaphore>,
+}
+
+impl SlowMode {
+    async fn save(&self, ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        ctx.cancel_save.store(false, Ordering::Release);
+        ctx.saving.store(true, Ordering::Release);
+        self.started.notify_one();
+        let saved = tokio::select! {
+            permit = self.release.acquire() => {
+                permit?.forget();
+                Ok(report())
+            }
+            () = cancelled(&ctx.cancel_save) => Err(SaveCancelled.into()),
+        };
+        ctx.saving.store(false, Ordering::Release);
+        saved
+    }
+}
+
+async fn cancelled(cancel: &AtomicBool) {
+    while !cancel.load(Ordering::Acquire) {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+fn report() -> SaveReport {
+    SaveReport {
+        path: "/clips/clip.mp4".to_string(),
+        save_duration_ms: 0,
+        clip_duration_ms: 0,
+        frame_rate: 0.0,
+        video_frames: 0,
+        audio_frames: 0,
+        bytes_on_disk: 0,
+        skipped_video_frames: 0,
+        skipped_audio_frames: 0,
+        dropped_video_frames: 0,
+        dropped_audio_frames: 0,
+    }
+}
+
+#[async_trait]
+impl AppMode for SlowMode {
+    fn to_dbus(&self) -> AppModeDbus {
+        AppModeDbus::Shadow
+    }
+
+    async fn init(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn on_save(&mut self, ctx: &mut AppContext) -> anyhow::Result<SaveReport> {
+        self.save(ctx).await
+    }
+
+    async fn on_save_window(
+        &mut self,
+        ctx: &mut AppContext,
+        _window: ClipWindow,
+    ) -> anyhow::Result<SaveReport> {
+        self.save(ctx).await
+    }
+
+    async fn recent_window(
+        &mut self,
+        _ctx: &mut AppContext,
+        _seconds: u32,
+    ) -> anyhow::Result<ClipWindow> {
+        Ok(ClipWindow::default())
+    }
+
+    async fn on_shutdown(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn on_exit(&mut self, _ctx: &mut AppContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A registry building a [`HookMode`] for Record mode, one which fails to start if `fail_init`.
+fn record_registry(hooks: &Hooks, fail_init: bool) -> ModeRegistry {
+    let mut modes = ModeRegistry::empty();
+    let hooks = Arc::clone(hooks);
+    modes.register(AppModeDbus::Record, move |_| {
+        let mode = HookMode {
+            fail_init,
+            ..HookMode::new(AppModeDbus::Record, &hooks)
+        };
+        Box::pin(async move {
+            let mode: Box<dyn AppMode> = Box::new(mode);
+            Ok(mode)
+        })
+    });
+    modes
+}
+
+/// Starts `mode` in a run loop over an [`IdleCapture`], serving its dbus interface over a private
+/// connection, with its config file in `dir`. Returns the run loop and the client's end of the
+/// connection.
+async fn start(mode: Box<dyn AppMode>, modes: ModeRegistry, dir: &Path) -> (WayCap, Connection) {
+    let state = ClipState::default();
+    let (channels, _capture_lost_tx, requests) = waycap::channels(&state);
+    let service = ClipService::new(channels, state.clone());
+
+    let (server, client) = tokio::net::UnixStream::pair().unwrap();
+    let server = connection::Builder::unix_stream(server)
+        .server(Guid::generate())
+        .unwrap()
+        .p2p()
+        .serve_at(PATH, service)
+        .unwrap()
+        .build();
+    let client = connection::Builder::unix_stream(client).p2p().build();
+    let (server, client) = tokio::try_join!(server, client).unwrap();
+
+    let config = AppConfig {
+        audio: false,
+        ..AppConfig::default()
+    };
+    // Nothing in the tests loads or saves the config
+    let config_source = ConfigSource::new(Some(dir.join("config.toml")), Default::default());
+    let app = WayCap::start(
+        mode,
+        config,
+        config_source,
+        state,
+        requests,
+        Box::new(IdleCapture),
+        server,
+    )
+    .await
+    .unwrap()
+    .with_modes(modes);
+    (app, client)
+}
+
+async fn call<B>(client: &Connection, method: &str, body: &B) -> zbus::Result<zbus::Message>
+where
+    B: serde::Serialize + zbus::zvariant::DynamicType,
+{
+    client
+        .call_method(None::<&str>, PATH, Some(INTERFACE), method, body)
+        .await
+}
+
+async fn save(client: &Connection, method: &str) -> (u32, String) {
+    match method {
+        "SaveClipLast" => call(client, method, &(5u32,)).await,
+        _ => call(client, method, &()).await,
+    }
+    .unwrap()
+    .body()
+    .deserialize()
+    .unwrap()
+}
+
+/// Waits for the next `ClipSaved`, `SaveFailed` or `SaveCancelled`, returning its name and the
+/// request id it carries.
+async fn next_save_signal(signals: &mut MessageStream) -> (String, u32) {
+    let next = async {
+        loop {
+            let msg = signals.next().await.unwrap().unwrap();
+            if msg.message_type() != Type::Signal {
+                continue;
+            }
+            let Some(member) = msg.header().member().map(|m| m.to_string()) else {
+                continue;
+            };
+            let body = msg.body();
+            let request_id = match member.as_str() {
+                "ClipSaved" => body.deserialize::<(SaveReport, u32)>().unwrap().1,
+                "SaveFailed" => body.deserialize::<(String, u32)>().unwrap().1,
+                "SaveCancelled" => body.deserialize::<u32>().unwrap(),
+                _ => continue,
+            };
+            return (member, request_id);
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), next)
+        .await
+        .unwrap()
+}
+
+async fn status(client: &Connection) -> AppStatus {
+    call(client, "GetStatus", &())
+        .await
+        .unwrap()
+        .body()
+        .deserialize()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_run_loop_calls_the_mode_hooks_in_order() {
+    let hooks = Hooks::default();
+    let mode = Box::new(HookMode::new(AppModeDbus::Shadow, &hooks));
+    let dir = tempfile::tempdir().unwrap();
+    let (mut app, client) = start(mode, record_registry(&hooks, false), dir.path()).await;
+
+    let requests = async {
+        call(&client, "Pause", &()).await.unwrap();
+        assert!(status(&client).await.paused);
+        call(&client, "Resume", &()).await.unwrap();
+        call(&client, "ChangeMode", &(AppModeDbus::Record,))
+            .await
+            .unwrap();
+        // The defaults of the mode fill in nothing and refuse what the mode can't do
+        assert_eq!(status(&client).await.mode, "Record Mode");
+        assert!(call(&client, "AddMarker", &("boss",)).await.is_err());
+        assert!(call(&client, "TakeScreenshot", &()).await.is_err());
+        call(&client, "Quit", &()).await.unwrap();
+    };
+    let (result, ()) = tokio::join!(app.run(), requests);
+    result.unwrap();
+
+    assert_eq!(
+        *hooks.lock().unwrap(),
+        [
+            "Shadow init",
+            "Shadow on_pause",
+            "Shadow on_resume",
+            "Shadow on_exit",
+            "Record init",
+            "Record on_shutdown",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_failed_mode_switch_restarts_the_previous_mode() {
+    let hooks = Hooks::default();
+    let mode = Box::new(HookMode::new(AppModeDbus::Shadow, &hooks));
+    let dir = tempfile::tempdir().unwrap();
+    let (mut app, client) = start(mode, record_registry(&hooks, true), dir.path()).await;
+
+    let requests = async {
+        let error = call(&client, "ChangeMode", &(AppModeDbus::Record,))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Record does not start"), "{error}");
+        // The loop keeps serving requests with the previous mode
+        assert_eq!(status(&client).await.mode, "Shadow Capture Mode");
+        call(&client, "Quit", &()).await.unwrap();
+    };
+    let (result, ()) = tokio::join!(app.run(), requests);
+    result.unwrap();
+
+    assert_eq!(
+        *hooks.lock().unwrap(),
+        [
+            "Shadow init",
+            "Shadow on_exit",
+            "Record init",
+            "Record on_exit",
+            "Shadow init",
+            "Shadow on_shutdown",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_save_requests_while_a_slow_save_runs() {
+    let started = Arc::new(Notify::new());
+    let release = Arc::new(Semaphore::new(0));
+    let mode = Box::new(SlowMode {
+        started: Arc::clone(&started),
+        release: Arc::clone(&release),
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let (mut app, client) = start(mode, ModeRegistry::empty(), dir.path()).await;
+    let mut signals = MessageStream::from(&client);
+
+    let requests = async {
+        let (first, status) = save(&client, "SaveClip").await;
+        assert_eq!(status, "accepted");
+        started.notified().await;
+
+        // The run loop is stuck in the first save, the requests meanwhile fold into one
+        let (second, status) = save(&client, "SaveClip").await;
+        assert_eq!(status, "accepted");
+        assert_ne!(second, first);
+        for _ in 0..20 {
+            assert_eq!(
+                save(&client, "SaveClip").await,
+                (second, "busy".to_string())
+            );
+        }
+
+        // Cancelling answers the first request right away instead of leaving it waiting
+        assert!(call(&client, "CancelSave", &())
+            .await
+            .unwrap()
+            .body()
+            .deserialize::<bool>()
+            .unwrap());
+        assert_eq!(
+            next_save_signal(&mut signals).await,
+            ("SaveCancelled".to_string(), first)
+        );
+
+        // Only one save runs for all the folded requests
+        started.notified().await;
+        release.add_permits(1);
+        assert_eq!(
+            next_save_signal(&mut signals).await,
+            ("ClipSaved".to_string(), second)
+        );
+
+        // A windowed save gets an id and a status of its own
+        let (third, status) = save(&client, "SaveClipLast").await;
+        assert_eq!(status, "accepted");
+        assert!(third > second);
+        started.notified().await;
+        release.add_permits(1);
+        assert_eq!(
+            next_save_signal(&mut signals).await,
+            ("ClipSaved".to_string(), third)
+        );
+
+        call(&client, "Quit", &()).await.unwrap();
+    };
+    let (result, ()) = tokio::join!(app.run(), requests);
+    result.unwrap();
+}